@@ -1,5 +1,6 @@
 use std::net::SocketAddr;
 
+use argon2::password_hash::{PasswordHasher, SaltString};
 use argon2::{Argon2, PasswordHash, PasswordVerifier};
 use axum::extract::{ConnectInfo, FromRequestParts, Query, State};
 use axum::http::header::{AUTHORIZATION, USER_AGENT};
@@ -17,7 +18,16 @@ use sha2::{Digest as _, Sha512};
 
 use crate::error::{AppError, AppJson, Result};
 use crate::globals::Globals;
-use crate::model::{Credentials, Items, Session, Token};
+use crate::mail::Mail;
+use crate::model::{Credentials, Items, RequestPasswordReset, ResetPassword, Session, Token};
+use crate::token::MailToken;
+
+/// How long a password reset mail can be used.
+const PASSWORD_RESET_VALID_FOR: TimeDelta = TimeDelta::hours(1);
+
+/// How many unexpired password reset mails a user can have, further requests
+/// are ignored.
+const MAX_PENDING_PASSWORD_RESETS: i64 = 3;
 
 pub fn routes() -> Router<Globals> {
     Router::new()
@@ -25,6 +35,8 @@ pub fn routes() -> Router<Globals> {
         .route("/", delete(end_current_session))
         .route("/session", get(get_sessions))
         .route("/session", delete(end_session))
+        .route("/password-reset", post(request_password_reset))
+        .route("/password-reset/confirm", post(reset_password))
 }
 
 async fn auth(
@@ -77,7 +89,7 @@ async fn auth(
         OsRng.fill_bytes(&mut token);
 
         let mut hasher = Sha512::new();
-        hasher.update(&token);
+        hasher.update(token);
         token_id = hasher.finalize().into();
 
         let res = sqlx::query!(
@@ -116,6 +128,7 @@ async fn auth(
 pub struct AuthUser {
     pub token: [u8; 128],
     pub username: String,
+    pub email_verified: bool,
 }
 
 impl FromRequestParts<Globals> for AuthUser {
@@ -147,7 +160,8 @@ impl FromRequestParts<Globals> for AuthUser {
         let mut tx = globals.db.begin().await?;
 
         let query = sqlx::query!(
-            "select u.username from sessions s join users u on u.username = s.username where s.token = $1",
+            r#"select u.username, u.email_verified_at is not null as "email_verified!"
+            from sessions s join users u on u.username = s.username where s.token = $1"#,
             &token
         );
         let Some(record) = query.fetch_optional(&mut *tx).await? else {
@@ -179,10 +193,34 @@ impl FromRequestParts<Globals> for AuthUser {
         Ok(AuthUser {
             token,
             username: record.username,
+            email_verified: record.email_verified,
         })
     }
 }
 
+/// An [`AuthUser`] who verified their email, needed to create drawings or
+/// reach other users. Unverified users can still sign in and manage their
+/// account.
+#[derive(Debug)]
+pub struct VerifiedUser(pub AuthUser);
+
+impl FromRequestParts<Globals> for VerifiedUser {
+    type Rejection = AppError;
+
+    async fn from_request_parts(
+        parts: &mut Parts,
+        globals: &Globals,
+    ) -> Result<Self, Self::Rejection> {
+        let auth_user = AuthUser::from_request_parts(parts, globals).await?;
+
+        if !auth_user.email_verified {
+            return Err(AppError::EmailNotVerified);
+        }
+
+        Ok(VerifiedUser(auth_user))
+    }
+}
+
 async fn get_sessions(
     State(globals): State<Globals>,
     auth_user: AuthUser,
@@ -252,3 +290,151 @@ async fn end_session(
 
     Ok(StatusCode::NO_CONTENT)
 }
+
+/// Mails a password reset token if the email belongs to a user. Responds the
+/// same either way so the endpoint can't be used to find accounts, which is
+/// why the mail is sent in the background and requests beyond
+/// [`MAX_PENDING_PASSWORD_RESETS`] are silently ignored.
+async fn request_password_reset(
+    State(globals): State<Globals>,
+    AppJson(request): AppJson<RequestPasswordReset>,
+) -> Result<StatusCode> {
+    let mut tx = globals.db.begin().await?;
+
+    let user = sqlx::query!(
+        "select username from users where email = $1 for update",
+        request.email
+    )
+    .fetch_optional(&mut *tx)
+    .await?;
+
+    let Some(user) = user else {
+        return Ok(StatusCode::NO_CONTENT);
+    };
+
+    let now = Utc::now();
+
+    let pending = sqlx::query!(
+        r#"select count(*) as "count!" from password_resets
+        where username = $1 and expires_at > $2"#,
+        user.username,
+        now.naive_utc()
+    )
+    .fetch_one(&mut *tx)
+    .await?
+    .count;
+
+    if pending >= MAX_PENDING_PASSWORD_RESETS {
+        return Ok(StatusCode::NO_CONTENT);
+    }
+
+    let token = MailToken::generate();
+
+    sqlx::query!(
+        "insert into password_resets (token_hash, username, email, created_at, expires_at)
+        values ($1, $2, $3, $4, $5)",
+        &token.hash,
+        user.username,
+        request.email,
+        now.naive_utc(),
+        (now + PASSWORD_RESET_VALID_FOR).naive_utc()
+    )
+    .execute(&mut *tx)
+    .await?;
+
+    tx.commit().await?;
+
+    let mail = Mail {
+        to: request.email,
+        subject: "Reset your password".to_string(),
+        body: format!(
+            "Hi {},\n\n\
+            use the following code to choose a new password, it expires in an hour:\n\n\
+            {}\n\n\
+            If you didn't ask for a new password, you can ignore this mail.",
+            user.username, token.token
+        ),
+    };
+
+    let mailer = globals.mailer.clone();
+
+    tokio::spawn(async move {
+        if let Err(error) = mailer.send(mail).await {
+            tracing::warn!(%error, "failed to send password reset mail");
+        }
+    });
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// Sets a new password with a token from [`request_password_reset`]. Every
+/// session of the user ends, and as the token arrived by mail, the email
+/// counts as verified if it is still the one the token was sent to.
+async fn reset_password(
+    State(globals): State<Globals>,
+    AppJson(reset): AppJson<ResetPassword>,
+) -> Result<StatusCode> {
+    if reset.new_password.trim().is_empty() {
+        return Err(AppError::InvalidData("invalid password".to_string()));
+    }
+
+    let Some(token_hash) = MailToken::hash(&reset.token) else {
+        return Err(AppError::InvalidMailToken);
+    };
+
+    let now = Utc::now().naive_utc();
+
+    let mut tx = globals.db.begin().await?;
+
+    let record = sqlx::query!(
+        "delete from password_resets where token_hash = $1
+        returning username, email, expires_at",
+        &token_hash
+    )
+    .fetch_optional(&mut *tx)
+    .await?;
+
+    let Some(record) = record.filter(|v| v.expires_at > now) else {
+        return Err(AppError::InvalidMailToken);
+    };
+
+    let argon2 = Argon2::default();
+    let salt = SaltString::generate(&mut OsRng);
+    let password_hash = argon2
+        .hash_password(reset.new_password.as_bytes(), &salt)?
+        .serialize();
+
+    sqlx::query!(
+        "update users set password_hash = $1 where username = $2",
+        password_hash.as_str(),
+        record.username
+    )
+    .execute(&mut *tx)
+    .await?;
+
+    // only the address the token was sent to counts as verified
+    sqlx::query!(
+        "update users set email_verified_at = coalesce(email_verified_at, $1)
+        where username = $2 and email = $3",
+        now,
+        record.username,
+        record.email
+    )
+    .execute(&mut *tx)
+    .await?;
+
+    sqlx::query!(
+        "delete from password_resets where username = $1",
+        record.username
+    )
+    .execute(&mut *tx)
+    .await?;
+
+    sqlx::query!("delete from sessions where username = $1", record.username)
+        .execute(&mut *tx)
+        .await?;
+
+    tx.commit().await?;
+
+    Ok(StatusCode::NO_CONTENT)
+}
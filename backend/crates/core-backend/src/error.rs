@@ -33,7 +33,13 @@ pub enum AppError {
     #[error("{0}")]
     EntityNotFound(String),
     #[error("{0}")]
+    Conflict(String),
+    #[error("{0}")]
     Unauthorized(String),
+    #[error("{0}")]
+    NotAcceptable(String),
+    #[error("{0}")]
+    TooManyRequests(String),
     #[error("invalid credentials")]
     InvalidCredentials,
     #[error("auth header missing")]
@@ -42,6 +48,10 @@ pub enum AppError {
     InvalidAuthToken,
     #[error("invalid auth token id")]
     InvalidAuthTokenId,
+    #[error("invalid or expired token")]
+    InvalidMailToken,
+    #[error("email not verified")]
+    EmailNotVerified,
     #[error("invalid json")]
     JsonRejection(#[from] JsonRejection),
     #[error("database error")]
@@ -49,9 +59,25 @@ pub enum AppError {
     #[error("password hashing error")]
     PasswordHashingError(#[from] argon2::password_hash::Error),
     #[error("external service error")]
-    ImageServiceError(#[from] image_backend::ServiceError),
+    ImageServiceError(image_backend::ServiceError),
     #[error(transparent)]
     MultipartError(#[from] MultipartError),
+    #[error("mail delivery error")]
+    MailError(#[from] crate::mail::MailError),
+}
+
+impl From<image_backend::ServiceError> for AppError {
+    fn from(error: image_backend::ServiceError) -> Self {
+        match error {
+            // operation parameters are passed through and validated by the
+            // image service
+            image_backend::ServiceError::Api {
+                code: StatusCode::BAD_REQUEST,
+                message,
+            } => AppError::InvalidData(message),
+            error => AppError::ImageServiceError(error),
+        }
+    }
 }
 
 #[derive(Debug, Clone, serde::Serialize)]
@@ -87,11 +113,16 @@ impl IntoResponse for AppError {
             AppError::InvalidData(_) => StatusCode::BAD_REQUEST,
             AppError::EntityExists(_) => StatusCode::CONFLICT,
             AppError::EntityNotFound(_) => StatusCode::NOT_FOUND,
+            AppError::Conflict(_) => StatusCode::CONFLICT,
             AppError::Unauthorized(_) => StatusCode::UNAUTHORIZED,
+            AppError::NotAcceptable(_) => StatusCode::NOT_ACCEPTABLE,
+            AppError::TooManyRequests(_) => StatusCode::TOO_MANY_REQUESTS,
             AppError::InvalidCredentials => StatusCode::UNAUTHORIZED,
             AppError::AuthHeaderMissing => StatusCode::UNAUTHORIZED,
             AppError::InvalidAuthToken => StatusCode::UNAUTHORIZED,
             AppError::InvalidAuthTokenId => StatusCode::UNAUTHORIZED,
+            AppError::InvalidMailToken => StatusCode::BAD_REQUEST,
+            AppError::EmailNotVerified => StatusCode::FORBIDDEN,
             AppError::JsonRejection(error) => error.status(),
             AppError::MultipartError(error) => error.status(),
             _ => StatusCode::INTERNAL_SERVER_ERROR,
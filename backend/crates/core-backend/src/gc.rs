@@ -0,0 +1,180 @@
+use std::collections::HashSet;
+use std::sync::Arc;
+use std::time::Duration;
+
+use axum::http::StatusCode;
+use chrono::{TimeDelta, Utc};
+use image_backend::model::{DeleteParams, ImageId};
+use image_backend::{ImageService, ServiceError};
+use serde::Serialize;
+use sqlx::{Pool, Postgres};
+use tokio::task::JoinHandle;
+
+use crate::error::Result;
+
+#[derive(Debug, Clone)]
+pub struct GcOptions {
+    /// Images modified more recently than this are never deleted. This
+    /// covers the window between an image being uploaded and the row that
+    /// references it being committed.
+    pub grace_period: TimeDelta,
+    pub dry_run: bool,
+}
+
+impl Default for GcOptions {
+    fn default() -> Self {
+        Self {
+            grace_period: TimeDelta::hours(24),
+            dry_run: false,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct GcReport {
+    pub dry_run: bool,
+    pub stored: usize,
+    pub referenced: usize,
+    /// Images within the grace period, including orphans modified between
+    /// being listed and being deleted.
+    pub skipped_recent: usize,
+    pub orphaned: Vec<ImageId>,
+    pub deleted: Vec<ImageId>,
+    pub failed: Vec<ImageId>,
+    /// Versions left behind by deleted drawings.
+    pub removed_versions: u64,
+}
+
+/// Runs one mark-and-sweep pass over the image service.
+///
+/// The stored images are listed before the references are collected, so an
+/// image that gets referenced while the pass is running is always seen as
+/// live. An orphan uploaded again after the listing has its modification
+/// time refreshed, which the image service checks again when deleting it.
+pub async fn collect_garbage(
+    db: &Pool<Postgres>,
+    image_service: &ImageService,
+    options: &GcOptions,
+) -> Result<GcReport> {
+    let stored = image_service.list_all_images().await?;
+    let referenced = referenced_images(db).await?;
+
+    let threshold = Utc::now() - options.grace_period;
+
+    let mut report = GcReport {
+        dry_run: options.dry_run,
+        stored: stored.len(),
+        referenced: referenced.len(),
+        ..Default::default()
+    };
+
+    for image in stored {
+        if referenced.contains(&image.id.0) {
+            continue;
+        }
+
+        if image.modified_at > threshold {
+            report.skipped_recent += 1;
+            continue;
+        }
+
+        report.orphaned.push(image.id);
+    }
+
+    if options.dry_run {
+        return Ok(report);
+    }
+
+    let params = DeleteParams {
+        unmodified_since: Some(threshold),
+    };
+
+    for id in report.orphaned.clone() {
+        match image_service.delete_image(id.clone(), params).await {
+            Ok(()) => report.deleted.push(id),
+            // uploaded again since it was listed
+            Err(ServiceError::Api {
+                code: StatusCode::PRECONDITION_FAILED,
+                ..
+            }) => report.skipped_recent += 1,
+            Err(error) => {
+                tracing::warn!(%error, id = id.0, "failed to delete orphaned image");
+                report.failed.push(id);
+            }
+        }
+    }
+
+    // cached diffs of deleted images can't be requested anymore
+    let deleted: Vec<String> = report.deleted.iter().map(|id| id.0.clone()).collect();
+
+    sqlx::query!(
+        "delete from image_diffs
+        where image_id = any($1) or other_image_id = any($1) or diff_image_id = any($1)",
+        &deleted
+    )
+    .execute(db)
+    .await?;
+
+    // their images weren't counted as referenced, drop the rows with them
+    report.removed_versions = sqlx::query!(
+        "delete from drawing_versions v
+        where not exists (select 1 from drawings d where d.id = v.drawing_id)"
+    )
+    .execute(db)
+    .await?
+    .rows_affected();
+
+    Ok(report)
+}
+
+/// Versions outlive deleted drawings until the next run, but only the
+/// versions of existing drawings keep their images alive. Cached diff highlights are kept as long
+/// as both images they compare are.
+async fn referenced_images(db: &Pool<Postgres>) -> Result<HashSet<String>> {
+    let records = sqlx::query!(
+        "with referenced as (
+            select image_id as id from drawings
+            union select thumbnail_image_id from drawings
+            union select v.image_id from drawing_versions v
+                join drawings d on d.id = v.drawing_id
+            union select v.thumbnail_image_id from drawing_versions v
+                join drawings d on d.id = v.drawing_id
+        )
+        select id from referenced
+        union select x.diff_image_id from image_diffs x
+        where x.image_id in (select id from referenced)
+            and x.other_image_id in (select id from referenced)"
+    )
+    .fetch_all(db)
+    .await?;
+
+    Ok(records.into_iter().filter_map(|record| record.id).collect())
+}
+
+/// Spawns a task that runs the collector every `interval`.
+pub fn spawn_scheduled(
+    db: Pool<Postgres>,
+    image_service: Arc<ImageService>,
+    interval: Duration,
+    options: GcOptions,
+) -> JoinHandle<()> {
+    tokio::spawn(async move {
+        let mut ticker = tokio::time::interval(interval);
+
+        loop {
+            ticker.tick().await;
+
+            match collect_garbage(&db, &image_service, &options).await {
+                Ok(report) => tracing::info!(
+                    stored = report.stored,
+                    referenced = report.referenced,
+                    deleted = report.deleted.len(),
+                    failed = report.failed.len(),
+                    "garbage collection finished"
+                ),
+                Err(error) => tracing::error!(%error, "garbage collection failed"),
+            }
+        }
+    })
+}
@@ -2,8 +2,11 @@ use std::sync::Arc;
 
 use image_backend::ImageService;
 
+use crate::mail::Mailer;
+
 #[derive(Clone)]
 pub struct Globals {
     pub image_service: Arc<ImageService>,
     pub db: sqlx::Pool<sqlx::Postgres>,
+    pub mailer: Arc<dyn Mailer>,
 }
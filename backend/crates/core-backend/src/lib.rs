@@ -1,8 +1,13 @@
 mod auth;
 mod error;
+pub mod gc;
 mod globals;
+pub mod mail;
 pub mod model;
+mod permission;
 mod resource;
+pub mod thumbnail;
+mod token;
 
 use std::net::SocketAddr;
 use std::sync::Arc;
@@ -20,6 +25,9 @@ use tower_http::trace::TraceLayer;
 
 use crate::error::{AppJson, ErrorResponse};
 use crate::globals::Globals;
+use crate::mail::Mailer;
+
+pub const IMAGE_SERVICE_URL: &str = "http://127.0.0.1:2024";
 
 async fn handle_timeout_error(err: BoxError) -> (StatusCode, AppJson<ErrorResponse>) {
     if err.is::<tower::timeout::error::Elapsed>() {
@@ -35,16 +43,26 @@ async fn handle_timeout_error(err: BoxError) -> (StatusCode, AppJson<ErrorRespon
     )
 }
 
-pub fn build_app(db: Pool<Postgres>) -> IntoMakeServiceWithConnectInfo<Router, SocketAddr> {
+pub fn build_app(
+    db: Pool<Postgres>,
+    mailer: Arc<dyn Mailer>,
+) -> IntoMakeServiceWithConnectInfo<Router, SocketAddr> {
     let globals = Globals {
-        image_service: Arc::new(ImageService::new("http://127.0.0.1:2024".to_string())),
+        image_service: Arc::new(ImageService::new(IMAGE_SERVICE_URL.to_string())),
         db,
+        mailer,
     };
 
     let api = Router::new()
         .nest("/auth", auth::routes())
         .nest("/user", resource::user::routes())
-        .nest("/drawing", resource::drawing::routes());
+        .nest("/drawing", resource::drawing::routes())
+        .nest("/drawing/{id}/permission", resource::permission::routes())
+        .nest("/drawing/{id}/share", resource::share::routes())
+        .nest("/share", resource::share::public_routes())
+        .nest("/drawing/{id}/transfer", resource::transfer::routes())
+        .nest("/transfer", resource::transfer::incoming_routes())
+        .nest("/team", resource::team::routes());
 
     Router::new()
         .nest("/api/v1", api)
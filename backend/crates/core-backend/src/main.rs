@@ -1,3 +1,14 @@
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Duration;
+
+use chrono::TimeDelta;
+use core_backend::IMAGE_SERVICE_URL;
+use core_backend::gc::{self, GcOptions};
+use core_backend::mail::{FileMailer, Mailer, SmtpMailer};
+use core_backend::thumbnail;
+use image_backend::ImageService;
+use sqlx::{Pool, Postgres};
 use tracing_subscriber::layer::SubscriberExt;
 use tracing_subscriber::util::SubscriberInitExt;
 
@@ -12,14 +23,91 @@ async fn main() {
         .with(tracing_subscriber::fmt::layer())
         .init();
 
+    let database_url = std::env::var("DATABASE_URL").unwrap();
+    let db = sqlx::Pool::connect(&database_url).await.unwrap();
+
+    let args: Vec<String> = std::env::args().skip(1).collect();
+
+    match args.first().map(String::as_str) {
+        None | Some("serve") => serve(db).await,
+        Some("gc") => run_gc(db, &args[1..]).await,
+        Some("backfill-thumbnails") => run_backfill_thumbnails(db).await,
+        Some(command) => {
+            eprintln!("unknown command: {command}");
+            eprintln!("usage: core-backend [serve | gc [--dry-run] | backfill-thumbnails]");
+            std::process::exit(2);
+        }
+    }
+}
+
+async fn serve(db: Pool<Postgres>) {
     tracing::info!("Starting core backend");
 
-    let listener = tokio::net::TcpListener::bind("0.0.0.0:2004").await.unwrap();
+    if let Some(interval) = env_secs("GC_INTERVAL_SECS") {
+        let image_service = Arc::new(ImageService::new(IMAGE_SERVICE_URL.to_string()));
+        gc::spawn_scheduled(db.clone(), image_service, interval, gc_options(false));
+    }
 
-    let database_url = std::env::var("DATABASE_URL").unwrap();
-    let db = sqlx::Pool::connect(&database_url).await.unwrap();
+    let listener = tokio::net::TcpListener::bind("0.0.0.0:2004").await.unwrap();
 
-    let app = core_backend::build_app(db);
+    let app = core_backend::build_app(db, mailer());
 
     axum::serve(listener, app).await.unwrap();
 }
+
+async fn run_gc(db: Pool<Postgres>, args: &[String]) {
+    let dry_run = args.iter().any(|arg| arg == "--dry-run");
+    let image_service = ImageService::new(IMAGE_SERVICE_URL.to_string());
+
+    let report = gc::collect_garbage(&db, &image_service, &gc_options(dry_run))
+        .await
+        .unwrap();
+
+    println!("{}", serde_json::to_string_pretty(&report).unwrap());
+}
+
+async fn run_backfill_thumbnails(db: Pool<Postgres>) {
+    let image_service = ImageService::new(IMAGE_SERVICE_URL.to_string());
+
+    let report = thumbnail::backfill_thumbnails(&db, &image_service)
+        .await
+        .unwrap();
+
+    println!("{}", serde_json::to_string_pretty(&report).unwrap());
+
+    if report.failed > 0 {
+        std::process::exit(1);
+    }
+}
+
+/// Delivers mail through `SMTP_URL` as `MAIL_FROM` if set, otherwise writes
+/// it to files below `MAIL_DIR`, `mail` by default.
+fn mailer() -> Arc<dyn Mailer> {
+    if let Ok(url) = std::env::var("SMTP_URL") {
+        let from = std::env::var("MAIL_FROM").unwrap();
+        return Arc::new(SmtpMailer::new(&url, &from).unwrap());
+    }
+
+    let path = std::env::var("MAIL_DIR").unwrap_or_else(|_| "mail".to_string());
+    tracing::warn!(path, "SMTP_URL not set, writing mail to files");
+
+    Arc::new(FileMailer::new(Path::new(&path)))
+}
+
+fn gc_options(dry_run: bool) -> GcOptions {
+    let mut options = GcOptions {
+        dry_run,
+        ..Default::default()
+    };
+
+    if let Some(grace_period) = env_secs("GC_GRACE_PERIOD_SECS") {
+        options.grace_period = TimeDelta::from_std(grace_period).unwrap();
+    }
+
+    options
+}
+
+fn env_secs(name: &str) -> Option<Duration> {
+    let value = std::env::var(name).ok()?;
+    Some(Duration::from_secs(value.parse().unwrap()))
+}
@@ -2,6 +2,7 @@ use std::fmt::{self, Display};
 use std::str::FromStr;
 
 use chrono::{DateTime, Utc};
+use image_backend::model::{Anchor, Background, Region};
 use serde::{Deserialize, Serialize};
 
 #[derive(Debug, Clone, Eq, PartialEq, Serialize, Deserialize)]
@@ -91,6 +92,7 @@ pub struct NewUser {
 pub struct User {
     pub username: String,
     pub email: String,
+    pub email_verified: bool,
     pub favourite_animal: FavouriteAnimal,
 }
 
@@ -109,6 +111,23 @@ pub struct UpdatePassword {
     pub new_password: String,
 }
 
+#[derive(Debug, Clone, Eq, PartialEq, Serialize, Deserialize)]
+pub struct VerifyEmail {
+    pub token: String,
+}
+
+#[derive(Debug, Clone, Eq, PartialEq, Serialize, Deserialize)]
+pub struct RequestPasswordReset {
+    pub email: String,
+}
+
+#[derive(Debug, Clone, Eq, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ResetPassword {
+    pub token: String,
+    pub new_password: String,
+}
+
 #[derive(Debug, Clone, Eq, PartialEq, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub struct Drawing {
@@ -116,25 +135,396 @@ pub struct Drawing {
     pub name: String,
     pub width: i32,
     pub height: i32,
+    pub background: Background,
+    /// Team the drawing belongs to, whose members have access to it.
+    pub team_id: Option<i32>,
+    /// Version shown as the drawing's image. Undo and redo move it along the
+    /// version history, new edits branch off from it.
+    pub current_version_id: i32,
     pub created_at: DateTime<Utc>,
     pub updated_at: DateTime<Utc>,
 }
 
+/// A drawing shared with the user, along with the role they were granted.
 #[derive(Debug, Clone, Eq, PartialEq, Serialize, Deserialize)]
-pub struct NewDrawing {
+#[serde(rename_all = "camelCase")]
+pub struct SharedDrawing {
+    #[serde(flatten)]
+    pub drawing: Drawing,
+    pub role: DrawingRole,
+}
+
+/// Access a user has to a drawing. Each role includes the ones before it.
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Ord, PartialOrd, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DrawingRole {
+    /// Can view the drawing and its history.
+    Viewer,
+    /// Can change the drawing.
+    Editor,
+    /// Can share and delete the drawing.
+    Owner,
+}
+
+impl DrawingRole {
+    pub fn as_str(&self) -> &str {
+        match self {
+            DrawingRole::Viewer => "viewer",
+            DrawingRole::Editor => "editor",
+            DrawingRole::Owner => "owner",
+        }
+    }
+}
+
+impl Display for DrawingRole {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl FromStr for DrawingRole {
+    type Err = InvalidDrawingRole;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "viewer" => Ok(Self::Viewer),
+            "editor" => Ok(Self::Editor),
+            "owner" => Ok(Self::Owner),
+            _ => Err(InvalidDrawingRole(s.to_string())),
+        }
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+#[error("invalid drawing role: {0:?}")]
+pub struct InvalidDrawingRole(pub String);
+
+#[derive(Debug, Clone, Eq, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct DrawingPermission {
+    pub username: String,
+    pub role: DrawingRole,
+    pub created_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Clone, Eq, PartialEq, Serialize, Deserialize)]
+pub struct GrantPermission {
+    pub role: DrawingRole,
+}
+
+/// Role of a team member.
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Ord, PartialOrd, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TeamRole {
+    /// Can view the team's drawings.
+    Viewer,
+    /// Can create and change the team's drawings.
+    Member,
+    /// Can manage members and owns every drawing of the team.
+    Admin,
+}
+
+impl TeamRole {
+    pub fn as_str(&self) -> &str {
+        match self {
+            TeamRole::Viewer => "viewer",
+            TeamRole::Member => "member",
+            TeamRole::Admin => "admin",
+        }
+    }
+
+    /// Role the member has on the team's drawings.
+    pub fn drawing_role(&self) -> DrawingRole {
+        match self {
+            TeamRole::Viewer => DrawingRole::Viewer,
+            TeamRole::Member => DrawingRole::Editor,
+            TeamRole::Admin => DrawingRole::Owner,
+        }
+    }
+}
+
+impl Display for TeamRole {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl FromStr for TeamRole {
+    type Err = InvalidTeamRole;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "viewer" => Ok(Self::Viewer),
+            "member" => Ok(Self::Member),
+            "admin" => Ok(Self::Admin),
+            _ => Err(InvalidTeamRole(s.to_string())),
+        }
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+#[error("invalid team role: {0:?}")]
+pub struct InvalidTeamRole(pub String);
+
+#[derive(Debug, Clone, Eq, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Team {
+    pub id: i32,
+    pub name: String,
+    /// Role of the user the team was loaded for.
+    pub role: TeamRole,
+    pub created_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Clone, Eq, PartialEq, Serialize, Deserialize)]
+pub struct NewTeam {
     pub name: String,
+}
+
+#[derive(Debug, Clone, Eq, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct TeamMember {
+    pub username: String,
+    pub role: TeamRole,
+    pub joined_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Clone, Eq, PartialEq, Serialize, Deserialize)]
+pub struct UpdateTeamMember {
+    pub role: TeamRole,
+}
+
+#[derive(Debug, Clone, Eq, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct TeamInvite {
+    pub team_id: i32,
+    pub team_name: String,
+    pub username: String,
+    /// Role the user gets when accepting the invite.
+    pub role: TeamRole,
+    pub invited_by: String,
+    pub created_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Clone, Eq, PartialEq, Serialize, Deserialize)]
+pub struct NewTeamInvite {
+    pub username: String,
+    pub role: TeamRole,
+}
+
+/// State of a request to hand a drawing to another user.
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TransferStatus {
+    /// Waiting for the recipient.
+    Pending,
+    Accepted,
+    Declined,
+    /// Withdrawn by the owner, or the drawing changed owners in the meantime.
+    Cancelled,
+}
+
+impl TransferStatus {
+    pub fn as_str(&self) -> &str {
+        match self {
+            TransferStatus::Pending => "pending",
+            TransferStatus::Accepted => "accepted",
+            TransferStatus::Declined => "declined",
+            TransferStatus::Cancelled => "cancelled",
+        }
+    }
+}
+
+impl Display for TransferStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl FromStr for TransferStatus {
+    type Err = InvalidTransferStatus;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "pending" => Ok(Self::Pending),
+            "accepted" => Ok(Self::Accepted),
+            "declined" => Ok(Self::Declined),
+            "cancelled" => Ok(Self::Cancelled),
+            _ => Err(InvalidTransferStatus(s.to_string())),
+        }
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+#[error("invalid transfer status: {0:?}")]
+pub struct InvalidTransferStatus(pub String);
+
+#[derive(Debug, Clone, Eq, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct DrawingTransfer {
+    pub id: i32,
+    pub drawing_id: i32,
+    pub drawing_name: String,
+    pub from_user: String,
+    pub to_user: String,
+    pub status: TransferStatus,
+    pub created_at: DateTime<Utc>,
+    pub resolved_at: Option<DateTime<Utc>>,
+}
+
+#[derive(Debug, Clone, Eq, PartialEq, Serialize, Deserialize)]
+pub struct NewDrawingTransfer {
+    pub username: String,
+}
+
+/// An entry in the ownership history of a drawing.
+#[derive(Debug, Clone, Eq, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct OwnerChange {
+    pub previous_owner: String,
+    pub new_owner: String,
+    pub changed_at: DateTime<Utc>,
+    /// Transfer the change was requested with, `None` for automatic
+    /// changes such as the owner leaving the drawing's team.
+    pub transfer_id: Option<i32>,
+}
+
+/// Read-only link to a drawing for people without an account.
+#[derive(Debug, Clone, Eq, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ShareLink {
+    pub id: i32,
+    /// Identifies the link in `/share/{token}`.
+    pub token: String,
+    /// Shared version, `None` if the link follows the current version.
+    pub version_id: Option<i32>,
+    pub has_password: bool,
+    pub created_by: String,
+    pub created_at: DateTime<Utc>,
+    pub expires_at: Option<DateTime<Utc>>,
+    pub last_used_at: Option<DateTime<Utc>>,
+    pub use_count: i32,
+}
+
+#[derive(Default, Debug, Clone, Eq, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct NewShareLink {
+    pub version_id: Option<i32>,
+    pub expires_at: Option<DateTime<Utc>>,
+    /// Has to be sent in the `X-Share-Password` header to use the link.
+    pub password: Option<String>,
+}
+
+/// What a share link shows of a drawing.
+#[derive(Debug, Clone, Eq, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SharedVersion {
+    pub name: String,
+    pub version_id: i32,
     pub width: i32,
     pub height: i32,
+    pub background: Background,
+    pub expires_at: Option<DateTime<Utc>>,
 }
 
 #[derive(Debug, Clone, Eq, PartialEq, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
+pub struct NewDrawing {
+    pub name: String,
+    pub width: i32,
+    pub height: i32,
+    /// Fill colour of the blank canvas, also used when the canvas grows.
+    #[serde(default)]
+    pub background: Background,
+    /// Creates the drawing in a team the user is a member of.
+    pub team_id: Option<i32>,
+}
+
+#[derive(Debug, Clone, Default, Eq, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
 pub struct UpdateDrawing {
     pub name: Option<String>,
     pub width: Option<i32>,
     pub height: Option<i32>,
+    /// Where the current image is placed on the resized canvas, defaults to
+    /// the top left corner.
+    pub anchor: Option<Anchor>,
+    /// Explicit position of the current image on the resized canvas,
+    /// overriding the anchor on that axis.
+    pub offset_x: Option<i32>,
+    pub offset_y: Option<i32>,
+}
+
+/// The change that produced a drawing version.
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum VersionOperation {
+    /// The blank canvas a drawing was created with.
+    Create,
+    /// The image a drawing was imported from.
+    Import,
+    Resize,
+    Upload,
+    Crop,
+    Invert,
+    Blur,
+    Sharpen,
+    Pipeline,
+    /// Brings back the image of an earlier version.
+    Restore,
+    /// Versions recorded before operations were tracked.
+    Unknown,
+}
+
+impl VersionOperation {
+    pub fn as_str(&self) -> &str {
+        match self {
+            VersionOperation::Create => "create",
+            VersionOperation::Import => "import",
+            VersionOperation::Resize => "resize",
+            VersionOperation::Upload => "upload",
+            VersionOperation::Crop => "crop",
+            VersionOperation::Invert => "invert",
+            VersionOperation::Blur => "blur",
+            VersionOperation::Sharpen => "sharpen",
+            VersionOperation::Pipeline => "pipeline",
+            VersionOperation::Restore => "restore",
+            VersionOperation::Unknown => "unknown",
+        }
+    }
+}
+
+impl Display for VersionOperation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl FromStr for VersionOperation {
+    type Err = InvalidVersionOperation;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "create" => Ok(Self::Create),
+            "import" => Ok(Self::Import),
+            "resize" => Ok(Self::Resize),
+            "upload" => Ok(Self::Upload),
+            "crop" => Ok(Self::Crop),
+            "invert" => Ok(Self::Invert),
+            "blur" => Ok(Self::Blur),
+            "sharpen" => Ok(Self::Sharpen),
+            "pipeline" => Ok(Self::Pipeline),
+            "restore" => Ok(Self::Restore),
+            "unknown" => Ok(Self::Unknown),
+            _ => Err(InvalidVersionOperation(s.to_string())),
+        }
+    }
 }
 
+#[derive(Debug, thiserror::Error)]
+#[error("invalid version operation: {0:?}")]
+pub struct InvalidVersionOperation(pub String);
+
 #[derive(Debug, Clone, Eq, PartialEq, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub struct DrawingVersion {
@@ -142,4 +532,29 @@ pub struct DrawingVersion {
     pub width: i32,
     pub height: i32,
     pub created_at: DateTime<Utc>,
+    pub operation: VersionOperation,
+    /// Parameters of the operation as passed to the endpoint, e.g.
+    /// `{"sigma": 10.0, "kind": "fast"}` for a blur.
+    pub parameters: Option<serde_json::Value>,
+    /// User who made the change.
+    pub author: String,
+    /// Version the change was applied to, `None` for the first version of a
+    /// drawing. That is its creation, except for drawings edited before
+    /// creations were recorded, whose history starts at their first edit.
+    pub parent_id: Option<i32>,
+}
+
+/// Pixels changed between two versions of a drawing, aligned at their top
+/// left corners. The highlight image is served next to it at `.../image`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct VersionDiff {
+    pub from_version_id: i32,
+    pub to_version_id: i32,
+    /// Size of the highlight image, large enough for both versions.
+    pub width: i32,
+    pub height: i32,
+    pub changed_pixels: u64,
+    pub percent_changed: f64,
+    pub bounding_box: Option<Region>,
 }
@@ -0,0 +1,159 @@
+use chrono::NaiveDateTime;
+use sqlx::{PgConnection, PgExecutor};
+
+use crate::error::{AppError, Result};
+use crate::model::{Drawing, DrawingRole, TeamRole};
+
+/// A drawing as stored in the database, loaded by [`authorize`].
+pub struct DrawingRecord {
+    pub id: i32,
+    pub name: String,
+    pub owner: String,
+    pub width: i32,
+    pub height: i32,
+    pub background: String,
+    pub team_id: Option<i32>,
+    pub image_id: String,
+    pub thumbnail_image_id: String,
+    pub current_version_id: i32,
+    pub created_at: NaiveDateTime,
+    pub updated_at: NaiveDateTime,
+    /// Role of the user the drawing was loaded for.
+    pub role: DrawingRole,
+}
+
+impl From<DrawingRecord> for Drawing {
+    fn from(record: DrawingRecord) -> Self {
+        Drawing {
+            id: record.id,
+            name: record.name,
+            width: record.width,
+            height: record.height,
+            background: record.background.parse().unwrap_or_default(),
+            team_id: record.team_id,
+            current_version_id: record.current_version_id,
+            created_at: record.created_at.and_utc(),
+            updated_at: record.updated_at.and_utc(),
+        }
+    }
+}
+
+/// Loads a drawing on behalf of `username`, who needs at least `role` on it.
+/// The owner has every role, anyone else the highest of the one granted to
+/// them in `drawing_permissions` and the one following from their role in
+/// the drawing's team.
+pub async fn authorize(
+    executor: impl PgExecutor<'_>,
+    username: &str,
+    id: i32,
+    role: DrawingRole,
+) -> Result<DrawingRecord> {
+    let query = sqlx::query!(
+        r#"select d.*, p.role as "granted_role?", m.role as "team_role?"
+        from drawings d
+        left join drawing_permissions p on p.drawing_id = d.id and p.username = $2
+        left join team_members m on m.team_id = d.team_id and m.username = $2
+        where d.id = $1"#,
+        id,
+        username
+    );
+
+    let Some(record) = query.fetch_optional(executor).await? else {
+        return Err(AppError::EntityNotFound("drawing not found".to_string()));
+    };
+
+    let team_role = record
+        .team_role
+        .and_then(|v| v.parse::<TeamRole>().ok())
+        .map(|v| v.drawing_role());
+
+    let granted_role = if record.owner == username {
+        Some(DrawingRole::Owner)
+    } else {
+        record
+            .granted_role
+            .and_then(|v| v.parse().ok())
+            .max(team_role)
+    };
+
+    let Some(granted_role) = granted_role else {
+        return Err(AppError::Unauthorized(
+            "drawing not shared with the user".to_string(),
+        ));
+    };
+
+    if granted_role < role {
+        return Err(AppError::Unauthorized(format!(
+            "drawing requires the {role} role"
+        )));
+    }
+
+    Ok(DrawingRecord {
+        id: record.id,
+        name: record.name,
+        owner: record.owner,
+        width: record.width,
+        height: record.height,
+        background: record.background,
+        team_id: record.team_id,
+        image_id: record.image_id,
+        thumbnail_image_id: record.thumbnail_image_id,
+        current_version_id: record.current_version_id,
+        created_at: record.created_at,
+        updated_at: record.updated_at,
+        role: granted_role,
+    })
+}
+
+/// Like [`authorize`], for changing the drawing within `tx`. The drawing's row
+/// is locked until the transaction ends, so changes to the same drawing run
+/// one after the other and each one starts from the versions recorded by
+/// the previous one.
+pub async fn authorize_change(
+    tx: &mut PgConnection,
+    username: &str,
+    id: i32,
+    role: DrawingRole,
+) -> Result<DrawingRecord> {
+    sqlx::query!("select id from drawings where id = $1 for update", id)
+        .fetch_optional(&mut *tx)
+        .await?;
+
+    authorize(&mut *tx, username, id, role).await
+}
+
+/// Checks that `username` is a member of a team with at least `role`,
+/// returning their actual role.
+pub async fn authorize_team(
+    executor: impl PgExecutor<'_>,
+    username: &str,
+    team_id: i32,
+    role: TeamRole,
+) -> Result<TeamRole> {
+    let query = sqlx::query!(
+        r#"select t.id, m.role as "role?"
+        from teams t
+        left join team_members m on m.team_id = t.id and m.username = $2
+        where t.id = $1"#,
+        team_id,
+        username
+    );
+
+    let Some(record) = query.fetch_optional(executor).await? else {
+        return Err(AppError::EntityNotFound("team not found".to_string()));
+    };
+
+    let Some(member_role) = record.role.and_then(|v| v.parse::<TeamRole>().ok()) else {
+        return Err(AppError::Unauthorized(
+            "user is not a member of the team".to_string(),
+        ));
+    };
+
+    if member_role < role {
+        return Err(AppError::Unauthorized(format!(
+            "team requires the {role} role"
+        )));
+    }
+
+    Ok(member_role)
+}
@@ -1,35 +1,329 @@
 use axum::Router;
-use axum::extract::{Multipart, Path, Query, State};
-use axum::http::header::{CONTENT_DISPOSITION, CONTENT_TYPE};
-use axum::http::{HeaderMap, StatusCode};
+use axum::body::Body;
+use axum::extract::{DefaultBodyLimit, Multipart, Path, Query, State};
+use axum::http::header::{ACCEPT, CACHE_CONTROL, CONTENT_DISPOSITION, CONTENT_TYPE, VARY};
+use axum::http::{HeaderMap, HeaderValue, StatusCode};
 use axum::routing::{delete, get, patch, post, put};
 use chrono::Utc;
-use image_backend::model::ImageId;
+use image_backend::model::{
+    Background, BlurParams, CanvasParams, DiffParams, DiffResult, ExportParams, ImageId,
+    InvertParams, Pipeline, Region, SharpenParams, UploadResult,
+};
+use percent_encoding::{NON_ALPHANUMERIC, utf8_percent_encode};
 use serde::{Deserialize, Serialize};
+use sqlx::PgConnection;
 
-use crate::auth::AuthUser;
+use crate::auth::{AuthUser, VerifiedUser};
 use crate::error::{AppError, AppJson, Result};
 use crate::globals::Globals;
-use crate::model::{Drawing, DrawingVersion, Items, NewDrawing, UpdateDrawing};
+use crate::model::{
+    Drawing, DrawingRole, DrawingVersion, Items, NewDrawing, SharedDrawing, TeamRole,
+    UpdateDrawing, VersionDiff, VersionOperation,
+};
+use crate::permission::{DrawingRecord, authorize, authorize_change, authorize_team};
+use crate::thumbnail::create_thumbnail;
+
+/// Largest accepted image upload, matching the image service.
+const MAX_IMAGE_SIZE: usize = 10 * 1024 * 1024;
+
+/// Largest width or height of a drawing.
+const MAX_DRAWING_DIMENSION: u32 = 2048;
+
+/// The latest version changes over time, so clients have to revalidate it.
+pub(super) const CACHE_CONTROL_LATEST: &str = "private, no-cache";
+
+/// Versions never change once created.
+const CACHE_CONTROL_VERSION: &str = "private, max-age=31536000, immutable";
 
 pub fn routes() -> Router<Globals> {
     Router::new()
         .route("/", post(create_drawing))
+        .route(
+            "/import",
+            post(import_drawing).layer(DefaultBodyLimit::max(MAX_IMAGE_SIZE)),
+        )
         .route("/owned", get(get_owned_drawings))
+        .route("/shared", get(get_shared_drawings))
         .route("/{id}", get(get_drawing))
         .route("/{id}", patch(update_drawing))
         .route("/{id}", delete(delete_drawing))
+        .route("/{id}/undo", post(undo_drawing))
+        .route("/{id}/redo", post(redo_drawing))
         .route("/{id}/version", get(get_versions))
         .route("/{id}/version/{version_id}", get(get_version))
-        .route("/{id}/version/latest", put(upload_new_version))
+        .route("/{id}/version/{version_id}/restore", post(restore_version))
+        .route(
+            "/{id}/version/{version_id}/diff/{other_version_id}",
+            get(get_version_diff),
+        )
+        .route(
+            "/{id}/version/{version_id}/diff/{other_version_id}/image",
+            get(get_version_diff_image),
+        )
+        .route(
+            "/{id}/version/latest",
+            put(upload_new_version).layer(DefaultBodyLimit::max(MAX_IMAGE_SIZE)),
+        )
         .route("/{id}/version/latest", get(get_latest_version))
+        .route("/{id}/operation/crop", post(crop_drawing))
         .route("/{id}/operation/invert", post(invert_drawing))
         .route("/{id}/operation/blur", post(blur_drawing))
+        .route("/{id}/operation/sharpen", post(sharpen_drawing))
+        .route("/{id}/operation/pipeline", post(apply_pipeline))
+}
+
+/// Describes what produced a new version.
+struct Change<'a> {
+    author: &'a str,
+    operation: VersionOperation,
+    parameters: Option<serde_json::Value>,
+}
+
+impl<'a> Change<'a> {
+    fn new(author: &'a str, operation: VersionOperation) -> Self {
+        Self {
+            author,
+            operation,
+            parameters: None,
+        }
+    }
+
+    fn with_parameters(self, parameters: &impl Serialize) -> Self {
+        Self {
+            parameters: serde_json::to_value(parameters).ok(),
+            ..self
+        }
+    }
+}
+
+/// Makes `upload` the current image of a drawing and records it as its
+/// newest version, along with a fresh thumbnail and the change that
+/// produced it. `base` is the drawing `upload` was made from, as loaded
+/// before calling the image service.
+async fn push_version(
+    globals: &Globals,
+    id: i32,
+    base: &DrawingRecord,
+    upload: &UploadResult,
+    change: Change<'_>,
+) -> Result<()> {
+    let image = version_image(globals, base, upload).await?;
+
+    let mut tx = globals.db.begin().await?;
+
+    lock_unchanged(&mut tx, change.author, id, base).await?;
+    record_version(&mut tx, id, &image, change).await?;
+
+    tx.commit().await?;
+
+    Ok(())
+}
+
+/// Creates the thumbnail of a new image of the drawing in `base`.
+async fn version_image(
+    globals: &Globals,
+    base: &DrawingRecord,
+    upload: &UploadResult,
+) -> Result<VersionImage> {
+    let background = base.background.parse().unwrap_or_default();
+
+    let thumbnail_upload =
+        create_thumbnail(&globals.image_service, upload.id.clone(), background).await?;
+
+    Ok(VersionImage {
+        image_id: upload.id.0.clone(),
+        thumbnail_image_id: thumbnail_upload.id.0,
+        width: upload.width as i32,
+        height: upload.height as i32,
+    })
+}
+
+/// Locks a drawing for a change computed from `base`. The image service is
+/// called before the drawing is locked, so the change is refused if another
+/// one got in first in the meantime.
+async fn lock_unchanged(
+    tx: &mut PgConnection,
+    username: &str,
+    id: i32,
+    base: &DrawingRecord,
+) -> Result<DrawingRecord> {
+    let record = authorize_change(tx, username, id, DrawingRole::Editor).await?;
+
+    if record.current_version_id != base.current_version_id || record.image_id != base.image_id {
+        return Err(AppError::Conflict(
+            "drawing changed in the meantime".to_string(),
+        ));
+    }
+
+    Ok(record)
+}
+
+/// Images and size of a drawing version.
+struct VersionImage {
+    image_id: String,
+    thumbnail_image_id: String,
+    width: i32,
+    height: i32,
+}
+
+/// Makes `image` the current image of a drawing and records it as a new
+/// version derived from the current one. After an undo this starts a new
+/// branch, the undone versions stay in the history. The drawing's row is locked,
+/// so concurrent changes can't pick the same version id or parent.
+async fn record_version(
+    tx: &mut PgConnection,
+    id: i32,
+    image: &VersionImage,
+    change: Change<'_>,
+) -> Result<()> {
+    let record = sqlx::query!(
+        "select
+            current_version_id,
+            (select max(version_id) from drawing_versions where drawing_id = $1) as newest_version_id
+        from drawings
+        where id = $1
+        for update",
+        id
+    )
+    .fetch_one(&mut *tx)
+    .await?;
+
+    let version_id = record.newest_version_id.unwrap_or(0) + 1;
+
+    insert_version(
+        tx,
+        id,
+        version_id,
+        Some(record.current_version_id),
+        image,
+        change,
+    )
+    .await?;
+
+    set_current_version(tx, id, version_id, image).await
+}
+
+async fn insert_version(
+    tx: &mut PgConnection,
+    id: i32,
+    version_id: i32,
+    parent_version_id: Option<i32>,
+    image: &VersionImage,
+    change: Change<'_>,
+) -> Result<()> {
+    sqlx::query!(
+        "insert into drawing_versions (
+            drawing_id, version_id, width, height, image_id, thumbnail_image_id, created_at,
+            operation, parameters, author, parent_version_id)
+        values ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11)",
+        id,
+        version_id,
+        image.width,
+        image.height,
+        image.image_id,
+        image.thumbnail_image_id,
+        Utc::now().naive_utc(),
+        change.operation.as_str(),
+        change.parameters,
+        change.author,
+        parent_version_id
+    )
+    .execute(&mut *tx)
+    .await?;
+
+    Ok(())
+}
+
+/// Points a drawing at one of its versions, whose image becomes the current
+/// one.
+async fn set_current_version(
+    tx: &mut PgConnection,
+    id: i32,
+    version_id: i32,
+    image: &VersionImage,
+) -> Result<()> {
+    sqlx::query!(
+        "update drawings set
+            image_id = $1, thumbnail_image_id = $2, width = $3, height = $4,
+            current_version_id = $5, updated_at = $6
+        where id = $7",
+        image.image_id,
+        image.thumbnail_image_id,
+        image.width,
+        image.height,
+        version_id,
+        Utc::now().naive_utc(),
+        id
+    )
+    .execute(&mut *tx)
+    .await?;
+
+    Ok(())
+}
+
+/// Creates a drawing owned by the author of `change` with `upload` as its
+/// first version.
+async fn insert_drawing(
+    globals: &Globals,
+    name: &str,
+    background: Background,
+    team_id: Option<i32>,
+    upload: &UploadResult,
+    change: Change<'_>,
+) -> Result<Drawing> {
+    let now = Utc::now();
+
+    let thumbnail_upload =
+        create_thumbnail(&globals.image_service, upload.id.clone(), background).await?;
+
+    let image = VersionImage {
+        image_id: upload.id.0.clone(),
+        thumbnail_image_id: thumbnail_upload.id.0,
+        width: upload.width as i32,
+        height: upload.height as i32,
+    };
+
+    let mut tx = globals.db.begin().await?;
+
+    let query = sqlx::query!(
+        "insert into drawings (
+            name, owner, width, height, background, team_id, image_id,
+            thumbnail_image_id, current_version_id, created_at, updated_at)
+        values ($1, $2, $3, $4, $5, $6, $7, $8, 1, $9, $10) returning *",
+        name,
+        change.author,
+        image.width,
+        image.height,
+        background.to_string(),
+        team_id,
+        image.image_id,
+        image.thumbnail_image_id,
+        now.naive_utc(),
+        now.naive_utc(),
+    );
+
+    let record = query.fetch_one(&mut *tx).await?;
+
+    insert_version(&mut tx, record.id, 1, None, &image, change).await?;
+
+    tx.commit().await?;
+
+    Ok(Drawing {
+        id: record.id,
+        name: record.name,
+        width: record.width,
+        height: record.height,
+        background: record.background.parse().unwrap_or_default(),
+        team_id: record.team_id,
+        current_version_id: record.current_version_id,
+        created_at: record.created_at.and_utc(),
+        updated_at: record.updated_at.and_utc(),
+    })
 }
 
 async fn create_drawing(
     State(globals): State<Globals>,
-    auth_user: AuthUser,
+    VerifiedUser(auth_user): VerifiedUser,
     AppJson(new_drawing): AppJson<NewDrawing>,
 ) -> Result<(StatusCode, AppJson<Drawing>)> {
     if new_drawing.name.trim().is_empty() {
@@ -52,49 +346,98 @@ async fn create_drawing(
         return Err(AppError::InvalidData("height too large".to_string()));
     }
 
-    let now = Utc::now();
+    if let Some(team_id) = new_drawing.team_id {
+        authorize_team(&globals.db, &auth_user.username, team_id, TeamRole::Member).await?;
+    }
 
-    let image_id = globals
+    let upload = globals
         .image_service
-        .create_white_image(new_drawing.width as u32, new_drawing.height as u32)
-        .await?
-        .id;
+        .create_blank_image(
+            new_drawing.width as u32,
+            new_drawing.height as u32,
+            new_drawing.background,
+        )
+        .await?;
 
-    let thumbnail_image_id = globals
-        .image_service
-        .resize_image(image_id.clone(), 256, 256)
-        .await?
-        .id;
+    let change =
+        Change::new(&auth_user.username, VersionOperation::Create).with_parameters(&new_drawing);
 
-    let query = sqlx::query!(
-        "insert into drawings (
-            name, owner, width, height, image_id,
-            thumbnail_image_id, created_at, updated_at)
-        values ($1, $2, $3, $4, $5, $6, $7, $8) returning *",
-        new_drawing.name,
-        auth_user.username,
-        new_drawing.width,
-        new_drawing.height,
-        image_id.0,
-        thumbnail_image_id.0,
-        now.naive_utc(),
-        now.naive_utc(),
-    );
+    let drawing = insert_drawing(
+        &globals,
+        &new_drawing.name,
+        new_drawing.background,
+        new_drawing.team_id,
+        &upload,
+        change,
+    )
+    .await?;
+
+    Ok((StatusCode::CREATED, AppJson(drawing)))
+}
 
-    let record = query.fetch_one(&globals.db).await?;
+/// Creates a drawing from an uploaded image in any supported format, sized
+/// to fit the image. Takes an `image` field and optional `name` and `teamId`
+/// fields, the name defaults to the uploaded file's name.
+async fn import_drawing(
+    State(globals): State<Globals>,
+    VerifiedUser(auth_user): VerifiedUser,
+    mut multipart: Multipart,
+) -> Result<(StatusCode, AppJson<Drawing>)> {
+    let mut name = None;
+    let mut team_id = None;
+    let mut image = None;
+
+    while let Some(field) = multipart.next_field().await? {
+        match field.name() {
+            Some("name") => name = Some(field.text().await?),
+            Some("teamId") => {
+                let Ok(id) = field.text().await?.parse() else {
+                    return Err(AppError::InvalidData("invalid team id".to_string()));
+                };
+                team_id = Some(id);
+            }
+            Some("image") => {
+                let file_name = field.file_name().map(|v| match v.rsplit_once('.') {
+                    Some((stem, _)) => stem.to_string(),
+                    None => v.to_string(),
+                });
+                image = Some((file_name, field.bytes().await?.to_vec()));
+            }
+            _ => {}
+        }
+    }
 
-    let drawing = Drawing {
-        id: record.id,
-        name: record.name,
-        width: record.width,
-        height: record.height,
-        created_at: record.created_at.and_utc(),
-        updated_at: record.updated_at.and_utc(),
+    let Some((file_name, data)) = image else {
+        return Err(AppError::InvalidData("no image provided".to_string()));
+    };
+
+    let Some(name) = name.or(file_name).filter(|v| !v.trim().is_empty()) else {
+        return Err(AppError::InvalidData("invalid name".to_string()));
     };
 
+    if let Some(team_id) = team_id {
+        authorize_team(&globals.db, &auth_user.username, team_id, TeamRole::Member).await?;
+    }
+
+    let upload = globals.image_service.import_image(data).await?;
+
+    if upload.width > 2048 {
+        return Err(AppError::InvalidData("width too large".to_string()));
+    }
+
+    if upload.height > 2048 {
+        return Err(AppError::InvalidData("height too large".to_string()));
+    }
+
+    let change = Change::new(&auth_user.username, VersionOperation::Import);
+    let drawing =
+        insert_drawing(&globals, &name, Background::White, team_id, &upload, change).await?;
+
     Ok((StatusCode::CREATED, AppJson(drawing)))
 }
 
+/// Lists the drawings the user owns, both personal ones and the ones in their
+/// teams. All drawings of a team are listed per team.
 async fn get_owned_drawings(
     State(globals): State<Globals>,
     auth_user: AuthUser,
@@ -113,6 +456,9 @@ async fn get_owned_drawings(
             name: record.name,
             width: record.width,
             height: record.height,
+            background: record.background.parse().unwrap_or_default(),
+            team_id: record.team_id,
+            current_version_id: record.current_version_id,
             created_at: record.created_at.and_utc(),
             updated_at: record.updated_at.and_utc(),
         })
@@ -121,42 +467,53 @@ async fn get_owned_drawings(
     Ok(AppJson(Items { items }))
 }
 
-async fn get_drawing(
+/// Lists the drawings other users shared with the user.
+async fn get_shared_drawings(
     State(globals): State<Globals>,
     auth_user: AuthUser,
-    Path(id): Path<i32>,
-) -> Result<AppJson<Drawing>> {
+) -> Result<AppJson<Items<SharedDrawing>>> {
     let query = sqlx::query!(
-        "select
-            name, owner, width, height, image_id,
-            thumbnail_image_id, created_at, updated_at
-        from drawings
-        where id = $1",
-        id
+        "select d.*, p.role
+        from drawings d
+        join drawing_permissions p on p.drawing_id = d.id
+        where p.username = $1
+        order by d.updated_at desc",
+        auth_user.username,
     );
 
-    let Some(record) = query.fetch_optional(&globals.db).await? else {
-        return Err(crate::error::AppError::EntityNotFound(
-            "drawing not found".to_string(),
-        ));
-    };
+    let records = query.fetch_all(&globals.db).await?;
 
-    if auth_user.username != record.owner {
-        return Err(crate::error::AppError::Unauthorized(
-            "drawing not owned by the user".to_string(),
-        ));
-    }
+    let items = records
+        .into_iter()
+        .filter_map(|record| {
+            Some(SharedDrawing {
+                role: record.role.parse().ok()?,
+                drawing: Drawing {
+                    id: record.id,
+                    name: record.name,
+                    width: record.width,
+                    height: record.height,
+                    background: record.background.parse().unwrap_or_default(),
+                    team_id: record.team_id,
+                    current_version_id: record.current_version_id,
+                    created_at: record.created_at.and_utc(),
+                    updated_at: record.updated_at.and_utc(),
+                },
+            })
+        })
+        .collect();
 
-    let drawing = Drawing {
-        id,
-        name: record.name,
-        width: record.width,
-        height: record.height,
-        created_at: record.created_at.and_utc(),
-        updated_at: record.updated_at.and_utc(),
-    };
+    Ok(AppJson(Items { items }))
+}
 
-    Ok(AppJson(drawing))
+async fn get_drawing(
+    State(globals): State<Globals>,
+    auth_user: AuthUser,
+    Path(id): Path<i32>,
+) -> Result<AppJson<Drawing>> {
+    let record = authorize(&globals.db, &auth_user.username, id, DrawingRole::Viewer).await?;
+
+    Ok(AppJson(record.into()))
 }
 
 async fn update_drawing(
@@ -165,81 +522,52 @@ async fn update_drawing(
     Path(id): Path<i32>,
     AppJson(update): AppJson<UpdateDrawing>,
 ) -> Result<AppJson<Drawing>> {
-    let mut tx = globals.db.begin().await?;
+    let base = authorize(&globals.db, &auth_user.username, id, DrawingRole::Editor).await?;
 
-    let query = sqlx::query!("select * from drawings where id = $1", id);
-    let Some(drawing) = query.fetch_optional(&mut *tx).await? else {
-        return Err(crate::error::AppError::EntityNotFound(
-            "drawing not found".to_string(),
-        ));
-    };
+    // the resized image is made before the drawing is locked
+    let mut version = None;
 
-    if auth_user.username != drawing.owner {
-        return Err(crate::error::AppError::Unauthorized(
-            "drawing not owned by the user".to_string(),
-        ));
-    }
-
-    if let Some(new_name) = update.name {
-        sqlx::query!("update drawings set name = $1 where id = $2", new_name, id)
-            .execute(&mut *tx)
-            .await?;
-    }
-
-    if update.width.is_some_and(|v| v != drawing.width)
-        || update.height.is_some_and(|v| v != drawing.height)
+    if update.width.is_some_and(|v| v != base.width)
+        || update.height.is_some_and(|v| v != base.height)
     {
-        let new_width = update.width.unwrap_or(drawing.width);
-        let new_height = update.height.unwrap_or(drawing.height);
+        let new_width = update.width.unwrap_or(base.width);
+        let new_height = update.height.unwrap_or(base.height);
+
+        let params = CanvasParams {
+            width: new_width as u32,
+            height: new_height as u32,
+            background: base.background.parse().unwrap_or_default(),
+            anchor: update.anchor.unwrap_or_default(),
+            x: update.offset_x.map(i64::from),
+            y: update.offset_y.map(i64::from),
+        };
 
         let upload = globals
             .image_service
-            .resize_image_fill(
-                ImageId(drawing.image_id),
-                new_width as u32,
-                new_height as u32,
-            )
+            .resize_canvas(ImageId(base.image_id.clone()), params)
             .await?;
 
-        let thumbnail_upload = globals
-            .image_service
-            .resize_image(upload.id.clone(), 256, 256)
-            .await?;
+        let change =
+            Change::new(&auth_user.username, VersionOperation::Resize).with_parameters(&params);
+        version = Some((version_image(&globals, &base, &upload).await?, change));
+    }
 
-        sqlx::query!(
-            "update drawings set image_id = $1, thumbnail_image_id = $2, width = $3, height = $4, updated_at = $5 where id = $6",
-            upload.id.0,
-            thumbnail_upload.id.0,
-            new_width, new_height,
-            Utc::now().naive_utc(),
-            id
-        )
-        .execute(&mut *tx)
-        .await?;
+    let mut tx = globals.db.begin().await?;
 
-        let num_versions = sqlx::query!(
-            "select count(*) from drawing_versions where drawing_id = $1",
-            id
-        )
-        .fetch_one(&mut *tx)
-        .await?
-        .count
-        .unwrap_or(0);
-
-        sqlx::query!(
-            "insert into drawing_versions (
-                drawing_id, version_id, width, height, image_id, thumbnail_image_id, created_at)
-            values ($1, $2, $3, $4, $5, $6, $7)",
-            id,
-            (num_versions as i32) + 1,
-            new_width,
-            new_height,
-            upload.id.0,
-            thumbnail_upload.id.0,
-            Utc::now().naive_utc()
-        )
-        .execute(&mut *tx)
-        .await?;
+    match version {
+        Some((image, change)) => {
+            lock_unchanged(&mut tx, &auth_user.username, id, &base).await?;
+            record_version(&mut tx, id, &image, change).await?;
+        }
+        None => {
+            authorize_change(&mut tx, &auth_user.username, id, DrawingRole::Editor).await?;
+        }
+    }
+
+    if let Some(new_name) = update.name {
+        sqlx::query!("update drawings set name = $1 where id = $2", new_name, id)
+            .execute(&mut *tx)
+            .await?;
     }
 
     let query = sqlx::query!("select * from drawings where id = $1", id);
@@ -256,6 +584,9 @@ async fn update_drawing(
         name: drawing.name,
         width: drawing.width,
         height: drawing.height,
+        background: drawing.background.parse().unwrap_or_default(),
+        team_id: drawing.team_id,
+        current_version_id: drawing.current_version_id,
         created_at: drawing.created_at.and_utc(),
         updated_at: drawing.updated_at.and_utc(),
     };
@@ -270,18 +601,7 @@ async fn delete_drawing(
 ) -> Result<StatusCode> {
     let mut tx = globals.db.begin().await?;
 
-    let query = sqlx::query!("select owner from drawings where id = $1", id);
-    let Some(record) = query.fetch_optional(&mut *tx).await? else {
-        return Err(crate::error::AppError::EntityNotFound(
-            "drawing not found".to_string(),
-        ));
-    };
-
-    if auth_user.username != record.owner {
-        return Err(crate::error::AppError::Unauthorized(
-            "drawing not owned by the user".to_string(),
-        ));
-    }
+    authorize_change(&mut tx, &auth_user.username, id, DrawingRole::Owner).await?;
 
     let query = sqlx::query!("delete from drawings where id = $1", id);
     query.execute(&mut *tx).await?;
@@ -291,28 +611,103 @@ async fn delete_drawing(
     Ok(StatusCode::NO_CONTENT)
 }
 
-async fn get_versions(
+/// Moves a drawing back to the version its current one was derived from.
+/// Nothing is deleted, the undone version can be brought back with a redo.
+/// The first version has nothing to go back to, for drawings edited before
+/// creations were recorded that is their first edit.
+async fn undo_drawing(
     State(globals): State<Globals>,
     auth_user: AuthUser,
     Path(id): Path<i32>,
-) -> Result<AppJson<Items<DrawingVersion>>> {
+) -> Result<StatusCode> {
     let mut tx = globals.db.begin().await?;
 
-    let query = sqlx::query!("select owner from drawings where id = $1", id);
-    let Some(record) = query.fetch_optional(&mut *tx).await? else {
-        return Err(crate::error::AppError::EntityNotFound(
-            "drawing not found".to_string(),
-        ));
+    let record = authorize_change(&mut tx, &auth_user.username, id, DrawingRole::Editor).await?;
+
+    let parent_version_id = sqlx::query!(
+        "select parent_version_id from drawing_versions where drawing_id = $1 and version_id = $2",
+        id,
+        record.current_version_id
+    )
+    .fetch_one(&mut *tx)
+    .await?
+    .parent_version_id;
+
+    let Some(version_id) = parent_version_id else {
+        return Err(AppError::Conflict("nothing to undo".to_string()));
     };
 
-    if auth_user.username != record.owner {
-        return Err(crate::error::AppError::Unauthorized(
-            "drawing not owned by the user".to_string(),
-        ));
-    }
+    move_to_version(&mut tx, id, version_id).await?;
+
+    tx.commit().await?;
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// Moves a drawing forward to the newest version derived from its current
+/// one, i.e. the most recent branch when edits were made after an undo.
+async fn redo_drawing(
+    State(globals): State<Globals>,
+    auth_user: AuthUser,
+    Path(id): Path<i32>,
+) -> Result<StatusCode> {
+    let mut tx = globals.db.begin().await?;
+
+    let record = authorize_change(&mut tx, &auth_user.username, id, DrawingRole::Editor).await?;
+
+    let query = sqlx::query!(
+        "select max(version_id) from drawing_versions
+        where drawing_id = $1 and parent_version_id = $2",
+        id,
+        record.current_version_id
+    );
+
+    let Some(version_id) = query.fetch_one(&mut *tx).await?.max else {
+        return Err(AppError::Conflict("nothing to redo".to_string()));
+    };
+
+    move_to_version(&mut tx, id, version_id).await?;
+
+    tx.commit().await?;
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+async fn move_to_version(tx: &mut PgConnection, id: i32, version_id: i32) -> Result<()> {
+    let record = sqlx::query!(
+        "select * from drawing_versions where drawing_id = $1 and version_id = $2",
+        id,
+        version_id
+    )
+    .fetch_one(&mut *tx)
+    .await?;
+
+    let image = VersionImage {
+        image_id: record.image_id,
+        thumbnail_image_id: record.thumbnail_image_id,
+        width: record.width,
+        height: record.height,
+    };
+
+    set_current_version(tx, id, version_id, &image).await
+}
 
+async fn get_versions(
+    State(globals): State<Globals>,
+    auth_user: AuthUser,
+    Path(id): Path<i32>,
+) -> Result<AppJson<Items<DrawingVersion>>> {
+    let mut tx = globals.db.begin().await?;
+
+    authorize(&mut *tx, &auth_user.username, id, DrawingRole::Viewer).await?;
+
+    // only versions of deleted drawings lack an author
     let rows = sqlx::query!(
-        "select * from drawing_versions where drawing_id = $1 order by created_at desc",
+        r#"select version_id, width, height, created_at, operation, parameters,
+            author as "author!", parent_version_id
+        from drawing_versions
+        where drawing_id = $1
+        order by created_at desc"#,
         id
     )
     .fetch_all(&mut *tx)
@@ -327,6 +722,13 @@ async fn get_versions(
             width: record.width,
             height: record.height,
             created_at: record.created_at.and_utc(),
+            operation: record
+                .operation
+                .parse()
+                .unwrap_or(VersionOperation::Unknown),
+            parameters: record.parameters,
+            author: record.author,
+            parent_id: record.parent_version_id,
         })
         .collect();
 
@@ -343,100 +745,42 @@ async fn upload_new_version(
         return Err(AppError::InvalidData("no image provided".to_string()));
     };
 
-    if field.content_type() != Some("image/png") {
-        return Err(AppError::InvalidData("unsupported image type".to_string()));
-    }
-
-    let mut tx = globals.db.begin().await?;
-
-    let query = sqlx::query!("select * from drawings where id = $1", id);
-    let Some(record) = query.fetch_optional(&mut *tx).await? else {
-        return Err(crate::error::AppError::EntityNotFound(
-            "drawing not found".to_string(),
-        ));
-    };
-
-    if auth_user.username != record.owner {
-        return Err(crate::error::AppError::Unauthorized(
-            "drawing not owned by the user".to_string(),
-        ));
-    }
+    let base = authorize(&globals.db, &auth_user.username, id, DrawingRole::Editor).await?;
 
+    // read before locking the drawing, a slow upload mustn't hold up others
     let data = field.bytes().await?.to_vec();
 
     let upload = globals
         .image_service
-        .create_image(record.width as u32, record.height as u32, data)
-        .await?;
-
-    let thumbnail_upload = globals
-        .image_service
-        .resize_image(upload.id.clone(), 256, 256)
+        .create_image(base.width as u32, base.height as u32, data)
         .await?;
 
-    sqlx::query!(
-        "update drawings set image_id = $1, thumbnail_image_id = $2, updated_at = $3 where id = $4",
-        upload.id.0,
-        thumbnail_upload.id.0,
-        Utc::now().naive_utc(),
-        id
-    )
-    .execute(&mut *tx)
-    .await?;
-
-    let num_versions = sqlx::query!(
-        "select count(*) from drawing_versions where drawing_id = $1",
-        id
-    )
-    .fetch_one(&mut *tx)
-    .await?
-    .count
-    .unwrap_or(0);
-
-    sqlx::query!(
-        "insert into drawing_versions (
-            drawing_id, version_id, width, height, image_id, thumbnail_image_id, created_at)
-        values ($1, $2, $3, $4, $5, $6, $7)",
-        id,
-        (num_versions as i32) + 1,
-        record.width,
-        record.height,
-        upload.id.0,
-        thumbnail_upload.id.0,
-        Utc::now().naive_utc()
-    )
-    .execute(&mut *tx)
-    .await?;
-
-    tx.commit().await?;
+    let change = Change::new(&auth_user.username, VersionOperation::Upload);
+    push_version(&globals, id, &base, &upload, change).await?;
 
     Ok(StatusCode::NO_CONTENT)
 }
 
-#[derive(Debug, Clone, Eq, PartialEq, Serialize, Deserialize)]
-struct GetLatestVersionQuery {
+/// Query of the endpoints downloading a drawing's image.
+#[derive(Debug, Clone, Default, Eq, PartialEq, Serialize, Deserialize)]
+pub(super) struct DownloadQuery {
+    /// Serves the thumbnail instead of the full image.
     #[serde(default)]
-    thumbnail: bool,
+    pub thumbnail: bool,
+    #[serde(flatten)]
+    pub export: ExportParams,
 }
 
 async fn get_latest_version(
     State(globals): State<Globals>,
     auth_user: AuthUser,
     Path(id): Path<i32>,
-    Query(query_params): Query<GetLatestVersionQuery>,
-) -> Result<(HeaderMap, Vec<u8>)> {
-    let query = sqlx::query!("select * from drawings where id = $1", id);
-    let Some(record) = query.fetch_optional(&globals.db).await? else {
-        return Err(crate::error::AppError::EntityNotFound(
-            "drawing not found".to_string(),
-        ));
-    };
+    Query(query_params): Query<DownloadQuery>,
+    request_headers: HeaderMap,
+) -> Result<(StatusCode, HeaderMap, Body)> {
+    let record = authorize(&globals.db, &auth_user.username, id, DrawingRole::Viewer).await?;
 
-    if auth_user.username != record.owner {
-        return Err(crate::error::AppError::Unauthorized(
-            "drawing not owned by the user".to_string(),
-        ));
-    }
+    let name = record.name;
 
     let id = if query_params.thumbnail {
         record.thumbnail_image_id
@@ -444,42 +788,27 @@ async fn get_latest_version(
         record.image_id
     };
 
-    let image = globals.image_service.get_image(ImageId(id)).await?;
-
-    let mut headers = HeaderMap::new();
-    headers.insert(CONTENT_TYPE, "image/png".parse().unwrap());
-    headers.insert(
-        CONTENT_DISPOSITION,
-        "attachment; filename=\"drawing.png\"".parse().unwrap(),
-    );
-
-    Ok((headers, image))
-}
-
-#[derive(Debug, Clone, Eq, PartialEq, Serialize, Deserialize)]
-struct GetVersionQuery {
-    #[serde(default)]
-    thumbnail: bool,
+    download_image(
+        &globals,
+        &request_headers,
+        &name,
+        ImageId(id),
+        query_params.export,
+        CACHE_CONTROL_LATEST,
+    )
+    .await
 }
 
 async fn get_version(
     State(globals): State<Globals>,
     auth_user: AuthUser,
     Path((id, version_id)): Path<(i32, i32)>,
-    Query(query_params): Query<GetLatestVersionQuery>,
-) -> Result<(HeaderMap, Vec<u8>)> {
-    let query = sqlx::query!("select * from drawings where id = $1", id);
-    let Some(record) = query.fetch_optional(&globals.db).await? else {
-        return Err(crate::error::AppError::EntityNotFound(
-            "drawing not found".to_string(),
-        ));
-    };
+    Query(query_params): Query<DownloadQuery>,
+    request_headers: HeaderMap,
+) -> Result<(StatusCode, HeaderMap, Body)> {
+    let record = authorize(&globals.db, &auth_user.username, id, DrawingRole::Viewer).await?;
 
-    if auth_user.username != record.owner {
-        return Err(crate::error::AppError::Unauthorized(
-            "drawing not owned by the user".to_string(),
-        ));
-    }
+    let name = record.name;
 
     let query = sqlx::query!(
         "select * from drawing_versions where drawing_id = $1 and version_id = $2",
@@ -499,152 +828,370 @@ async fn get_version(
         record.image_id
     };
 
-    let image = globals.image_service.get_image(ImageId(id)).await?;
-
-    let mut headers = HeaderMap::new();
-    headers.insert(CONTENT_TYPE, "image/png".parse().unwrap());
-    headers.insert(
-        CONTENT_DISPOSITION,
-        "attachment; filename=\"drawing.png\"".parse().unwrap(),
-    );
-
-    Ok((headers, image))
+    download_image(
+        &globals,
+        &request_headers,
+        &name,
+        ImageId(id),
+        query_params.export,
+        CACHE_CONTROL_VERSION,
+    )
+    .await
 }
 
-async fn invert_drawing(
+/// Makes an earlier version current again by recording it as a new version.
+/// Images are content-addressed, so nothing has to be copied.
+async fn restore_version(
     State(globals): State<Globals>,
     auth_user: AuthUser,
-    Path(id): Path<i32>,
+    Path((id, version_id)): Path<(i32, i32)>,
 ) -> Result<StatusCode> {
     let mut tx = globals.db.begin().await?;
 
-    let query = sqlx::query!("select * from drawings where id = $1", id);
+    authorize_change(&mut tx, &auth_user.username, id, DrawingRole::Editor).await?;
+
+    let query = sqlx::query!(
+        "select * from drawing_versions where drawing_id = $1 and version_id = $2",
+        id,
+        version_id
+    );
+
     let Some(record) = query.fetch_optional(&mut *tx).await? else {
         return Err(crate::error::AppError::EntityNotFound(
-            "drawing not found".to_string(),
+            "version not found".to_string(),
         ));
     };
 
-    if auth_user.username != record.owner {
-        return Err(crate::error::AppError::Unauthorized(
-            "drawing not owned by the user".to_string(),
-        ));
+    let image = VersionImage {
+        image_id: record.image_id,
+        thumbnail_image_id: record.thumbnail_image_id,
+        width: record.width,
+        height: record.height,
+    };
+
+    let change = Change::new(&auth_user.username, VersionOperation::Restore)
+        .with_parameters(&serde_json::json!({ "versionId": version_id }));
+
+    record_version(&mut tx, id, &image, change).await?;
+
+    tx.commit().await?;
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// Compares two versions of a drawing the user can view, returning the
+/// drawing's name along with the result. Diffs are cached by the pair of
+/// images compared, the highlight image is only stored once `highlight`
+/// asks for it.
+async fn diff_versions(
+    globals: &Globals,
+    auth_user: &AuthUser,
+    id: i32,
+    version_id: i32,
+    other_version_id: i32,
+    highlight: bool,
+) -> Result<(String, DiffResult)> {
+    let record = authorize(&globals.db, &auth_user.username, id, DrawingRole::Viewer).await?;
+
+    let query = sqlx::query!(
+        "select version_id, image_id from drawing_versions
+        where drawing_id = $1 and version_id in ($2, $3)",
+        id,
+        version_id,
+        other_version_id
+    );
+
+    let versions = query.fetch_all(&globals.db).await?;
+
+    let version_image_id = |version_id: i32| {
+        versions
+            .iter()
+            .find(|v| v.version_id == version_id)
+            .map(|v| ImageId(v.image_id.clone()))
+            .ok_or_else(|| AppError::EntityNotFound("version not found".to_string()))
+    };
+
+    let image_id = version_image_id(version_id)?;
+    let other_image_id = version_image_id(other_version_id)?;
+
+    let query = sqlx::query!(
+        "select width, height, changed_pixels, percent_changed, bounding_box, diff_image_id
+        from image_diffs
+        where image_id = $1 and other_image_id = $2",
+        image_id.0,
+        other_image_id.0
+    );
+
+    let cached = query.fetch_optional(&globals.db).await?;
+
+    if let Some(cached) = cached.filter(|v| !highlight || v.diff_image_id.is_some()) {
+        let diff = DiffResult {
+            id: cached.diff_image_id.map(ImageId),
+            width: cached.width as u32,
+            height: cached.height as u32,
+            changed_pixels: cached.changed_pixels as u64,
+            percent_changed: cached.percent_changed,
+            bounding_box: cached
+                .bounding_box
+                .and_then(|v| serde_json::from_value(v).ok()),
+        };
+
+        return Ok((record.name, diff));
     }
 
-    let upload = globals
-        .image_service
-        .invert_image(ImageId(record.image_id))
-        .await?;
+    let params = DiffParams {
+        stats_only: !highlight,
+    };
 
-    let thumbnail_upload = globals
+    let diff = globals
         .image_service
-        .resize_image(upload.id.clone(), 256, 256)
+        .diff_images(image_id.clone(), other_image_id.clone(), params)
         .await?;
 
     sqlx::query!(
-        "update drawings set image_id = $1, thumbnail_image_id = $2, updated_at = $3 where id = $4",
-        upload.id.0,
-        thumbnail_upload.id.0,
-        Utc::now().naive_utc(),
-        id
+        "insert into image_diffs (
+            image_id, other_image_id, width, height, changed_pixels, percent_changed,
+            bounding_box, diff_image_id)
+        values ($1, $2, $3, $4, $5, $6, $7, $8)
+        on conflict (image_id, other_image_id) do update
+        set diff_image_id = coalesce(excluded.diff_image_id, image_diffs.diff_image_id)",
+        image_id.0,
+        other_image_id.0,
+        diff.width as i32,
+        diff.height as i32,
+        diff.changed_pixels as i64,
+        diff.percent_changed,
+        diff.bounding_box.and_then(|v| serde_json::to_value(v).ok()),
+        diff.id.as_ref().map(|v| v.0.clone())
     )
-    .execute(&mut *tx)
+    .execute(&globals.db)
     .await?;
 
-    let num_versions = sqlx::query!(
-        "select count(*) from drawing_versions where drawing_id = $1",
-        id
-    )
-    .fetch_one(&mut *tx)
-    .await?
-    .count
-    .unwrap_or(0);
+    Ok((record.name, diff))
+}
 
-    sqlx::query!(
-        "insert into drawing_versions (
-            drawing_id, version_id, width, height, image_id, thumbnail_image_id, created_at)
-        values ($1, $2, $3, $4, $5, $6, $7)",
+/// Reports which pixels changed from `version_id` to `other_version_id`.
+async fn get_version_diff(
+    State(globals): State<Globals>,
+    auth_user: AuthUser,
+    Path((id, version_id, other_version_id)): Path<(i32, i32, i32)>,
+) -> Result<AppJson<VersionDiff>> {
+    let (_, diff) = diff_versions(
+        &globals,
+        &auth_user,
         id,
-        (num_versions as i32) + 1,
-        record.width,
-        record.height,
-        upload.id.0,
-        thumbnail_upload.id.0,
-        Utc::now().naive_utc()
+        version_id,
+        other_version_id,
+        false,
     )
-    .execute(&mut *tx)
     .await?;
 
-    tx.commit().await?;
+    Ok(AppJson(VersionDiff {
+        from_version_id: version_id,
+        to_version_id: other_version_id,
+        width: diff.width as i32,
+        height: diff.height as i32,
+        changed_pixels: diff.changed_pixels,
+        percent_changed: diff.percent_changed,
+        bounding_box: diff.bounding_box,
+    }))
+}
+
+/// Serves the highlight image of [`get_version_diff`]. Versions never
+/// change, so neither does their diff.
+async fn get_version_diff_image(
+    State(globals): State<Globals>,
+    auth_user: AuthUser,
+    Path((id, version_id, other_version_id)): Path<(i32, i32, i32)>,
+    Query(params): Query<ExportParams>,
+    request_headers: HeaderMap,
+) -> Result<(StatusCode, HeaderMap, Body)> {
+    let (name, diff) =
+        diff_versions(&globals, &auth_user, id, version_id, other_version_id, true).await?;
+
+    let Some(diff_image_id) = diff.id else {
+        return Err(AppError::Internal("diff image missing".to_string()));
+    };
+
+    download_image(
+        &globals,
+        &request_headers,
+        &format!("{name}-diff-{version_id}-{other_version_id}"),
+        diff_image_id,
+        params,
+        CACHE_CONTROL_VERSION,
+    )
+    .await
+}
+
+/// Fetches an image in the format requested by `params` or the `Accept`
+/// header, named after the drawing. Conditional requests are passed on to
+/// the image service, whose ETags identify the image content.
+pub(super) async fn download_image(
+    globals: &Globals,
+    request_headers: &HeaderMap,
+    name: &str,
+    id: ImageId,
+    params: ExportParams,
+    cache_control: &'static str,
+) -> Result<(StatusCode, HeaderMap, Body)> {
+    let accept = request_headers
+        .get(ACCEPT)
+        .map(|v| v.to_str().unwrap_or_default());
+
+    let Some(format) = params.resolve_format(accept) else {
+        return Err(AppError::NotAcceptable("no acceptable format".to_string()));
+    };
+
+    let negotiated = params.format.is_none();
+
+    let params = ExportParams {
+        format: Some(format),
+        ..params
+    };
+
+    let image = globals
+        .image_service
+        .export_image(id, params, request_headers)
+        .await?;
+
+    let mut headers = image.headers;
+    headers.insert(CACHE_CONTROL, HeaderValue::from_static(cache_control));
+
+    if negotiated {
+        headers.insert(VARY, ACCEPT.into());
+    }
+
+    if !image.status.is_success() {
+        return Ok((image.status, headers, image.body));
+    }
+
+    let file_name = format!("{name}.{}", format.extension());
+
+    // plain fallback for clients without support for `filename*`
+    let fallback: String = file_name
+        .chars()
+        .map(|c| match c {
+            'a'..='z' | 'A'..='Z' | '0'..='9' | ' ' | '.' | '-' | '_' => c,
+            _ => '_',
+        })
+        .collect();
+
+    let content_disposition = format!(
+        "attachment; filename=\"{fallback}\"; filename*=UTF-8''{}",
+        utf8_percent_encode(&file_name, NON_ALPHANUMERIC)
+    );
+
+    headers.insert(CONTENT_TYPE, format.mime_type().parse().unwrap());
+    headers.insert(CONTENT_DISPOSITION, content_disposition.parse().unwrap());
+
+    Ok((image.status, headers, image.body))
+}
+
+async fn crop_drawing(
+    State(globals): State<Globals>,
+    auth_user: AuthUser,
+    Path(id): Path<i32>,
+    Query(region): Query<Region>,
+) -> Result<StatusCode> {
+    let base = authorize(&globals.db, &auth_user.username, id, DrawingRole::Editor).await?;
+
+    let upload = globals
+        .image_service
+        .crop_image(ImageId(base.image_id.clone()), region)
+        .await?;
+
+    let change = Change::new(&auth_user.username, VersionOperation::Crop).with_parameters(&region);
+    push_version(&globals, id, &base, &upload, change).await?;
 
     Ok(StatusCode::NO_CONTENT)
 }
 
-async fn blur_drawing(
+async fn invert_drawing(
     State(globals): State<Globals>,
     auth_user: AuthUser,
     Path(id): Path<i32>,
+    Query(params): Query<InvertParams>,
 ) -> Result<StatusCode> {
-    let mut tx = globals.db.begin().await?;
+    let base = authorize(&globals.db, &auth_user.username, id, DrawingRole::Editor).await?;
 
-    let query = sqlx::query!("select * from drawings where id = $1", id);
-    let Some(record) = query.fetch_optional(&mut *tx).await? else {
-        return Err(crate::error::AppError::EntityNotFound(
-            "drawing not found".to_string(),
-        ));
-    };
+    let upload = globals
+        .image_service
+        .invert_image(ImageId(base.image_id.clone()), params)
+        .await?;
 
-    if auth_user.username != record.owner {
-        return Err(crate::error::AppError::Unauthorized(
-            "drawing not owned by the user".to_string(),
-        ));
-    }
+    let change =
+        Change::new(&auth_user.username, VersionOperation::Invert).with_parameters(&params);
+    push_version(&globals, id, &base, &upload, change).await?;
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+async fn blur_drawing(
+    State(globals): State<Globals>,
+    auth_user: AuthUser,
+    Path(id): Path<i32>,
+    Query(params): Query<BlurParams>,
+) -> Result<StatusCode> {
+    let base = authorize(&globals.db, &auth_user.username, id, DrawingRole::Editor).await?;
 
     let upload = globals
         .image_service
-        .blur_image(ImageId(record.image_id))
+        .blur_image(ImageId(base.image_id.clone()), params)
         .await?;
 
-    let thumbnail_upload = globals
+    let change = Change::new(&auth_user.username, VersionOperation::Blur).with_parameters(&params);
+    push_version(&globals, id, &base, &upload, change).await?;
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+async fn sharpen_drawing(
+    State(globals): State<Globals>,
+    auth_user: AuthUser,
+    Path(id): Path<i32>,
+    Query(params): Query<SharpenParams>,
+) -> Result<StatusCode> {
+    let base = authorize(&globals.db, &auth_user.username, id, DrawingRole::Editor).await?;
+
+    let upload = globals
         .image_service
-        .resize_image(upload.id.clone(), 256, 256)
+        .sharpen_image(ImageId(base.image_id.clone()), params)
         .await?;
 
-    sqlx::query!(
-        "update drawings set image_id = $1, thumbnail_image_id = $2, updated_at = $3 where id = $4",
-        upload.id.0,
-        thumbnail_upload.id.0,
-        Utc::now().naive_utc(),
-        id
-    )
-    .execute(&mut *tx)
-    .await?;
+    let change =
+        Change::new(&auth_user.username, VersionOperation::Sharpen).with_parameters(&params);
+    push_version(&globals, id, &base, &upload, change).await?;
 
-    let num_versions = sqlx::query!(
-        "select count(*) from drawing_versions where drawing_id = $1",
-        id
-    )
-    .fetch_one(&mut *tx)
-    .await?
-    .count
-    .unwrap_or(0);
+    Ok(StatusCode::NO_CONTENT)
+}
 
-    sqlx::query!(
-        "insert into drawing_versions (
-            drawing_id, version_id, width, height, image_id, thumbnail_image_id, created_at)
-        values ($1, $2, $3, $4, $5, $6, $7)",
-        id,
-        (num_versions as i32) + 1,
-        record.width,
-        record.height,
-        upload.id.0,
-        thumbnail_upload.id.0,
-        Utc::now().naive_utc()
-    )
-    .execute(&mut *tx)
-    .await?;
+async fn apply_pipeline(
+    State(globals): State<Globals>,
+    auth_user: AuthUser,
+    Path(id): Path<i32>,
+    AppJson(pipeline): AppJson<Pipeline>,
+) -> Result<StatusCode> {
+    if pipeline.operations.is_empty() {
+        return Err(AppError::InvalidData("no operations provided".to_string()));
+    }
 
-    tx.commit().await?;
+    let base = authorize(&globals.db, &auth_user.username, id, DrawingRole::Editor).await?;
+
+    // every image along the way is kept within the size of a drawing, so a
+    // pipeline growing the image is refused before the work is done
+    let upload = globals
+        .image_service
+        .apply_pipeline(
+            ImageId(base.image_id.clone()),
+            &pipeline,
+            MAX_DRAWING_DIMENSION,
+        )
+        .await?;
+
+    let change =
+        Change::new(&auth_user.username, VersionOperation::Pipeline).with_parameters(&pipeline);
+    push_version(&globals, id, &base, &upload, change).await?;
 
     Ok(StatusCode::NO_CONTENT)
 }
@@ -1,2 +1,6 @@
 pub mod user;
 pub mod drawing;
+pub mod permission;
+pub mod share;
+pub mod team;
+pub mod transfer;
@@ -0,0 +1,137 @@
+use axum::Router;
+use axum::extract::{Path, State};
+use axum::http::StatusCode;
+use axum::routing::{delete, get, put};
+use chrono::Utc;
+
+use crate::auth::{AuthUser, VerifiedUser};
+use crate::error::{AppError, AppJson, Result};
+use crate::globals::Globals;
+use crate::model::{DrawingPermission, DrawingRole, GrantPermission, Items};
+use crate::permission::authorize;
+
+pub fn routes() -> Router<Globals> {
+    Router::new()
+        .route("/", get(get_permissions))
+        .route("/{username}", put(grant_permission))
+        .route("/{username}", delete(revoke_permission))
+}
+
+/// Lists everyone with access to a drawing, starting with its owner.
+async fn get_permissions(
+    State(globals): State<Globals>,
+    auth_user: AuthUser,
+    Path(id): Path<i32>,
+) -> Result<AppJson<Items<DrawingPermission>>> {
+    let mut tx = globals.db.begin().await?;
+
+    let drawing = authorize(&mut *tx, &auth_user.username, id, DrawingRole::Viewer).await?;
+
+    let records = sqlx::query!(
+        "select username, role, created_at from drawing_permissions
+        where drawing_id = $1
+        order by created_at",
+        id
+    )
+    .fetch_all(&mut *tx)
+    .await?;
+
+    tx.commit().await?;
+
+    let owner = DrawingPermission {
+        username: drawing.owner,
+        role: DrawingRole::Owner,
+        created_at: drawing.created_at.and_utc(),
+    };
+
+    let granted = records.into_iter().filter_map(|record| {
+        Some(DrawingPermission {
+            username: record.username,
+            role: record.role.parse().ok()?,
+            created_at: record.created_at.and_utc(),
+        })
+    });
+
+    let items = std::iter::once(owner).chain(granted).collect();
+
+    Ok(AppJson(Items { items }))
+}
+
+/// Gives a user a role on a drawing, replacing the one they had.
+async fn grant_permission(
+    State(globals): State<Globals>,
+    VerifiedUser(auth_user): VerifiedUser,
+    Path((id, username)): Path<(i32, String)>,
+    AppJson(grant): AppJson<GrantPermission>,
+) -> Result<AppJson<DrawingPermission>> {
+    let mut tx = globals.db.begin().await?;
+
+    let drawing = authorize(&mut *tx, &auth_user.username, id, DrawingRole::Owner).await?;
+
+    if username == drawing.owner {
+        return Err(AppError::InvalidData(
+            "the owner already has every role".to_string(),
+        ));
+    }
+
+    let query = sqlx::query!("select username from users where username = $1", username);
+    if query.fetch_optional(&mut *tx).await?.is_none() {
+        return Err(AppError::EntityNotFound("user not found".to_string()));
+    }
+
+    let record = sqlx::query!(
+        "insert into drawing_permissions (drawing_id, username, role, created_at)
+        values ($1, $2, $3, $4)
+        on conflict (drawing_id, username) do update set role = excluded.role
+        returning created_at",
+        id,
+        username,
+        grant.role.as_str(),
+        Utc::now().naive_utc()
+    )
+    .fetch_one(&mut *tx)
+    .await?;
+
+    tx.commit().await?;
+
+    Ok(AppJson(DrawingPermission {
+        username,
+        role: grant.role,
+        created_at: record.created_at.and_utc(),
+    }))
+}
+
+/// Takes away a user's access to a drawing. Besides the owner, users may
+/// revoke their own access.
+async fn revoke_permission(
+    State(globals): State<Globals>,
+    auth_user: AuthUser,
+    Path((id, username)): Path<(i32, String)>,
+) -> Result<StatusCode> {
+    let mut tx = globals.db.begin().await?;
+
+    let drawing = authorize(&mut *tx, &auth_user.username, id, DrawingRole::Viewer).await?;
+
+    if drawing.role < DrawingRole::Owner && username != auth_user.username {
+        return Err(AppError::Unauthorized(format!(
+            "drawing requires the {} role",
+            DrawingRole::Owner
+        )));
+    }
+
+    let res = sqlx::query!(
+        "delete from drawing_permissions where drawing_id = $1 and username = $2",
+        id,
+        username
+    )
+    .execute(&mut *tx)
+    .await?;
+
+    if res.rows_affected() == 0 {
+        return Err(AppError::EntityNotFound("permission not found".to_string()));
+    }
+
+    tx.commit().await?;
+
+    Ok(StatusCode::NO_CONTENT)
+}
@@ -0,0 +1,355 @@
+use std::net::SocketAddr;
+
+use argon2::password_hash::{PasswordHasher, SaltString};
+use argon2::{Argon2, PasswordHash, PasswordVerifier as _};
+use axum::Router;
+use axum::body::Body;
+use axum::extract::{ConnectInfo, Path, Query, State};
+use axum::http::{HeaderMap, HeaderName, StatusCode};
+use axum::routing::{delete, get, post};
+use base64::Engine;
+use base64::prelude::BASE64_URL_SAFE_NO_PAD;
+use chrono::{NaiveDateTime, TimeDelta, Utc};
+use image_backend::model::ImageId;
+use rand::RngCore;
+use rand::rngs::OsRng;
+use sqlx::PgPool;
+
+use crate::auth::{AuthUser, VerifiedUser};
+use crate::error::{AppError, AppJson, Result};
+use crate::globals::Globals;
+use crate::model::{DrawingRole, Items, NewShareLink, ShareLink, SharedVersion};
+use crate::permission::authorize;
+use crate::resource::drawing::{CACHE_CONTROL_LATEST, DownloadQuery, download_image};
+
+/// Carries the password of a protected share link.
+const SHARE_PASSWORD: HeaderName = HeaderName::from_static("x-share-password");
+
+/// Wrong passwords in a row after which a link is locked.
+const MAX_FAILED_ATTEMPTS: i32 = 5;
+
+/// How long a link stays locked after too many wrong passwords.
+const LOCKOUT_DURATION: TimeDelta = TimeDelta::minutes(15);
+
+/// Routes for owners managing the links of a drawing.
+pub fn routes() -> Router<Globals> {
+    Router::new()
+        .route("/", post(create_share_link))
+        .route("/", get(get_share_links))
+        .route("/{link_id}", delete(revoke_share_link))
+}
+
+/// Routes for anyone holding a link, no account required.
+pub fn public_routes() -> Router<Globals> {
+    Router::new()
+        .route("/{token}", get(get_shared_version))
+        .route("/{token}/image", get(get_shared_image))
+}
+
+async fn create_share_link(
+    State(globals): State<Globals>,
+    VerifiedUser(auth_user): VerifiedUser,
+    Path(id): Path<i32>,
+    AppJson(new_link): AppJson<NewShareLink>,
+) -> Result<(StatusCode, AppJson<ShareLink>)> {
+    let now = Utc::now();
+
+    if new_link.expires_at.is_some_and(|v| v <= now) {
+        return Err(AppError::InvalidData("expiry in the past".to_string()));
+    }
+
+    let password_hash = match &new_link.password {
+        Some(password) if password.is_empty() => {
+            return Err(AppError::InvalidData("invalid password".to_string()));
+        }
+        Some(password) => {
+            let salt = SaltString::generate(&mut OsRng);
+            let argon2 = Argon2::default();
+            Some(
+                argon2
+                    .hash_password(password.as_bytes(), &salt)?
+                    .to_string(),
+            )
+        }
+        None => None,
+    };
+
+    let mut tx = globals.db.begin().await?;
+
+    authorize(&mut *tx, &auth_user.username, id, DrawingRole::Owner).await?;
+
+    if let Some(version_id) = new_link.version_id {
+        let query = sqlx::query!(
+            "select version_id from drawing_versions where drawing_id = $1 and version_id = $2",
+            id,
+            version_id
+        );
+
+        if query.fetch_optional(&mut *tx).await?.is_none() {
+            return Err(AppError::EntityNotFound("version not found".to_string()));
+        }
+    }
+
+    let mut token = [0; 32];
+    OsRng.fill_bytes(&mut token);
+
+    let record = sqlx::query!(
+        "insert into share_links (
+            token, drawing_id, version_id, password_hash, created_by, created_at, expires_at)
+        values ($1, $2, $3, $4, $5, $6, $7)
+        returning id",
+        &token,
+        id,
+        new_link.version_id,
+        password_hash,
+        auth_user.username,
+        now.naive_utc(),
+        new_link.expires_at.map(|v| v.naive_utc())
+    )
+    .fetch_one(&mut *tx)
+    .await?;
+
+    tx.commit().await?;
+
+    let link = ShareLink {
+        id: record.id,
+        token: BASE64_URL_SAFE_NO_PAD.encode(token),
+        version_id: new_link.version_id,
+        has_password: password_hash.is_some(),
+        created_by: auth_user.username,
+        created_at: now,
+        expires_at: new_link.expires_at,
+        last_used_at: None,
+        use_count: 0,
+    };
+
+    Ok((StatusCode::CREATED, AppJson(link)))
+}
+
+async fn get_share_links(
+    State(globals): State<Globals>,
+    auth_user: AuthUser,
+    Path(id): Path<i32>,
+) -> Result<AppJson<Items<ShareLink>>> {
+    let mut tx = globals.db.begin().await?;
+
+    authorize(&mut *tx, &auth_user.username, id, DrawingRole::Owner).await?;
+
+    let records = sqlx::query!(
+        "select * from share_links where drawing_id = $1 order by created_at desc",
+        id
+    )
+    .fetch_all(&mut *tx)
+    .await?;
+
+    tx.commit().await?;
+
+    let items = records
+        .into_iter()
+        .map(|record| ShareLink {
+            id: record.id,
+            token: BASE64_URL_SAFE_NO_PAD.encode(record.token),
+            version_id: record.version_id,
+            has_password: record.password_hash.is_some(),
+            created_by: record.created_by,
+            created_at: record.created_at.and_utc(),
+            expires_at: record.expires_at.map(|v| v.and_utc()),
+            last_used_at: record.last_used_at.map(|v| v.and_utc()),
+            use_count: record.use_count,
+        })
+        .collect();
+
+    Ok(AppJson(Items { items }))
+}
+
+async fn revoke_share_link(
+    State(globals): State<Globals>,
+    auth_user: AuthUser,
+    Path((id, link_id)): Path<(i32, i32)>,
+) -> Result<StatusCode> {
+    let mut tx = globals.db.begin().await?;
+
+    authorize(&mut *tx, &auth_user.username, id, DrawingRole::Owner).await?;
+
+    let res = sqlx::query!(
+        "delete from share_links where id = $1 and drawing_id = $2",
+        link_id,
+        id
+    )
+    .execute(&mut *tx)
+    .await?;
+
+    if res.rows_affected() == 0 {
+        return Err(AppError::EntityNotFound("share link not found".to_string()));
+    }
+
+    tx.commit().await?;
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// The version a share link points at.
+struct SharedImage {
+    version: SharedVersion,
+    image_id: String,
+    thumbnail_image_id: String,
+}
+
+/// Checks a share link and records its use. Unknown, revoked and expired
+/// links are all reported as not found.
+///
+/// Guessing passwords is limited per link: after too many wrong ones the link
+/// is locked for a while, for everyone. Every attempt is counted before the
+/// password is checked, so parallel guesses can't get past the limit either.
+async fn open_share_link(
+    db: &PgPool,
+    token: &str,
+    headers: &HeaderMap,
+    ip_address: String,
+) -> Result<SharedImage> {
+    let not_found = || AppError::EntityNotFound("share link not found".to_string());
+
+    let token = BASE64_URL_SAFE_NO_PAD
+        .decode(token)
+        .map_err(|_| not_found())?;
+
+    let query = sqlx::query!(
+        r#"select
+            l.id, l.password_hash, l.expires_at, d.name, d.background,
+            coalesce(l.version_id, d.current_version_id) as "version_id!",
+            coalesce(v.width, d.width) as "width!",
+            coalesce(v.height, d.height) as "height!",
+            coalesce(v.image_id, d.image_id) as "image_id!",
+            coalesce(v.thumbnail_image_id, d.thumbnail_image_id) as "thumbnail_image_id!"
+        from share_links l
+        join drawings d on d.id = l.drawing_id
+        left join drawing_versions v on v.drawing_id = l.drawing_id and v.version_id = l.version_id
+        where l.token = $1"#,
+        &token
+    );
+
+    let Some(record) = query.fetch_optional(db).await? else {
+        return Err(not_found());
+    };
+
+    let now = Utc::now().naive_utc();
+
+    if record.expires_at.is_some_and(|v| v <= now) {
+        return Err(not_found());
+    }
+
+    if let Some(password_hash) = &record.password_hash {
+        let Some(password) = headers.get(SHARE_PASSWORD) else {
+            return Err(AppError::Unauthorized(
+                "share link requires a password".to_string(),
+            ));
+        };
+
+        if !reserve_attempt(db, record.id, now).await? {
+            return Err(AppError::TooManyRequests(
+                "too many wrong passwords, try again later".to_string(),
+            ));
+        }
+
+        let argon2 = Argon2::default();
+        let password_hash = PasswordHash::new(password_hash).unwrap();
+
+        if argon2
+            .verify_password(password.as_bytes(), &password_hash)
+            .is_err()
+        {
+            return Err(AppError::InvalidCredentials);
+        }
+    }
+
+    sqlx::query!(
+        "update share_links
+        set last_used_at = $1, last_ip_address = $2, use_count = use_count + 1,
+            failed_attempts = 0, locked_until = null
+        where id = $3",
+        now,
+        ip_address,
+        record.id
+    )
+    .execute(db)
+    .await?;
+
+    Ok(SharedImage {
+        version: SharedVersion {
+            name: record.name,
+            version_id: record.version_id,
+            width: record.width,
+            height: record.height,
+            background: record.background.parse().unwrap_or_default(),
+            expires_at: record.expires_at.map(|v| v.and_utc()),
+        },
+        image_id: record.image_id,
+        thumbnail_image_id: record.thumbnail_image_id,
+    })
+}
+
+/// Counts a password attempt up front, locking the link once there were too
+/// many in a row. Returns false if the link is locked right now.
+async fn reserve_attempt(db: &PgPool, id: i32, now: NaiveDateTime) -> Result<bool> {
+    // an expired lock starts counting from scratch
+    let reserved = sqlx::query_scalar!(
+        "update share_links set
+            failed_attempts = case when locked_until is null then failed_attempts + 1
+                else 1 end,
+            locked_until = case when locked_until is null and failed_attempts + 1 >= $2
+                then $3::timestamp end
+        where id = $1 and (locked_until is null or locked_until <= $4)
+        returning id",
+        id,
+        MAX_FAILED_ATTEMPTS,
+        now + LOCKOUT_DURATION,
+        now
+    )
+    .fetch_optional(db)
+    .await?;
+
+    Ok(reserved.is_some())
+}
+
+async fn get_shared_version(
+    State(globals): State<Globals>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    Path(token): Path<String>,
+    headers: HeaderMap,
+) -> Result<AppJson<SharedVersion>> {
+    let ip_address = addr.ip().to_canonical().to_string();
+
+    let shared = open_share_link(&globals.db, &token, &headers, ip_address).await?;
+
+    Ok(AppJson(shared.version))
+}
+
+/// Serves the shared image. Links can be revoked at any time, so clients
+/// always have to revalidate it.
+async fn get_shared_image(
+    State(globals): State<Globals>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    Path(token): Path<String>,
+    Query(query_params): Query<DownloadQuery>,
+    headers: HeaderMap,
+) -> Result<(StatusCode, HeaderMap, Body)> {
+    let ip_address = addr.ip().to_canonical().to_string();
+
+    let shared = open_share_link(&globals.db, &token, &headers, ip_address).await?;
+
+    let id = if query_params.thumbnail {
+        shared.thumbnail_image_id
+    } else {
+        shared.image_id
+    };
+
+    download_image(
+        &globals,
+        &headers,
+        &shared.version.name,
+        ImageId(id),
+        query_params.export,
+        CACHE_CONTROL_LATEST,
+    )
+    .await
+}
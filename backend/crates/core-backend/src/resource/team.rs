@@ -0,0 +1,578 @@
+use axum::Router;
+use axum::extract::{Path, State};
+use axum::http::StatusCode;
+use axum::routing::{delete, get, patch, post};
+use chrono::Utc;
+use sqlx::PgConnection;
+
+use crate::auth::{AuthUser, VerifiedUser};
+use crate::error::{AppError, AppJson, Result};
+use crate::globals::Globals;
+use crate::model::{
+    Drawing, Items, NewTeam, NewTeamInvite, Team, TeamInvite, TeamMember, TeamRole,
+    UpdateTeamMember,
+};
+use crate::permission::authorize_team;
+
+pub fn routes() -> Router<Globals> {
+    Router::new()
+        .route("/", post(create_team))
+        .route("/", get(get_teams))
+        .route("/invite", get(get_own_invites))
+        .route("/invite/{id}/accept", post(accept_invite))
+        .route("/invite/{id}", delete(decline_invite))
+        .route("/{id}", get(get_team))
+        .route("/{id}/drawing", get(get_team_drawings))
+        .route("/{id}/member", get(get_members))
+        .route("/{id}/member/{username}", patch(update_member))
+        .route("/{id}/member/{username}", delete(remove_member))
+        .route("/{id}/invite", post(invite_member))
+        .route("/{id}/invite", get(get_invites))
+        .route("/{id}/invite/{username}", delete(cancel_invite))
+}
+
+/// Creates a team with the user as its first admin.
+async fn create_team(
+    State(globals): State<Globals>,
+    VerifiedUser(auth_user): VerifiedUser,
+    AppJson(new_team): AppJson<NewTeam>,
+) -> Result<(StatusCode, AppJson<Team>)> {
+    if new_team.name.trim().is_empty() {
+        return Err(AppError::InvalidData("invalid name".to_string()));
+    }
+
+    let now = Utc::now();
+
+    let mut tx = globals.db.begin().await?;
+
+    let record = sqlx::query!(
+        "insert into teams (name, created_at) values ($1, $2) returning id",
+        new_team.name,
+        now.naive_utc()
+    )
+    .fetch_one(&mut *tx)
+    .await?;
+
+    sqlx::query!(
+        "insert into team_members (team_id, username, role, joined_at) values ($1, $2, $3, $4)",
+        record.id,
+        auth_user.username,
+        TeamRole::Admin.as_str(),
+        now.naive_utc()
+    )
+    .execute(&mut *tx)
+    .await?;
+
+    tx.commit().await?;
+
+    let team = Team {
+        id: record.id,
+        name: new_team.name,
+        role: TeamRole::Admin,
+        created_at: now,
+    };
+
+    Ok((StatusCode::CREATED, AppJson(team)))
+}
+
+/// Lists the teams the user is a member of.
+async fn get_teams(
+    State(globals): State<Globals>,
+    auth_user: AuthUser,
+) -> Result<AppJson<Items<Team>>> {
+    let records = sqlx::query!(
+        "select t.id, t.name, t.created_at, m.role
+        from teams t
+        join team_members m on m.team_id = t.id
+        where m.username = $1
+        order by t.name",
+        auth_user.username
+    )
+    .fetch_all(&globals.db)
+    .await?;
+
+    let items = records
+        .into_iter()
+        .filter_map(|record| {
+            Some(Team {
+                id: record.id,
+                name: record.name,
+                role: record.role.parse().ok()?,
+                created_at: record.created_at.and_utc(),
+            })
+        })
+        .collect();
+
+    Ok(AppJson(Items { items }))
+}
+
+async fn get_team(
+    State(globals): State<Globals>,
+    auth_user: AuthUser,
+    Path(id): Path<i32>,
+) -> Result<AppJson<Team>> {
+    let mut tx = globals.db.begin().await?;
+
+    let role = authorize_team(&mut *tx, &auth_user.username, id, TeamRole::Viewer).await?;
+
+    let record = sqlx::query!("select name, created_at from teams where id = $1", id)
+        .fetch_one(&mut *tx)
+        .await?;
+
+    tx.commit().await?;
+
+    Ok(AppJson(Team {
+        id,
+        name: record.name,
+        role,
+        created_at: record.created_at.and_utc(),
+    }))
+}
+
+/// Lists the drawings of a team, the counterpart of the user's own drawings.
+async fn get_team_drawings(
+    State(globals): State<Globals>,
+    auth_user: AuthUser,
+    Path(id): Path<i32>,
+) -> Result<AppJson<Items<Drawing>>> {
+    let mut tx = globals.db.begin().await?;
+
+    authorize_team(&mut *tx, &auth_user.username, id, TeamRole::Viewer).await?;
+
+    let records = sqlx::query!(
+        "select * from drawings where team_id = $1 order by updated_at desc",
+        id
+    )
+    .fetch_all(&mut *tx)
+    .await?;
+
+    tx.commit().await?;
+
+    let items = records
+        .into_iter()
+        .map(|record| Drawing {
+            id: record.id,
+            name: record.name,
+            width: record.width,
+            height: record.height,
+            background: record.background.parse().unwrap_or_default(),
+            team_id: record.team_id,
+            current_version_id: record.current_version_id,
+            created_at: record.created_at.and_utc(),
+            updated_at: record.updated_at.and_utc(),
+        })
+        .collect();
+
+    Ok(AppJson(Items { items }))
+}
+
+async fn get_members(
+    State(globals): State<Globals>,
+    auth_user: AuthUser,
+    Path(id): Path<i32>,
+) -> Result<AppJson<Items<TeamMember>>> {
+    let mut tx = globals.db.begin().await?;
+
+    authorize_team(&mut *tx, &auth_user.username, id, TeamRole::Viewer).await?;
+
+    let records = sqlx::query!(
+        "select username, role, joined_at from team_members where team_id = $1 order by joined_at",
+        id
+    )
+    .fetch_all(&mut *tx)
+    .await?;
+
+    tx.commit().await?;
+
+    let items = records
+        .into_iter()
+        .filter_map(|record| {
+            Some(TeamMember {
+                username: record.username,
+                role: record.role.parse().ok()?,
+                joined_at: record.joined_at.and_utc(),
+            })
+        })
+        .collect();
+
+    Ok(AppJson(Items { items }))
+}
+
+/// Role of a member of the team, failing if the user is not one.
+async fn member_role(tx: &mut PgConnection, id: i32, username: &str) -> Result<TeamRole> {
+    let record = sqlx::query!(
+        "select role from team_members where team_id = $1 and username = $2",
+        id,
+        username
+    )
+    .fetch_optional(&mut *tx)
+    .await?;
+
+    record
+        .and_then(|v| v.role.parse().ok())
+        .ok_or_else(|| AppError::EntityNotFound("member not found".to_string()))
+}
+
+/// Locks the team row, so that changes to its admins are made one at a time
+/// and two of them can't each leave the other as the only admin. Take it
+/// before authorizing, so the role checked is the one after any change that
+/// was waiting ahead of this one.
+async fn lock_team(tx: &mut PgConnection, id: i32) -> Result<()> {
+    let query = sqlx::query!("select id from teams where id = $1 for update", id);
+
+    if query.fetch_optional(&mut *tx).await?.is_none() {
+        return Err(AppError::EntityNotFound("team not found".to_string()));
+    }
+
+    Ok(())
+}
+
+/// Fails if `username` is the only admin of the team, who can't stop being
+/// one before someone else is promoted.
+async fn ensure_other_admin(tx: &mut PgConnection, id: i32, username: &str) -> Result<()> {
+    let admins = sqlx::query!(
+        r#"select count(*) as "count!" from team_members
+        where team_id = $1 and role = $2 and username <> $3"#,
+        id,
+        TeamRole::Admin.as_str(),
+        username
+    )
+    .fetch_one(&mut *tx)
+    .await?
+    .count;
+
+    if admins == 0 {
+        return Err(AppError::Conflict("team needs another admin".to_string()));
+    }
+
+    Ok(())
+}
+
+async fn update_member(
+    State(globals): State<Globals>,
+    auth_user: AuthUser,
+    Path((id, username)): Path<(i32, String)>,
+    AppJson(update): AppJson<UpdateTeamMember>,
+) -> Result<AppJson<TeamMember>> {
+    let mut tx = globals.db.begin().await?;
+
+    lock_team(&mut tx, id).await?;
+    authorize_team(&mut *tx, &auth_user.username, id, TeamRole::Admin).await?;
+
+    let role = member_role(&mut tx, id, &username).await?;
+
+    if role == TeamRole::Admin && update.role != TeamRole::Admin {
+        ensure_other_admin(&mut tx, id, &username).await?;
+    }
+
+    let record = sqlx::query!(
+        "update team_members set role = $1 where team_id = $2 and username = $3
+        returning joined_at",
+        update.role.as_str(),
+        id,
+        username
+    )
+    .fetch_one(&mut *tx)
+    .await?;
+
+    tx.commit().await?;
+
+    Ok(AppJson(TeamMember {
+        username,
+        role: update.role,
+        joined_at: record.joined_at.and_utc(),
+    }))
+}
+
+/// Removes a member from the team, either by an admin or by the member
+/// themselves. The team drawings they own are handed over to the admin who
+/// has been in the team the longest, so the last admin has to promote
+/// someone else before leaving.
+async fn remove_member(
+    State(globals): State<Globals>,
+    auth_user: AuthUser,
+    Path((id, username)): Path<(i32, String)>,
+) -> Result<StatusCode> {
+    let mut tx = globals.db.begin().await?;
+
+    let required_role = if username == auth_user.username {
+        TeamRole::Viewer
+    } else {
+        TeamRole::Admin
+    };
+
+    lock_team(&mut tx, id).await?;
+    authorize_team(&mut *tx, &auth_user.username, id, required_role).await?;
+
+    member_role(&mut tx, id, &username).await?;
+    ensure_other_admin(&mut tx, id, &username).await?;
+
+    let successor = sqlx::query!(
+        "select username from team_members
+        where team_id = $1 and role = $2 and username <> $3
+        order by joined_at
+        limit 1",
+        id,
+        TeamRole::Admin.as_str(),
+        username
+    )
+    .fetch_one(&mut *tx)
+    .await?
+    .username;
+
+    sqlx::query!(
+        "insert into drawing_owner_history (drawing_id, previous_owner, new_owner, changed_at)
+        select id, owner, $1, $2 from drawings where team_id = $3 and owner = $4",
+        successor,
+        Utc::now().naive_utc(),
+        id,
+        username
+    )
+    .execute(&mut *tx)
+    .await?;
+
+    sqlx::query!(
+        "update drawings set owner = $1 where team_id = $2 and owner = $3",
+        successor,
+        id,
+        username
+    )
+    .execute(&mut *tx)
+    .await?;
+
+    sqlx::query!(
+        "delete from team_members where team_id = $1 and username = $2",
+        id,
+        username
+    )
+    .execute(&mut *tx)
+    .await?;
+
+    tx.commit().await?;
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// Invites a user to the team, replacing an earlier invite.
+async fn invite_member(
+    State(globals): State<Globals>,
+    VerifiedUser(auth_user): VerifiedUser,
+    Path(id): Path<i32>,
+    AppJson(new_invite): AppJson<NewTeamInvite>,
+) -> Result<(StatusCode, AppJson<TeamInvite>)> {
+    let mut tx = globals.db.begin().await?;
+
+    authorize_team(&mut *tx, &auth_user.username, id, TeamRole::Admin).await?;
+
+    let query = sqlx::query!(
+        "select username from users where username = $1",
+        new_invite.username
+    );
+    if query.fetch_optional(&mut *tx).await?.is_none() {
+        return Err(AppError::EntityNotFound("user not found".to_string()));
+    }
+
+    if member_role(&mut tx, id, &new_invite.username).await.is_ok() {
+        return Err(AppError::EntityExists(
+            "user is already a member".to_string(),
+        ));
+    }
+
+    let now = Utc::now();
+
+    let record = sqlx::query!(
+        "with invite as (
+            insert into team_invites (team_id, username, role, invited_by, created_at)
+            values ($1, $2, $3, $4, $5)
+            on conflict (team_id, username) do update
+            set role = excluded.role,
+                invited_by = excluded.invited_by,
+                created_at = excluded.created_at
+            returning team_id
+        )
+        select t.name from teams t join invite i on i.team_id = t.id",
+        id,
+        new_invite.username,
+        new_invite.role.as_str(),
+        auth_user.username,
+        now.naive_utc()
+    )
+    .fetch_one(&mut *tx)
+    .await?;
+
+    tx.commit().await?;
+
+    let invite = TeamInvite {
+        team_id: id,
+        team_name: record.name,
+        username: new_invite.username,
+        role: new_invite.role,
+        invited_by: auth_user.username,
+        created_at: now,
+    };
+
+    Ok((StatusCode::CREATED, AppJson(invite)))
+}
+
+/// Lists the pending invites of a team.
+async fn get_invites(
+    State(globals): State<Globals>,
+    auth_user: AuthUser,
+    Path(id): Path<i32>,
+) -> Result<AppJson<Items<TeamInvite>>> {
+    let mut tx = globals.db.begin().await?;
+
+    authorize_team(&mut *tx, &auth_user.username, id, TeamRole::Admin).await?;
+
+    let records = sqlx::query!(
+        "select i.*, t.name as team_name
+        from team_invites i
+        join teams t on t.id = i.team_id
+        where i.team_id = $1
+        order by i.created_at",
+        id
+    )
+    .fetch_all(&mut *tx)
+    .await?;
+
+    tx.commit().await?;
+
+    let items = records
+        .into_iter()
+        .filter_map(|record| {
+            Some(TeamInvite {
+                team_id: record.team_id,
+                team_name: record.team_name,
+                username: record.username,
+                role: record.role.parse().ok()?,
+                invited_by: record.invited_by,
+                created_at: record.created_at.and_utc(),
+            })
+        })
+        .collect();
+
+    Ok(AppJson(Items { items }))
+}
+
+async fn cancel_invite(
+    State(globals): State<Globals>,
+    auth_user: AuthUser,
+    Path((id, username)): Path<(i32, String)>,
+) -> Result<StatusCode> {
+    let mut tx = globals.db.begin().await?;
+
+    authorize_team(&mut *tx, &auth_user.username, id, TeamRole::Admin).await?;
+
+    let res = sqlx::query!(
+        "delete from team_invites where team_id = $1 and username = $2",
+        id,
+        username
+    )
+    .execute(&mut *tx)
+    .await?;
+
+    if res.rows_affected() == 0 {
+        return Err(AppError::EntityNotFound("invite not found".to_string()));
+    }
+
+    tx.commit().await?;
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// Lists the invites the user has yet to accept or decline.
+async fn get_own_invites(
+    State(globals): State<Globals>,
+    auth_user: AuthUser,
+) -> Result<AppJson<Items<TeamInvite>>> {
+    let records = sqlx::query!(
+        "select i.*, t.name as team_name
+        from team_invites i
+        join teams t on t.id = i.team_id
+        where i.username = $1
+        order by i.created_at",
+        auth_user.username
+    )
+    .fetch_all(&globals.db)
+    .await?;
+
+    let items = records
+        .into_iter()
+        .filter_map(|record| {
+            Some(TeamInvite {
+                team_id: record.team_id,
+                team_name: record.team_name,
+                username: record.username,
+                role: record.role.parse().ok()?,
+                invited_by: record.invited_by,
+                created_at: record.created_at.and_utc(),
+            })
+        })
+        .collect();
+
+    Ok(AppJson(Items { items }))
+}
+
+/// Joins a team with the role the user was invited with.
+async fn accept_invite(
+    State(globals): State<Globals>,
+    VerifiedUser(auth_user): VerifiedUser,
+    Path(id): Path<i32>,
+) -> Result<AppJson<Team>> {
+    let mut tx = globals.db.begin().await?;
+
+    let record = sqlx::query!(
+        "delete from team_invites where team_id = $1 and username = $2 returning role",
+        id,
+        auth_user.username
+    )
+    .fetch_optional(&mut *tx)
+    .await?;
+
+    let Some(role) = record.and_then(|v| v.role.parse::<TeamRole>().ok()) else {
+        return Err(AppError::EntityNotFound("invite not found".to_string()));
+    };
+
+    sqlx::query!(
+        "insert into team_members (team_id, username, role, joined_at) values ($1, $2, $3, $4)",
+        id,
+        auth_user.username,
+        role.as_str(),
+        Utc::now().naive_utc()
+    )
+    .execute(&mut *tx)
+    .await?;
+
+    let record = sqlx::query!("select name, created_at from teams where id = $1", id)
+        .fetch_one(&mut *tx)
+        .await?;
+
+    tx.commit().await?;
+
+    Ok(AppJson(Team {
+        id,
+        name: record.name,
+        role,
+        created_at: record.created_at.and_utc(),
+    }))
+}
+
+async fn decline_invite(
+    State(globals): State<Globals>,
+    auth_user: AuthUser,
+    Path(id): Path<i32>,
+) -> Result<StatusCode> {
+    let res = sqlx::query!(
+        "delete from team_invites where team_id = $1 and username = $2",
+        id,
+        auth_user.username
+    )
+    .execute(&globals.db)
+    .await?;
+
+    if res.rows_affected() == 0 {
+        return Err(AppError::EntityNotFound("invite not found".to_string()));
+    }
+
+    Ok(StatusCode::NO_CONTENT)
+}
@@ -0,0 +1,405 @@
+use axum::Router;
+use axum::extract::{Path, State};
+use axum::http::StatusCode;
+use axum::routing::{delete, get, post};
+use chrono::{NaiveDateTime, Utc};
+use sqlx::PgConnection;
+
+use crate::auth::{AuthUser, VerifiedUser};
+use crate::error::{AppError, AppJson, Result};
+use crate::globals::Globals;
+use crate::model::{
+    DrawingRole, DrawingTransfer, Items, NewDrawingTransfer, OwnerChange, TransferStatus,
+};
+use crate::permission::{authorize, authorize_change};
+
+/// Routes for owners handing over one of their drawings.
+pub fn routes() -> Router<Globals> {
+    Router::new()
+        .route("/", post(create_transfer))
+        .route("/", get(get_transfers))
+        .route("/", delete(cancel_transfer))
+        .route("/history", get(get_owner_history))
+}
+
+/// Routes for recipients answering the transfers offered to them.
+pub fn incoming_routes() -> Router<Globals> {
+    Router::new()
+        .route("/", get(get_incoming_transfers))
+        .route("/{transfer_id}/accept", post(accept_transfer))
+        .route("/{transfer_id}/decline", post(decline_transfer))
+}
+
+struct TransferRecord {
+    id: i32,
+    drawing_id: i32,
+    drawing_name: String,
+    from_user: String,
+    to_user: String,
+    status: String,
+    created_at: NaiveDateTime,
+    resolved_at: Option<NaiveDateTime>,
+}
+
+impl TransferRecord {
+    fn into_transfer(self) -> Option<DrawingTransfer> {
+        Some(DrawingTransfer {
+            id: self.id,
+            drawing_id: self.drawing_id,
+            drawing_name: self.drawing_name,
+            from_user: self.from_user,
+            to_user: self.to_user,
+            status: self.status.parse().ok()?,
+            created_at: self.created_at.and_utc(),
+            resolved_at: self.resolved_at.map(|v| v.and_utc()),
+        })
+    }
+}
+
+/// Offers a drawing to another user. Only the owner can hand it over, a
+/// drawing can have a single pending transfer at a time. The drawing stays
+/// locked until the offer is recorded, so concurrent offers are checked one
+/// after the other.
+async fn create_transfer(
+    State(globals): State<Globals>,
+    VerifiedUser(auth_user): VerifiedUser,
+    Path(id): Path<i32>,
+    AppJson(new_transfer): AppJson<NewDrawingTransfer>,
+) -> Result<(StatusCode, AppJson<DrawingTransfer>)> {
+    let mut tx = globals.db.begin().await?;
+
+    let record = authorize_change(&mut tx, &auth_user.username, id, DrawingRole::Owner).await?;
+
+    if record.owner != auth_user.username {
+        return Err(AppError::Unauthorized(
+            "only the owner can transfer the drawing".to_string(),
+        ));
+    }
+
+    if new_transfer.username == auth_user.username {
+        return Err(AppError::InvalidData(
+            "drawing already owned by the user".to_string(),
+        ));
+    }
+
+    let query = sqlx::query!(
+        "select username from users where username = $1",
+        new_transfer.username
+    );
+
+    if query.fetch_optional(&mut *tx).await?.is_none() {
+        return Err(AppError::EntityNotFound("user not found".to_string()));
+    }
+
+    let query = sqlx::query!(
+        "select id from drawing_transfers where drawing_id = $1 and status = $2",
+        id,
+        TransferStatus::Pending.as_str()
+    );
+
+    if query.fetch_optional(&mut *tx).await?.is_some() {
+        return Err(AppError::Conflict(
+            "drawing already has a pending transfer".to_string(),
+        ));
+    }
+
+    let now = Utc::now();
+
+    let transfer_id = sqlx::query!(
+        "insert into drawing_transfers (drawing_id, from_user, to_user, status, created_at)
+        values ($1, $2, $3, $4, $5)
+        returning id",
+        id,
+        auth_user.username,
+        new_transfer.username,
+        TransferStatus::Pending.as_str(),
+        now.naive_utc()
+    )
+    .fetch_one(&mut *tx)
+    .await?
+    .id;
+
+    tx.commit().await?;
+
+    let transfer = DrawingTransfer {
+        id: transfer_id,
+        drawing_id: id,
+        drawing_name: record.name,
+        from_user: auth_user.username,
+        to_user: new_transfer.username,
+        status: TransferStatus::Pending,
+        created_at: now,
+        resolved_at: None,
+    };
+
+    Ok((StatusCode::CREATED, AppJson(transfer)))
+}
+
+/// Lists the transfers of a drawing, pending and resolved.
+async fn get_transfers(
+    State(globals): State<Globals>,
+    auth_user: AuthUser,
+    Path(id): Path<i32>,
+) -> Result<AppJson<Items<DrawingTransfer>>> {
+    let mut tx = globals.db.begin().await?;
+
+    authorize(&mut *tx, &auth_user.username, id, DrawingRole::Owner).await?;
+
+    let records = sqlx::query_as!(
+        TransferRecord,
+        r#"select t.id, t.drawing_id, d.name as drawing_name, t.from_user, t.to_user,
+            t.status, t.created_at, t.resolved_at
+        from drawing_transfers t
+        join drawings d on d.id = t.drawing_id
+        where t.drawing_id = $1
+        order by t.created_at desc, t.id desc"#,
+        id
+    )
+    .fetch_all(&mut *tx)
+    .await?;
+
+    tx.commit().await?;
+
+    let items = records
+        .into_iter()
+        .filter_map(TransferRecord::into_transfer)
+        .collect();
+
+    Ok(AppJson(Items { items }))
+}
+
+async fn cancel_transfer(
+    State(globals): State<Globals>,
+    auth_user: AuthUser,
+    Path(id): Path<i32>,
+) -> Result<StatusCode> {
+    let mut tx = globals.db.begin().await?;
+
+    authorize(&mut *tx, &auth_user.username, id, DrawingRole::Owner).await?;
+
+    let res = sqlx::query!(
+        "update drawing_transfers set status = $1, resolved_at = $2
+        where drawing_id = $3 and status = $4",
+        TransferStatus::Cancelled.as_str(),
+        Utc::now().naive_utc(),
+        id,
+        TransferStatus::Pending.as_str()
+    )
+    .execute(&mut *tx)
+    .await?;
+
+    if res.rows_affected() == 0 {
+        return Err(AppError::EntityNotFound("transfer not found".to_string()));
+    }
+
+    tx.commit().await?;
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// Lists the previous owners of a drawing, oldest change first.
+async fn get_owner_history(
+    State(globals): State<Globals>,
+    auth_user: AuthUser,
+    Path(id): Path<i32>,
+) -> Result<AppJson<Items<OwnerChange>>> {
+    let mut tx = globals.db.begin().await?;
+
+    authorize(&mut *tx, &auth_user.username, id, DrawingRole::Owner).await?;
+
+    let records = sqlx::query!(
+        "select previous_owner, new_owner, changed_at, transfer_id
+        from drawing_owner_history
+        where drawing_id = $1
+        order by changed_at, id",
+        id
+    )
+    .fetch_all(&mut *tx)
+    .await?;
+
+    tx.commit().await?;
+
+    let items = records
+        .into_iter()
+        .map(|record| OwnerChange {
+            previous_owner: record.previous_owner,
+            new_owner: record.new_owner,
+            changed_at: record.changed_at.and_utc(),
+            transfer_id: record.transfer_id,
+        })
+        .collect();
+
+    Ok(AppJson(Items { items }))
+}
+
+/// Lists the transfers waiting for the user to accept or decline them.
+async fn get_incoming_transfers(
+    State(globals): State<Globals>,
+    auth_user: AuthUser,
+) -> Result<AppJson<Items<DrawingTransfer>>> {
+    let records = sqlx::query_as!(
+        TransferRecord,
+        r#"select t.id, t.drawing_id, d.name as drawing_name, t.from_user, t.to_user,
+            t.status, t.created_at, t.resolved_at
+        from drawing_transfers t
+        join drawings d on d.id = t.drawing_id
+        where t.to_user = $1 and t.status = $2
+        order by t.created_at desc, t.id desc"#,
+        auth_user.username,
+        TransferStatus::Pending.as_str()
+    )
+    .fetch_all(&globals.db)
+    .await?;
+
+    let items = records
+        .into_iter()
+        .filter_map(TransferRecord::into_transfer)
+        .collect();
+
+    Ok(AppJson(Items { items }))
+}
+
+/// Locks a pending transfer offered to `username`.
+async fn pending_transfer(
+    tx: &mut PgConnection,
+    transfer_id: i32,
+    username: &str,
+) -> Result<TransferRecord> {
+    let record = sqlx::query_as!(
+        TransferRecord,
+        r#"select t.id, t.drawing_id, d.name as drawing_name, t.from_user, t.to_user,
+            t.status, t.created_at, t.resolved_at
+        from drawing_transfers t
+        join drawings d on d.id = t.drawing_id
+        where t.id = $1 and t.to_user = $2 and t.status = $3
+        for update"#,
+        transfer_id,
+        username,
+        TransferStatus::Pending.as_str()
+    )
+    .fetch_optional(&mut *tx)
+    .await?;
+
+    record.ok_or_else(|| AppError::EntityNotFound("transfer not found".to_string()))
+}
+
+async fn resolve_transfer(
+    tx: &mut PgConnection,
+    transfer: TransferRecord,
+    status: TransferStatus,
+    now: NaiveDateTime,
+) -> Result<DrawingTransfer> {
+    sqlx::query!(
+        "update drawing_transfers set status = $1, resolved_at = $2 where id = $3",
+        status.as_str(),
+        now,
+        transfer.id
+    )
+    .execute(&mut *tx)
+    .await?;
+
+    Ok(DrawingTransfer {
+        id: transfer.id,
+        drawing_id: transfer.drawing_id,
+        drawing_name: transfer.drawing_name,
+        from_user: transfer.from_user,
+        to_user: transfer.to_user,
+        status,
+        created_at: transfer.created_at.and_utc(),
+        resolved_at: Some(now.and_utc()),
+    })
+}
+
+/// Makes the user the owner of the drawing, along with its whole version
+/// history. The drawing leaves its team unless the user is a member, and
+/// permissions granted to the user before are dropped as owners need none.
+async fn accept_transfer(
+    State(globals): State<Globals>,
+    VerifiedUser(auth_user): VerifiedUser,
+    Path(transfer_id): Path<i32>,
+) -> Result<AppJson<DrawingTransfer>> {
+    let now = Utc::now().naive_utc();
+
+    let mut tx = globals.db.begin().await?;
+
+    let transfer = pending_transfer(&mut tx, transfer_id, &auth_user.username).await?;
+
+    let owner = sqlx::query!(
+        "select owner from drawings where id = $1 for update",
+        transfer.drawing_id
+    )
+    .fetch_one(&mut *tx)
+    .await?
+    .owner;
+
+    // the drawing changed hands some other way, e.g. its owner left its team
+    if owner != transfer.from_user {
+        resolve_transfer(&mut tx, transfer, TransferStatus::Cancelled, now).await?;
+        tx.commit().await?;
+
+        return Err(AppError::Conflict(
+            "drawing owner changed since the transfer was requested".to_string(),
+        ));
+    }
+
+    sqlx::query!(
+        "update drawings set
+            owner = $1,
+            team_id = case when exists (
+                select 1 from team_members m where m.team_id = drawings.team_id and m.username = $1
+            ) then team_id end
+        where id = $2",
+        auth_user.username,
+        transfer.drawing_id
+    )
+    .execute(&mut *tx)
+    .await?;
+
+    sqlx::query!(
+        "delete from drawing_permissions where drawing_id = $1 and username = $2",
+        transfer.drawing_id,
+        auth_user.username
+    )
+    .execute(&mut *tx)
+    .await?;
+
+    sqlx::query!(
+        "insert into drawing_owner_history (
+            drawing_id, previous_owner, new_owner, changed_at, transfer_id)
+        values ($1, $2, $3, $4, $5)",
+        transfer.drawing_id,
+        transfer.from_user,
+        auth_user.username,
+        now,
+        transfer.id
+    )
+    .execute(&mut *tx)
+    .await?;
+
+    let transfer = resolve_transfer(&mut tx, transfer, TransferStatus::Accepted, now).await?;
+
+    tx.commit().await?;
+
+    Ok(AppJson(transfer))
+}
+
+async fn decline_transfer(
+    State(globals): State<Globals>,
+    auth_user: AuthUser,
+    Path(transfer_id): Path<i32>,
+) -> Result<AppJson<DrawingTransfer>> {
+    let mut tx = globals.db.begin().await?;
+
+    let transfer = pending_transfer(&mut tx, transfer_id, &auth_user.username).await?;
+    let transfer = resolve_transfer(
+        &mut tx,
+        transfer,
+        TransferStatus::Declined,
+        Utc::now().naive_utc(),
+    )
+    .await?;
+
+    tx.commit().await?;
+
+    Ok(AppJson(transfer))
+}
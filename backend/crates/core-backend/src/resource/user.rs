@@ -6,20 +6,82 @@ use axum::Router;
 use axum::extract::{Path, State};
 use axum::http::StatusCode;
 use axum::routing::{get, patch, post};
+use chrono::{TimeDelta, Utc};
 use rand::rngs::OsRng;
+use sqlx::PgConnection;
 
 use crate::auth::AuthUser;
 use crate::error::{AppError, AppJson, Result};
 use crate::globals::Globals;
-use crate::model::{FavouriteAnimal, NewUser, UpdateUser, User};
+use crate::mail::Mail;
+use crate::model::{FavouriteAnimal, NewUser, UpdateUser, User, VerifyEmail};
+use crate::token::MailToken;
+
+/// How long an email verification mail can be used.
+const VERIFICATION_VALID_FOR: TimeDelta = TimeDelta::hours(48);
+
+/// How long a user has to wait before asking for another verification mail.
+const VERIFICATION_RESEND_AFTER: TimeDelta = TimeDelta::minutes(1);
 
 pub fn routes() -> Router<Globals> {
     Router::new()
         .route("/", post(create_user))
+        .route("/verify", post(verify_email))
+        .route("/verify/resend", post(resend_verification))
         .route("/{username}", get(get_user))
         .route("/{username}", patch(update_user))
 }
 
+/// Replaces the pending verifications of the user with one for `email`,
+/// returning the mail to send once the transaction is committed.
+async fn create_verification(tx: &mut PgConnection, username: &str, email: &str) -> Result<Mail> {
+    let now = Utc::now();
+    let token = MailToken::generate();
+
+    sqlx::query!(
+        "delete from email_verifications where username = $1",
+        username
+    )
+    .execute(&mut *tx)
+    .await?;
+
+    sqlx::query!(
+        "insert into email_verifications (token_hash, username, email, created_at, expires_at)
+        values ($1, $2, $3, $4, $5)",
+        &token.hash,
+        username,
+        email,
+        now.naive_utc(),
+        (now + VERIFICATION_VALID_FOR).naive_utc()
+    )
+    .execute(&mut *tx)
+    .await?;
+
+    Ok(Mail {
+        to: email.to_string(),
+        subject: "Verify your email".to_string(),
+        body: format!(
+            "Hi {username},\n\n\
+            use the following code to verify your email, it expires in 48 hours:\n\n\
+            {}\n\n\
+            If you didn't create an account, you can ignore this mail.",
+            token.token
+        ),
+    })
+}
+
+/// Sends a verification mail for a change that already went through in the
+/// background, only logging failures as the user can ask for the mail again.
+fn send_verification(globals: &Globals, mail: Mail) {
+    let mailer = globals.mailer.clone();
+
+    tokio::spawn(async move {
+        if let Err(error) = mailer.send(mail).await {
+            tracing::warn!(%error, "failed to send verification mail");
+        }
+    });
+}
+
 async fn create_user(
     State(globals): State<Globals>,
     AppJson(user): AppJson<NewUser>,
@@ -42,6 +104,8 @@ async fn create_user(
         .hash_password(user.password.as_bytes(), &salt)?
         .serialize();
 
+    let mut tx = globals.db.begin().await?;
+
     let res = sqlx::query!(
         "
         insert into users (username, email, password_hash, favourite_animal) values ($1, $2, $3, $4);
@@ -51,7 +115,7 @@ async fn create_user(
         password_hash.as_str(),
         user.favourite_animal.as_str(),
     )
-    .execute(&globals.db)
+    .execute(&mut *tx)
     .await;
 
     let db_err = res.as_ref().err().and_then(|e| e.as_database_error());
@@ -72,9 +136,101 @@ async fn create_user(
 
     res?;
 
+    let mail = create_verification(&mut tx, &user.username, &user.email).await?;
+
+    tx.commit().await?;
+
+    send_verification(&globals, mail);
+
     Ok(StatusCode::CREATED)
 }
 
+async fn verify_email(
+    State(globals): State<Globals>,
+    AppJson(verify): AppJson<VerifyEmail>,
+) -> Result<StatusCode> {
+    let Some(token_hash) = MailToken::hash(&verify.token) else {
+        return Err(AppError::InvalidMailToken);
+    };
+
+    let now = Utc::now().naive_utc();
+
+    let mut tx = globals.db.begin().await?;
+
+    let record = sqlx::query!(
+        "delete from email_verifications where token_hash = $1
+        returning username, email, expires_at",
+        &token_hash
+    )
+    .fetch_optional(&mut *tx)
+    .await?;
+
+    let Some(record) = record.filter(|v| v.expires_at > now) else {
+        return Err(AppError::InvalidMailToken);
+    };
+
+    // the token is for an address the user no longer has
+    let res = sqlx::query!(
+        "update users set email_verified_at = $1 where username = $2 and email = $3",
+        now,
+        record.username,
+        record.email
+    )
+    .execute(&mut *tx)
+    .await?;
+
+    if res.rows_affected() == 0 {
+        return Err(AppError::InvalidMailToken);
+    }
+
+    tx.commit().await?;
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// Sends a new verification mail, at most once every
+/// [`VERIFICATION_RESEND_AFTER`].
+async fn resend_verification(
+    State(globals): State<Globals>,
+    auth_user: AuthUser,
+) -> Result<StatusCode> {
+    if auth_user.email_verified {
+        return Err(AppError::Conflict("email already verified".to_string()));
+    }
+
+    let mut tx = globals.db.begin().await?;
+
+    let email = sqlx::query!(
+        "select email from users where username = $1 for update",
+        auth_user.username
+    )
+    .fetch_one(&mut *tx)
+    .await?
+    .email;
+
+    let last_sent = sqlx::query!(
+        "select max(created_at) as last_sent from email_verifications where username = $1",
+        auth_user.username
+    )
+    .fetch_one(&mut *tx)
+    .await?
+    .last_sent;
+
+    if last_sent.is_some_and(|v| v.and_utc() + VERIFICATION_RESEND_AFTER > Utc::now()) {
+        return Err(AppError::TooManyRequests(
+            "verification mail sent recently, try again later".to_string(),
+        ));
+    }
+
+    let mail = create_verification(&mut tx, &auth_user.username, &email).await?;
+
+    tx.commit().await?;
+
+    send_verification(&globals, mail);
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
 async fn get_user(
     State(globals): State<Globals>,
     Path(username): Path<String>,
@@ -82,7 +238,7 @@ async fn get_user(
 ) -> Result<AppJson<User>> {
     let res = sqlx::query!(
         "
-        select username, email, favourite_animal from users where username = $1;
+        select username, email, email_verified_at, favourite_animal from users where username = $1;
         ",
         if username == "me" {
             &auth_user.username
@@ -105,6 +261,7 @@ async fn get_user(
     let user = User {
         username: user.username,
         email: user.email,
+        email_verified: user.email_verified_at.is_some(),
         favourite_animal,
     };
 
@@ -128,15 +285,31 @@ async fn update_user(
     }
 
     let mut tx = globals.db.begin().await?;
+    let mut mail = None;
 
+    // a new address has to be verified again
     if let Some(email) = update.email {
-        sqlx::query!(
-            "update users set email = $1 where username = $2",
+        if !email.contains('@') {
+            return Err(AppError::InvalidData("invalid email".to_string()));
+        }
+
+        let res = sqlx::query!(
+            "update users set email = $1, email_verified_at = null
+            where username = $2 and email <> $1",
             email,
             username
         )
         .execute(&mut *tx)
         .await?;
+
+        if res.rows_affected() > 0 {
+            // resets mailed to the old address no longer apply
+            sqlx::query!("delete from password_resets where username = $1", username)
+                .execute(&mut *tx)
+                .await?;
+
+            mail = Some(create_verification(&mut tx, username, &email).await?);
+        }
     }
 
     if let Some(favourite_animal) = update.favourite_animal {
@@ -188,12 +361,17 @@ async fn update_user(
 
     tx.commit().await?;
 
+    if let Some(mail) = mail {
+        send_verification(&globals, mail);
+    }
+
     let favourite_animal = FavouriteAnimal::from_str(&record.favourite_animal)
         .map_err(|e| AppError::Internal(e.to_string()))?;
 
     let user = User {
         username: record.username,
         email: record.email,
+        email_verified: record.email_verified_at.is_some(),
         favourite_animal,
     };
 
@@ -0,0 +1,133 @@
+use image_backend::ImageService;
+use image_backend::model::{Background, Fit, ImageId, ThumbnailParams, UploadResult};
+use serde::Serialize;
+use sqlx::{Pool, Postgres};
+
+use crate::error::Result;
+
+/// Width and height of the thumbnails stored for drawings and versions.
+pub const THUMBNAIL_SIZE: u32 = 256;
+
+/// Creates the thumbnail of a drawing image: scaled to fit and letterboxed
+/// to exactly [`THUMBNAIL_SIZE`] in the drawing's background.
+pub async fn create_thumbnail(
+    image_service: &ImageService,
+    id: ImageId,
+    background: Background,
+) -> Result<UploadResult> {
+    let params = ThumbnailParams {
+        width: THUMBNAIL_SIZE,
+        height: THUMBNAIL_SIZE,
+        fit: Fit::Contain,
+        background,
+    };
+
+    Ok(image_service.thumbnail_image(id, params).await?)
+}
+
+#[derive(Debug, Clone, Default, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct BackfillReport {
+    pub drawings: usize,
+    pub versions: usize,
+    pub unchanged: usize,
+    pub failed: usize,
+}
+
+/// Regenerates the thumbnails of every drawing and version.
+///
+/// Thumbnails are content-addressed, so running this again only touches
+/// rows whose thumbnail is still outdated. Replaced thumbnails are left for
+/// the garbage collector.
+pub async fn backfill_thumbnails(
+    db: &Pool<Postgres>,
+    image_service: &ImageService,
+) -> Result<BackfillReport> {
+    let mut report = BackfillReport::default();
+
+    let drawings = sqlx::query!(
+        "select id, image_id, thumbnail_image_id, background from drawings order by id"
+    )
+    .fetch_all(db)
+    .await?;
+
+    for drawing in drawings {
+        let background = drawing.background.parse().unwrap_or_default();
+
+        let thumbnail =
+            match create_thumbnail(image_service, ImageId(drawing.image_id.clone()), background)
+                .await
+            {
+                Ok(v) => v,
+                Err(error) => {
+                    tracing::warn!(%error, id = drawing.id, "failed to create thumbnail");
+                    report.failed += 1;
+                    continue;
+                }
+            };
+
+        if thumbnail.id.0 == drawing.thumbnail_image_id {
+            report.unchanged += 1;
+            continue;
+        }
+
+        // skips drawings that changed in the meantime, they already got a
+        // new thumbnail
+        sqlx::query!(
+            "update drawings set thumbnail_image_id = $1 where id = $2 and image_id = $3",
+            thumbnail.id.0,
+            drawing.id,
+            drawing.image_id
+        )
+        .execute(db)
+        .await?;
+
+        report.drawings += 1;
+    }
+
+    let versions = sqlx::query!(
+        "select v.drawing_id, v.version_id, v.image_id, v.thumbnail_image_id, d.background
+        from drawing_versions v join drawings d on d.id = v.drawing_id
+        order by v.drawing_id, v.version_id"
+    )
+    .fetch_all(db)
+    .await?;
+
+    for version in versions {
+        let background = version.background.parse().unwrap_or_default();
+
+        let thumbnail =
+            match create_thumbnail(image_service, ImageId(version.image_id), background).await {
+                Ok(v) => v,
+                Err(error) => {
+                    tracing::warn!(
+                        %error,
+                        id = version.drawing_id,
+                        version = version.version_id,
+                        "failed to create thumbnail"
+                    );
+                    report.failed += 1;
+                    continue;
+                }
+            };
+
+        if thumbnail.id.0 == version.thumbnail_image_id {
+            report.unchanged += 1;
+            continue;
+        }
+
+        sqlx::query!(
+            "update drawing_versions set thumbnail_image_id = $1
+            where drawing_id = $2 and version_id = $3",
+            thumbnail.id.0,
+            version.drawing_id,
+            version.version_id
+        )
+        .execute(db)
+        .await?;
+
+        report.versions += 1;
+    }
+
+    Ok(report)
+}
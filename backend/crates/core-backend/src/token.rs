@@ -0,0 +1,30 @@
+use base64::Engine;
+use base64::prelude::BASE64_URL_SAFE_NO_PAD;
+use rand::RngCore;
+use rand::rngs::OsRng;
+use sha2::{Digest as _, Sha512};
+
+/// Single-use token sent to a user by mail. Only its hash is stored, like
+/// the id of a session, so a leaked table can't be used to take over accounts.
+pub struct MailToken {
+    pub token: String,
+    pub hash: [u8; 64],
+}
+
+impl MailToken {
+    pub fn generate() -> Self {
+        let mut token = [0; 32];
+        OsRng.fill_bytes(&mut token);
+
+        Self {
+            token: BASE64_URL_SAFE_NO_PAD.encode(token),
+            hash: Sha512::digest(token).into(),
+        }
+    }
+
+    /// Hash of a token received from a user, `None` if it isn't one.
+    pub fn hash(token: &str) -> Option<[u8; 64]> {
+        let token = BASE64_URL_SAFE_NO_PAD.decode(token).ok()?;
+        Some(Sha512::digest(token).into())
+    }
+}
@@ -0,0 +1,86 @@
+use std::ops::Deref;
+use std::sync::Arc;
+use std::time::Duration;
+
+use axum_test::TestServer;
+use core_backend::mail::FileMailer;
+use sqlx::PgPool;
+use tempfile::TempDir;
+
+/// Test server whose mail is written to a temporary directory, so tests can
+/// read the tokens sent to users.
+pub struct TestApp {
+    server: TestServer,
+    mail_dir: TempDir,
+}
+
+impl TestApp {
+    pub fn new(db: PgPool) -> Self {
+        let mail_dir = tempfile::tempdir().unwrap();
+        let mailer = Arc::new(FileMailer::new(mail_dir.path()));
+        let app = core_backend::build_app(db, mailer);
+
+        Self {
+            server: TestServer::new(app).unwrap(),
+            mail_dir,
+        }
+    }
+
+    /// Mails sent to `email` so far, oldest first.
+    pub fn mails(&self, email: &str) -> Vec<String> {
+        let mut paths: Vec<_> = std::fs::read_dir(self.mail_dir.path())
+            .into_iter()
+            .flatten()
+            .map(|entry| entry.unwrap().path())
+            .collect();
+        paths.sort();
+
+        let to = format!("To: {email}\n");
+
+        paths
+            .into_iter()
+            .map(|path| std::fs::read_to_string(path).unwrap())
+            .filter(|mail| mail.starts_with(&to))
+            .collect()
+    }
+
+    /// Waits for `count` mails to have been sent to `email`, for mails sent in
+    /// the background.
+    pub async fn wait_for_mails(&self, email: &str, count: usize) -> Vec<String> {
+        for _ in 0..100 {
+            let mails = self.mails(email);
+
+            if mails.len() >= count {
+                return mails;
+            }
+
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+
+        panic!("expected {count} mails to {email}");
+    }
+
+    /// Token from the last mail sent to `email`.
+    pub fn last_token(&self, email: &str) -> String {
+        let mails = self.mails(email);
+        let mail = mails.last().expect("no mail sent");
+
+        mail.lines()
+            .find(|line| {
+                line.len() == 43
+                    && line
+                        .bytes()
+                        .all(|b| b.is_ascii_alphanumeric() || b == b'-' || b == b'_')
+            })
+            .expect("no token in mail")
+            .to_string()
+    }
+}
+
+impl Deref for TestApp {
+    type Target = TestServer;
+
+    fn deref(&self) -> &TestServer {
+        &self.server
+    }
+}
@@ -1,15 +1,16 @@
 use axum::http::StatusCode;
 use axum::http::header::{AUTHORIZATION, USER_AGENT};
-use axum_test::TestServer;
-use core_backend::model::{Credentials, Items, Session, Token};
+use core_backend::model::{
+    Credentials, Items, RequestPasswordReset, ResetPassword, Session, Token, UpdateUser, User,
+};
 use sqlx::PgPool;
 
+use crate::app::TestApp;
 use crate::user::TestUser;
 
 #[sqlx::test(migrations = "../../migrations")]
 async fn auth_via_username(db: PgPool) {
-    let app = core_backend::build_app(db);
-    let server = TestServer::new(app).unwrap();
+    let server = TestApp::new(db);
     TestUser::ALEX.create(&server).await;
 
     let res = server
@@ -34,8 +35,7 @@ async fn auth_via_username(db: PgPool) {
 
 #[sqlx::test(migrations = "../../migrations")]
 async fn auth_via_email(db: PgPool) {
-    let app = core_backend::build_app(db);
-    let server = TestServer::new(app).unwrap();
+    let server = TestApp::new(db);
     TestUser::ALEX.create(&server).await;
 
     let res = server
@@ -60,8 +60,7 @@ async fn auth_via_email(db: PgPool) {
 
 #[sqlx::test(migrations = "../../migrations")]
 async fn end_current_session(db: PgPool) {
-    let app = core_backend::build_app(db);
-    let server = TestServer::new(app).unwrap();
+    let server = TestApp::new(db);
     let token = TestUser::ALEX.create_and_auth(&server).await;
 
     let res = server
@@ -79,8 +78,7 @@ async fn end_current_session(db: PgPool) {
 
 #[sqlx::test(migrations = "../../migrations")]
 async fn end_session_by_token_id(db: PgPool) {
-    let app = core_backend::build_app(db);
-    let server = TestServer::new(app).unwrap();
+    let server = TestApp::new(db);
     let token = TestUser::ALEX.create_and_auth(&server).await;
 
     let res = server
@@ -98,9 +96,9 @@ async fn end_session_by_token_id(db: PgPool) {
 }
 
 #[sqlx::test(migrations = "../../migrations")]
+#[allow(clippy::bool_assert_comparison)]
 async fn list_sessions(db: PgPool) {
-    let app = core_backend::build_app(db);
-    let server = TestServer::new(app).unwrap();
+    let server = TestApp::new(db);
 
     TestUser::ALEX.create(&server).await;
 
@@ -144,3 +142,196 @@ async fn list_sessions(db: PgPool) {
     assert_eq!(sessions.items[1].user_agent, "2");
     assert_eq!(sessions.items[1].ip_address, "127.0.0.1");
 }
+
+#[sqlx::test(migrations = "../../migrations")]
+async fn reset_password(db: PgPool) {
+    let server = TestApp::new(db.clone());
+    let token = TestUser::ALEX.create_and_auth(&server).await;
+    let mails = server.mails(TestUser::ALEX.email).len();
+
+    // unknown addresses look the same from the outside
+    let res = server
+        .post("/api/v1/auth/password-reset")
+        .json(&RequestPasswordReset {
+            email: "nobody@example.com".to_string(),
+        })
+        .await;
+    res.assert_status(StatusCode::NO_CONTENT);
+    assert!(server.mails("nobody@example.com").is_empty());
+
+    let res = server
+        .post("/api/v1/auth/password-reset")
+        .json(&RequestPasswordReset {
+            email: TestUser::ALEX.email.to_string(),
+        })
+        .await;
+    res.assert_status(StatusCode::NO_CONTENT);
+    server.wait_for_mails(TestUser::ALEX.email, mails + 1).await;
+
+    let new_password = "c0rrect-h0rse";
+
+    let res = server
+        .post("/api/v1/auth/password-reset/confirm")
+        .json(&ResetPassword {
+            token: "bm90IGEgdG9rZW4".to_string(),
+            new_password: new_password.to_string(),
+        })
+        .await;
+    res.assert_status_bad_request();
+
+    let reset = ResetPassword {
+        token: server.last_token(TestUser::ALEX.email),
+        new_password: new_password.to_string(),
+    };
+
+    let res = server
+        .post("/api/v1/auth/password-reset/confirm")
+        .json(&reset)
+        .await;
+    res.assert_status(StatusCode::NO_CONTENT);
+
+    let res = server
+        .post("/api/v1/auth/password-reset/confirm")
+        .json(&reset)
+        .await;
+    res.assert_status_bad_request();
+
+    // every session ends with the old password
+    let res = server
+        .get("/api/v1/user/me")
+        .add_header(AUTHORIZATION, &token.token)
+        .await;
+    res.assert_status_unauthorized();
+
+    let res = server
+        .post("/api/v1/auth")
+        .json(&Credentials {
+            username_or_email: TestUser::ALEX.username.to_string(),
+            password: TestUser::ALEX.password.to_string(),
+            extend_session: false,
+        })
+        .await;
+    res.assert_status_unauthorized();
+
+    let res = server
+        .post("/api/v1/auth")
+        .json(&Credentials {
+            username_or_email: TestUser::ALEX.username.to_string(),
+            password: new_password.to_string(),
+            extend_session: false,
+        })
+        .await;
+    res.assert_status_ok();
+
+    // tokens stop working once they expire
+    let res = server
+        .post("/api/v1/auth/password-reset")
+        .json(&RequestPasswordReset {
+            email: TestUser::ALEX.email.to_string(),
+        })
+        .await;
+    res.assert_status(StatusCode::NO_CONTENT);
+    server.wait_for_mails(TestUser::ALEX.email, mails + 2).await;
+
+    sqlx::query("update password_resets set expires_at = now() - interval '1 hour'")
+        .execute(&db)
+        .await
+        .unwrap();
+
+    let res = server
+        .post("/api/v1/auth/password-reset/confirm")
+        .json(&ResetPassword {
+            token: server.last_token(TestUser::ALEX.email),
+            new_password: "an0ther-pa55".to_string(),
+        })
+        .await;
+    res.assert_status_bad_request();
+}
+
+#[sqlx::test(migrations = "../../migrations")]
+async fn reset_password_after_email_change(db: PgPool) {
+    let server = TestApp::new(db.clone());
+    let token = TestUser::ALEX.create_and_auth(&server).await;
+    let mails = server.mails(TestUser::ALEX.email).len();
+
+    let res = server
+        .post("/api/v1/auth/password-reset")
+        .json(&RequestPasswordReset {
+            email: TestUser::ALEX.email.to_string(),
+        })
+        .await;
+    res.assert_status(StatusCode::NO_CONTENT);
+    server.wait_for_mails(TestUser::ALEX.email, mails + 1).await;
+
+    let reset = ResetPassword {
+        token: server.last_token(TestUser::ALEX.email),
+        new_password: "c0rrect-h0rse".to_string(),
+    };
+
+    let res = server
+        .patch("/api/v1/user/me")
+        .add_header(AUTHORIZATION, &token.token)
+        .json(&UpdateUser {
+            email: Some("someone@example.com".to_string()),
+            ..Default::default()
+        })
+        .await;
+    res.assert_status_ok();
+
+    // the token went to the old address, it can't verify the new one
+    let res = server
+        .post("/api/v1/auth/password-reset/confirm")
+        .json(&reset)
+        .await;
+    res.assert_status_bad_request();
+
+    let res = server
+        .get("/api/v1/user/me")
+        .add_header(AUTHORIZATION, &token.token)
+        .await;
+    let user: User = res.json();
+    assert_eq!(user.email, "someone@example.com");
+    assert!(!user.email_verified);
+}
+
+#[sqlx::test(migrations = "../../migrations")]
+async fn reset_password_limit(db: PgPool) {
+    let server = TestApp::new(db.clone());
+    TestUser::ALEX.create(&server).await;
+    let mails = server.mails(TestUser::ALEX.email).len();
+
+    let request = RequestPasswordReset {
+        email: TestUser::ALEX.email.to_string(),
+    };
+
+    // requests beyond the limit look the same but send nothing
+    for _ in 0..5 {
+        let res = server
+            .post("/api/v1/auth/password-reset")
+            .json(&request)
+            .await;
+        res.assert_status(StatusCode::NO_CONTENT);
+    }
+
+    server.wait_for_mails(TestUser::ALEX.email, mails + 3).await;
+
+    let pending = sqlx::query!(r#"select count(*) as "count!" from password_resets"#)
+        .fetch_one(&db)
+        .await
+        .unwrap()
+        .count;
+    assert_eq!(pending, 3);
+
+    // expired tokens don't count
+    sqlx::query("update password_resets set expires_at = now() - interval '1 hour'")
+        .execute(&db)
+        .await
+        .unwrap();
+
+    let res = server
+        .post("/api/v1/auth/password-reset")
+        .json(&request)
+        .await;
+    res.assert_status(StatusCode::NO_CONTENT);
+    server.wait_for_mails(TestUser::ALEX.email, mails + 4).await;
+}
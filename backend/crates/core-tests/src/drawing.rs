@@ -1,11 +1,21 @@
+use std::io::Cursor;
+
 use axum::http::StatusCode;
-use axum::http::header::{AUTHORIZATION, CONTENT_TYPE};
+use axum::http::header::{
+    ACCEPT, ACCEPT_RANGES, AUTHORIZATION, CACHE_CONTROL, CONTENT_DISPOSITION, CONTENT_LENGTH,
+    CONTENT_RANGE, CONTENT_TYPE, ETAG, IF_NONE_MATCH, IF_RANGE, RANGE,
+};
 use axum_test::TestServer;
 use axum_test::multipart::{MultipartForm, Part};
 use chrono::{TimeDelta, Utc};
-use core_backend::model::{Drawing, Items, NewDrawing, Token};
+use core_backend::model::{
+    Drawing, DrawingVersion, Items, NewDrawing, Token, UpdateDrawing, VersionDiff, VersionOperation,
+};
+use image::{DynamicImage, ImageFormat, Rgb, RgbImage};
+use image_backend::model::{Anchor, Background, Pipeline, Region};
 use sqlx::PgPool;
 
+use crate::app::TestApp;
 use crate::user::TestUser;
 
 pub struct TestDrawing {
@@ -38,6 +48,8 @@ impl TestDrawing {
             name: self.name.to_string(),
             width: self.width,
             height: self.height,
+            background: Background::White,
+            team_id: None,
         }
     }
 
@@ -53,8 +65,7 @@ impl TestDrawing {
 
 #[sqlx::test(migrations = "../../migrations")]
 async fn create_drawing(db: PgPool) {
-    let app = core_backend::build_app(db);
-    let server = TestServer::new(app).unwrap();
+    let server = TestApp::new(db);
     let token = TestUser::ALEX.create_and_auth(&server).await;
 
     let now = Utc::now();
@@ -78,8 +89,7 @@ async fn create_drawing(db: PgPool) {
 
 #[sqlx::test(migrations = "../../migrations")]
 async fn get_drawing(db: PgPool) {
-    let app = core_backend::build_app(db);
-    let server = TestServer::new(app).unwrap();
+    let server = TestApp::new(db);
     let token = TestUser::ALEX.create_and_auth(&server).await;
 
     let drawing = TestDrawing::SHARK.create(&server, &token).await;
@@ -94,9 +104,9 @@ async fn get_drawing(db: PgPool) {
 }
 
 #[sqlx::test(migrations = "../../migrations")]
+#[allow(clippy::useless_format)]
 async fn get_owned_drawing(db: PgPool) {
-    let app = core_backend::build_app(db);
-    let server = TestServer::new(app).unwrap();
+    let server = TestApp::new(db);
     let token = TestUser::ALEX.create_and_auth(&server).await;
 
     let shark = TestDrawing::SHARK.create(&server, &token).await;
@@ -115,8 +125,7 @@ async fn get_owned_drawing(db: PgPool) {
 
 #[sqlx::test(migrations = "../../migrations")]
 async fn delete_drawing(db: PgPool) {
-    let app = core_backend::build_app(db);
-    let server = TestServer::new(app).unwrap();
+    let server = TestApp::new(db);
     let token = TestUser::ALEX.create_and_auth(&server).await;
 
     let drawing = TestDrawing::SHARK.create(&server, &token).await;
@@ -138,8 +147,7 @@ async fn delete_drawing(db: PgPool) {
 
 #[sqlx::test(migrations = "../../migrations")]
 async fn get_latest_version(db: PgPool) {
-    let app = core_backend::build_app(db);
-    let server = TestServer::new(app).unwrap();
+    let server = TestApp::new(db);
     let token = TestUser::ALEX.create_and_auth(&server).await;
 
     let drawing = TestDrawing::SHARK.create(&server, &token).await;
@@ -155,8 +163,7 @@ async fn get_latest_version(db: PgPool) {
 
 #[sqlx::test(migrations = "../../migrations")]
 async fn upload_new_version(db: PgPool) {
-    let app = core_backend::build_app(db);
-    let server = TestServer::new(app).unwrap();
+    let server = TestApp::new(db);
     let token = TestUser::ALEX.create_and_auth(&server).await;
 
     let drawing = TestDrawing::KITTEN.create(&server, &token).await;
@@ -177,3 +184,943 @@ async fn upload_new_version(db: PgPool) {
 
     res.assert_status(StatusCode::NO_CONTENT);
 }
+
+#[sqlx::test(migrations = "../../migrations")]
+async fn apply_pipeline(db: PgPool) {
+    let server = TestApp::new(db);
+    let token = TestUser::ALEX.create_and_auth(&server).await;
+
+    let drawing = TestDrawing::SHARK.create(&server, &token).await;
+
+    let pipeline = Pipeline::new().crop(0, 0, 400, 300).rotate(90).grayscale();
+
+    let res = server
+        .post(&format!(
+            "/api/v1/drawing/{}/operation/pipeline",
+            drawing.id
+        ))
+        .add_header(AUTHORIZATION, &token.token)
+        .json(&pipeline)
+        .await;
+
+    res.assert_status(StatusCode::NO_CONTENT);
+
+    let drawing: Drawing = server
+        .get(&format!("/api/v1/drawing/{}", drawing.id))
+        .add_header(AUTHORIZATION, &token.token)
+        .await
+        .json();
+
+    assert_eq!(drawing.width, 300);
+    assert_eq!(drawing.height, 400);
+
+    let versions: Items<DrawingVersion> = server
+        .get(&format!("/api/v1/drawing/{}/version", drawing.id))
+        .add_header(AUTHORIZATION, &token.token)
+        .await
+        .json();
+
+    assert_eq!(versions.items.len(), 2);
+    assert_eq!(versions.items[0].width, 300);
+
+    let res = server
+        .post(&format!(
+            "/api/v1/drawing/{}/operation/pipeline",
+            drawing.id
+        ))
+        .add_header(AUTHORIZATION, &token.token)
+        .json(&Pipeline::new().resize_canvas(4096, 300))
+        .await;
+
+    res.assert_status_bad_request();
+
+    // so is a pipeline that only grows the image along the way
+    let res = server
+        .post(&format!(
+            "/api/v1/drawing/{}/operation/pipeline",
+            drawing.id
+        ))
+        .add_header(AUTHORIZATION, &token.token)
+        .json(
+            &Pipeline::new()
+                .resize_canvas(4096, 300)
+                .crop(0, 0, 300, 300),
+        )
+        .await;
+
+    res.assert_status_bad_request();
+
+    let res = server
+        .post(&format!(
+            "/api/v1/drawing/{}/operation/pipeline",
+            drawing.id
+        ))
+        .add_header(AUTHORIZATION, &token.token)
+        .json(&Pipeline::new().crop(200, 0, 200, 200))
+        .await;
+
+    res.assert_status_bad_request();
+}
+
+#[sqlx::test(migrations = "../../migrations")]
+async fn blur_and_sharpen_drawing(db: PgPool) {
+    let server = TestApp::new(db);
+    let token = TestUser::ALEX.create_and_auth(&server).await;
+
+    let drawing = TestDrawing::SHARK.create(&server, &token).await;
+
+    let res = server
+        .post(&format!("/api/v1/drawing/{}/operation/blur", drawing.id))
+        .add_header(AUTHORIZATION, &token.token)
+        .add_query_param("sigma", 3)
+        .add_query_param("kind", "gaussian")
+        .await;
+
+    res.assert_status(StatusCode::NO_CONTENT);
+
+    let res = server
+        .post(&format!("/api/v1/drawing/{}/operation/sharpen", drawing.id))
+        .add_header(AUTHORIZATION, &token.token)
+        .add_query_param("sigma", 2)
+        .await;
+
+    res.assert_status(StatusCode::NO_CONTENT);
+
+    let versions: Items<DrawingVersion> = server
+        .get(&format!("/api/v1/drawing/{}/version", drawing.id))
+        .add_header(AUTHORIZATION, &token.token)
+        .await
+        .json();
+
+    assert_eq!(versions.items.len(), 3);
+
+    let res = server
+        .post(&format!("/api/v1/drawing/{}/operation/blur", drawing.id))
+        .add_header(AUTHORIZATION, &token.token)
+        .add_query_param("sigma", -1)
+        .await;
+
+    res.assert_status_bad_request();
+}
+
+#[sqlx::test(migrations = "../../migrations")]
+async fn crop_and_resize_drawing(db: PgPool) {
+    let server = TestApp::new(db);
+    let token = TestUser::ALEX.create_and_auth(&server).await;
+
+    let drawing = TestDrawing::SHARK.create(&server, &token).await;
+
+    let res = server
+        .patch(&format!("/api/v1/drawing/{}", drawing.id))
+        .add_header(AUTHORIZATION, &token.token)
+        .json(&UpdateDrawing {
+            width: Some(1000),
+            anchor: Some(Anchor::Center),
+            offset_y: Some(-100),
+            ..Default::default()
+        })
+        .await;
+
+    res.assert_status_ok();
+    let drawing: Drawing = res.json();
+    assert_eq!((drawing.width, drawing.height), (1000, 600));
+
+    let res = server
+        .post(&format!("/api/v1/drawing/{}/operation/crop", drawing.id))
+        .add_header(AUTHORIZATION, &token.token)
+        .add_query_param("x", 100)
+        .add_query_param("y", 50)
+        .add_query_param("width", 500)
+        .add_query_param("height", 300)
+        .await;
+
+    res.assert_status(StatusCode::NO_CONTENT);
+
+    let res = server
+        .post(&format!("/api/v1/drawing/{}/operation/invert", drawing.id))
+        .add_header(AUTHORIZATION, &token.token)
+        .add_query_param("region", "0,0,100,100")
+        .await;
+
+    res.assert_status(StatusCode::NO_CONTENT);
+
+    let drawing: Drawing = server
+        .get(&format!("/api/v1/drawing/{}", drawing.id))
+        .add_header(AUTHORIZATION, &token.token)
+        .await
+        .json();
+
+    assert_eq!((drawing.width, drawing.height), (500, 300));
+
+    let res = server
+        .post(&format!("/api/v1/drawing/{}/operation/invert", drawing.id))
+        .add_header(AUTHORIZATION, &token.token)
+        .add_query_param("region", "400,0,200,100")
+        .await;
+
+    res.assert_status_bad_request();
+}
+
+#[sqlx::test(migrations = "../../migrations")]
+async fn create_transparent_drawing(db: PgPool) {
+    let server = TestApp::new(db);
+    let token = TestUser::ALEX.create_and_auth(&server).await;
+
+    let res = server
+        .post("/api/v1/drawing")
+        .add_header(AUTHORIZATION, &token.token)
+        .json(&NewDrawing {
+            background: Background::Transparent,
+            ..TestDrawing::SHARK.as_new_drawing()
+        })
+        .await;
+
+    res.assert_status(StatusCode::CREATED);
+    let drawing: Drawing = res.json();
+    assert_eq!(drawing.background, Background::Transparent);
+
+    // new canvas space uses the drawing's background
+    let res = server
+        .patch(&format!("/api/v1/drawing/{}", drawing.id))
+        .add_header(AUTHORIZATION, &token.token)
+        .json(&UpdateDrawing {
+            width: Some(900),
+            ..Default::default()
+        })
+        .await;
+    res.assert_status_ok();
+
+    let res = server
+        .get(&format!("/api/v1/drawing/{}/version/latest", drawing.id))
+        .add_header(AUTHORIZATION, &token.token)
+        .await;
+    res.assert_status_ok();
+
+    let image = image::load_from_memory(res.as_bytes()).unwrap().to_rgba8();
+    assert_eq!(image.dimensions(), (900, 600));
+    assert_eq!(image.get_pixel(899, 0).0[3], 0);
+
+    let res = server
+        .post("/api/v1/drawing")
+        .add_header(AUTHORIZATION, &token.token)
+        .json(&serde_json::json!({
+            "name": "invalid",
+            "width": 10,
+            "height": 10,
+            "background": "pink",
+        }))
+        .await;
+    res.assert_status(StatusCode::UNPROCESSABLE_ENTITY);
+}
+
+#[sqlx::test(migrations = "../../migrations")]
+async fn import_drawing(db: PgPool) {
+    let server = TestApp::new(db);
+    let token = TestUser::ALEX.create_and_auth(&server).await;
+
+    let image = DynamicImage::ImageRgb8(RgbImage::from_pixel(300, 200, Rgb([20, 120, 220])));
+    let mut data = Vec::new();
+    image
+        .write_to(&mut Cursor::new(&mut data), ImageFormat::Jpeg)
+        .unwrap();
+
+    let res = server
+        .post("/api/v1/drawing/import")
+        .add_header(AUTHORIZATION, &token.token)
+        .multipart(
+            MultipartForm::new().add_part(
+                "image",
+                Part::bytes(data.clone())
+                    .file_name("holiday.jpg")
+                    .mime_type("image/jpeg"),
+            ),
+        )
+        .await;
+
+    res.assert_status(StatusCode::CREATED);
+    let drawing: Drawing = res.json();
+    assert_eq!(drawing.name, "holiday");
+    assert_eq!((drawing.width, drawing.height), (300, 200));
+
+    let res = server
+        .post("/api/v1/drawing/import")
+        .add_header(AUTHORIZATION, &token.token)
+        .multipart(
+            MultipartForm::new()
+                .add_text("name", "photo")
+                .add_part("image", Part::bytes(data)),
+        )
+        .await;
+
+    res.assert_status(StatusCode::CREATED);
+    let drawing: Drawing = res.json();
+    assert_eq!(drawing.name, "photo");
+
+    // new versions may use any supported format as well
+    let image = DynamicImage::ImageRgb8(RgbImage::from_pixel(300, 200, Rgb([0, 0, 0])));
+    let mut data = Vec::new();
+    image
+        .write_to(&mut Cursor::new(&mut data), ImageFormat::Jpeg)
+        .unwrap();
+
+    let res = server
+        .put(&format!("/api/v1/drawing/{}/version/latest", drawing.id))
+        .add_header(AUTHORIZATION, &token.token)
+        .multipart(MultipartForm::new().add_part("image", Part::bytes(data)))
+        .await;
+
+    res.assert_status(StatusCode::NO_CONTENT);
+
+    let res = server
+        .post("/api/v1/drawing/import")
+        .add_header(AUTHORIZATION, &token.token)
+        .multipart(MultipartForm::new().add_text("name", "nothing"))
+        .await;
+
+    res.assert_status_bad_request();
+}
+
+#[sqlx::test(migrations = "../../migrations")]
+async fn download_formats(db: PgPool) {
+    let server = TestApp::new(db);
+    let token = TestUser::ALEX.create_and_auth(&server).await;
+
+    let drawing = TestDrawing::SHARK.create(&server, &token).await;
+    let path = format!("/api/v1/drawing/{}/version/latest", drawing.id);
+
+    let res = server
+        .get(&path)
+        .add_header(AUTHORIZATION, &token.token)
+        .add_query_param("format", "jpeg")
+        .add_query_param("quality", 80)
+        .await;
+
+    res.assert_status_ok();
+    res.assert_header(CONTENT_TYPE, "image/jpeg");
+    res.assert_header(
+        CONTENT_DISPOSITION,
+        "attachment; filename=\"shark.jpg\"; filename*=UTF-8''shark%2Ejpg",
+    );
+
+    let res = server
+        .get(&path)
+        .add_header(AUTHORIZATION, &token.token)
+        .add_header(ACCEPT, "image/webp")
+        .add_query_param("thumbnail", true)
+        .await;
+
+    res.assert_status_ok();
+    res.assert_header(CONTENT_TYPE, "image/webp");
+
+    let res = server
+        .get(&path)
+        .add_header(AUTHORIZATION, &token.token)
+        .add_header(ACCEPT, "application/json")
+        .await;
+
+    res.assert_status(StatusCode::NOT_ACCEPTABLE);
+
+    let drawing = TestDrawing::KITTEN.create(&server, &token).await;
+
+    let res = server
+        .get(&format!("/api/v1/drawing/{}/version/latest", drawing.id))
+        .add_header(AUTHORIZATION, &token.token)
+        .await;
+
+    res.assert_status_ok();
+    res.assert_header(CONTENT_TYPE, "image/png");
+    res.assert_header(
+        CONTENT_DISPOSITION,
+        "attachment; filename=\"kitteh_3.png\"; filename*=UTF-8''kitteh%3A3%2Epng",
+    );
+}
+
+#[sqlx::test(migrations = "../../migrations")]
+async fn download_previews(db: PgPool) {
+    let server = TestApp::new(db);
+    let token = TestUser::ALEX.create_and_auth(&server).await;
+
+    let drawing = TestDrawing::SHARK.create(&server, &token).await;
+    let path = format!("/api/v1/drawing/{}/version/latest", drawing.id);
+
+    for (fit, expected) in [("contain", (160, 120)), ("cover", (160, 160))] {
+        let res = server
+            .get(&path)
+            .add_header(AUTHORIZATION, &token.token)
+            .add_query_param("w", 160)
+            .add_query_param("h", 160)
+            .add_query_param("fit", fit)
+            .await;
+
+        res.assert_status_ok();
+        let image = image::load_from_memory(res.as_bytes()).unwrap();
+        assert_eq!((image.width(), image.height()), expected);
+    }
+
+    let res = server
+        .get(&path)
+        .add_header(AUTHORIZATION, &token.token)
+        .add_query_param("h", 300)
+        .add_query_param("format", "jpeg")
+        .await;
+
+    res.assert_status_ok();
+    res.assert_header(CONTENT_TYPE, "image/jpeg");
+    let image = image::load_from_memory(res.as_bytes()).unwrap();
+    assert_eq!((image.width(), image.height()), (400, 300));
+
+    let res = server
+        .get(&path)
+        .add_header(AUTHORIZATION, &token.token)
+        .add_query_param("w", 100_000)
+        .await;
+
+    res.assert_status_bad_request();
+}
+
+#[sqlx::test(migrations = "../../migrations")]
+async fn conditional_downloads(db: PgPool) {
+    let server = TestApp::new(db);
+    let token = TestUser::ALEX.create_and_auth(&server).await;
+
+    let drawing = TestDrawing::SHARK.create(&server, &token).await;
+    let latest = format!("/api/v1/drawing/{}/version/latest", drawing.id);
+
+    let res = server
+        .get(&latest)
+        .add_header(AUTHORIZATION, &token.token)
+        .await;
+    res.assert_status_ok();
+    res.assert_header(CACHE_CONTROL, "private, no-cache");
+    let etag = res.header(ETAG);
+
+    let res = server
+        .get(&latest)
+        .add_header(AUTHORIZATION, &token.token)
+        .add_header(IF_NONE_MATCH, etag.clone())
+        .await;
+    res.assert_status(StatusCode::NOT_MODIFIED);
+    res.assert_header(ETAG, etag.clone());
+    assert!(res.as_bytes().is_empty());
+
+    let res = server
+        .patch(&format!("/api/v1/drawing/{}", drawing.id))
+        .add_header(AUTHORIZATION, &token.token)
+        .json(&UpdateDrawing {
+            width: Some(900),
+            ..Default::default()
+        })
+        .await;
+    res.assert_status_ok();
+
+    // the latest version changed
+    let res = server
+        .get(&latest)
+        .add_header(AUTHORIZATION, &token.token)
+        .add_header(IF_NONE_MATCH, etag.clone())
+        .await;
+    res.assert_status_ok();
+    let latest_etag = res.header(ETAG);
+    assert_ne!(latest_etag, etag);
+
+    let version = format!("/api/v1/drawing/{}/version/2", drawing.id);
+
+    let res = server
+        .get(&version)
+        .add_header(AUTHORIZATION, &token.token)
+        .add_header(IF_NONE_MATCH, latest_etag.clone())
+        .await;
+    res.assert_status(StatusCode::NOT_MODIFIED);
+    res.assert_header(CACHE_CONTROL, "private, max-age=31536000, immutable");
+
+    let res = server
+        .get(&version)
+        .add_header(AUTHORIZATION, &token.token)
+        .add_header(IF_NONE_MATCH, latest_etag)
+        .add_query_param("thumbnail", true)
+        .await;
+    res.assert_status_ok();
+}
+
+#[sqlx::test(migrations = "../../migrations")]
+async fn download_keeps_content_length(db: PgPool) {
+    let server = TestApp::new(db);
+    let token = TestUser::ALEX.create_and_auth(&server).await;
+
+    let drawing = TestDrawing::MIKU.create(&server, &token).await;
+
+    for format in ["png", "webp"] {
+        let res = server
+            .get(&format!("/api/v1/drawing/{}/version/latest", drawing.id))
+            .add_header(AUTHORIZATION, &token.token)
+            .add_query_param("format", format)
+            .await;
+
+        res.assert_status_ok();
+        res.assert_header(CONTENT_LENGTH, res.as_bytes().len().to_string());
+
+        let image = image::load_from_memory(res.as_bytes()).unwrap();
+        assert_eq!((image.width(), image.height()), (1920, 1080));
+    }
+}
+
+#[sqlx::test(migrations = "../../migrations")]
+async fn resume_download(db: PgPool) {
+    let server = TestApp::new(db);
+    let token = TestUser::ALEX.create_and_auth(&server).await;
+
+    let drawing = TestDrawing::MIKU.create(&server, &token).await;
+    let path = format!("/api/v1/drawing/{}/version/latest", drawing.id);
+
+    let res = server
+        .get(&path)
+        .add_header(AUTHORIZATION, &token.token)
+        .await;
+    res.assert_status_ok();
+    res.assert_header(ACCEPT_RANGES, "bytes");
+    let full = res.as_bytes().clone();
+    let etag = res.header(ETAG);
+
+    let res = server
+        .get(&path)
+        .add_header(AUTHORIZATION, &token.token)
+        .add_header(RANGE, "bytes=0-99")
+        .await;
+    res.assert_status(StatusCode::PARTIAL_CONTENT);
+    res.assert_header(CONTENT_RANGE, format!("bytes 0-99/{}", full.len()));
+    res.assert_header(CONTENT_LENGTH, "100");
+    let mut resumed = res.as_bytes().to_vec();
+
+    let res = server
+        .get(&path)
+        .add_header(AUTHORIZATION, &token.token)
+        .add_header(RANGE, "bytes=100-")
+        .add_header(IF_RANGE, etag)
+        .await;
+    res.assert_status(StatusCode::PARTIAL_CONTENT);
+    resumed.extend_from_slice(res.as_bytes());
+
+    assert_eq!(resumed, full);
+
+    let res = server
+        .get(&path)
+        .add_header(AUTHORIZATION, &token.token)
+        .add_header(RANGE, format!("bytes={}-", full.len()))
+        .await;
+    res.assert_status(StatusCode::RANGE_NOT_SATISFIABLE);
+    res.assert_header(CONTENT_RANGE, format!("bytes */{}", full.len()));
+}
+
+#[sqlx::test(migrations = "../../migrations")]
+async fn version_history(db: PgPool) {
+    let server = TestApp::new(db);
+    let token = TestUser::ALEX.create_and_auth(&server).await;
+
+    let drawing = TestDrawing::SHARK.create(&server, &token).await;
+
+    let res = server
+        .patch(&format!("/api/v1/drawing/{}", drawing.id))
+        .add_header(AUTHORIZATION, &token.token)
+        .json(&UpdateDrawing {
+            width: Some(900),
+            ..Default::default()
+        })
+        .await;
+    res.assert_status_ok();
+
+    let res = server
+        .post(&format!("/api/v1/drawing/{}/operation/blur", drawing.id))
+        .add_header(AUTHORIZATION, &token.token)
+        .add_query_param("sigma", 10)
+        .await;
+    res.assert_status(StatusCode::NO_CONTENT);
+
+    let res = server
+        .post(&format!("/api/v1/drawing/{}/operation/invert", drawing.id))
+        .add_header(AUTHORIZATION, &token.token)
+        .await;
+    res.assert_status(StatusCode::NO_CONTENT);
+
+    let versions: Items<DrawingVersion> = server
+        .get(&format!("/api/v1/drawing/{}/version", drawing.id))
+        .add_header(AUTHORIZATION, &token.token)
+        .await
+        .json();
+
+    let [invert, blur, resize, create] = &versions.items[..] else {
+        panic!("expected four versions, got {:?}", versions.items);
+    };
+
+    assert_eq!(create.operation, VersionOperation::Create);
+    assert_eq!(create.parent_id, None);
+    assert_eq!(create.author, TestUser::ALEX.username);
+
+    assert_eq!(resize.operation, VersionOperation::Resize);
+    assert_eq!(resize.parent_id, Some(create.id));
+    assert_eq!(resize.author, TestUser::ALEX.username);
+    let parameters = resize.parameters.as_ref().unwrap();
+    assert_eq!(parameters["width"], 900);
+    assert_eq!(parameters["height"], 600);
+
+    assert_eq!(blur.operation, VersionOperation::Blur);
+    assert_eq!(blur.parent_id, Some(resize.id));
+    assert_eq!(blur.parameters.as_ref().unwrap()["sigma"], 10.0);
+    assert_eq!(blur.parameters.as_ref().unwrap()["kind"], "fast");
+
+    assert_eq!(invert.operation, VersionOperation::Invert);
+    assert_eq!(invert.parent_id, Some(blur.id));
+    assert_eq!(invert.author, TestUser::ALEX.username);
+}
+
+#[sqlx::test(migrations = "../../migrations")]
+async fn restore_version(db: PgPool) {
+    let server = TestApp::new(db);
+    let token = TestUser::ALEX.create_and_auth(&server).await;
+
+    let drawing = TestDrawing::SHARK.create(&server, &token).await;
+
+    let res = server
+        .patch(&format!("/api/v1/drawing/{}", drawing.id))
+        .add_header(AUTHORIZATION, &token.token)
+        .json(&UpdateDrawing {
+            width: Some(900),
+            ..Default::default()
+        })
+        .await;
+    res.assert_status_ok();
+
+    let res = server
+        .post(&format!("/api/v1/drawing/{}/operation/crop", drawing.id))
+        .add_header(AUTHORIZATION, &token.token)
+        .add_query_param("x", 0)
+        .add_query_param("y", 0)
+        .add_query_param("width", 100)
+        .add_query_param("height", 100)
+        .await;
+    res.assert_status(StatusCode::NO_CONTENT);
+
+    let res = server
+        .post(&format!("/api/v1/drawing/{}/version/2/restore", drawing.id))
+        .add_header(AUTHORIZATION, &token.token)
+        .await;
+    res.assert_status(StatusCode::NO_CONTENT);
+
+    let restored: Drawing = server
+        .get(&format!("/api/v1/drawing/{}", drawing.id))
+        .add_header(AUTHORIZATION, &token.token)
+        .await
+        .json();
+    assert_eq!((restored.width, restored.height), (900, 600));
+
+    let etag = |version: &'static str| {
+        let server = &server;
+        let token = &token;
+        async move {
+            server
+                .get(&format!("/api/v1/drawing/{}/version/{version}", drawing.id))
+                .add_header(AUTHORIZATION, &token.token)
+                .await
+                .header(ETAG)
+        }
+    };
+
+    assert_eq!(etag("latest").await, etag("2").await);
+    assert_eq!(etag("4").await, etag("2").await);
+
+    let versions: Items<DrawingVersion> = server
+        .get(&format!("/api/v1/drawing/{}/version", drawing.id))
+        .add_header(AUTHORIZATION, &token.token)
+        .await
+        .json();
+
+    let restore = &versions.items[0];
+    assert_eq!(restore.id, 4);
+    assert_eq!(restore.operation, VersionOperation::Restore);
+    assert_eq!(restore.parent_id, Some(3));
+    assert_eq!(restore.parameters.as_ref().unwrap()["versionId"], 2);
+
+    let res = server
+        .post(&format!("/api/v1/drawing/{}/version/9/restore", drawing.id))
+        .add_header(AUTHORIZATION, &token.token)
+        .await;
+    res.assert_status_not_found();
+}
+
+#[sqlx::test(migrations = "../../migrations")]
+async fn undo_and_redo(db: PgPool) {
+    let server = TestApp::new(db);
+    let token = TestUser::ALEX.create_and_auth(&server).await;
+
+    let drawing = TestDrawing::SHARK.create(&server, &token).await;
+    assert_eq!(drawing.current_version_id, 1);
+
+    let post = |action: &'static str| {
+        let server = &server;
+        let token = &token;
+        async move {
+            server
+                .post(&format!("/api/v1/drawing/{}/{action}", drawing.id))
+                .add_header(AUTHORIZATION, &token.token)
+                .await
+        }
+    };
+
+    let current = || {
+        let server = &server;
+        let token = &token;
+        async move {
+            let drawing: Drawing = server
+                .get(&format!("/api/v1/drawing/{}", drawing.id))
+                .add_header(AUTHORIZATION, &token.token)
+                .await
+                .json();
+            drawing
+        }
+    };
+
+    post("undo").await.assert_status(StatusCode::CONFLICT);
+
+    let res = server
+        .patch(&format!("/api/v1/drawing/{}", drawing.id))
+        .add_header(AUTHORIZATION, &token.token)
+        .json(&UpdateDrawing {
+            width: Some(900),
+            ..Default::default()
+        })
+        .await;
+    res.assert_status_ok();
+
+    post("operation/invert")
+        .await
+        .assert_status(StatusCode::NO_CONTENT);
+    assert_eq!(current().await.current_version_id, 3);
+
+    post("redo").await.assert_status(StatusCode::CONFLICT);
+
+    post("undo").await.assert_status(StatusCode::NO_CONTENT);
+    post("undo").await.assert_status(StatusCode::NO_CONTENT);
+
+    let undone = current().await;
+    assert_eq!(undone.current_version_id, 1);
+    assert_eq!(
+        (undone.width, undone.height),
+        (drawing.width, drawing.height)
+    );
+
+    post("redo").await.assert_status(StatusCode::NO_CONTENT);
+
+    let redone = current().await;
+    assert_eq!(redone.current_version_id, 2);
+    assert_eq!(redone.width, 900);
+
+    // editing after an undo branches off the current version
+    post("operation/invert")
+        .await
+        .assert_status(StatusCode::NO_CONTENT);
+
+    let versions: Items<DrawingVersion> = server
+        .get(&format!("/api/v1/drawing/{}/version", drawing.id))
+        .add_header(AUTHORIZATION, &token.token)
+        .await
+        .json();
+
+    assert_eq!(versions.items.len(), 4);
+    assert_eq!(versions.items[0].id, 4);
+    assert_eq!(versions.items[0].parent_id, Some(2));
+    assert_eq!(current().await.current_version_id, 4);
+
+    // redo follows the newest branch
+    post("undo").await.assert_status(StatusCode::NO_CONTENT);
+    post("redo").await.assert_status(StatusCode::NO_CONTENT);
+    assert_eq!(current().await.current_version_id, 4);
+}
+
+#[sqlx::test(migrations = "../../migrations")]
+async fn concurrent_edits(db: PgPool) {
+    let server = TestApp::new(db);
+    let token = TestUser::ALEX.create_and_auth(&server).await;
+
+    let drawing = TestDrawing::SHARK.create(&server, &token).await;
+
+    let restore = async || {
+        server
+            .post(&format!("/api/v1/drawing/{}/version/1/restore", drawing.id))
+            .add_header(AUTHORIZATION, &token.token)
+            .await
+    };
+
+    // changes to the same drawing run one after the other, each one building
+    // on the version recorded by the previous one
+    let (a, b, c, d) = tokio::join!(restore(), restore(), restore(), restore());
+
+    for res in [a, b, c, d] {
+        res.assert_status(StatusCode::NO_CONTENT);
+    }
+
+    let versions: Items<DrawingVersion> = server
+        .get(&format!("/api/v1/drawing/{}/version", drawing.id))
+        .add_header(AUTHORIZATION, &token.token)
+        .await
+        .json();
+
+    assert_eq!(versions.items.len(), 5);
+
+    for version in &versions.items[..4] {
+        assert_eq!(version.parent_id, Some(version.id - 1));
+    }
+
+    let res = server
+        .get(&format!("/api/v1/drawing/{}", drawing.id))
+        .add_header(AUTHORIZATION, &token.token)
+        .await;
+    assert_eq!(res.json::<Drawing>().current_version_id, 5);
+}
+
+#[sqlx::test(migrations = "../../migrations")]
+async fn concurrent_operations(db: PgPool) {
+    let server = TestApp::new(db);
+    let token = TestUser::ALEX.create_and_auth(&server).await;
+
+    let drawing = TestDrawing::SHARK.create(&server, &token).await;
+
+    let invert = async || {
+        server
+            .post(&format!("/api/v1/drawing/{}/operation/invert", drawing.id))
+            .add_header(AUTHORIZATION, &token.token)
+            .await
+    };
+
+    // operations run on the image service before the drawing is locked, one
+    // applied to a version that was replaced in the meantime is refused
+    let (a, b, c, d) = tokio::join!(invert(), invert(), invert(), invert());
+
+    let mut applied = 0;
+
+    for res in [a, b, c, d] {
+        match res.status_code() {
+            StatusCode::NO_CONTENT => applied += 1,
+            status => assert_eq!(status, StatusCode::CONFLICT),
+        }
+    }
+
+    assert!(applied >= 1);
+
+    let versions: Items<DrawingVersion> = server
+        .get(&format!("/api/v1/drawing/{}/version", drawing.id))
+        .add_header(AUTHORIZATION, &token.token)
+        .await
+        .json();
+
+    assert_eq!(versions.items.len(), 1 + applied);
+
+    for version in &versions.items[..applied] {
+        assert_eq!(version.parent_id, Some(version.id - 1));
+    }
+}
+
+#[sqlx::test(migrations = "../../migrations")]
+async fn version_diff(db: PgPool) {
+    let server = TestApp::new(db.clone());
+    let token = TestUser::ALEX.create_and_auth(&server).await;
+
+    let drawing = TestDrawing::SHARK.create(&server, &token).await;
+
+    let res = server
+        .patch(&format!("/api/v1/drawing/{}", drawing.id))
+        .add_header(AUTHORIZATION, &token.token)
+        .json(&UpdateDrawing {
+            width: Some(900),
+            ..Default::default()
+        })
+        .await;
+    res.assert_status_ok();
+
+    let res = server
+        .post(&format!("/api/v1/drawing/{}/operation/invert", drawing.id))
+        .add_header(AUTHORIZATION, &token.token)
+        .add_query_param("region", "0,0,10,10")
+        .await;
+    res.assert_status(StatusCode::NO_CONTENT);
+
+    let diff = |a: i32, b: i32| {
+        server
+            .get(&format!(
+                "/api/v1/drawing/{}/version/{a}/diff/{b}",
+                drawing.id
+            ))
+            .add_header(AUTHORIZATION, &token.token)
+    };
+
+    // the resize changed the size of the drawing
+    let res = diff(1, 2).await;
+    res.assert_status_ok();
+    let result: VersionDiff = res.json();
+    assert_eq!((result.width, result.height), (900, drawing.height));
+    assert!(result.percent_changed > 0.0);
+
+    let res = diff(2, 3).await;
+    res.assert_status_ok();
+    let result: VersionDiff = res.json();
+    assert_eq!((result.from_version_id, result.to_version_id), (2, 3));
+
+    let cached_diffs = || {
+        let db = db.clone();
+        async move {
+            sqlx::query!("select diff_image_id from image_diffs")
+                .fetch_all(&db)
+                .await
+                .unwrap()
+                .into_iter()
+                .map(|record| record.diff_image_id)
+                .collect::<Vec<_>>()
+        }
+    };
+
+    // the statistics alone don't need a highlight image
+    assert_eq!(cached_diffs().await.len(), 2);
+    assert!(cached_diffs().await.iter().all(Option::is_none));
+    assert_eq!(result.changed_pixels, 100);
+    assert_eq!(
+        result.bounding_box,
+        Some(Region {
+            x: 0,
+            y: 0,
+            width: 10,
+            height: 10,
+        })
+    );
+
+    let res = server
+        .get(&format!(
+            "/api/v1/drawing/{}/version/2/diff/3/image",
+            drawing.id
+        ))
+        .add_header(AUTHORIZATION, &token.token)
+        .await;
+    res.assert_status_ok();
+    res.assert_header(CONTENT_TYPE, "image/png");
+    res.assert_header(CACHE_CONTROL, "private, max-age=31536000, immutable");
+
+    let image = image::load_from_memory(res.as_bytes()).unwrap().to_rgb8();
+    assert_eq!(image.dimensions(), (900, drawing.height as u32));
+    assert_eq!(*image.get_pixel(5, 5), Rgb([255, 0, 0]));
+
+    let highlights: Vec<_> = cached_diffs().await.into_iter().flatten().collect();
+    assert_eq!(highlights.len(), 1);
+
+    // served from the cache, the statistics still match
+    let res = diff(2, 3).await;
+    res.assert_status_ok();
+    assert_eq!(res.json::<VersionDiff>(), result);
+    assert_eq!(
+        cached_diffs()
+            .await
+            .into_iter()
+            .flatten()
+            .collect::<Vec<_>>(),
+        highlights
+    );
+
+    let res = diff(2, 9).await;
+    res.assert_status_not_found();
+}
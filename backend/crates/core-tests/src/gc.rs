@@ -0,0 +1,101 @@
+use axum::http::StatusCode;
+use axum::http::header::AUTHORIZATION;
+use chrono::TimeDelta;
+use core_backend::IMAGE_SERVICE_URL;
+use core_backend::gc::{self, GcOptions};
+use core_backend::model::{Drawing, NewDrawing};
+use image_backend::ImageService;
+use image_backend::model::{Background, ImageId};
+use sqlx::PgPool;
+
+use crate::app::TestApp;
+use crate::drawing::TestDrawing;
+use crate::user::TestUser;
+
+#[sqlx::test(migrations = "../../migrations")]
+async fn gc_dry_run_reports_deleted_drawing(db: PgPool) {
+    let server = TestApp::new(db.clone());
+    let token = TestUser::ALEX.create_and_auth(&server).await;
+
+    let kept = TestDrawing::SHARK.create(&server, &token).await;
+    // blank thumbnails are letterboxed to the same size, so give the
+    // drawings different backgrounds to keep their images apart
+    let deleted: Drawing = server
+        .post("/api/v1/drawing")
+        .add_header(AUTHORIZATION, &token.token)
+        .json(&NewDrawing {
+            background: Background::Transparent,
+            ..TestDrawing::MIKU.as_new_drawing()
+        })
+        .await
+        .json();
+
+    let images = |id: i32| {
+        let db = db.clone();
+        async move {
+            let record = sqlx::query!(
+                "select image_id, thumbnail_image_id from drawings where id = $1",
+                id
+            )
+            .fetch_one(&db)
+            .await
+            .unwrap();
+            [ImageId(record.image_id), ImageId(record.thumbnail_image_id)]
+        }
+    };
+
+    let kept_images = images(kept.id).await;
+    let deleted_images = images(deleted.id).await;
+
+    let res = server
+        .delete(&format!("/api/v1/drawing/{}", deleted.id))
+        .add_header(AUTHORIZATION, &token.token)
+        .await;
+    res.assert_status(StatusCode::NO_CONTENT);
+
+    let image_service = ImageService::new(IMAGE_SERVICE_URL.to_string());
+    let options = GcOptions {
+        grace_period: TimeDelta::zero(),
+        dry_run: true,
+    };
+
+    let report = gc::collect_garbage(&db, &image_service, &options)
+        .await
+        .unwrap();
+
+    assert!(report.dry_run);
+    assert!(report.deleted.is_empty());
+
+    for id in &deleted_images {
+        assert!(report.orphaned.contains(id));
+    }
+
+    for id in &kept_images {
+        assert!(!report.orphaned.contains(id));
+    }
+
+    // a real run with the default grace period leaves the images alone, but
+    // still drops the versions of the deleted drawing
+    let report = gc::collect_garbage(&db, &image_service, &GcOptions::default())
+        .await
+        .unwrap();
+
+    assert!(report.deleted.is_empty());
+    assert_eq!(report.removed_versions, 1);
+
+    let versions = |id: i32| {
+        let db = db.clone();
+        async move {
+            sqlx::query_scalar!(
+                r#"select count(*) as "count!" from drawing_versions where drawing_id = $1"#,
+                id
+            )
+            .fetch_one(&db)
+            .await
+            .unwrap()
+        }
+    };
+
+    assert_eq!(versions(deleted.id).await, 0);
+    assert_eq!(versions(kept.id).await, 1);
+}
@@ -1,6 +1,20 @@
 #[cfg(test)]
+mod app;
+#[cfg(test)]
 mod auth;
 #[cfg(test)]
 mod drawing;
 #[cfg(test)]
+mod gc;
+#[cfg(test)]
+mod permission;
+#[cfg(test)]
+mod share;
+#[cfg(test)]
+mod team;
+#[cfg(test)]
+mod thumbnail;
+#[cfg(test)]
+mod transfer;
+#[cfg(test)]
 mod user;
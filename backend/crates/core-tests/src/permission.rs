@@ -0,0 +1,152 @@
+use axum::http::StatusCode;
+use axum::http::header::AUTHORIZATION;
+use core_backend::model::{
+    Drawing, DrawingPermission, DrawingRole, GrantPermission, Items, SharedDrawing,
+};
+use sqlx::PgPool;
+
+use crate::app::TestApp;
+use crate::drawing::TestDrawing;
+use crate::user::TestUser;
+
+#[sqlx::test(migrations = "../../migrations")]
+async fn share_drawing(db: PgPool) {
+    let server = TestApp::new(db);
+    let alex = TestUser::ALEX.create_and_auth(&server).await;
+    let sam = TestUser::SAM.create_and_auth(&server).await;
+
+    let drawing = TestDrawing::SHARK.create(&server, &alex).await;
+    let path = format!("/api/v1/drawing/{}", drawing.id);
+    let sam_path = format!("/api/v1/drawing/{}/permission/sam", drawing.id);
+
+    let res = server
+        .get(&path)
+        .add_header(AUTHORIZATION, &sam.token)
+        .await;
+    res.assert_status_unauthorized();
+
+    let grant = |role: DrawingRole| {
+        server
+            .put(&sam_path)
+            .add_header(AUTHORIZATION, &alex.token)
+            .json(&GrantPermission { role })
+    };
+
+    let res = grant(DrawingRole::Viewer).await;
+    res.assert_status_ok();
+    let permission: DrawingPermission = res.json();
+    assert_eq!(permission.username, TestUser::SAM.username);
+    assert_eq!(permission.role, DrawingRole::Viewer);
+
+    let res = server
+        .get(&path)
+        .add_header(AUTHORIZATION, &sam.token)
+        .await;
+    res.assert_json(&drawing);
+
+    let res = server
+        .get(&format!("{path}/version/latest"))
+        .add_header(AUTHORIZATION, &sam.token)
+        .await;
+    res.assert_status_ok();
+
+    let invert = || {
+        server
+            .post(&format!("{path}/operation/invert"))
+            .add_header(AUTHORIZATION, &sam.token)
+    };
+
+    invert().await.assert_status_unauthorized();
+
+    let shared: Items<SharedDrawing> = server
+        .get("/api/v1/drawing/shared")
+        .add_header(AUTHORIZATION, &sam.token)
+        .await
+        .json();
+    assert_eq!(shared.items.len(), 1);
+    assert_eq!(shared.items[0].drawing, drawing);
+    assert_eq!(shared.items[0].role, DrawingRole::Viewer);
+
+    grant(DrawingRole::Editor).await.assert_status_ok();
+    invert().await.assert_status(StatusCode::NO_CONTENT);
+
+    // only owners manage access and delete drawings
+    let res = server
+        .put(&format!("{path}/permission/alex"))
+        .add_header(AUTHORIZATION, &sam.token)
+        .json(&GrantPermission {
+            role: DrawingRole::Viewer,
+        })
+        .await;
+    res.assert_status_unauthorized();
+
+    let res = server
+        .delete(&path)
+        .add_header(AUTHORIZATION, &sam.token)
+        .await;
+    res.assert_status_unauthorized();
+
+    let permissions: Items<DrawingPermission> = server
+        .get(&format!("{path}/permission"))
+        .add_header(AUTHORIZATION, &sam.token)
+        .await
+        .json();
+    let roles: Vec<_> = permissions
+        .items
+        .iter()
+        .map(|v| (v.username.as_str(), v.role))
+        .collect();
+    assert_eq!(
+        roles,
+        [("alex", DrawingRole::Owner), ("sam", DrawingRole::Editor)]
+    );
+
+    // users may leave drawings shared with them
+    let res = server
+        .delete(&sam_path)
+        .add_header(AUTHORIZATION, &sam.token)
+        .await;
+    res.assert_status(StatusCode::NO_CONTENT);
+
+    let res = server
+        .get(&path)
+        .add_header(AUTHORIZATION, &sam.token)
+        .await;
+    res.assert_status_unauthorized();
+
+    let shared: Items<Drawing> = server
+        .get("/api/v1/drawing/shared")
+        .add_header(AUTHORIZATION, &sam.token)
+        .await
+        .json();
+    assert!(shared.items.is_empty());
+
+    let res = server
+        .delete(&sam_path)
+        .add_header(AUTHORIZATION, &alex.token)
+        .await;
+    res.assert_status_not_found();
+}
+
+#[sqlx::test(migrations = "../../migrations")]
+async fn grant_permission_checks_user(db: PgPool) {
+    let server = TestApp::new(db);
+    let alex = TestUser::ALEX.create_and_auth(&server).await;
+
+    let drawing = TestDrawing::SHARK.create(&server, &alex).await;
+
+    let grant = |username: &'static str| {
+        server
+            .put(&format!(
+                "/api/v1/drawing/{}/permission/{username}",
+                drawing.id
+            ))
+            .add_header(AUTHORIZATION, &alex.token)
+            .json(&GrantPermission {
+                role: DrawingRole::Editor,
+            })
+    };
+
+    grant("nobody").await.assert_status_not_found();
+    grant("alex").await.assert_status_bad_request();
+}
@@ -0,0 +1,269 @@
+use axum::http::StatusCode;
+use axum::http::header::{AUTHORIZATION, CONTENT_TYPE};
+use chrono::{TimeDelta, Utc};
+use core_backend::model::{Items, NewShareLink, ShareLink, SharedVersion};
+use sqlx::PgPool;
+
+use crate::app::TestApp;
+use crate::drawing::TestDrawing;
+use crate::user::TestUser;
+
+#[sqlx::test(migrations = "../../migrations")]
+async fn share_links(db: PgPool) {
+    let server = TestApp::new(db.clone());
+    let alex = TestUser::ALEX.create_and_auth(&server).await;
+    let sam = TestUser::SAM.create_and_auth(&server).await;
+
+    let drawing = TestDrawing::SHARK.create(&server, &alex).await;
+    let links = format!("/api/v1/drawing/{}/share", drawing.id);
+
+    let res = server
+        .post(&format!("/api/v1/drawing/{}/operation/invert", drawing.id))
+        .add_header(AUTHORIZATION, &alex.token)
+        .await;
+    res.assert_status(StatusCode::NO_CONTENT);
+
+    let res = server
+        .post(&links)
+        .add_header(AUTHORIZATION, &alex.token)
+        .json(&NewShareLink::default())
+        .await;
+    res.assert_status(StatusCode::CREATED);
+    let latest: ShareLink = res.json();
+    assert!(!latest.has_password);
+
+    // no account needed to follow a link
+    let res = server.get(&format!("/api/v1/share/{}", latest.token)).await;
+    res.assert_status_ok();
+    let shared: SharedVersion = res.json();
+    assert_eq!(shared.version_id, 2);
+    assert_eq!(shared.name, drawing.name);
+
+    let res = server
+        .get(&format!("/api/v1/share/{}/image", latest.token))
+        .await;
+    res.assert_status_ok();
+    res.assert_header(CONTENT_TYPE, "image/png");
+
+    let res = server
+        .get(&format!("/api/v1/share/{}/image", latest.token))
+        .add_query_param("thumbnail", true)
+        .await;
+    res.assert_status_ok();
+
+    let res = server
+        .post(&links)
+        .add_header(AUTHORIZATION, &alex.token)
+        .json(&NewShareLink {
+            version_id: Some(1),
+            password: Some("swordfish".to_string()),
+            ..Default::default()
+        })
+        .await;
+    res.assert_status(StatusCode::CREATED);
+    let protected: ShareLink = res.json();
+    assert!(protected.has_password);
+
+    let path = format!("/api/v1/share/{}", protected.token);
+
+    let res = server.get(&path).await;
+    res.assert_status_unauthorized();
+
+    let res = server
+        .get(&path)
+        .add_header("x-share-password", "trout")
+        .await;
+    res.assert_status_unauthorized();
+
+    let res = server
+        .get(&path)
+        .add_header("x-share-password", "swordfish")
+        .await;
+    res.assert_status_ok();
+    let shared: SharedVersion = res.json();
+    assert_eq!(shared.version_id, 1);
+
+    let res = server
+        .get(&links)
+        .add_header(AUTHORIZATION, &alex.token)
+        .await;
+    res.assert_status_ok();
+    let listed: Items<ShareLink> = res.json();
+    let listed_latest = listed.items.iter().find(|v| v.id == latest.id).unwrap();
+    assert_eq!(listed.items.len(), 2);
+    assert_eq!(listed_latest.use_count, 3);
+    assert!(listed_latest.last_used_at.is_some());
+
+    // only owners manage links
+    let res = server
+        .get(&links)
+        .add_header(AUTHORIZATION, &sam.token)
+        .await;
+    res.assert_status_unauthorized();
+
+    let res = server
+        .delete(&format!("{links}/{}", latest.id))
+        .add_header(AUTHORIZATION, &alex.token)
+        .await;
+    res.assert_status(StatusCode::NO_CONTENT);
+
+    let res = server.get(&format!("/api/v1/share/{}", latest.token)).await;
+    res.assert_status_not_found();
+
+    sqlx::query!(
+        "update share_links set expires_at = $1 where id = $2",
+        (Utc::now() - TimeDelta::minutes(1)).naive_utc(),
+        protected.id
+    )
+    .execute(&db)
+    .await
+    .unwrap();
+
+    let res = server
+        .get(&path)
+        .add_header("x-share-password", "swordfish")
+        .await;
+    res.assert_status_not_found();
+
+    let res = server.get("/api/v1/share/not-a-token").await;
+    res.assert_status_not_found();
+}
+
+#[sqlx::test(migrations = "../../migrations")]
+async fn create_share_link_validation(db: PgPool) {
+    let server = TestApp::new(db);
+    let alex = TestUser::ALEX.create_and_auth(&server).await;
+
+    let drawing = TestDrawing::SHARK.create(&server, &alex).await;
+
+    let create = |new_link: NewShareLink| {
+        server
+            .post(&format!("/api/v1/drawing/{}/share", drawing.id))
+            .add_header(AUTHORIZATION, &alex.token)
+            .json(&new_link)
+    };
+
+    let res = create(NewShareLink {
+        expires_at: Some(Utc::now() - TimeDelta::hours(1)),
+        ..Default::default()
+    })
+    .await;
+    res.assert_status_bad_request();
+
+    let res = create(NewShareLink {
+        password: Some(String::new()),
+        ..Default::default()
+    })
+    .await;
+    res.assert_status_bad_request();
+
+    let res = create(NewShareLink {
+        version_id: Some(9),
+        ..Default::default()
+    })
+    .await;
+    res.assert_status_not_found();
+}
+
+#[sqlx::test(migrations = "../../migrations")]
+async fn share_link_password_lockout(db: PgPool) {
+    let server = TestApp::new(db.clone());
+    let alex = TestUser::ALEX.create_and_auth(&server).await;
+
+    let drawing = TestDrawing::SHARK.create(&server, &alex).await;
+
+    let res = server
+        .post(&format!("/api/v1/drawing/{}/share", drawing.id))
+        .add_header(AUTHORIZATION, &alex.token)
+        .json(&NewShareLink {
+            password: Some("swordfish".to_string()),
+            ..Default::default()
+        })
+        .await;
+    res.assert_status(StatusCode::CREATED);
+    let link: ShareLink = res.json();
+
+    let path = format!("/api/v1/share/{}", link.token);
+    let open = |password: &'static str| server.get(&path).add_header("x-share-password", password);
+
+    // a correct password resets the count
+    for _ in 0..4 {
+        open("trout").await.assert_status_unauthorized();
+    }
+    open("swordfish").await.assert_status_ok();
+
+    for _ in 0..5 {
+        open("trout").await.assert_status_unauthorized();
+    }
+
+    // locked even for the correct password
+    let res = open("swordfish").await;
+    res.assert_status(StatusCode::TOO_MANY_REQUESTS);
+
+    let res = server
+        .get(&format!("{path}/image"))
+        .add_header("x-share-password", "swordfish")
+        .await;
+    res.assert_status(StatusCode::TOO_MANY_REQUESTS);
+
+    sqlx::query!(
+        "update share_links set locked_until = $1 where id = $2",
+        (Utc::now() - TimeDelta::minutes(1)).naive_utc(),
+        link.id
+    )
+    .execute(&db)
+    .await
+    .unwrap();
+
+    open("swordfish").await.assert_status_ok();
+}
+
+#[sqlx::test(migrations = "../../migrations")]
+async fn share_link_parallel_guesses(db: PgPool) {
+    let server = TestApp::new(db);
+    let alex = TestUser::ALEX.create_and_auth(&server).await;
+
+    let drawing = TestDrawing::SHARK.create(&server, &alex).await;
+
+    let res = server
+        .post(&format!("/api/v1/drawing/{}/share", drawing.id))
+        .add_header(AUTHORIZATION, &alex.token)
+        .json(&NewShareLink {
+            password: Some("swordfish".to_string()),
+            ..Default::default()
+        })
+        .await;
+    res.assert_status(StatusCode::CREATED);
+    let link: ShareLink = res.json();
+
+    let path = format!("/api/v1/share/{}", link.token);
+    let guess = async || {
+        server
+            .get(&path)
+            .add_header("x-share-password", "trout")
+            .await
+    };
+
+    // attempts are counted before the password is checked, so guesses running
+    // at the same time can't all slip past the limit
+    let (a, b, c, d) = tokio::join!(guess(), guess(), guess(), guess());
+    let (e, f, g, h) = tokio::join!(guess(), guess(), guess(), guess());
+
+    let mut checked = 0;
+
+    for res in [a, b, c, d, e, f, g, h] {
+        match res.status_code() {
+            StatusCode::UNAUTHORIZED => checked += 1,
+            StatusCode::TOO_MANY_REQUESTS => {}
+            status => panic!("unexpected status {status}"),
+        }
+    }
+
+    assert_eq!(checked, 5);
+
+    let res = server
+        .get(&path)
+        .add_header("x-share-password", "swordfish")
+        .await;
+    res.assert_status(StatusCode::TOO_MANY_REQUESTS);
+}
@@ -0,0 +1,253 @@
+use axum::http::StatusCode;
+use axum::http::header::AUTHORIZATION;
+use axum_test::TestServer;
+use core_backend::model::{
+    Drawing, DrawingPermission, DrawingRole, Items, NewDrawing, NewTeam, NewTeamInvite,
+    OwnerChange, Team, TeamInvite, TeamMember, TeamRole, Token, UpdateTeamMember,
+};
+use sqlx::PgPool;
+
+use crate::app::TestApp;
+use crate::drawing::TestDrawing;
+use crate::user::TestUser;
+
+async fn create_team(server: &TestServer, token: &Token) -> Team {
+    let res = server
+        .post("/api/v1/team")
+        .add_header(AUTHORIZATION, &token.token)
+        .json(&NewTeam {
+            name: "Studio".to_string(),
+        })
+        .await;
+    res.assert_status(StatusCode::CREATED);
+    res.json()
+}
+
+async fn create_team_drawing(server: &TestServer, token: &Token, team: &Team) -> Drawing {
+    let res = server
+        .post("/api/v1/drawing")
+        .add_header(AUTHORIZATION, &token.token)
+        .json(&NewDrawing {
+            team_id: Some(team.id),
+            ..TestDrawing::SHARK.as_new_drawing()
+        })
+        .await;
+    res.assert_status(StatusCode::CREATED);
+    res.json()
+}
+
+async fn join_team(
+    server: &TestServer,
+    admin: &Token,
+    user: &TestUser,
+    token: &Token,
+    team: &Team,
+    role: TeamRole,
+) {
+    let res = server
+        .post(&format!("/api/v1/team/{}/invite", team.id))
+        .add_header(AUTHORIZATION, &admin.token)
+        .json(&NewTeamInvite {
+            username: user.username.to_string(),
+            role,
+        })
+        .await;
+    res.assert_status(StatusCode::CREATED);
+
+    let res = server
+        .post(&format!("/api/v1/team/invite/{}/accept", team.id))
+        .add_header(AUTHORIZATION, &token.token)
+        .await;
+    res.assert_status_ok();
+}
+
+#[sqlx::test(migrations = "../../migrations")]
+async fn team_drawings(db: PgPool) {
+    let server = TestApp::new(db);
+    let alex = TestUser::ALEX.create_and_auth(&server).await;
+    let sam = TestUser::SAM.create_and_auth(&server).await;
+
+    let team = create_team(&server, &alex).await;
+    assert_eq!(team.role, TeamRole::Admin);
+
+    let drawing = create_team_drawing(&server, &alex, &team).await;
+    assert_eq!(drawing.team_id, Some(team.id));
+    let path = format!("/api/v1/drawing/{}", drawing.id);
+
+    // team drawings are still listed with the drawings the user owns
+    let owned: Items<Drawing> = server
+        .get("/api/v1/drawing/owned")
+        .add_header(AUTHORIZATION, &alex.token)
+        .await
+        .json();
+    assert_eq!(owned.items, vec![drawing.clone()]);
+
+    let res = server
+        .get(&path)
+        .add_header(AUTHORIZATION, &sam.token)
+        .await;
+    res.assert_status_unauthorized();
+
+    let res = server
+        .post("/api/v1/drawing")
+        .add_header(AUTHORIZATION, &sam.token)
+        .json(&NewDrawing {
+            team_id: Some(team.id),
+            ..TestDrawing::SHARK.as_new_drawing()
+        })
+        .await;
+    res.assert_status_unauthorized();
+
+    let res = server
+        .post(&format!("/api/v1/team/{}/invite", team.id))
+        .add_header(AUTHORIZATION, &alex.token)
+        .json(&NewTeamInvite {
+            username: TestUser::SAM.username.to_string(),
+            role: TeamRole::Member,
+        })
+        .await;
+    res.assert_status(StatusCode::CREATED);
+
+    let invites: Items<TeamInvite> = server
+        .get("/api/v1/team/invite")
+        .add_header(AUTHORIZATION, &sam.token)
+        .await
+        .json();
+    assert_eq!(invites.items.len(), 1);
+    assert_eq!(invites.items[0].team_name, "Studio");
+    assert_eq!(invites.items[0].invited_by, TestUser::ALEX.username);
+
+    let res = server
+        .post(&format!("/api/v1/team/invite/{}/accept", team.id))
+        .add_header(AUTHORIZATION, &sam.token)
+        .await;
+    res.assert_status_ok();
+    let joined: Team = res.json();
+    assert_eq!(joined.role, TeamRole::Member);
+
+    // membership grants access to every team drawing
+    let res = server
+        .get(&path)
+        .add_header(AUTHORIZATION, &sam.token)
+        .await;
+    res.assert_json(&drawing);
+
+    let res = server
+        .post(&format!("{path}/operation/invert"))
+        .add_header(AUTHORIZATION, &sam.token)
+        .await;
+    res.assert_status(StatusCode::NO_CONTENT);
+
+    let res = server
+        .delete(&path)
+        .add_header(AUTHORIZATION, &sam.token)
+        .await;
+    res.assert_status_unauthorized();
+
+    create_team_drawing(&server, &sam, &team).await;
+
+    let drawings: Items<Drawing> = server
+        .get(&format!("/api/v1/team/{}/drawing", team.id))
+        .add_header(AUTHORIZATION, &sam.token)
+        .await
+        .json();
+    assert_eq!(drawings.items.len(), 2);
+
+    let members: Items<TeamMember> = server
+        .get(&format!("/api/v1/team/{}/member", team.id))
+        .add_header(AUTHORIZATION, &sam.token)
+        .await
+        .json();
+    let members: Vec<_> = members
+        .items
+        .iter()
+        .map(|v| (v.username.as_str(), v.role))
+        .collect();
+    assert_eq!(
+        members,
+        [("alex", TeamRole::Admin), ("sam", TeamRole::Member)]
+    );
+
+    // only admins manage members
+    let res = server
+        .patch(&format!("/api/v1/team/{}/member/sam", team.id))
+        .add_header(AUTHORIZATION, &sam.token)
+        .json(&UpdateTeamMember {
+            role: TeamRole::Admin,
+        })
+        .await;
+    res.assert_status_unauthorized();
+
+    let res = server
+        .post(&format!("/api/v1/team/{}/invite", team.id))
+        .add_header(AUTHORIZATION, &alex.token)
+        .json(&NewTeamInvite {
+            username: TestUser::SAM.username.to_string(),
+            role: TeamRole::Viewer,
+        })
+        .await;
+    res.assert_status(StatusCode::CONFLICT);
+}
+
+#[sqlx::test(migrations = "../../migrations")]
+async fn leaving_team_transfers_drawings(db: PgPool) {
+    let server = TestApp::new(db);
+    let alex = TestUser::ALEX.create_and_auth(&server).await;
+    let sam = TestUser::SAM.create_and_auth(&server).await;
+
+    let team = create_team(&server, &alex).await;
+    join_team(
+        &server,
+        &alex,
+        &TestUser::SAM,
+        &sam,
+        &team,
+        TeamRole::Member,
+    )
+    .await;
+
+    let drawing = create_team_drawing(&server, &sam, &team).await;
+    let path = format!("/api/v1/drawing/{}", drawing.id);
+
+    // the last admin has to promote someone before leaving
+    let res = server
+        .delete(&format!("/api/v1/team/{}/member/alex", team.id))
+        .add_header(AUTHORIZATION, &alex.token)
+        .await;
+    res.assert_status(StatusCode::CONFLICT);
+
+    let res = server
+        .delete(&format!("/api/v1/team/{}/member/sam", team.id))
+        .add_header(AUTHORIZATION, &sam.token)
+        .await;
+    res.assert_status(StatusCode::NO_CONTENT);
+
+    let res = server
+        .get(&path)
+        .add_header(AUTHORIZATION, &sam.token)
+        .await;
+    res.assert_status_unauthorized();
+
+    let permissions: Items<DrawingPermission> = server
+        .get(&format!("{path}/permission"))
+        .add_header(AUTHORIZATION, &alex.token)
+        .await
+        .json();
+    assert_eq!(permissions.items[0].username, TestUser::ALEX.username);
+    assert_eq!(permissions.items[0].role, DrawingRole::Owner);
+
+    let history: Items<OwnerChange> = server
+        .get(&format!("{path}/transfer/history"))
+        .add_header(AUTHORIZATION, &alex.token)
+        .await
+        .json();
+    assert_eq!(history.items.len(), 1);
+    assert_eq!(history.items[0].previous_owner, TestUser::SAM.username);
+    assert_eq!(history.items[0].transfer_id, None);
+
+    let res = server
+        .delete(&format!("/api/v1/team/{}/member/sam", team.id))
+        .add_header(AUTHORIZATION, &alex.token)
+        .await;
+    res.assert_status_not_found();
+}
@@ -0,0 +1,94 @@
+use axum::http::header::AUTHORIZATION;
+use axum_test::TestServer;
+use core_backend::IMAGE_SERVICE_URL;
+use core_backend::model::Token;
+use core_backend::thumbnail::{self, THUMBNAIL_SIZE};
+use image_backend::ImageService;
+use sqlx::PgPool;
+
+use crate::app::TestApp;
+use crate::drawing::TestDrawing;
+use crate::user::TestUser;
+
+async fn thumbnail_size(server: &TestServer, token: &Token, path: &str) -> (u32, u32) {
+    let res = server
+        .get(path)
+        .add_header(AUTHORIZATION, &token.token)
+        .add_query_param("thumbnail", true)
+        .await;
+    res.assert_status_ok();
+
+    let image = image::load_from_memory(res.as_bytes()).unwrap();
+    (image.width(), image.height())
+}
+
+#[sqlx::test(migrations = "../../migrations")]
+async fn thumbnails_have_fixed_size(db: PgPool) {
+    let server = TestApp::new(db);
+    let token = TestUser::ALEX.create_and_auth(&server).await;
+
+    for drawing in [TestDrawing::SHARK, TestDrawing::MIKU, TestDrawing::KITTEN] {
+        let drawing = drawing.create(&server, &token).await;
+        let path = format!("/api/v1/drawing/{}/version/latest", drawing.id);
+
+        assert_eq!(
+            thumbnail_size(&server, &token, &path).await,
+            (THUMBNAIL_SIZE, THUMBNAIL_SIZE)
+        );
+    }
+}
+
+#[sqlx::test(migrations = "../../migrations")]
+async fn backfill_thumbnails(db: PgPool) {
+    let server = TestApp::new(db.clone());
+    let token = TestUser::ALEX.create_and_auth(&server).await;
+
+    let drawing = TestDrawing::SHARK.create(&server, &token).await;
+
+    // thumbnails made before the thumbnail operation existed
+    sqlx::query!(
+        "update drawings set thumbnail_image_id = image_id where id = $1",
+        drawing.id
+    )
+    .execute(&db)
+    .await
+    .unwrap();
+
+    sqlx::query!(
+        "update drawing_versions set thumbnail_image_id = image_id where drawing_id = $1",
+        drawing.id
+    )
+    .execute(&db)
+    .await
+    .unwrap();
+
+    let latest = format!("/api/v1/drawing/{}/version/latest", drawing.id);
+    let version = format!("/api/v1/drawing/{}/version/1", drawing.id);
+
+    assert_eq!(thumbnail_size(&server, &token, &latest).await, (800, 600));
+
+    let image_service = ImageService::new(IMAGE_SERVICE_URL.to_string());
+
+    let report = thumbnail::backfill_thumbnails(&db, &image_service)
+        .await
+        .unwrap();
+
+    assert_eq!(report.drawings, 1);
+    assert_eq!(report.versions, 1);
+    assert_eq!(report.failed, 0);
+
+    for path in [&latest, &version] {
+        assert_eq!(
+            thumbnail_size(&server, &token, path).await,
+            (THUMBNAIL_SIZE, THUMBNAIL_SIZE)
+        );
+    }
+
+    let report = thumbnail::backfill_thumbnails(&db, &image_service)
+        .await
+        .unwrap();
+
+    assert_eq!(report.drawings, 0);
+    assert_eq!(report.versions, 0);
+    assert_eq!(report.unchanged, 2);
+}
@@ -0,0 +1,255 @@
+use axum::http::StatusCode;
+use axum::http::header::AUTHORIZATION;
+use core_backend::model::{
+    Drawing, DrawingRole, DrawingTransfer, DrawingVersion, GrantPermission, Items,
+    NewDrawingTransfer, OwnerChange, TransferStatus,
+};
+use sqlx::PgPool;
+
+use crate::app::TestApp;
+use crate::drawing::TestDrawing;
+use crate::user::TestUser;
+
+#[sqlx::test(migrations = "../../migrations")]
+async fn transfer_drawing(db: PgPool) {
+    let server = TestApp::new(db);
+    let alex = TestUser::ALEX.create_and_auth(&server).await;
+    let sam = TestUser::SAM.create_and_auth(&server).await;
+
+    let drawing = TestDrawing::SHARK.create(&server, &alex).await;
+    let path = format!("/api/v1/drawing/{}", drawing.id);
+
+    let res = server
+        .post(&format!("{path}/operation/invert"))
+        .add_header(AUTHORIZATION, &alex.token)
+        .await;
+    res.assert_status(StatusCode::NO_CONTENT);
+
+    let versions: Items<DrawingVersion> = server
+        .get(&format!("{path}/version"))
+        .add_header(AUTHORIZATION, &alex.token)
+        .await
+        .json();
+
+    // an editor can't give the drawing away
+    let res = server
+        .put(&format!("{path}/permission/sam"))
+        .add_header(AUTHORIZATION, &alex.token)
+        .json(&GrantPermission {
+            role: DrawingRole::Owner,
+        })
+        .await;
+    res.assert_status_ok();
+
+    let res = server
+        .post(&format!("{path}/transfer"))
+        .add_header(AUTHORIZATION, &sam.token)
+        .json(&NewDrawingTransfer {
+            username: TestUser::SAM.username.to_string(),
+        })
+        .await;
+    res.assert_status_unauthorized();
+
+    let res = server
+        .post(&format!("{path}/transfer"))
+        .add_header(AUTHORIZATION, &alex.token)
+        .json(&NewDrawingTransfer {
+            username: TestUser::SAM.username.to_string(),
+        })
+        .await;
+    res.assert_status(StatusCode::CREATED);
+    let transfer: DrawingTransfer = res.json();
+    assert_eq!(transfer.status, TransferStatus::Pending);
+    assert_eq!(transfer.drawing_name, drawing.name);
+
+    let res = server
+        .post(&format!("{path}/transfer"))
+        .add_header(AUTHORIZATION, &alex.token)
+        .json(&NewDrawingTransfer {
+            username: TestUser::SAM.username.to_string(),
+        })
+        .await;
+    res.assert_status(StatusCode::CONFLICT);
+
+    let incoming: Items<DrawingTransfer> = server
+        .get("/api/v1/transfer")
+        .add_header(AUTHORIZATION, &sam.token)
+        .await
+        .json();
+    assert_eq!(incoming.items.len(), 1);
+    assert_eq!(incoming.items[0].id, transfer.id);
+    assert_eq!(incoming.items[0].from_user, TestUser::ALEX.username);
+
+    // only the recipient can accept
+    let res = server
+        .post(&format!("/api/v1/transfer/{}/accept", transfer.id))
+        .add_header(AUTHORIZATION, &alex.token)
+        .await;
+    res.assert_status_not_found();
+
+    let res = server
+        .post(&format!("/api/v1/transfer/{}/accept", transfer.id))
+        .add_header(AUTHORIZATION, &sam.token)
+        .await;
+    res.assert_status_ok();
+    let accepted: DrawingTransfer = res.json();
+    assert_eq!(accepted.status, TransferStatus::Accepted);
+    assert!(accepted.resolved_at.is_some());
+
+    let owned: Items<Drawing> = server
+        .get("/api/v1/drawing/owned")
+        .add_header(AUTHORIZATION, &sam.token)
+        .await
+        .json();
+    assert_eq!(owned.items.len(), 1);
+    assert_eq!(owned.items[0].id, drawing.id);
+
+    // the history moves along with the drawing
+    let res = server
+        .get(&format!("{path}/version"))
+        .add_header(AUTHORIZATION, &sam.token)
+        .await;
+    res.assert_json(&versions);
+
+    let res = server
+        .get(&path)
+        .add_header(AUTHORIZATION, &alex.token)
+        .await;
+    res.assert_status_unauthorized();
+
+    let history: Items<OwnerChange> = server
+        .get(&format!("{path}/transfer/history"))
+        .add_header(AUTHORIZATION, &sam.token)
+        .await
+        .json();
+    assert_eq!(history.items.len(), 1);
+    assert_eq!(history.items[0].previous_owner, TestUser::ALEX.username);
+    assert_eq!(history.items[0].new_owner, TestUser::SAM.username);
+    assert_eq!(history.items[0].transfer_id, Some(transfer.id));
+
+    let res = server
+        .post(&format!("/api/v1/transfer/{}/accept", transfer.id))
+        .add_header(AUTHORIZATION, &sam.token)
+        .await;
+    res.assert_status_not_found();
+}
+
+#[sqlx::test(migrations = "../../migrations")]
+async fn decline_and_cancel_transfer(db: PgPool) {
+    let server = TestApp::new(db);
+    let alex = TestUser::ALEX.create_and_auth(&server).await;
+    let sam = TestUser::SAM.create_and_auth(&server).await;
+
+    let drawing = TestDrawing::SHARK.create(&server, &alex).await;
+    let path = format!("/api/v1/drawing/{}", drawing.id);
+
+    let res = server
+        .post(&format!("{path}/transfer"))
+        .add_header(AUTHORIZATION, &alex.token)
+        .json(&NewDrawingTransfer {
+            username: "nobody".to_string(),
+        })
+        .await;
+    res.assert_status_not_found();
+
+    let res = server
+        .post(&format!("{path}/transfer"))
+        .add_header(AUTHORIZATION, &alex.token)
+        .json(&NewDrawingTransfer {
+            username: TestUser::ALEX.username.to_string(),
+        })
+        .await;
+    res.assert_status_bad_request();
+
+    let transfer: DrawingTransfer = server
+        .post(&format!("{path}/transfer"))
+        .add_header(AUTHORIZATION, &alex.token)
+        .json(&NewDrawingTransfer {
+            username: TestUser::SAM.username.to_string(),
+        })
+        .await
+        .json();
+
+    let res = server
+        .post(&format!("/api/v1/transfer/{}/decline", transfer.id))
+        .add_header(AUTHORIZATION, &sam.token)
+        .await;
+    res.assert_status_ok();
+    let declined: DrawingTransfer = res.json();
+    assert_eq!(declined.status, TransferStatus::Declined);
+
+    let transfer: DrawingTransfer = server
+        .post(&format!("{path}/transfer"))
+        .add_header(AUTHORIZATION, &alex.token)
+        .json(&NewDrawingTransfer {
+            username: TestUser::SAM.username.to_string(),
+        })
+        .await
+        .json();
+
+    let res = server
+        .delete(&format!("{path}/transfer"))
+        .add_header(AUTHORIZATION, &alex.token)
+        .await;
+    res.assert_status(StatusCode::NO_CONTENT);
+
+    let res = server
+        .post(&format!("/api/v1/transfer/{}/accept", transfer.id))
+        .add_header(AUTHORIZATION, &sam.token)
+        .await;
+    res.assert_status_not_found();
+
+    let transfers: Items<DrawingTransfer> = server
+        .get(&format!("{path}/transfer"))
+        .add_header(AUTHORIZATION, &alex.token)
+        .await
+        .json();
+    let statuses: Vec<_> = transfers.items.iter().map(|v| v.status).collect();
+    assert_eq!(
+        statuses,
+        [TransferStatus::Cancelled, TransferStatus::Declined]
+    );
+
+    let history: Items<OwnerChange> = server
+        .get(&format!("{path}/transfer/history"))
+        .add_header(AUTHORIZATION, &alex.token)
+        .await
+        .json();
+    assert!(history.items.is_empty());
+}
+
+#[sqlx::test(migrations = "../../migrations")]
+async fn concurrent_transfers(db: PgPool) {
+    let server = TestApp::new(db);
+    let alex = TestUser::ALEX.create_and_auth(&server).await;
+    TestUser::SAM.create_and_auth(&server).await;
+
+    let drawing = TestDrawing::SHARK.create(&server, &alex).await;
+
+    let offer = async || {
+        server
+            .post(&format!("/api/v1/drawing/{}/transfer", drawing.id))
+            .add_header(AUTHORIZATION, &alex.token)
+            .json(&NewDrawingTransfer {
+                username: TestUser::SAM.username.to_string(),
+            })
+            .await
+    };
+
+    // offers for the same drawing are checked one after the other, so all
+    // but one of them find the pending transfer
+    let (a, b, c, d) = tokio::join!(offer(), offer(), offer(), offer());
+
+    let mut statuses: Vec<_> = [a, b, c, d].iter().map(|res| res.status_code()).collect();
+    statuses.sort();
+
+    assert_eq!(
+        statuses,
+        [
+            StatusCode::CREATED,
+            StatusCode::CONFLICT,
+            StatusCode::CONFLICT,
+            StatusCode::CONFLICT
+        ]
+    );
+}
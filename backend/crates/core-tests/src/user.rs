@@ -2,10 +2,13 @@ use axum::http::StatusCode;
 use axum::http::header::AUTHORIZATION;
 use axum_test::TestServer;
 use core_backend::model::{
-    Credentials, FavouriteAnimal, NewUser, Token, UpdatePassword, UpdateUser, User,
+    Credentials, FavouriteAnimal, NewUser, Token, UpdatePassword, UpdateUser, User, VerifyEmail,
 };
 use sqlx::PgPool;
 
+use crate::app::TestApp;
+use crate::drawing::TestDrawing;
+
 pub struct TestUser {
     pub username: &'static str,
     pub email: &'static str,
@@ -21,10 +24,18 @@ impl TestUser {
         favourite_animal: FavouriteAnimal::Cat,
     };
 
+    pub const SAM: Self = Self {
+        username: "sam",
+        email: "sam@example.com",
+        password: "hunter2hunter2",
+        favourite_animal: FavouriteAnimal::Dog,
+    };
+
     pub fn as_user(&self) -> User {
         User {
             username: self.username.to_string(),
             email: self.email.to_string(),
+            email_verified: true,
             favourite_animal: self.favourite_animal,
         }
     }
@@ -38,19 +49,22 @@ impl TestUser {
         }
     }
 
-    pub async fn create(&self, server: &TestServer) {
-        let res = server
-            .post("/api/v1/user")
-            .json(&NewUser {
-                username: "alex".to_string(),
-                email: "alex@nyaalex.site".to_string(),
-                password: "password123".to_string(),
-                favourite_animal: FavouriteAnimal::Cat,
-            })
-            .await;
+    /// Creates the user and verifies their email.
+    pub async fn create(&self, server: &TestApp) {
+        let res = server.post("/api/v1/user").json(&self.as_new_user()).await;
 
         res.assert_status(StatusCode::CREATED);
         res.assert_text("");
+
+        server.wait_for_mails(self.email, 1).await;
+
+        let res = server
+            .post("/api/v1/user/verify")
+            .json(&VerifyEmail {
+                token: server.last_token(self.email),
+            })
+            .await;
+        res.assert_status(StatusCode::NO_CONTENT);
     }
 
     pub async fn auth(&self, server: &TestServer) -> Token {
@@ -65,7 +79,7 @@ impl TestUser {
             .json()
     }
 
-    pub async fn create_and_auth(&self, server: &TestServer) -> Token {
+    pub async fn create_and_auth(&self, server: &TestApp) -> Token {
         self.create(server).await;
         self.auth(server).await
     }
@@ -73,8 +87,7 @@ impl TestUser {
 
 #[sqlx::test(migrations = "../../migrations")]
 async fn create_user(db: PgPool) {
-    let app = core_backend::build_app(db);
-    let server = TestServer::new(app).unwrap();
+    let server = TestApp::new(db);
 
     let res = server
         .post("/api/v1/user")
@@ -87,8 +100,7 @@ async fn create_user(db: PgPool) {
 
 #[sqlx::test(migrations = "../../migrations")]
 async fn get_user(db: PgPool) {
-    let app = core_backend::build_app(db);
-    let server = TestServer::new(app).unwrap();
+    let server = TestApp::new(db);
     let token = TestUser::ALEX.create_and_auth(&server).await;
 
     let res = server
@@ -101,8 +113,7 @@ async fn get_user(db: PgPool) {
 
 #[sqlx::test(migrations = "../../migrations")]
 async fn get_user_me(db: PgPool) {
-    let app = core_backend::build_app(db);
-    let server = TestServer::new(app).unwrap();
+    let server = TestApp::new(db);
     let token = TestUser::ALEX.create_and_auth(&server).await;
 
     let res = server
@@ -115,8 +126,7 @@ async fn get_user_me(db: PgPool) {
 
 #[sqlx::test(migrations = "../../migrations")]
 async fn update_email(db: PgPool) {
-    let app = core_backend::build_app(db);
-    let server = TestServer::new(app).unwrap();
+    let server = TestApp::new(db);
     let token = TestUser::ALEX.create_and_auth(&server).await;
 
     let new_email = "new@email.com".to_string();
@@ -132,6 +142,27 @@ async fn update_email(db: PgPool) {
         .await;
 
     res.assert_status_ok();
+    res.assert_json(&User {
+        email: new_email.clone(),
+        email_verified: false,
+        ..TestUser::ALEX.as_user()
+    });
+
+    // the new address has to be verified again
+    server.wait_for_mails(&new_email, 1).await;
+
+    let res = server
+        .post("/api/v1/user/verify")
+        .json(&VerifyEmail {
+            token: server.last_token(&new_email),
+        })
+        .await;
+    res.assert_status(StatusCode::NO_CONTENT);
+
+    let res = server
+        .get("/api/v1/user/me")
+        .add_header(AUTHORIZATION, &token.token)
+        .await;
     res.assert_json(&User {
         email: new_email,
         ..TestUser::ALEX.as_user()
@@ -140,8 +171,7 @@ async fn update_email(db: PgPool) {
 
 #[sqlx::test(migrations = "../../migrations")]
 async fn update_favourite_animal(db: PgPool) {
-    let app = core_backend::build_app(db);
-    let server = TestServer::new(app).unwrap();
+    let server = TestApp::new(db);
     let token = TestUser::ALEX.create_and_auth(&server).await;
 
     let new_favourite_animal = FavouriteAnimal::Dog;
@@ -165,8 +195,7 @@ async fn update_favourite_animal(db: PgPool) {
 
 #[sqlx::test(migrations = "../../migrations")]
 async fn update_password(db: PgPool) {
-    let app = core_backend::build_app(db);
-    let server = TestServer::new(app).unwrap();
+    let server = TestApp::new(db);
     let token = TestUser::ALEX.create_and_auth(&server).await;
 
     let new_password = "ne@pa55w0rD";
@@ -198,3 +227,120 @@ async fn update_password(db: PgPool) {
 
     res.assert_status_ok();
 }
+
+#[sqlx::test(migrations = "../../migrations")]
+async fn verify_email(db: PgPool) {
+    let server = TestApp::new(db.clone());
+
+    let res = server
+        .post("/api/v1/user")
+        .json(&TestUser::ALEX.as_new_user())
+        .await;
+    res.assert_status(StatusCode::CREATED);
+
+    server.wait_for_mails(TestUser::ALEX.email, 1).await;
+
+    let first_token = server.last_token(TestUser::ALEX.email);
+    let token = TestUser::ALEX.auth(&server).await;
+
+    let res = server
+        .get("/api/v1/user/me")
+        .add_header(AUTHORIZATION, &token.token)
+        .await;
+    res.assert_json(&User {
+        email_verified: false,
+        ..TestUser::ALEX.as_user()
+    });
+
+    // unverified users can't create anything
+    let res = server
+        .post("/api/v1/drawing")
+        .add_header(AUTHORIZATION, &token.token)
+        .json(&TestDrawing::SHARK.as_new_drawing())
+        .await;
+    res.assert_status(StatusCode::FORBIDDEN);
+
+    // the mail was only just sent
+    let res = server
+        .post("/api/v1/user/verify/resend")
+        .add_header(AUTHORIZATION, &token.token)
+        .await;
+    res.assert_status(StatusCode::TOO_MANY_REQUESTS);
+    assert_eq!(server.mails(TestUser::ALEX.email).len(), 1);
+
+    sqlx::query("update email_verifications set created_at = created_at - interval '1 minute'")
+        .execute(&db)
+        .await
+        .unwrap();
+
+    let res = server
+        .post("/api/v1/user/verify/resend")
+        .add_header(AUTHORIZATION, &token.token)
+        .await;
+    res.assert_status(StatusCode::NO_CONTENT);
+    server.wait_for_mails(TestUser::ALEX.email, 2).await;
+
+    // a new mail replaces the previous one
+    let res = server
+        .post("/api/v1/user/verify")
+        .json(&VerifyEmail { token: first_token })
+        .await;
+    res.assert_status_bad_request();
+
+    let res = server
+        .post("/api/v1/user/verify")
+        .json(&VerifyEmail {
+            token: "not a token".to_string(),
+        })
+        .await;
+    res.assert_status_bad_request();
+
+    let verify = VerifyEmail {
+        token: server.last_token(TestUser::ALEX.email),
+    };
+
+    let res = server.post("/api/v1/user/verify").json(&verify).await;
+    res.assert_status(StatusCode::NO_CONTENT);
+
+    let res = server.post("/api/v1/user/verify").json(&verify).await;
+    res.assert_status_bad_request();
+
+    let res = server
+        .post("/api/v1/user/verify/resend")
+        .add_header(AUTHORIZATION, &token.token)
+        .await;
+    res.assert_status(StatusCode::CONFLICT);
+
+    let res = server
+        .post("/api/v1/drawing")
+        .add_header(AUTHORIZATION, &token.token)
+        .json(&TestDrawing::SHARK.as_new_drawing())
+        .await;
+    res.assert_status(StatusCode::CREATED);
+}
+
+#[sqlx::test(migrations = "../../migrations")]
+async fn verify_email_expired(db: PgPool) {
+    let server = TestApp::new(db.clone());
+
+    let res = server
+        .post("/api/v1/user")
+        .json(&TestUser::ALEX.as_new_user())
+        .await;
+    res.assert_status(StatusCode::CREATED);
+
+    sqlx::query("update email_verifications set expires_at = now() - interval '1 hour'")
+        .execute(&db)
+        .await
+        .unwrap();
+
+    server.wait_for_mails(TestUser::ALEX.email, 1).await;
+
+    let res = server
+        .post("/api/v1/user/verify")
+        .json(&VerifyEmail {
+            token: server.last_token(TestUser::ALEX.email),
+        })
+        .await;
+    res.assert_status_bad_request();
+}
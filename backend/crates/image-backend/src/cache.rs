@@ -0,0 +1,295 @@
+use std::collections::{BTreeMap, HashMap};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use axum::body::Bytes;
+use tokio::io::AsyncWriteExt as _;
+
+use crate::model::ImageId;
+
+/// Identifies a converted version of a stored image, e.g. `jpeg-q90`.
+#[derive(Debug, Clone, Eq, PartialEq, Hash)]
+pub struct CacheKey {
+    pub id: ImageId,
+    pub variant: String,
+}
+
+struct Entry<V> {
+    value: V,
+    size: u64,
+    last_used: u64,
+}
+
+/// Size-bounded least recently used index shared by the caches below.
+struct Lru<V> {
+    capacity: u64,
+    entries: HashMap<CacheKey, Entry<V>>,
+    /// Keys ordered from least to most recently used.
+    order: BTreeMap<u64, CacheKey>,
+    size: u64,
+    clock: u64,
+}
+
+impl<V: Clone> Lru<V> {
+    fn new(capacity: u64) -> Self {
+        Self {
+            capacity,
+            entries: HashMap::new(),
+            order: BTreeMap::new(),
+            size: 0,
+            clock: 0,
+        }
+    }
+
+    fn get(&mut self, key: &CacheKey) -> Option<V> {
+        self.clock += 1;
+
+        let entry = self.entries.get_mut(key)?;
+        let last_used = std::mem::replace(&mut entry.last_used, self.clock);
+        let value = entry.value.clone();
+
+        self.order.remove(&last_used);
+        self.order.insert(self.clock, key.clone());
+
+        Some(value)
+    }
+
+    /// Adds an entry and returns the ones evicted to make room for it. An
+    /// entry larger than the whole cache is returned right away.
+    fn insert(&mut self, key: CacheKey, value: V, size: u64) -> Vec<(CacheKey, V)> {
+        let mut evicted = Vec::new();
+
+        if size > self.capacity {
+            evicted.push((key, value));
+            return evicted;
+        }
+
+        self.clock += 1;
+        self.size += size;
+        self.order.insert(self.clock, key.clone());
+
+        let entry = Entry {
+            value,
+            size,
+            last_used: self.clock,
+        };
+
+        if let Some(old) = self.entries.insert(key, entry) {
+            self.size -= old.size;
+            self.order.remove(&old.last_used);
+        }
+
+        while self.size > self.capacity {
+            let Some((_, key)) = self.order.pop_first() else {
+                break;
+            };
+
+            if let Some(entry) = self.entries.remove(&key) {
+                self.size -= entry.size;
+                evicted.push((key, entry.value));
+            }
+        }
+
+        evicted
+    }
+
+    fn remove(&mut self, key: &CacheKey) {
+        if let Some(entry) = self.entries.remove(key) {
+            self.order.remove(&entry.last_used);
+            self.size -= entry.size;
+        }
+    }
+
+    fn remove_image(&mut self, id: &ImageId) {
+        self.entries.retain(|key, entry| {
+            if key.id != *id {
+                return true;
+            }
+
+            self.order.remove(&entry.last_used);
+            self.size -= entry.size;
+            false
+        });
+    }
+}
+
+/// Keeps recently converted images in memory, evicting the least recently
+/// used ones once their total size exceeds the capacity. Stored images never
+/// change, so entries only have to be dropped when the image is deleted.
+pub struct ConversionCache {
+    inner: Mutex<Lru<Bytes>>,
+}
+
+impl ConversionCache {
+    pub fn new(capacity: u64) -> Self {
+        Self {
+            inner: Mutex::new(Lru::new(capacity)),
+        }
+    }
+
+    pub fn get(&self, key: &CacheKey) -> Option<Bytes> {
+        self.inner.lock().unwrap().get(key)
+    }
+
+    pub fn insert(&self, key: CacheKey, data: Bytes) {
+        let size = data.len() as u64;
+        self.inner.lock().unwrap().insert(key, data, size);
+    }
+
+    /// Drops every converted version of an image.
+    pub fn remove_image(&self, id: &ImageId) {
+        self.inner.lock().unwrap().remove_image(id);
+    }
+}
+
+/// Keeps renditions on disk as `<id>/<variant>` below a directory, evicting
+/// the least recently used ones once their total size exceeds the capacity.
+///
+/// The index only lives in memory. It is rebuilt from the files on startup,
+/// ordered by modification time, so a restart keeps the cache warm.
+pub struct RenditionCache {
+    path: PathBuf,
+    inner: Mutex<Lru<()>>,
+}
+
+impl RenditionCache {
+    pub fn open(path: &Path, capacity: u64) -> std::io::Result<Self> {
+        std::fs::create_dir_all(path)?;
+
+        let mut files = Vec::new();
+
+        for dir in std::fs::read_dir(path)? {
+            let dir = dir?;
+
+            if !dir.file_type()?.is_dir() {
+                continue;
+            }
+
+            let id = ImageId(dir.file_name().to_string_lossy().into_owned());
+
+            for file in std::fs::read_dir(dir.path())? {
+                let file = file?;
+                let variant = file.file_name().to_string_lossy().into_owned();
+
+                // left behind by an interrupted write
+                if variant.starts_with('.') {
+                    let _ = std::fs::remove_file(file.path());
+                    continue;
+                }
+
+                let metadata = file.metadata()?;
+                let key = CacheKey {
+                    id: id.clone(),
+                    variant,
+                };
+                files.push((metadata.modified()?, key, metadata.len()));
+            }
+        }
+
+        files.sort_by_key(|(modified, _, _)| *modified);
+
+        let cache = Self {
+            path: path.to_path_buf(),
+            inner: Mutex::new(Lru::new(capacity)),
+        };
+
+        for (_, key, size) in files {
+            let evicted = cache.inner.lock().unwrap().insert(key, (), size);
+
+            for (key, ()) in evicted {
+                let _ = std::fs::remove_file(cache.file_path(&key));
+            }
+        }
+
+        Ok(cache)
+    }
+
+    /// Ids and variants end up in paths, so only plain names are cached.
+    fn is_plain(name: &str) -> bool {
+        !name.is_empty()
+            && name
+                .bytes()
+                .all(|b| b.is_ascii_alphanumeric() || b == b'-' || b == b'_')
+    }
+
+    fn is_valid(key: &CacheKey) -> bool {
+        Self::is_plain(&key.id.0) && Self::is_plain(&key.variant)
+    }
+
+    fn file_path(&self, key: &CacheKey) -> PathBuf {
+        self.path.join(&key.id.0).join(&key.variant)
+    }
+
+    pub async fn get(&self, key: &CacheKey) -> Option<Bytes> {
+        if !Self::is_valid(key) {
+            return None;
+        }
+
+        self.inner.lock().unwrap().get(key)?;
+
+        match tokio::fs::read(self.file_path(key)).await {
+            Ok(data) => Some(Bytes::from(data)),
+            Err(_) => {
+                self.inner.lock().unwrap().remove(key);
+                None
+            }
+        }
+    }
+
+    /// Stores a rendition. Failures are only logged, the rendition is simply
+    /// produced again on the next request.
+    pub async fn insert(&self, key: CacheKey, data: Bytes) {
+        if !Self::is_valid(&key) {
+            return;
+        }
+
+        if let Err(error) = self.write(&key, &data).await {
+            tracing::warn!(%error, "failed to cache rendition");
+            return;
+        }
+
+        let evicted = self
+            .inner
+            .lock()
+            .unwrap()
+            .insert(key, (), data.len() as u64);
+
+        for (key, ()) in evicted {
+            let _ = tokio::fs::remove_file(self.file_path(&key)).await;
+        }
+    }
+
+    async fn write(&self, key: &CacheKey, data: &[u8]) -> std::io::Result<()> {
+        let path = self.file_path(key);
+        let parent = path.parent().unwrap();
+        tokio::fs::create_dir_all(parent).await?;
+
+        let tmp_path = parent.join(format!(
+            ".{}.{:016x}.tmp",
+            key.variant,
+            rand::random::<u64>()
+        ));
+
+        let res = async {
+            let mut file = tokio::fs::File::create(&tmp_path).await?;
+            file.write_all(data).await?;
+            tokio::fs::rename(&tmp_path, &path).await
+        }
+        .await;
+
+        if res.is_err() {
+            let _ = tokio::fs::remove_file(&tmp_path).await;
+        }
+
+        res
+    }
+
+    /// Drops every rendition of an image.
+    pub async fn remove_image(&self, id: &ImageId) {
+        self.inner.lock().unwrap().remove_image(id);
+
+        if Self::is_plain(&id.0) {
+            let _ = tokio::fs::remove_dir_all(self.path.join(&id.0)).await;
+        }
+    }
+}
@@ -0,0 +1,168 @@
+use std::io::Cursor;
+
+use image::buffer::ConvertBuffer;
+use image::codecs::jpeg::JpegEncoder;
+use image::error::{ImageFormatHint, UnsupportedError, UnsupportedErrorKind};
+use image::{
+    DynamicImage, ImageDecoder, ImageError, ImageFormat, ImageReader, ImageResult, Limits,
+    RgbImage, Rgba, RgbaImage, imageops,
+};
+
+use crate::model::{ExportFormat, ImageId};
+use crate::ops::MAX_DIMENSION;
+
+/// Formats accepted for uploads, they are all stored as png.
+const UPLOAD_FORMATS: [ImageFormat; 6] = [
+    ImageFormat::Png,
+    ImageFormat::Jpeg,
+    ImageFormat::WebP,
+    ImageFormat::Gif,
+    ImageFormat::Bmp,
+    ImageFormat::Tiff,
+];
+
+/// Computes the content address of an image. The id is derived from the
+/// decoded pixels rather than the encoded file, so it can be recomputed from
+/// a stored file to check its integrity.
+///
+/// The hash covers the RGBA pixels under a `v1` prefix and the id starts
+/// with `1`. Older images were hashed as RGB under `v0` with ids starting
+/// with `0`, see [`matches`].
+pub fn image_id(image: &RgbaImage) -> ImageId {
+    let mut hasher = blake3::Hasher::new();
+    hasher.update(b"v1");
+    hasher.update(&image.width().to_le_bytes());
+    hasher.update(&image.height().to_le_bytes());
+    hasher.update(image.as_raw());
+    let hash = hasher.finalize();
+
+    ImageId(format!("1{}", hash.to_hex()))
+}
+
+fn image_id_v0(image: &DynamicImage) -> ImageId {
+    let image = image.to_rgb8();
+
+    let mut hasher = blake3::Hasher::new();
+    hasher.update(b"v0");
+    hasher.update(&image.width().to_le_bytes());
+    hasher.update(&image.height().to_le_bytes());
+    hasher.update(image.as_raw());
+    let hash = hasher.finalize();
+
+    ImageId(format!("0{}", hash.to_hex()))
+}
+
+/// Checks whether `id` is the content address of `image`, using the hash
+/// version the id was created with.
+pub fn matches(id: &ImageId, image: &DynamicImage) -> bool {
+    match id.0.as_bytes().first() {
+        Some(b'0') => image_id_v0(image) == *id,
+        Some(b'1') => image_id(&image.to_rgba8()) == *id,
+        _ => false,
+    }
+}
+
+/// Encodes an image as png. Fully opaque images are stored without an alpha
+/// channel, which doesn't change their id.
+pub fn encode(image: &RgbaImage) -> ImageResult<Vec<u8>> {
+    let mut bytes: Vec<u8> = Vec::new();
+
+    if image.pixels().all(|pixel| pixel.0[3] == 255) {
+        let image: RgbImage = image.convert();
+        image.write_to(&mut Cursor::new(&mut bytes), ImageFormat::Png)?;
+    } else {
+        image.write_to(&mut Cursor::new(&mut bytes), ImageFormat::Png)?;
+    }
+
+    Ok(bytes)
+}
+
+/// Encodes an image for download. Jpeg has no alpha channel, so
+/// transparent areas are flattened onto white.
+pub fn encode_as(image: &DynamicImage, format: ExportFormat, quality: u8) -> ImageResult<Vec<u8>> {
+    let mut bytes: Vec<u8> = Vec::new();
+    let mut cursor = Cursor::new(&mut bytes);
+
+    let image = if image.color().has_alpha() && format == ExportFormat::Jpeg {
+        let mut flat = RgbaImage::from_pixel(image.width(), image.height(), Rgba([255; 4]));
+        imageops::overlay(&mut flat, image, 0, 0);
+        DynamicImage::ImageRgb8(flat.convert())
+    } else if image.color().has_alpha() {
+        DynamicImage::ImageRgba8(image.to_rgba8())
+    } else {
+        DynamicImage::ImageRgb8(image.to_rgb8())
+    };
+
+    match format {
+        ExportFormat::Png => image.write_to(&mut cursor, ImageFormat::Png)?,
+        ExportFormat::Jpeg => {
+            JpegEncoder::new_with_quality(&mut cursor, quality).encode_image(&image)?
+        }
+        ExportFormat::Webp => image.write_to(&mut cursor, ImageFormat::WebP)?,
+        ExportFormat::Bmp => image.write_to(&mut cursor, ImageFormat::Bmp)?,
+        ExportFormat::Tiff => image.write_to(&mut cursor, ImageFormat::Tiff)?,
+    }
+
+    Ok(bytes)
+}
+
+/// Length of the start of a png file holding its dimensions: the signature
+/// followed by the length, type, width and height of the `IHDR` chunk.
+pub const PNG_HEADER_LEN: u64 = 24;
+
+/// Reads the dimensions from the start of a png file without decoding it.
+/// Returns `None` if `header` isn't the start of a png file.
+pub fn png_dimensions(header: &[u8]) -> Option<(u32, u32)> {
+    const SIGNATURE: &[u8] = b"\x89PNG\r\n\x1a\n";
+
+    if header.len() < PNG_HEADER_LEN as usize
+        || &header[..8] != SIGNATURE
+        || &header[12..16] != b"IHDR"
+    {
+        return None;
+    }
+
+    let width = u32::from_be_bytes(header[16..20].try_into().unwrap());
+    let height = u32::from_be_bytes(header[20..24].try_into().unwrap());
+
+    Some((width, height))
+}
+
+pub fn decode(data: &[u8]) -> ImageResult<DynamicImage> {
+    ImageReader::with_format(Cursor::new(data), ImageFormat::Png).decode()
+}
+
+/// Decodes an uploaded file. The format is detected from the content rather
+/// than trusting the declared type, animated images are reduced to their
+/// first frame and EXIF orientation is applied.
+pub fn decode_upload(data: &[u8]) -> ImageResult<DynamicImage> {
+    let reader = ImageReader::new(Cursor::new(data)).with_guessed_format()?;
+
+    let format = reader
+        .format()
+        .filter(|format| UPLOAD_FORMATS.contains(format));
+
+    let Some(format) = format else {
+        return Err(ImageError::Unsupported(
+            UnsupportedError::from_format_and_kind(
+                ImageFormatHint::Unknown,
+                UnsupportedErrorKind::Format(ImageFormatHint::Unknown),
+            ),
+        ));
+    };
+
+    let mut limits = Limits::default();
+    limits.max_image_width = Some(MAX_DIMENSION);
+    limits.max_image_height = Some(MAX_DIMENSION);
+
+    let mut reader = ImageReader::with_format(Cursor::new(data), format);
+    reader.limits(limits);
+
+    let mut decoder = reader.into_decoder()?;
+    let orientation = decoder.orientation()?;
+
+    let mut image = DynamicImage::from_decoder(decoder)?;
+    image.apply_orientation(orientation);
+
+    Ok(image)
+}
@@ -7,6 +7,8 @@ use axum::http::StatusCode;
 use axum::response::{IntoResponse, Response};
 use chrono::{DateTime, Utc};
 
+use crate::storage::StorageError;
+
 pub type Result<T, E = AppError> = std::result::Result<T, E>;
 
 #[derive(FromRequest)]
@@ -30,6 +32,10 @@ pub enum AppError {
     InvalidData(String),
     #[error("{0}")]
     EntityNotFound(String),
+    #[error("{0}")]
+    NotAcceptable(String),
+    #[error("{0}")]
+    PreconditionFailed(String),
     #[error("invalid json")]
     JsonRejection(#[from] JsonRejection),
     #[error(transparent)]
@@ -38,6 +44,19 @@ pub enum AppError {
     IoError(#[from] std::io::Error),
 }
 
+impl From<StorageError> for AppError {
+    fn from(error: StorageError) -> Self {
+        match error {
+            StorageError::NotFound => AppError::EntityNotFound("image not found".to_string()),
+            StorageError::Modified => {
+                AppError::PreconditionFailed("image modified since".to_string())
+            }
+            StorageError::Io(error) => AppError::IoError(error),
+            StorageError::Backend(message) => AppError::Internal(message),
+        }
+    }
+}
+
 #[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
 pub struct ErrorResponse {
     pub message: String,
@@ -71,6 +90,8 @@ impl IntoResponse for AppError {
             AppError::InvalidData(_) => StatusCode::BAD_REQUEST,
             AppError::MultipartError(e) => e.status(),
             AppError::EntityNotFound(_) => StatusCode::NOT_FOUND,
+            AppError::NotAcceptable(_) => StatusCode::NOT_ACCEPTABLE,
+            AppError::PreconditionFailed(_) => StatusCode::PRECONDITION_FAILED,
             AppError::JsonRejection(error) => error.status(),
             _ => StatusCode::INTERNAL_SERVER_ERROR,
         };
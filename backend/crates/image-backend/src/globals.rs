@@ -1,7 +1,36 @@
-use std::path::Path;
 use std::sync::Arc;
 
+use crate::cache::{ConversionCache, RenditionCache};
+use crate::storage::{Storage, StorageConfig};
+
+/// Total size of converted downloads kept in memory.
+const CONVERSION_CACHE_SIZE: u64 = 64 * 1024 * 1024;
+
+/// Total size of renditions kept on disk.
+const RENDITION_CACHE_SIZE: u64 = 1024 * 1024 * 1024;
+
+/// The storage along with the caches of images derived from it, which have
+/// to be cleared whenever an image goes away.
 #[derive(Clone)]
 pub struct Globals {
-    pub data_path: Arc<Path>,
+    pub(crate) storage: Arc<dyn Storage>,
+    pub(crate) conversions: Arc<ConversionCache>,
+    /// Missing if the storage has no place for a disk cache.
+    pub(crate) renditions: Option<Arc<RenditionCache>>,
+}
+
+impl Globals {
+    pub fn new(storage: &StorageConfig) -> Self {
+        let renditions = storage.cache_path().and_then(|path| {
+            RenditionCache::open(&path, RENDITION_CACHE_SIZE)
+                .inspect_err(|error| tracing::error!(%error, "failed to open rendition cache"))
+                .ok()
+        });
+
+        Self {
+            storage: storage.build(),
+            conversions: Arc::new(ConversionCache::new(CONVERSION_CACHE_SIZE)),
+            renditions: renditions.map(Arc::new),
+        }
+    }
 }
@@ -1,26 +1,39 @@
+mod cache;
+mod codec;
 mod error;
 mod globals;
 pub mod model;
+mod ops;
 mod resource;
+pub mod storage;
+pub mod verify;
+
+pub use globals::Globals;
 
 use std::net::SocketAddr;
-use std::path::Path;
-use std::sync::Arc;
 use std::time::Duration;
 
+use axum::body::Body;
 use axum::error_handling::HandleErrorLayer;
 use axum::extract::connect_info::IntoMakeServiceWithConnectInfo;
 use axum::extract::{MatchedPath, Request};
-use axum::http::StatusCode;
+use axum::http::header::{
+    ACCEPT_RANGES, CONTENT_LENGTH, CONTENT_RANGE, ETAG, IF_NONE_MATCH, IF_RANGE, RANGE,
+};
+use axum::http::{HeaderMap, HeaderName, StatusCode};
 use axum::{BoxError, Router};
-use model::{ImageId, UploadResult};
+use model::{
+    Background, BlurParams, CanvasParams, DeleteParams, DiffParams, DiffResult, ExportParams,
+    ImageId, ImageInfo, ImagePage, InvertParams, Pipeline, Region, SharpenParams, StorageStats,
+    ThumbnailParams, UploadResult,
+};
 use reqwest::multipart::{Form, Part};
 use reqwest::{Client, Response};
 use tower::ServiceBuilder;
 use tower_http::trace::TraceLayer;
 
 use crate::error::{AppJson, ErrorResponse};
-use crate::globals::Globals;
+use crate::storage::StorageConfig;
 
 async fn handle_timeout_error(err: BoxError) -> (StatusCode, AppJson<ErrorResponse>) {
     if err.is::<tower::timeout::error::Elapsed>() {
@@ -36,12 +49,12 @@ async fn handle_timeout_error(err: BoxError) -> (StatusCode, AppJson<ErrorRespon
     )
 }
 
-pub fn build_app(data_path: &Path) -> IntoMakeServiceWithConnectInfo<Router, SocketAddr> {
-    let api = Router::new().nest("/image", resource::image::routes());
+pub fn build_app(storage: StorageConfig) -> IntoMakeServiceWithConnectInfo<Router, SocketAddr> {
+    let api = Router::new()
+        .nest("/image", resource::image::routes())
+        .nest("/admin", resource::admin::routes());
 
-    let globals = Globals {
-        data_path: Arc::from(data_path),
-    };
+    let globals = Globals::new(&storage);
 
     Router::new()
         .nest("/api/v1", api)
@@ -73,6 +86,21 @@ pub enum ServiceError {
     Api { code: StatusCode, message: String },
 }
 
+/// Headers of a download that are passed on to clients.
+const FORWARDED_HEADERS: [HeaderName; 4] = [ETAG, CONTENT_LENGTH, CONTENT_RANGE, ACCEPT_RANGES];
+
+/// An image download from [`ImageService::export_image`]. The body is
+/// streamed from the image service as it is read.
+pub struct ExportedImage {
+    /// `200`, `206` for a range, `304` if the image matched the
+    /// `If-None-Match` condition, or `416` for an unsatisfiable range.
+    pub status: StatusCode,
+    /// `ETag`, `Content-Length`, `Content-Range` and `Accept-Ranges` of the
+    /// response, if present.
+    pub headers: HeaderMap,
+    pub body: Body,
+}
+
 pub struct ImageService {
     client: Client,
     base_url: String,
@@ -105,21 +133,25 @@ impl ImageService {
         }
     }
 
-    pub async fn create_white_image(
+    pub async fn create_blank_image(
         &self,
         width: u32,
         height: u32,
+        background: Background,
     ) -> Result<UploadResult, ServiceError> {
         let res = self
             .client
             .post(format!("{}/api/v1/image", self.base_url))
             .query(&[("width", width), ("height", height)])
+            .query(&[("background", background)])
             .send()
             .await?;
         let res = Self::check_res(res).await?;
         Ok(res.json().await?)
     }
 
+    /// Uploads an image in any supported format, checking that it has the
+    /// given size.
     pub async fn create_image(
         &self,
         width: u32,
@@ -130,13 +162,66 @@ impl ImageService {
             .client
             .post(format!("{}/api/v1/image", self.base_url))
             .query(&[("width", width), ("height", height)])
-            .multipart(Form::new().part("image", Part::bytes(data).mime_str("image/png").unwrap()))
+            .multipart(Form::new().part("image", Part::bytes(data)))
             .send()
             .await?;
         let res = Self::check_res(res).await?;
         Ok(res.json().await?)
     }
 
+    /// Uploads an image in any supported format, whatever its size.
+    pub async fn import_image(&self, data: Vec<u8>) -> Result<UploadResult, ServiceError> {
+        let res = self
+            .client
+            .post(format!("{}/api/v1/image", self.base_url))
+            .multipart(Form::new().part("image", Part::bytes(data)))
+            .send()
+            .await?;
+        let res = Self::check_res(res).await?;
+        Ok(res.json().await?)
+    }
+
+    /// Downloads an image in the given format without buffering it. The
+    /// conditional and range headers of `request_headers` are passed on.
+    pub async fn export_image(
+        &self,
+        id: ImageId,
+        params: ExportParams,
+        request_headers: &HeaderMap,
+    ) -> Result<ExportedImage, ServiceError> {
+        let mut req = self
+            .client
+            .get(format!("{}/api/v1/image/{}", self.base_url, id.0))
+            .query(&params);
+
+        for name in [IF_NONE_MATCH, RANGE, IF_RANGE] {
+            if let Some(value) = request_headers.get(&name) {
+                req = req.header(name, value);
+            }
+        }
+
+        let res = req.send().await?;
+        let status = res.status();
+
+        let res = match status {
+            StatusCode::NOT_MODIFIED | StatusCode::RANGE_NOT_SATISFIABLE => res,
+            _ => Self::check_res(res).await?,
+        };
+
+        let mut headers = HeaderMap::new();
+        for name in FORWARDED_HEADERS {
+            if let Some(value) = res.headers().get(&name) {
+                headers.insert(name, value.clone());
+            }
+        }
+
+        Ok(ExportedImage {
+            status,
+            headers,
+            body: Body::from_stream(res.bytes_stream()),
+        })
+    }
+
     pub async fn get_image(&self, id: ImageId) -> Result<Vec<u8>, ServiceError> {
         let res = self
             .client
@@ -147,6 +232,65 @@ impl ImageService {
         Ok(res.bytes().await?.to_vec())
     }
 
+    pub async fn list_images(
+        &self,
+        after: Option<&ImageId>,
+        limit: Option<usize>,
+    ) -> Result<ImagePage, ServiceError> {
+        let mut req = self.client.get(format!("{}/api/v1/image", self.base_url));
+
+        if let Some(after) = after {
+            req = req.query(&[("after", &after.0)]);
+        }
+
+        if let Some(limit) = limit {
+            req = req.query(&[("limit", limit)]);
+        }
+
+        let res = Self::check_res(req.send().await?).await?;
+        Ok(res.json().await?)
+    }
+
+    pub async fn list_all_images(&self) -> Result<Vec<ImageInfo>, ServiceError> {
+        let mut images = Vec::new();
+        let mut after = None;
+
+        loop {
+            let page = self.list_images(after.as_ref(), None).await?;
+            images.extend(page.items);
+
+            match page.next_after {
+                Some(next_after) => after = Some(next_after),
+                None => return Ok(images),
+            }
+        }
+    }
+
+    pub async fn get_stats(&self) -> Result<StorageStats, ServiceError> {
+        let res = self
+            .client
+            .get(format!("{}/api/v1/image/stats", self.base_url))
+            .send()
+            .await?;
+        let res = Self::check_res(res).await?;
+        Ok(res.json().await?)
+    }
+
+    pub async fn delete_image(
+        &self,
+        id: ImageId,
+        params: DeleteParams,
+    ) -> Result<(), ServiceError> {
+        let res = self
+            .client
+            .delete(format!("{}/api/v1/image/{}", self.base_url, id.0))
+            .query(&params)
+            .send()
+            .await?;
+        Self::check_res(res).await?;
+        Ok(())
+    }
+
     pub async fn resize_image(
         &self,
         id: ImageId,
@@ -163,40 +307,136 @@ impl ImageService {
         Ok(res.json().await?)
     }
 
-    pub async fn resize_image_fill(
+    /// Changes the canvas size without scaling the image.
+    pub async fn resize_canvas(
         &self,
         id: ImageId,
-        width: u32,
-        height: u32,
+        params: CanvasParams,
     ) -> Result<UploadResult, ServiceError> {
         let res = self
             .client
             .post(format!("{}/api/v1/image/{}/resize", self.base_url, id.0))
-            .query(&[
-                ("width", width.to_string()),
-                ("height", height.to_string()),
-                ("fill", "true".to_string()),
-            ])
+            .query(&params)
+            .query(&[("fill", true)])
+            .send()
+            .await?;
+        let res = Self::check_res(res).await?;
+        Ok(res.json().await?)
+    }
+
+    pub async fn crop_image(
+        &self,
+        id: ImageId,
+        region: Region,
+    ) -> Result<UploadResult, ServiceError> {
+        let res = self
+            .client
+            .post(format!("{}/api/v1/image/{}/crop", self.base_url, id.0))
+            .query(&region)
             .send()
             .await?;
         let res = Self::check_res(res).await?;
         Ok(res.json().await?)
     }
 
-    pub async fn blur_image(&self, id: ImageId) -> Result<UploadResult, ServiceError> {
+    pub async fn blur_image(
+        &self,
+        id: ImageId,
+        params: BlurParams,
+    ) -> Result<UploadResult, ServiceError> {
         let res = self
             .client
             .post(format!("{}/api/v1/image/{}/blur", self.base_url, id.0))
+            .query(&params)
+            .send()
+            .await?;
+        let res = Self::check_res(res).await?;
+        Ok(res.json().await?)
+    }
+
+    /// Scales an image into a box of exactly the given size, unless the fit
+    /// is [`Fit::Within`](model::Fit::Within).
+    pub async fn thumbnail_image(
+        &self,
+        id: ImageId,
+        params: ThumbnailParams,
+    ) -> Result<UploadResult, ServiceError> {
+        let res = self
+            .client
+            .post(format!("{}/api/v1/image/{}/thumbnail", self.base_url, id.0))
+            .query(&params)
             .send()
             .await?;
         let res = Self::check_res(res).await?;
         Ok(res.json().await?)
     }
 
-    pub async fn invert_image(&self, id: ImageId) -> Result<UploadResult, ServiceError> {
+    pub async fn sharpen_image(
+        &self,
+        id: ImageId,
+        params: SharpenParams,
+    ) -> Result<UploadResult, ServiceError> {
+        let res = self
+            .client
+            .post(format!("{}/api/v1/image/{}/sharpen", self.base_url, id.0))
+            .query(&params)
+            .send()
+            .await?;
+        let res = Self::check_res(res).await?;
+        Ok(res.json().await?)
+    }
+
+    pub async fn invert_image(
+        &self,
+        id: ImageId,
+        params: InvertParams,
+    ) -> Result<UploadResult, ServiceError> {
         let res = self
             .client
             .post(format!("{}/api/v1/image/{}/invert", self.base_url, id.0))
+            .query(&params)
+            .send()
+            .await?;
+        let res = Self::check_res(res).await?;
+        Ok(res.json().await?)
+    }
+
+    /// Compares `id` with `other` and stores an image highlighting the
+    /// changed pixels, unless `params` asks for the statistics only.
+    pub async fn diff_images(
+        &self,
+        id: ImageId,
+        other: ImageId,
+        params: DiffParams,
+    ) -> Result<DiffResult, ServiceError> {
+        let res = self
+            .client
+            .post(format!(
+                "{}/api/v1/image/{}/diff/{}",
+                self.base_url, id.0, other.0
+            ))
+            .query(&params)
+            .send()
+            .await?;
+        let res = Self::check_res(res).await?;
+        Ok(res.json().await?)
+    }
+
+    /// Applies `pipeline` in a single pass, e.g.
+    /// `apply_pipeline(id, &Pipeline::new().crop(0, 0, 64, 64).invert(), 2048)`.
+    /// Pipelines making any image wider or higher than `max_dimension` along
+    /// the way are rejected before the work is done.
+    pub async fn apply_pipeline(
+        &self,
+        id: ImageId,
+        pipeline: &Pipeline,
+        max_dimension: u32,
+    ) -> Result<UploadResult, ServiceError> {
+        let res = self
+            .client
+            .post(format!("{}/api/v1/image/{}/pipeline", self.base_url, id.0))
+            .query(&[("max_dimension", max_dimension)])
+            .json(pipeline)
             .send()
             .await?;
         let res = Self::check_res(res).await?;
@@ -1,8 +1,13 @@
-use std::path::PathBuf;
-
+use image_backend::Globals;
+use image_backend::model::VerifyReport;
+use image_backend::storage::{LocalStorage, StorageConfig};
+use image_backend::verify;
 use tracing_subscriber::layer::SubscriberExt;
 use tracing_subscriber::util::SubscriberInitExt;
 
+/// Images checked per storage listing by the verify command.
+const VERIFY_PAGE_SIZE: usize = 500;
+
 #[tokio::main]
 async fn main() {
     tracing_subscriber::registry()
@@ -14,13 +19,79 @@ async fn main() {
         .with(tracing_subscriber::fmt::layer())
         .init();
 
+    let storage = StorageConfig::from_env();
+
+    let args: Vec<String> = std::env::args().skip(1).collect();
+
+    match args.first().map(String::as_str) {
+        None | Some("serve") => serve(storage).await,
+        Some("verify") => run_verify(storage, &args[1..]).await,
+        Some(command) => {
+            eprintln!("unknown command: {command}");
+            eprintln!("usage: image-backend [serve | verify [--quarantine]]");
+            std::process::exit(2);
+        }
+    }
+}
+
+async fn serve(storage: StorageConfig) {
     tracing::info!("Starting image backend");
 
-    let data_path = PathBuf::from(std::env::var("DATA_PATH").unwrap());
+    if let StorageConfig::Local(path) = &storage {
+        // Lookups fall back to the old flat layout, so serving can start
+        // while files are being moved.
+        let local = LocalStorage::new(path);
+        tokio::spawn(async move {
+            match local.migrate_flat_layout().await {
+                Ok(moved) => tracing::info!(moved, "migrated flat image layout"),
+                Err(error) => tracing::error!(%error, "failed to migrate flat image layout"),
+            }
+        });
+    }
 
     let listener = tokio::net::TcpListener::bind("0.0.0.0:2024").await.unwrap();
 
-    let app = image_backend::build_app(&data_path);
+    let app = image_backend::build_app(storage);
 
     axum::serve(listener, app).await.unwrap();
 }
+
+async fn run_verify(storage: StorageConfig, args: &[String]) {
+    let quarantine = args.iter().any(|arg| arg == "--quarantine");
+
+    // renditions cached on disk outlive the server, so they are dropped too
+    let globals = Globals::new(&storage);
+
+    let mut report = VerifyReport {
+        checked: 0,
+        corrupt: Vec::new(),
+        quarantined: quarantine,
+        next_after: None,
+    };
+
+    // walk the store a page at a time instead of listing it all up front
+    loop {
+        let page = verify::verify_images(
+            &globals,
+            report.next_after.as_ref(),
+            VERIFY_PAGE_SIZE,
+            quarantine,
+        )
+        .await
+        .unwrap();
+
+        report.checked += page.checked;
+        report.corrupt.extend(page.corrupt);
+        report.next_after = page.next_after;
+
+        if report.next_after.is_none() {
+            break;
+        }
+    }
+
+    println!("{}", serde_json::to_string_pretty(&report).unwrap());
+
+    if !report.corrupt.is_empty() {
+        std::process::exit(1);
+    }
+}
@@ -1,10 +1,648 @@
+use std::fmt::{Display, Formatter};
+use std::str::FromStr;
+
+use chrono::{DateTime, Utc};
 use serde::{Deserialize, Serialize};
 
-#[derive(Debug, Clone, Eq, PartialEq, Serialize, Deserialize)]
+#[derive(Debug, Clone, Eq, PartialEq, Hash, Serialize, Deserialize)]
 #[serde(transparent)]
 pub struct ImageId(pub String);
 
 #[derive(Debug, Clone, Eq, PartialEq, Serialize, Deserialize)]
 pub struct UploadResult {
     pub id: ImageId,
+    pub width: u32,
+    pub height: u32,
+}
+
+/// Result of comparing two images. The images are aligned at their top left
+/// corners, pixels covered by only one of them count as changed.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct DiffResult {
+    /// Highlight image covering both images, with changed pixels in red on
+    /// a faded copy of the second image. `None` if only the statistics were
+    /// requested.
+    pub id: Option<ImageId>,
+    pub width: u32,
+    pub height: u32,
+    pub changed_pixels: u64,
+    /// Share of the highlight image's pixels that changed, from 0 to 100.
+    pub percent_changed: f64,
+    /// Smallest region containing every changed pixel, `None` if the images
+    /// are identical.
+    pub bounding_box: Option<Region>,
+}
+
+#[derive(Debug, Clone, Eq, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ImageInfo {
+    pub id: ImageId,
+    pub size: u64,
+    /// `None` if the stored file doesn't start with a png header. Only the
+    /// header is read, see `/admin/verify` for finding and quarantining
+    /// corrupt files.
+    pub width: Option<u32>,
+    pub height: Option<u32>,
+    pub modified_at: DateTime<Utc>,
+}
+
+/// A page of images ordered by id. `next_after` is the cursor to pass as
+/// `after` to fetch the following page, absent on the last one.
+#[derive(Debug, Clone, Eq, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ImagePage {
+    pub items: Vec<ImageInfo>,
+    pub next_after: Option<ImageId>,
+}
+
+#[derive(Debug, Clone, Eq, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct StorageStats {
+    pub count: u64,
+    pub total_size: u64,
+    pub oldest_modified_at: Option<DateTime<Utc>>,
+    pub newest_modified_at: Option<DateTime<Utc>>,
+}
+
+/// Result of verifying a page of stored images. `corrupt` lists images that
+/// failed to decode or whose content no longer matches their id.
+#[derive(Debug, Clone, Eq, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct VerifyReport {
+    pub checked: usize,
+    pub corrupt: Vec<ImageId>,
+    pub quarantined: bool,
+    pub next_after: Option<ImageId>,
+}
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum FlipDirection {
+    Horizontal,
+    Vertical,
+}
+
+/// Fill colour for new canvas space, written as `white`, `transparent` or
+/// `#rrggbb[aa]`.
+#[derive(Debug, Clone, Copy, Default, Eq, PartialEq, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub enum Background {
+    #[default]
+    White,
+    Transparent,
+    /// RGBA colour.
+    Color([u8; 4]),
+}
+
+impl Background {
+    pub fn rgba(self) -> [u8; 4] {
+        match self {
+            Background::White => [255, 255, 255, 255],
+            Background::Transparent => [0, 0, 0, 0],
+            Background::Color(color) => color,
+        }
+    }
+}
+
+impl Display for Background {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Background::White => write!(f, "white"),
+            Background::Transparent => write!(f, "transparent"),
+            Background::Color([r, g, b, 255]) => write!(f, "#{r:02x}{g:02x}{b:02x}"),
+            Background::Color([r, g, b, a]) => write!(f, "#{r:02x}{g:02x}{b:02x}{a:02x}"),
+        }
+    }
+}
+
+impl FromStr for Background {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "white" => return Ok(Background::White),
+            "transparent" => return Ok(Background::Transparent),
+            _ => {}
+        }
+
+        let invalid = || format!("invalid background: {s:?}");
+
+        let hex = s.strip_prefix('#').ok_or_else(invalid)?;
+        if !matches!(hex.len(), 6 | 8) || !hex.is_ascii() {
+            return Err(invalid());
+        }
+
+        let mut color = [255; 4];
+        for (i, channel) in color.iter_mut().take(hex.len() / 2).enumerate() {
+            *channel = u8::from_str_radix(&hex[i * 2..i * 2 + 2], 16).map_err(|_| invalid())?;
+        }
+
+        Ok(Background::Color(color))
+    }
+}
+
+impl TryFrom<String> for Background {
+    type Error = String;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        value.parse()
+    }
+}
+
+impl From<Background> for String {
+    fn from(value: Background) -> Self {
+        value.to_string()
+    }
+}
+
+/// Where the original image is placed when the canvas is resized.
+#[derive(Debug, Clone, Copy, Default, Eq, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Anchor {
+    #[default]
+    TopLeft,
+    Top,
+    TopRight,
+    Left,
+    Center,
+    Right,
+    BottomLeft,
+    Bottom,
+    BottomRight,
+}
+
+/// A rectangle within an image. In query strings it is written as
+/// `region=x,y,width,height`.
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Serialize, Deserialize)]
+pub struct Region {
+    pub x: u32,
+    pub y: u32,
+    pub width: u32,
+    pub height: u32,
+}
+
+impl Display for Region {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{},{},{},{}", self.x, self.y, self.width, self.height)
+    }
+}
+
+impl FromStr for Region {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let parts = s
+            .split(',')
+            .map(|v| v.trim().parse::<u32>())
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|_| format!("invalid region: {s:?}"))?;
+
+        let [x, y, width, height] = parts[..] else {
+            return Err(format!("invalid region: {s:?}"));
+        };
+
+        Ok(Region {
+            x,
+            y,
+            width,
+            height,
+        })
+    }
+}
+
+/// (De)serializes an optional [`Region`] in its query string form.
+mod region_param {
+    use serde::{Deserialize, Deserializer, Serializer};
+
+    use super::Region;
+
+    pub fn serialize<S: Serializer>(region: &Option<Region>, s: S) -> Result<S::Ok, S::Error> {
+        match region {
+            Some(region) => s.collect_str(region),
+            None => s.serialize_none(),
+        }
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(d: D) -> Result<Option<Region>, D::Error> {
+        Option::<String>::deserialize(d)?
+            .map(|v| v.parse().map_err(serde::de::Error::custom))
+            .transpose()
+    }
+}
+
+/// Deserializes an optional number from its query string form. Query
+/// parameters only reach structs flattened into another one as strings.
+mod number_param {
+    use std::fmt::Display;
+    use std::str::FromStr;
+
+    use serde::{Deserialize, Deserializer};
+
+    pub fn deserialize<'de, D, T>(d: D) -> Result<Option<T>, D::Error>
+    where
+        D: Deserializer<'de>,
+        T: FromStr,
+        T::Err: Display,
+    {
+        Option::<String>::deserialize(d)?
+            .map(|v| v.parse().map_err(serde::de::Error::custom))
+            .transpose()
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, Eq, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum BlurKind {
+    /// Box blur approximating a Gaussian, much faster for large sigmas.
+    #[default]
+    Fast,
+    Gaussian,
+}
+
+#[derive(Debug, Clone, Copy, Default, Eq, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct DeleteParams {
+    /// Keeps the image if it was modified after this time, failing with
+    /// `412 Precondition Failed`. Storing an image that already exists
+    /// refreshes its modification time.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub unmodified_since: Option<DateTime<Utc>>,
+}
+
+#[derive(Debug, Clone, Copy, Default, Eq, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct DiffParams {
+    /// Only compute the statistics, without storing a highlight image.
+    pub stats_only: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct BlurParams {
+    pub sigma: f32,
+    pub kind: BlurKind,
+    /// Only blur this part of the image.
+    #[serde(with = "region_param", skip_serializing_if = "Option::is_none")]
+    pub region: Option<Region>,
+}
+
+impl Default for BlurParams {
+    fn default() -> Self {
+        Self {
+            sigma: 10.0,
+            kind: BlurKind::Fast,
+            region: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, Eq, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct InvertParams {
+    /// Only invert this part of the image.
+    #[serde(with = "region_param", skip_serializing_if = "Option::is_none")]
+    pub region: Option<Region>,
+}
+
+/// Parameters for changing the canvas size without scaling.
+///
+/// The image is placed according to `anchor`; `x` and `y` override the
+/// anchored position on their axis and may be negative, which cuts off the
+/// left or top of the image.
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Serialize, Deserialize)]
+pub struct CanvasParams {
+    pub width: u32,
+    pub height: u32,
+    #[serde(default)]
+    pub background: Background,
+    #[serde(default)]
+    pub anchor: Anchor,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub x: Option<i64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub y: Option<i64>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct SharpenParams {
+    pub sigma: f32,
+    /// Smallest difference from the blurred image that gets sharpened.
+    pub threshold: i32,
+}
+
+impl Default for SharpenParams {
+    fn default() -> Self {
+        Self {
+            sigma: 1.0,
+            threshold: 0,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Serialize, Deserialize)]
+pub struct ThumbnailParams {
+    pub width: u32,
+    pub height: u32,
+    #[serde(default)]
+    pub fit: Fit,
+    /// Fills the letterbox bars of [`Fit::Contain`].
+    #[serde(default)]
+    pub background: Background,
+}
+
+/// A single step of an image pipeline.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "op", rename_all = "snake_case")]
+pub enum Operation {
+    /// Scales the image to fit within the given size, keeping its aspect
+    /// ratio.
+    Resize {
+        width: u32,
+        height: u32,
+    },
+    /// Changes the canvas size without scaling, filling new space with
+    /// [`CanvasParams::background`].
+    ResizeCanvas(CanvasParams),
+    /// Scales the image into the given box. Unlike [`Operation::Resize`],
+    /// [`Fit::Contain`] and [`Fit::Cover`] always produce exactly the given
+    /// size.
+    Thumbnail(ThumbnailParams),
+    Crop {
+        x: u32,
+        y: u32,
+        width: u32,
+        height: u32,
+    },
+    Blur {
+        sigma: f32,
+        #[serde(default)]
+        kind: BlurKind,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        region: Option<Region>,
+    },
+    /// Unsharp mask: subtracts a Gaussian blur of radius `sigma` from the
+    /// image.
+    Sharpen {
+        sigma: f32,
+        #[serde(default)]
+        threshold: i32,
+    },
+    Invert {
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        region: Option<Region>,
+    },
+    /// Rotates clockwise by 90, 180 or 270 degrees.
+    Rotate {
+        degrees: u32,
+    },
+    Flip {
+        direction: FlipDirection,
+    },
+    Grayscale,
+    /// Adds `value` to every channel, negative values darken.
+    Brightness {
+        value: i32,
+    },
+    /// Adjusts contrast by `value` percent, negative values reduce it.
+    Contrast {
+        value: f32,
+    },
+}
+
+/// A list of operations applied in order within one decode/encode pass.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct Pipeline {
+    pub operations: Vec<Operation>,
+}
+
+impl Pipeline {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn then(mut self, operation: Operation) -> Self {
+        self.operations.push(operation);
+        self
+    }
+
+    pub fn resize(self, width: u32, height: u32) -> Self {
+        self.then(Operation::Resize { width, height })
+    }
+
+    pub fn resize_canvas(self, width: u32, height: u32) -> Self {
+        self.resize_canvas_anchored(width, height, Anchor::TopLeft)
+    }
+
+    pub fn resize_canvas_anchored(self, width: u32, height: u32, anchor: Anchor) -> Self {
+        self.then(Operation::ResizeCanvas(CanvasParams {
+            width,
+            height,
+            background: Background::White,
+            anchor,
+            x: None,
+            y: None,
+        }))
+    }
+
+    pub fn thumbnail(self, width: u32, height: u32, fit: Fit) -> Self {
+        self.then(Operation::Thumbnail(ThumbnailParams {
+            width,
+            height,
+            fit,
+            background: Background::White,
+        }))
+    }
+
+    pub fn crop(self, x: u32, y: u32, width: u32, height: u32) -> Self {
+        self.then(Operation::Crop {
+            x,
+            y,
+            width,
+            height,
+        })
+    }
+
+    pub fn blur(self, sigma: f32) -> Self {
+        self.then(Operation::Blur {
+            sigma,
+            kind: BlurKind::Fast,
+            region: None,
+        })
+    }
+
+    pub fn gaussian_blur(self, sigma: f32) -> Self {
+        self.then(Operation::Blur {
+            sigma,
+            kind: BlurKind::Gaussian,
+            region: None,
+        })
+    }
+
+    pub fn sharpen(self, sigma: f32, threshold: i32) -> Self {
+        self.then(Operation::Sharpen { sigma, threshold })
+    }
+
+    pub fn invert(self) -> Self {
+        self.then(Operation::Invert { region: None })
+    }
+
+    pub fn rotate(self, degrees: u32) -> Self {
+        self.then(Operation::Rotate { degrees })
+    }
+
+    pub fn flip(self, direction: FlipDirection) -> Self {
+        self.then(Operation::Flip { direction })
+    }
+
+    pub fn grayscale(self) -> Self {
+        self.then(Operation::Grayscale)
+    }
+
+    pub fn brightness(self, value: i32) -> Self {
+        self.then(Operation::Brightness { value })
+    }
+
+    pub fn contrast(self, value: f32) -> Self {
+        self.then(Operation::Contrast { value })
+    }
+}
+
+/// Formats images can be downloaded in. Images are stored as png and
+/// converted on request.
+#[derive(Debug, Clone, Copy, Default, Eq, PartialEq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ExportFormat {
+    #[default]
+    Png,
+    #[serde(alias = "jpg")]
+    Jpeg,
+    Webp,
+    Bmp,
+    Tiff,
+}
+
+impl ExportFormat {
+    pub fn mime_type(self) -> &'static str {
+        match self {
+            ExportFormat::Png => "image/png",
+            ExportFormat::Jpeg => "image/jpeg",
+            ExportFormat::Webp => "image/webp",
+            ExportFormat::Bmp => "image/bmp",
+            ExportFormat::Tiff => "image/tiff",
+        }
+    }
+
+    pub fn extension(self) -> &'static str {
+        match self {
+            ExportFormat::Png => "png",
+            ExportFormat::Jpeg => "jpg",
+            ExportFormat::Webp => "webp",
+            ExportFormat::Bmp => "bmp",
+            ExportFormat::Tiff => "tiff",
+        }
+    }
+
+    /// Picks the most preferred format from an `Accept` header, or `None` if
+    /// it doesn't accept any of them. Wildcards select png.
+    pub fn negotiate(accept: &str) -> Option<Self> {
+        let mut best: Option<(f32, Self)> = None;
+
+        for item in accept.split(',') {
+            let mut parts = item.split(';').map(str::trim);
+            let media_type = parts.next().unwrap_or_default().to_ascii_lowercase();
+
+            let quality = parts
+                .filter_map(|param| param.strip_prefix("q="))
+                .find_map(|v| v.parse::<f32>().ok())
+                .unwrap_or(1.0);
+
+            let format = match media_type.as_str() {
+                "*/*" | "image/*" | "image/png" => ExportFormat::Png,
+                "image/jpeg" | "image/jpg" => ExportFormat::Jpeg,
+                "image/webp" => ExportFormat::Webp,
+                "image/bmp" => ExportFormat::Bmp,
+                "image/tiff" => ExportFormat::Tiff,
+                _ => continue,
+            };
+
+            // earlier entries win ties
+            if quality > 0.0 && best.is_none_or(|(best, _)| quality > best) {
+                best = Some((quality, format));
+            }
+        }
+
+        best.map(|(_, format)| format)
+    }
+}
+
+/// How a rendition is fitted into the requested box.
+#[derive(Debug, Clone, Copy, Default, Eq, PartialEq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Fit {
+    /// Scales the whole image to fit inside the box.
+    #[default]
+    Contain,
+    /// Scales the image to fill the box and crops the overflow around the
+    /// centre.
+    Cover,
+    /// Like [`Fit::Contain`], but never enlarges the image.
+    Within,
+}
+
+impl Display for Fit {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Fit::Contain => f.write_str("contain"),
+            Fit::Cover => f.write_str("cover"),
+            Fit::Within => f.write_str("within"),
+        }
+    }
+}
+
+/// Format and size of a downloaded image. Can be flattened into the query
+/// of other endpoints serving images.
+#[derive(Debug, Clone, Copy, Default, Eq, PartialEq, Serialize, Deserialize)]
+pub struct ExportParams {
+    /// Overrides the `Accept` header.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub format: Option<ExportFormat>,
+    /// JPEG quality from 1 to 100.
+    #[serde(
+        default,
+        deserialize_with = "number_param::deserialize",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub quality: Option<u8>,
+    /// Width of a scaled rendition. If only one of `w` and `h` is given the
+    /// other follows from the aspect ratio.
+    #[serde(
+        default,
+        deserialize_with = "number_param::deserialize",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub w: Option<u32>,
+    /// Height of a scaled rendition.
+    #[serde(
+        default,
+        deserialize_with = "number_param::deserialize",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub h: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub fit: Option<Fit>,
+}
+
+impl ExportParams {
+    /// Returns the explicitly requested format, or negotiates one from the
+    /// `Accept` header, defaulting to png. Returns `None` if the header
+    /// doesn't accept any supported format.
+    pub fn resolve_format(&self, accept: Option<&str>) -> Option<ExportFormat> {
+        match (self.format, accept) {
+            (Some(format), _) => Some(format),
+            (None, Some(accept)) => ExportFormat::negotiate(accept),
+            (None, None) => Some(ExportFormat::Png),
+        }
+    }
 }
@@ -0,0 +1,360 @@
+use image::imageops::{self, FilterType};
+use image::{DynamicImage, Rgba, RgbaImage};
+
+use crate::error::{AppError, Result};
+use crate::model::{
+    Anchor, BlurKind, CanvasParams, Fit, FlipDirection, Operation, Region, ThumbnailParams,
+};
+
+/// Largest width or height an operation may produce.
+pub const MAX_DIMENSION: u32 = 8192;
+
+/// Largest number of operations in a single pipeline.
+pub const MAX_OPERATIONS: usize = 32;
+
+/// Largest width or height of a rendition.
+pub const MAX_RENDITION_DIMENSION: u32 = 2048;
+
+const MAX_SIGMA: f32 = 100.0;
+
+fn check_size(width: u32, height: u32) -> Result<()> {
+    if width < 1 || height < 1 {
+        return Err(AppError::InvalidData("invalid size".to_string()));
+    }
+
+    if width > MAX_DIMENSION || height > MAX_DIMENSION {
+        return Err(AppError::InvalidData("size too large".to_string()));
+    }
+
+    Ok(())
+}
+
+fn check_sigma(sigma: f32) -> Result<()> {
+    if !(sigma > 0.0 && sigma <= MAX_SIGMA) {
+        return Err(AppError::InvalidData("invalid sigma".to_string()));
+    }
+
+    Ok(())
+}
+
+fn check_region(image: &DynamicImage, region: Region) -> Result<()> {
+    let fits = region
+        .x
+        .checked_add(region.width)
+        .is_some_and(|right| right <= image.width())
+        && region
+            .y
+            .checked_add(region.height)
+            .is_some_and(|bottom| bottom <= image.height());
+
+    if region.width < 1 || region.height < 1 || !fits {
+        return Err(AppError::InvalidData("invalid region".to_string()));
+    }
+
+    Ok(())
+}
+
+/// Applies `f` to `region` of the image only, or to the whole image if no
+/// region is given.
+fn apply_to_region(
+    mut image: DynamicImage,
+    region: Option<Region>,
+    f: impl FnOnce(DynamicImage) -> DynamicImage,
+) -> Result<DynamicImage> {
+    let Some(region) = region else {
+        return Ok(f(image));
+    };
+
+    check_region(&image, region)?;
+
+    let part = f(image.crop_imm(region.x, region.y, region.width, region.height));
+    imageops::replace(&mut image, &part, region.x.into(), region.y.into());
+
+    Ok(image)
+}
+
+fn resize_canvas(image: &DynamicImage, params: CanvasParams) -> Result<DynamicImage> {
+    check_size(params.width, params.height)?;
+
+    let (h, v) = match params.anchor {
+        Anchor::TopLeft => (0, 0),
+        Anchor::Top => (1, 0),
+        Anchor::TopRight => (2, 0),
+        Anchor::Left => (0, 1),
+        Anchor::Center => (1, 1),
+        Anchor::Right => (2, 1),
+        Anchor::BottomLeft => (0, 2),
+        Anchor::Bottom => (1, 2),
+        Anchor::BottomRight => (2, 2),
+    };
+
+    // free space on each axis, negative when shrinking
+    let free_x = i64::from(params.width) - i64::from(image.width());
+    let free_y = i64::from(params.height) - i64::from(image.height());
+
+    let x = params.x.unwrap_or(free_x * h / 2);
+    let y = params.y.unwrap_or(free_y * v / 2);
+
+    let background = Rgba(params.background.rgba());
+    let mut new_image = RgbaImage::from_pixel(params.width, params.height, background);
+    imageops::replace(&mut new_image, &image.to_rgba8(), x, y);
+
+    Ok(DynamicImage::ImageRgba8(new_image))
+}
+
+/// Scales an image for display. A missing width or height follows from the
+/// aspect ratio of the image.
+pub fn rendition(
+    image: &DynamicImage,
+    width: Option<u32>,
+    height: Option<u32>,
+    fit: Fit,
+) -> Result<DynamicImage> {
+    let scale = |size: u32, from: u32, to: u32| {
+        let scaled = u64::from(size) * u64::from(to) / u64::from(from.max(1));
+        scaled.clamp(1, MAX_RENDITION_DIMENSION.into()) as u32
+    };
+
+    let (width, height) = match (width, height) {
+        (Some(width), Some(height)) => (width, height),
+        (Some(width), None) => (width, scale(image.height(), image.width(), width)),
+        (None, Some(height)) => (scale(image.width(), image.height(), height), height),
+        (None, None) => return Ok(image.clone()),
+    };
+
+    if width < 1 || height < 1 {
+        return Err(AppError::InvalidData("invalid size".to_string()));
+    }
+
+    if width > MAX_RENDITION_DIMENSION || height > MAX_RENDITION_DIMENSION {
+        return Err(AppError::InvalidData("size too large".to_string()));
+    }
+
+    Ok(fit_into(image, width, height, fit))
+}
+
+fn fit_into(image: &DynamicImage, width: u32, height: u32, fit: Fit) -> DynamicImage {
+    match fit {
+        Fit::Contain => image.resize(width, height, FilterType::Lanczos3),
+        Fit::Cover => image.resize_to_fill(width, height, FilterType::Lanczos3),
+        Fit::Within if image.width() <= width && image.height() <= height => image.clone(),
+        Fit::Within => image.resize(width, height, FilterType::Lanczos3),
+    }
+}
+
+fn thumbnail(image: &DynamicImage, params: ThumbnailParams) -> Result<DynamicImage> {
+    check_size(params.width, params.height)?;
+
+    if params.width > MAX_RENDITION_DIMENSION || params.height > MAX_RENDITION_DIMENSION {
+        return Err(AppError::InvalidData("size too large".to_string()));
+    }
+
+    let image = fit_into(image, params.width, params.height, params.fit);
+
+    if params.fit != Fit::Contain {
+        return Ok(image);
+    }
+
+    // letterbox to the exact size
+    let canvas = CanvasParams {
+        width: params.width,
+        height: params.height,
+        background: params.background,
+        anchor: Anchor::Center,
+        x: None,
+        y: None,
+    };
+
+    resize_canvas(&image, canvas)
+}
+
+/// Colour of changed pixels in a diff.
+const DIFF_HIGHLIGHT: Rgba<u8> = Rgba([255, 0, 0, 255]);
+
+/// Changed pixels found by [`diff`].
+pub struct DiffStats {
+    pub changed_pixels: u64,
+    pub bounding_box: Option<Region>,
+}
+
+/// Compares two images pixel by pixel, aligned at their top left corners.
+/// Returns a highlight image large enough for both, showing `after` faded
+/// out with changed pixels on top. Fully transparent pixels are equal
+/// whatever their colour.
+pub fn diff(before: &DynamicImage, after: &DynamicImage) -> (RgbaImage, DiffStats) {
+    let before = before.to_rgba8();
+    let after = after.to_rgba8();
+
+    let width = before.width().max(after.width());
+    let height = before.height().max(after.height());
+
+    let mut highlight = RgbaImage::new(width, height);
+    let mut changed_pixels = 0;
+    let (mut left, mut top, mut right, mut bottom) = (u32::MAX, u32::MAX, 0, 0);
+
+    for y in 0..height {
+        for x in 0..width {
+            let old = before.get_pixel_checked(x, y);
+            let new = after.get_pixel_checked(x, y);
+
+            let unchanged = match (old, new) {
+                (Some(old), Some(new)) => old == new || (old[3] == 0 && new[3] == 0),
+                (None, None) => true,
+                _ => false,
+            };
+
+            if unchanged {
+                if let Some(&Rgba([r, g, b, a])) = new {
+                    // a light grey keeps the highlight readable
+                    let luma =
+                        (299 * u32::from(r) + 587 * u32::from(g) + 114 * u32::from(b)) / 1000;
+                    let value = (255 - (255 - luma) / 4) as u8;
+                    highlight.put_pixel(x, y, Rgba([value, value, value, a]));
+                }
+
+                continue;
+            }
+
+            highlight.put_pixel(x, y, DIFF_HIGHLIGHT);
+            changed_pixels += 1;
+            left = left.min(x);
+            top = top.min(y);
+            right = right.max(x);
+            bottom = bottom.max(y);
+        }
+    }
+
+    let bounding_box = (changed_pixels > 0).then(|| Region {
+        x: left,
+        y: top,
+        width: right - left + 1,
+        height: bottom - top + 1,
+    });
+
+    let stats = DiffStats {
+        changed_pixels,
+        bounding_box,
+    };
+
+    (highlight, stats)
+}
+
+pub fn apply(image: DynamicImage, operation: &Operation) -> Result<DynamicImage> {
+    let image = match *operation {
+        Operation::Resize { width, height } => {
+            check_size(width, height)?;
+            image.resize(width, height, FilterType::Lanczos3)
+        }
+        Operation::ResizeCanvas(params) => resize_canvas(&image, params)?,
+        Operation::Thumbnail(params) => thumbnail(&image, params)?,
+        Operation::Crop {
+            x,
+            y,
+            width,
+            height,
+        } => {
+            let region = Region {
+                x,
+                y,
+                width,
+                height,
+            };
+            check_region(&image, region)?;
+
+            image.crop_imm(x, y, width, height)
+        }
+        Operation::Blur {
+            sigma,
+            kind,
+            region,
+        } => {
+            check_sigma(sigma)?;
+
+            apply_to_region(image, region, |image| match kind {
+                BlurKind::Fast => image.fast_blur(sigma),
+                BlurKind::Gaussian => image.blur(sigma),
+            })?
+        }
+        Operation::Sharpen { sigma, threshold } => {
+            check_sigma(sigma)?;
+
+            if !(0..=255).contains(&threshold) {
+                return Err(AppError::InvalidData("invalid threshold".to_string()));
+            }
+
+            image.unsharpen(sigma, threshold)
+        }
+        Operation::Invert { region } => apply_to_region(image, region, |mut image| {
+            image.invert();
+            image
+        })?,
+        Operation::Rotate { degrees } => match degrees {
+            90 => image.rotate90(),
+            180 => image.rotate180(),
+            270 => image.rotate270(),
+            _ => return Err(AppError::InvalidData("invalid rotation".to_string())),
+        },
+        Operation::Flip { direction } => match direction {
+            FlipDirection::Horizontal => image.fliph(),
+            FlipDirection::Vertical => image.flipv(),
+        },
+        Operation::Grayscale => image.grayscale(),
+        Operation::Brightness { value } => {
+            if !(-255..=255).contains(&value) {
+                return Err(AppError::InvalidData("invalid brightness".to_string()));
+            }
+
+            image.brighten(value)
+        }
+        Operation::Contrast { value } => {
+            if !(-100.0..=100.0).contains(&value) {
+                return Err(AppError::InvalidData("invalid contrast".to_string()));
+            }
+
+            image.adjust_contrast(value)
+        }
+    };
+
+    Ok(image)
+}
+
+/// Size an operation scales the image or its canvas to, if it sets one.
+fn requested_size(operation: &Operation) -> Option<(u32, u32)> {
+    match *operation {
+        Operation::Resize { width, height } => Some((width, height)),
+        Operation::ResizeCanvas(params) => Some((params.width, params.height)),
+        Operation::Thumbnail(params) => Some((params.width, params.height)),
+        _ => None,
+    }
+}
+
+/// Applies `operations` in order. No image along the way may be wider or
+/// higher than `max_dimension`, which is checked before an operation runs,
+/// so a pipeline doomed to be too large fails before doing the work.
+pub fn apply_all(
+    mut image: DynamicImage,
+    operations: &[Operation],
+    max_dimension: u32,
+) -> Result<DynamicImage> {
+    if operations.len() > MAX_OPERATIONS {
+        return Err(AppError::InvalidData("too many operations".to_string()));
+    }
+
+    let too_large = |(width, height): (u32, u32)| width > max_dimension || height > max_dimension;
+
+    if too_large((image.width(), image.height())) {
+        return Err(AppError::InvalidData("image too large".to_string()));
+    }
+
+    for operation in operations {
+        // the other operations keep within the image, rotations included as
+        // the limit is the same for both sides
+        if requested_size(operation).is_some_and(too_large) {
+            return Err(AppError::InvalidData("size too large".to_string()));
+        }
+
+        image = apply(image, operation)?;
+    }
+
+    Ok(image)
+}
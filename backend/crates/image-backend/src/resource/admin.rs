@@ -0,0 +1,40 @@
+use axum::Router;
+use axum::extract::{Query, State};
+use axum::routing::post;
+use serde::{Deserialize, Serialize};
+
+use crate::error::{AppError, AppJson, Result};
+use crate::globals::Globals;
+use crate::model::{ImageId, VerifyReport};
+use crate::verify;
+
+const DEFAULT_VERIFY_PAGE_SIZE: usize = 100;
+const MAX_VERIFY_PAGE_SIZE: usize = 1000;
+
+pub fn routes() -> Router<Globals> {
+    Router::new().route("/verify", post(verify_images))
+}
+
+#[derive(Debug, Clone, Eq, PartialEq, Serialize, Deserialize)]
+struct VerifyQuery {
+    after: Option<ImageId>,
+    limit: Option<usize>,
+    #[serde(default)]
+    quarantine: bool,
+}
+
+async fn verify_images(
+    State(globals): State<Globals>,
+    Query(query): Query<VerifyQuery>,
+) -> Result<AppJson<VerifyReport>> {
+    let limit = query.limit.unwrap_or(DEFAULT_VERIFY_PAGE_SIZE);
+
+    if limit == 0 || limit > MAX_VERIFY_PAGE_SIZE {
+        return Err(AppError::InvalidData("invalid limit".to_string()));
+    }
+
+    let report =
+        verify::verify_images(&globals, query.after.as_ref(), limit, query.quarantine).await?;
+
+    Ok(AppJson(report))
+}
@@ -1,24 +1,37 @@
-use std::io::Cursor;
+use std::ops::Range;
 
 use axum::Router;
-use axum::body::Body;
+use axum::body::{Body, Bytes};
 use axum::extract::multipart::MultipartRejection;
 use axum::extract::{DefaultBodyLimit, Multipart, Path, Query, State};
-use axum::http::header::{CONTENT_DISPOSITION, CONTENT_TYPE};
-use axum::http::{HeaderMap, StatusCode};
+use axum::http::header::{
+    ACCEPT, ACCEPT_RANGES, CACHE_CONTROL, CONTENT_DISPOSITION, CONTENT_LENGTH, CONTENT_RANGE,
+    CONTENT_TYPE, ETAG, IF_NONE_MATCH, IF_RANGE, RANGE, VARY,
+};
+use axum::http::{HeaderMap, HeaderValue, StatusCode};
 use axum::routing::{delete, get, post};
-use futures_util::StreamExt as _;
-use image::imageops::{self, FilterType};
-use image::{ImageFormat, ImageReader, Rgb, RgbImage};
+use futures_util::TryStreamExt as _;
+use image::{DynamicImage, ImageError, Rgba, RgbaImage};
 use serde::{Deserialize, Serialize};
 use tokio::task::spawn_blocking;
-use tokio_util::io::ReaderStream;
 
+use crate::cache::CacheKey;
+use crate::codec;
 use crate::error::{AppError, AppJson, Result};
 use crate::globals::Globals;
-use crate::model::{ImageId, UploadResult};
+use crate::model::{
+    Anchor, Background, BlurParams, CanvasParams, DeleteParams, DiffParams, DiffResult,
+    ExportFormat, ExportParams, ImageId, ImageInfo, ImagePage, InvertParams, Operation, Pipeline,
+    Region, SharpenParams, StorageStats, ThumbnailParams, UploadResult,
+};
+use crate::ops;
+use crate::storage::StorageError;
 
 const MAX_IMAGE_SIZE: usize = 10 * 1024 * 1024;
+const DEFAULT_JPEG_QUALITY: u8 = 90;
+const CACHE_CONTROL_IMMUTABLE: &str = "public, max-age=31536000, immutable";
+const DEFAULT_PAGE_SIZE: usize = 100;
+const MAX_PAGE_SIZE: usize = 1000;
 
 pub fn routes() -> Router<Globals> {
     Router::new()
@@ -26,9 +39,16 @@ pub fn routes() -> Router<Globals> {
             "/",
             post(upload_image).layer(DefaultBodyLimit::max(MAX_IMAGE_SIZE)),
         )
+        .route("/", get(list_images))
+        .route("/stats", get(get_stats))
         .route("/{id}/resize", post(resize_image))
+        .route("/{id}/crop", post(crop_image))
         .route("/{id}/blur", post(blur_image))
+        .route("/{id}/sharpen", post(sharpen_image))
+        .route("/{id}/thumbnail", post(thumbnail_image))
         .route("/{id}/invert", post(invert_image))
+        .route("/{id}/pipeline", post(run_pipeline))
+        .route("/{id}/diff/{other}", post(diff_image))
         .route("/{id}", get(get_image))
         .route("/{id}", delete(delete_image))
 }
@@ -37,6 +57,9 @@ pub fn routes() -> Router<Globals> {
 struct UploadQuery {
     width: Option<u32>,
     height: Option<u32>,
+    /// Fill colour of a blank canvas.
+    #[serde(default)]
+    background: Background,
 }
 
 #[axum::debug_handler]
@@ -52,33 +75,25 @@ async fn upload_image(
 
     let image = match multipart_field {
         Some(field) => {
-            if field.content_type() != Some("image/png") {
-                return Err(AppError::InvalidData("unsupported image type".to_string()));
-            }
-
             let bytes = field.bytes().await?;
 
             spawn_blocking(move || {
-                let reader =
-                    ImageReader::with_format(Cursor::new(bytes.to_vec()), ImageFormat::Png);
-
-                let image = reader
-                    .decode()
-                    .map_err(|_| AppError::InvalidData("invalid image".to_string()))?;
-
-                if let Some(width) = query.width {
-                    if width != image.width() {
-                        return Err(AppError::InvalidData("invalid width".to_string()));
+                let image = codec::decode_upload(&bytes).map_err(|error| match error {
+                    ImageError::Unsupported(_) => {
+                        AppError::InvalidData("unsupported image type".to_string())
                     }
+                    _ => AppError::InvalidData("invalid image".to_string()),
+                })?;
+
+                if query.width.is_some_and(|width| width != image.width()) {
+                    return Err(AppError::InvalidData("invalid width".to_string()));
                 }
 
-                if let Some(height) = query.height {
-                    if height != image.height() {
-                        return Err(AppError::InvalidData("invalid height".to_string()));
-                    }
+                if query.height.is_some_and(|height| height != image.height()) {
+                    return Err(AppError::InvalidData("invalid height".to_string()));
                 }
 
-                Ok(image.into_rgb8())
+                Ok(image.into_rgba8())
             })
             .await
             .unwrap()?
@@ -96,107 +111,462 @@ async fn upload_image(
                 ));
             };
 
-            spawn_blocking(move || {
-                let mut image = RgbImage::new(width, height);
-                for pixel in image.pixels_mut() {
-                    *pixel = Rgb([255, 255, 255]);
-                }
-                image
-            })
-            .await
-            .unwrap()
+            let background = Rgba(query.background.rgba());
+
+            spawn_blocking(move || RgbaImage::from_pixel(width, height, background))
+                .await
+                .unwrap()
         }
     };
 
-    let (id, bytes) = encode_image(image).await?;
+    let upload = save_image(&globals, image).await?;
+
+    Ok(AppJson(upload))
+}
 
-    let mut path = globals.data_path.join(&id.0);
-    path.set_extension("png");
+async fn load_image(globals: &Globals, id: &ImageId) -> Result<DynamicImage> {
+    let data = globals.storage.get(id).await?;
 
-    if !tokio::fs::try_exists(&path).await? {
-        tokio::fs::write(&path, bytes).await?;
-    }
+    spawn_blocking(move || {
+        codec::decode(&data).map_err(|_| AppError::Internal("invalid image".to_string()))
+    })
+    .await
+    .unwrap()
+}
 
-    Ok(AppJson(UploadResult { id }))
+async fn save_image(globals: &Globals, image: RgbaImage) -> Result<UploadResult> {
+    let (width, height) = image.dimensions();
+    let (id, data) = encode_image(image).await?;
+    globals.storage.put(&id, data).await?;
+    Ok(UploadResult { id, width, height })
 }
 
-async fn encode_image(image: RgbImage) -> Result<(ImageId, Vec<u8>)> {
+async fn encode_image(image: RgbaImage) -> Result<(ImageId, Vec<u8>)> {
     spawn_blocking(move || {
-        let mut hasher = blake3::Hasher::new();
-        hasher.update(b"v0");
-        hasher.update(&image.width().to_le_bytes());
-        hasher.update(&image.height().to_le_bytes());
-        hasher.update(&image.as_raw());
-        hasher.finalize();
-        let hash = hasher.finalize();
-
-        let mut bytes: Vec<u8> = Vec::new();
-        image
-            .write_to(&mut Cursor::new(&mut bytes), image::ImageFormat::Png)
+        let bytes = codec::encode(&image)
             .map_err(|_| AppError::Internal("failed to encode image".to_string()))?;
 
-        let id = ImageId(format!("0{}", hash.to_hex()));
-
-        Ok::<_, AppError>((id, bytes))
+        Ok::<_, AppError>((codec::image_id(&image), bytes))
     })
     .await
     .unwrap()
 }
 
+#[derive(Debug, Clone, Eq, PartialEq, Serialize, Deserialize)]
+struct ListQuery {
+    after: Option<ImageId>,
+    limit: Option<usize>,
+}
+
+/// Lists a page of images. The dimensions are read from the png header only,
+/// so the garbage collector walking every image through this listing doesn't
+/// read every stored byte.
+async fn list_images(
+    State(globals): State<Globals>,
+    Query(query): Query<ListQuery>,
+) -> Result<AppJson<ImagePage>> {
+    let limit = query.limit.unwrap_or(DEFAULT_PAGE_SIZE);
+
+    if limit == 0 || limit > MAX_PAGE_SIZE {
+        return Err(AppError::InvalidData("invalid limit".to_string()));
+    }
+
+    // one more than asked for tells whether there is a next page
+    let mut objects = globals
+        .storage
+        .list_page(query.after.as_ref(), limit + 1)
+        .await?;
+
+    let next_after = if objects.len() > limit {
+        objects.truncate(limit);
+        objects.last().map(|object| object.id.clone())
+    } else {
+        None
+    };
+
+    let mut items = Vec::with_capacity(objects.len());
+    for object in objects {
+        let header = match read_header(&globals, &object.id).await {
+            Ok(header) => header,
+            // deleted since it was listed
+            Err(StorageError::NotFound) => continue,
+            Err(error) => return Err(error.into()),
+        };
+
+        // a corrupt file must not hide the rest of the page
+        let dimensions = codec::png_dimensions(&header);
+
+        if dimensions.is_none() {
+            tracing::warn!(id = object.id.0, "stored image is invalid");
+        }
+
+        let (width, height) = dimensions.unzip();
+
+        items.push(ImageInfo {
+            id: object.id,
+            size: object.size,
+            width,
+            height,
+            modified_at: object.modified_at,
+        });
+    }
+
+    Ok(AppJson(ImagePage { items, next_after }))
+}
+
+/// Reads the start of a stored image, up to its dimensions.
+async fn read_header(globals: &Globals, id: &ImageId) -> Result<Vec<u8>, StorageError> {
+    let mut stream = globals
+        .storage
+        .read(id, Some(0..codec::PNG_HEADER_LEN))
+        .await?;
+
+    let mut header = Vec::new();
+    while let Some(chunk) = stream.try_next().await? {
+        header.extend_from_slice(&chunk);
+    }
+
+    Ok(header)
+}
+
+async fn get_stats(State(globals): State<Globals>) -> Result<AppJson<StorageStats>> {
+    let objects = globals.storage.list().await?;
+
+    let stats = StorageStats {
+        count: objects.len() as u64,
+        total_size: objects.iter().map(|object| object.size).sum(),
+        oldest_modified_at: objects.iter().map(|object| object.modified_at).min(),
+        newest_modified_at: objects.iter().map(|object| object.modified_at).max(),
+    };
+
+    Ok(AppJson(stats))
+}
+
+/// Returns the image as png, or converted to the format given by the
+/// `format` parameter or the `Accept` header.
+///
+/// Images never change, so responses can be cached forever. The ETag is the
+/// image id, extended by the variant for converted images and renditions.
+/// A single byte range can be requested to resume a download.
 async fn get_image(
     State(globals): State<Globals>,
     Path(id): Path<ImageId>,
-) -> Result<(HeaderMap, Body)> {
-    let mut path = globals.data_path.join(&id.0);
-    path.set_extension("png");
+    Query(params): Query<ExportParams>,
+    request_headers: HeaderMap,
+) -> Result<(StatusCode, HeaderMap, Body)> {
+    let accept = request_headers
+        .get(ACCEPT)
+        .map(|v| v.to_str().unwrap_or_default());
+    let Some(format) = params.resolve_format(accept) else {
+        return Err(AppError::NotAcceptable("no acceptable format".to_string()));
+    };
 
-    let file = tokio::fs::File::open(path)
-        .await
-        .map_err(|_| AppError::EntityNotFound("image not found".to_string()))?;
+    let quality = params.quality.unwrap_or(DEFAULT_JPEG_QUALITY);
+    if !(1..=100).contains(&quality) {
+        return Err(AppError::InvalidData("invalid quality".to_string()));
+    }
 
-    let stream = ReaderStream::new(file).map(|v| {
-        v.map_err(|error| {
-            tracing::error!(%error, "read error: ");
-            AppError::Internal("internal error".to_string())
-        })
-    });
+    let rendition = params.w.is_some() || params.h.is_some();
+
+    let variant = if rendition {
+        Some(rendition_variant(&params, format, quality))
+    } else if format == ExportFormat::Png {
+        None
+    } else {
+        Some(format_variant(format, quality))
+    };
+
+    let etag = match &variant {
+        Some(variant) => format!("\"{}.{variant}\"", id.0),
+        None => format!("\"{}\"", id.0),
+    };
 
     let mut headers = HeaderMap::new();
-    headers.insert(CONTENT_TYPE, "image/png".parse().unwrap());
+    headers.insert(ETAG, etag.parse().unwrap());
+    headers.insert(
+        CACHE_CONTROL,
+        HeaderValue::from_static(CACHE_CONTROL_IMMUTABLE),
+    );
+
+    if params.format.is_none() {
+        headers.insert(VARY, ACCEPT.into());
+    }
+
+    let not_modified = request_headers
+        .get(IF_NONE_MATCH)
+        .and_then(|v| v.to_str().ok())
+        .is_some_and(|v| etag_matches(v, &etag));
+
+    if not_modified && globals.storage.exists(&id).await? {
+        return Ok((StatusCode::NOT_MODIFIED, headers, Body::empty()));
+    }
+
+    headers.insert(ACCEPT_RANGES, HeaderValue::from_static("bytes"));
+
+    // a range of an outdated copy is useless, so send everything instead
+    let range = request_headers
+        .get(RANGE)
+        .and_then(|v| v.to_str().ok())
+        .filter(|_| {
+            request_headers
+                .get(IF_RANGE)
+                .is_none_or(|v| v.as_bytes() == etag.as_bytes())
+        });
+
+    let data = match variant {
+        Some(variant) if rendition => {
+            let key = CacheKey {
+                id: id.clone(),
+                variant,
+            };
+            render_image(&globals, key, &params, format, quality).await?
+        }
+        Some(variant) => {
+            let key = CacheKey {
+                id: id.clone(),
+                variant,
+            };
+            convert_image(&globals, key, format, quality).await?
+        }
+        // stored images can be large, so they are streamed from storage and
+        // only the requested range is read
+        None => {
+            let size = globals.storage.stat(&id).await?.size;
+            let range = range.map_or(ByteRange::Full, |v| ByteRange::parse(v, size));
+            let status = range.insert_headers(&mut headers, size);
+
+            let (range, len) = match range {
+                ByteRange::Full => (None, size),
+                ByteRange::Partial(range) => (Some(range.clone()), range.end - range.start),
+                ByteRange::Unsatisfiable => return Ok((status, headers, Body::empty())),
+            };
+
+            let stream = globals.storage.read(&id, range).await?;
+
+            headers.insert(CONTENT_LENGTH, len.into());
+            insert_content_headers(&mut headers, &id, format);
+
+            return Ok((status, headers, Body::from_stream(stream)));
+        }
+    };
+
+    let len = data.len() as u64;
+    let range = range.map_or(ByteRange::Full, |v| ByteRange::parse(v, len));
+    let status = range.insert_headers(&mut headers, len);
+
+    let data = match range {
+        ByteRange::Full => data,
+        ByteRange::Partial(range) => data.slice(range.start as usize..range.end as usize),
+        ByteRange::Unsatisfiable => return Ok((status, headers, Body::empty())),
+    };
+
+    insert_content_headers(&mut headers, &id, format);
+
+    Ok((status, headers, Body::from(data)))
+}
+
+fn insert_content_headers(headers: &mut HeaderMap, id: &ImageId, format: ExportFormat) {
+    headers.insert(CONTENT_TYPE, format.mime_type().parse().unwrap());
     headers.insert(
         CONTENT_DISPOSITION,
-        format!("attachment; filename=\"{}.png\"", id.0)
+        format!("attachment; filename=\"{}.{}\"", id.0, format.extension())
             .parse()
             .unwrap(),
     );
+}
+
+enum ByteRange {
+    Full,
+    Partial(Range<u64>),
+    Unsatisfiable,
+}
 
-    let body = Body::from_stream(stream);
+impl ByteRange {
+    /// Parses a `Range` header for a body of `len` bytes. Anything other than
+    /// a single byte range is ignored, which the spec allows.
+    fn parse(header: &str, len: u64) -> Self {
+        let Some((start, end)) = header
+            .strip_prefix("bytes=")
+            .filter(|v| !v.contains(','))
+            .and_then(|v| v.trim().split_once('-'))
+        else {
+            return Self::Full;
+        };
 
-    Ok((headers, body))
+        let range = if start.is_empty() {
+            // the last `end` bytes
+            match end.parse::<u64>() {
+                Ok(suffix) => len.saturating_sub(suffix)..len,
+                Err(_) => return Self::Full,
+            }
+        } else {
+            match (start.parse::<u64>(), end.parse::<u64>()) {
+                (Ok(start), _) if end.is_empty() => start..len,
+                (Ok(start), Ok(end)) if start <= end => start..len.min(end.saturating_add(1)),
+                _ => return Self::Full,
+            }
+        };
+
+        if range.is_empty() {
+            Self::Unsatisfiable
+        } else {
+            Self::Partial(range)
+        }
+    }
+
+    /// Adds the `Content-Range` header for a body of `len` bytes and returns
+    /// the status of the response.
+    fn insert_headers(&self, headers: &mut HeaderMap, len: u64) -> StatusCode {
+        match self {
+            Self::Full => StatusCode::OK,
+            Self::Partial(range) => {
+                let content_range = format!("bytes {}-{}/{len}", range.start, range.end - 1);
+                headers.insert(CONTENT_RANGE, content_range.parse().unwrap());
+                StatusCode::PARTIAL_CONTENT
+            }
+            Self::Unsatisfiable => {
+                let content_range = format!("bytes */{len}");
+                headers.insert(CONTENT_RANGE, content_range.parse().unwrap());
+                StatusCode::RANGE_NOT_SATISFIABLE
+            }
+        }
+    }
+}
+
+/// Checks an `If-None-Match` header against an ETag, using the weak
+/// comparison required for `GET`.
+fn etag_matches(if_none_match: &str, etag: &str) -> bool {
+    if_none_match
+        .split(',')
+        .map(str::trim)
+        .any(|tag| tag == "*" || tag.strip_prefix("W/").unwrap_or(tag) == etag)
+}
+
+/// Converts a stored image, reusing earlier conversions.
+async fn convert_image(
+    globals: &Globals,
+    key: CacheKey,
+    format: ExportFormat,
+    quality: u8,
+) -> Result<Bytes> {
+    if let Some(data) = globals.conversions.get(&key) {
+        return Ok(data);
+    }
+
+    let image = load_image(globals, &key.id).await?;
+
+    let data = spawn_blocking(move || codec::encode_as(&image, format, quality))
+        .await
+        .unwrap()
+        .map_err(|_| AppError::Internal("failed to encode image".to_string()))?;
+
+    let data = Bytes::from(data);
+    globals.conversions.insert(key, data.clone());
+
+    Ok(data)
+}
+
+/// Scales a stored image as requested by `params`, reusing renditions cached
+/// on disk.
+async fn render_image(
+    globals: &Globals,
+    key: CacheKey,
+    params: &ExportParams,
+    format: ExportFormat,
+    quality: u8,
+) -> Result<Bytes> {
+    let (width, height) = (params.w, params.h);
+    let fit = params.fit.unwrap_or_default();
+
+    if let Some(renditions) = &globals.renditions
+        && let Some(data) = renditions.get(&key).await
+    {
+        return Ok(data);
+    }
+
+    let image = load_image(globals, &key.id).await?;
+
+    let data = spawn_blocking(move || {
+        let image = ops::rendition(&image, width, height, fit)?;
+        codec::encode_as(&image, format, quality)
+            .map_err(|_| AppError::Internal("failed to encode image".to_string()))
+    })
+    .await
+    .unwrap()?;
+
+    let data = Bytes::from(data);
+
+    if let Some(renditions) = &globals.renditions {
+        renditions.insert(key, data.clone()).await;
+    }
+
+    Ok(data)
+}
+
+fn rendition_variant(params: &ExportParams, format: ExportFormat, quality: u8) -> String {
+    let size = |v: Option<u32>| v.map_or_else(|| "auto".to_string(), |v| v.to_string());
+
+    format!(
+        "{}x{}-{}-{}",
+        size(params.w),
+        size(params.h),
+        params.fit.unwrap_or_default(),
+        format_variant(format, quality)
+    )
+}
+
+fn format_variant(format: ExportFormat, quality: u8) -> String {
+    match format {
+        ExportFormat::Jpeg => format!("jpeg-q{quality}"),
+        _ => format.extension().to_string(),
+    }
 }
 
 async fn delete_image(
     State(globals): State<Globals>,
     Path(id): Path<ImageId>,
+    Query(params): Query<DeleteParams>,
 ) -> Result<StatusCode> {
-    let mut path = globals.data_path.join(&id.0);
-    path.set_extension("png");
+    globals.storage.delete(&id, params.unmodified_since).await?;
+    globals.conversions.remove_image(&id);
 
-    if !tokio::fs::try_exists(&path).await? {
-        return Err(AppError::EntityNotFound("image not found".to_string()));
+    if let Some(renditions) = &globals.renditions {
+        renditions.remove_image(&id).await;
     }
 
-    tokio::fs::remove_file(&path).await?;
-
     Ok(StatusCode::NO_CONTENT)
 }
 
+/// Decodes an image, applies `operations` and stores the result, see
+/// [`ops::apply_all`] for `max_dimension`.
+async fn transform_image(
+    globals: &Globals,
+    id: &ImageId,
+    operations: Vec<Operation>,
+    max_dimension: u32,
+) -> Result<UploadResult> {
+    let image = load_image(globals, id).await?;
+
+    let image = spawn_blocking(move || ops::apply_all(image, &operations, max_dimension))
+        .await
+        .unwrap()?;
+
+    save_image(globals, image.into_rgba8()).await
+}
+
 #[derive(Debug, Clone, Eq, PartialEq, Serialize, Deserialize)]
 struct ResizeQuery {
     width: u32,
     height: u32,
+    /// Changes the canvas size instead of scaling, see [`CanvasParams`].
     #[serde(default)]
     fill: bool,
+    #[serde(default)]
+    background: Background,
+    #[serde(default)]
+    anchor: Anchor,
+    x: Option<i64>,
+    y: Option<i64>,
 }
 
 async fn resize_image(
@@ -204,130 +574,158 @@ async fn resize_image(
     Path(id): Path<ImageId>,
     Query(query): Query<ResizeQuery>,
 ) -> Result<AppJson<UploadResult>> {
-    let mut path = globals.data_path.join(&id.0);
-    path.set_extension("png");
-
-    if !tokio::fs::try_exists(&path).await? {
-        return Err(AppError::EntityNotFound("image not found".to_string()));
-    }
-
-    let data = tokio::fs::read(&path).await?;
-
-    let image = spawn_blocking(move || {
-        let reader = ImageReader::with_format(Cursor::new(data.to_vec()), ImageFormat::Png);
-
-        let image = reader
-            .decode()
-            .map_err(|_| AppError::Internal("invalid image".to_string()))?;
-
-        let resized = if query.fill {
-            let mut new_image = RgbImage::new(query.width, query.height);
-
-            for pixel in new_image.pixels_mut() {
-                *pixel = Rgb([255, 255, 255]);
-            }
-
-            imageops::overlay(&mut new_image, &image.to_rgb8(), 0, 0);
-
-            new_image
-        } else {
-            image
-                .resize(query.width, query.height, FilterType::Lanczos3)
-                .to_rgb8()
-        };
-
-        Ok::<_, AppError>(resized)
-    })
-    .await
-    .unwrap()?;
-
-    let (id, data) = encode_image(image).await?;
+    let operation = if query.fill {
+        Operation::ResizeCanvas(CanvasParams {
+            width: query.width,
+            height: query.height,
+            background: query.background,
+            anchor: query.anchor,
+            x: query.x,
+            y: query.y,
+        })
+    } else {
+        Operation::Resize {
+            width: query.width,
+            height: query.height,
+        }
+    };
 
-    let mut path = globals.data_path.join(&id.0);
-    path.set_extension("png");
+    let upload = transform_image(&globals, &id, vec![operation], ops::MAX_DIMENSION).await?;
 
-    if !tokio::fs::try_exists(&path).await? {
-        tokio::fs::write(&path, data).await?;
-    }
-
-    Ok(AppJson(UploadResult { id }))
+    Ok(AppJson(upload))
 }
 
 async fn blur_image(
     State(globals): State<Globals>,
     Path(id): Path<ImageId>,
+    Query(params): Query<BlurParams>,
 ) -> Result<AppJson<UploadResult>> {
-    let mut path = globals.data_path.join(&id.0);
-    path.set_extension("png");
-
-    if !tokio::fs::try_exists(&path).await? {
-        return Err(AppError::EntityNotFound("image not found".to_string()));
-    }
+    let operation = Operation::Blur {
+        sigma: params.sigma,
+        kind: params.kind,
+        region: params.region,
+    };
 
-    let data = tokio::fs::read(&path).await?;
+    let upload = transform_image(&globals, &id, vec![operation], ops::MAX_DIMENSION).await?;
 
-    let image = spawn_blocking(move || {
-        let reader = ImageReader::with_format(Cursor::new(data.to_vec()), ImageFormat::Png);
+    Ok(AppJson(upload))
+}
 
-        let image = reader
-            .decode()
-            .map_err(|_| AppError::Internal("invalid image".to_string()))?;
+async fn thumbnail_image(
+    State(globals): State<Globals>,
+    Path(id): Path<ImageId>,
+    Query(params): Query<ThumbnailParams>,
+) -> Result<AppJson<UploadResult>> {
+    let upload = transform_image(
+        &globals,
+        &id,
+        vec![Operation::Thumbnail(params)],
+        ops::MAX_DIMENSION,
+    )
+    .await?;
+
+    Ok(AppJson(upload))
+}
 
-        let new_image = imageops::fast_blur(&image.into_rgb8(), 10.0);
+async fn sharpen_image(
+    State(globals): State<Globals>,
+    Path(id): Path<ImageId>,
+    Query(params): Query<SharpenParams>,
+) -> Result<AppJson<UploadResult>> {
+    let operation = Operation::Sharpen {
+        sigma: params.sigma,
+        threshold: params.threshold,
+    };
 
-        Ok::<_, AppError>(new_image)
-    })
-    .await
-    .unwrap()?;
+    let upload = transform_image(&globals, &id, vec![operation], ops::MAX_DIMENSION).await?;
 
-    let (id, data) = encode_image(image).await?;
+    Ok(AppJson(upload))
+}
 
-    let mut path = globals.data_path.join(&id.0);
-    path.set_extension("png");
+async fn invert_image(
+    State(globals): State<Globals>,
+    Path(id): Path<ImageId>,
+    Query(params): Query<InvertParams>,
+) -> Result<AppJson<UploadResult>> {
+    let operation = Operation::Invert {
+        region: params.region,
+    };
 
-    if !tokio::fs::try_exists(&path).await? {
-        tokio::fs::write(&path, data).await?;
-    }
+    let upload = transform_image(&globals, &id, vec![operation], ops::MAX_DIMENSION).await?;
 
-    Ok(AppJson(UploadResult { id }))
+    Ok(AppJson(upload))
 }
 
-async fn invert_image(
+async fn crop_image(
     State(globals): State<Globals>,
     Path(id): Path<ImageId>,
+    Query(region): Query<Region>,
 ) -> Result<AppJson<UploadResult>> {
-    let mut path = globals.data_path.join(&id.0);
-    path.set_extension("png");
+    let operation = Operation::Crop {
+        x: region.x,
+        y: region.y,
+        width: region.width,
+        height: region.height,
+    };
 
-    if !tokio::fs::try_exists(&path).await? {
-        return Err(AppError::EntityNotFound("image not found".to_string()));
-    }
+    let upload = transform_image(&globals, &id, vec![operation], ops::MAX_DIMENSION).await?;
 
-    let data = tokio::fs::read(&path).await?;
+    Ok(AppJson(upload))
+}
 
-    let image = spawn_blocking(move || {
-        let reader = ImageReader::with_format(Cursor::new(data.to_vec()), ImageFormat::Png);
+#[derive(Debug, Clone, Eq, PartialEq, Serialize, Deserialize)]
+struct PipelineQuery {
+    /// Lower limit than [`ops::MAX_DIMENSION`] for every image along the
+    /// way, for callers that can't use larger results anyway.
+    max_dimension: Option<u32>,
+}
 
-        let mut image = reader
-            .decode()
-            .map_err(|_| AppError::Internal("invalid image".to_string()))?
-            .into_rgb8();
+async fn run_pipeline(
+    State(globals): State<Globals>,
+    Path(id): Path<ImageId>,
+    Query(query): Query<PipelineQuery>,
+    AppJson(pipeline): AppJson<Pipeline>,
+) -> Result<AppJson<UploadResult>> {
+    let max_dimension = query
+        .max_dimension
+        .map_or(ops::MAX_DIMENSION, |v| v.min(ops::MAX_DIMENSION));
 
-        imageops::invert(&mut image);
+    let upload = transform_image(&globals, &id, pipeline.operations, max_dimension).await?;
 
-        Ok::<_, AppError>(image)
-    })
-    .await
-    .unwrap()?;
+    Ok(AppJson(upload))
+}
 
-    let (id, data) = encode_image(image).await?;
+/// Compares an image with `other` and stores a highlight of the changed
+/// pixels, unless only the statistics are requested. The highlight is only
+/// referenced by the response, so callers that keep it around have to store
+/// its id.
+async fn diff_image(
+    State(globals): State<Globals>,
+    Path((id, other)): Path<(ImageId, ImageId)>,
+    Query(params): Query<DiffParams>,
+) -> Result<AppJson<DiffResult>> {
+    let before = load_image(&globals, &id).await?;
+    let after = load_image(&globals, &other).await?;
+
+    let (highlight, stats) = spawn_blocking(move || ops::diff(&before, &after))
+        .await
+        .unwrap();
 
-    let mut path = globals.data_path.join(&id.0);
-    path.set_extension("png");
+    let (width, height) = highlight.dimensions();
+    let total_pixels = u64::from(width) * u64::from(height);
 
-    if !tokio::fs::try_exists(&path).await? {
-        tokio::fs::write(&path, data).await?;
-    }
+    let id = if params.stats_only {
+        None
+    } else {
+        Some(save_image(&globals, highlight).await?.id)
+    };
 
-    Ok(AppJson(UploadResult { id }))
+    Ok(AppJson(DiffResult {
+        id,
+        width,
+        height,
+        changed_pixels: stats.changed_pixels,
+        percent_changed: stats.changed_pixels as f64 * 100.0 / total_pixels as f64,
+        bounding_box: stats.bounding_box,
+    }))
 }
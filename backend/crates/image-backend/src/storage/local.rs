@@ -0,0 +1,422 @@
+use std::future::Future;
+use std::hash::{DefaultHasher, Hash as _, Hasher as _};
+use std::io::SeekFrom;
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use futures_util::StreamExt as _;
+use tokio::io::{AsyncReadExt as _, AsyncSeekExt as _, AsyncWriteExt as _};
+use tokio::sync::{Mutex, MutexGuard};
+use tokio_util::io::ReaderStream;
+
+use super::{ObjectMeta, ObjectStream, Storage, StorageError, validate_id};
+use crate::model::ImageId;
+
+/// Stores images as png files in a directory, fanned out git-style into
+/// `ab/cd/<id>.png`. The shard names are taken from the hash part of the id,
+/// skipping the leading version character so files spread evenly.
+///
+/// Files are written to a temporary name and renamed into place, so a crash
+/// never leaves a truncated file under a valid id. Quarantined files are
+/// moved into a `quarantine` directory next to the shards.
+///
+/// Pages of the listing follow the directory layout: ids are ordered by their
+/// hash part, then by their version character.
+///
+/// Directories created by older versions keep every file flat in the root.
+/// Lookups fall back to the flat location, so the store stays usable while
+/// [`LocalStorage::migrate_flat_layout`] moves files into place. A file moved
+/// between its lookup and its use is looked up again once.
+///
+/// Writes and deletes of the same id take turns, so a delete conditional on
+/// the modification time can't remove a file that is being refreshed.
+pub struct LocalStorage {
+    path: PathBuf,
+    locks: Box<[Mutex<()>]>,
+}
+
+/// Number of locks ids are spread over, see [`LocalStorage::lock`].
+const LOCK_STRIPES: usize = 64;
+
+impl LocalStorage {
+    pub fn new(path: &Path) -> Self {
+        Self {
+            path: path.to_path_buf(),
+            locks: (0..LOCK_STRIPES).map(|_| Mutex::new(())).collect(),
+        }
+    }
+
+    /// Lock held while an image is written or deleted. Ids share a fixed set
+    /// of locks, ids hashing to the same one just wait for each other.
+    async fn lock(&self, id: &ImageId) -> MutexGuard<'_, ()> {
+        let mut hasher = DefaultHasher::new();
+        id.0.hash(&mut hasher);
+        self.locks[hasher.finish() as usize % LOCK_STRIPES]
+            .lock()
+            .await
+    }
+
+    fn sharded_path(&self, id: &ImageId) -> Result<PathBuf, StorageError> {
+        let id = validate_id(id)?;
+        Ok(self
+            .path
+            .join(&id[1..3])
+            .join(&id[3..5])
+            .join(format!("{id}.png")))
+    }
+
+    fn flat_path(&self, id: &ImageId) -> Result<PathBuf, StorageError> {
+        let id = validate_id(id)?;
+        Ok(self.path.join(format!("{id}.png")))
+    }
+
+    /// Returns the current location of an image, if it is stored.
+    async fn find(&self, id: &ImageId) -> Result<Option<PathBuf>, StorageError> {
+        for path in [self.sharded_path(id)?, self.flat_path(id)?] {
+            if tokio::fs::try_exists(&path).await? {
+                return Ok(Some(path));
+            }
+        }
+
+        Ok(None)
+    }
+
+    /// Runs `op` on the current location of an image. The migration may move
+    /// a flat file into its shard right after it was found, so a miss is
+    /// retried once if the image turns up somewhere else.
+    async fn with_path<T, F, Fut>(&self, id: &ImageId, op: F) -> Result<T, StorageError>
+    where
+        F: Fn(PathBuf) -> Fut,
+        Fut: Future<Output = Result<T, StorageError>>,
+    {
+        let path = self.find(id).await?.ok_or(StorageError::NotFound)?;
+
+        match op(path.clone()).await {
+            Err(StorageError::NotFound) => match self.find(id).await? {
+                Some(moved) if moved != path => op(moved).await,
+                _ => Err(StorageError::NotFound),
+            },
+            res => res,
+        }
+    }
+
+    async fn touch(path: PathBuf) -> Result<(), StorageError> {
+        let file = tokio::fs::File::options()
+            .append(true)
+            .open(&path)
+            .await
+            .map_err(not_found)?;
+        file.into_std().await.set_modified(SystemTime::now())?;
+        Ok(())
+    }
+
+    async fn meta(id: ImageId, path: &Path) -> Result<ObjectMeta, StorageError> {
+        let metadata = tokio::fs::metadata(path).await.map_err(not_found)?;
+
+        Ok(ObjectMeta {
+            id,
+            size: metadata.len(),
+            modified_at: DateTime::<Utc>::from(metadata.modified()?),
+        })
+    }
+
+    /// Ids of the images stored directly in `dir`, with their paths.
+    async fn list_ids(dir: &Path) -> Result<Vec<(ImageId, PathBuf)>, StorageError> {
+        let mut entries = tokio::fs::read_dir(dir).await?;
+        let mut ids = Vec::new();
+
+        while let Some(entry) = entries.next_entry().await? {
+            let path = entry.path();
+
+            if path.extension().is_none_or(|ext| ext != "png") {
+                continue;
+            }
+
+            let Some(id) = path.file_stem().and_then(|v| v.to_str()) else {
+                continue;
+            };
+
+            ids.push((ImageId(id.to_string()), path));
+        }
+
+        Ok(ids)
+    }
+
+    async fn list_dir(dir: &Path, objects: &mut Vec<ObjectMeta>) -> Result<(), StorageError> {
+        for (id, path) in Self::list_ids(dir).await? {
+            match Self::meta(id, &path).await {
+                Ok(meta) => objects.push(meta),
+                // moved or deleted concurrently
+                Err(StorageError::NotFound) => continue,
+                Err(error) => return Err(error),
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Position of an id in the listing order, see [`LocalStorage`].
+    fn order_key(id: &ImageId) -> (&str, &str) {
+        (id.0.get(1..).unwrap_or_default(), &id.0)
+    }
+
+    /// Shard directories below `dir`, sorted by name.
+    async fn shard_dirs(dir: &Path) -> Result<Vec<PathBuf>, StorageError> {
+        let mut entries = tokio::fs::read_dir(dir).await?;
+        let mut dirs = Vec::new();
+
+        while let Some(entry) = entries.next_entry().await? {
+            let name = entry.file_name();
+            let is_shard = name.len() == 2
+                && name
+                    .to_str()
+                    .is_some_and(|v| v.bytes().all(|b| b.is_ascii_alphanumeric()));
+
+            if is_shard && entry.file_type().await?.is_dir() {
+                dirs.push(entry.path());
+            }
+        }
+
+        dirs.sort();
+
+        Ok(dirs)
+    }
+
+    /// Moves images stored flat in the root directory into their shard
+    /// directories and returns how many were moved. Safe to run while the
+    /// storage is in use.
+    pub async fn migrate_flat_layout(&self) -> Result<usize, StorageError> {
+        let mut objects = Vec::new();
+        Self::list_dir(&self.path, &mut objects).await?;
+
+        let mut moved = 0;
+
+        for object in objects {
+            let (Ok(from), Ok(to)) = (self.flat_path(&object.id), self.sharded_path(&object.id))
+            else {
+                continue;
+            };
+
+            if let Some(parent) = to.parent() {
+                tokio::fs::create_dir_all(parent).await?;
+            }
+
+            match tokio::fs::rename(&from, &to).await {
+                Ok(()) => moved += 1,
+                Err(error) if error.kind() == std::io::ErrorKind::NotFound => continue,
+                Err(error) => return Err(error.into()),
+            }
+        }
+
+        Ok(moved)
+    }
+}
+
+fn not_found(error: std::io::Error) -> StorageError {
+    match error.kind() {
+        std::io::ErrorKind::NotFound => StorageError::NotFound,
+        _ => StorageError::Io(error),
+    }
+}
+
+#[async_trait]
+impl Storage for LocalStorage {
+    async fn put(&self, id: &ImageId, data: Vec<u8>) -> Result<(), StorageError> {
+        let _lock = self.lock(id).await;
+
+        match self.with_path(id, Self::touch).await {
+            Ok(()) => return Ok(()),
+            Err(StorageError::NotFound) => {}
+            Err(error) => return Err(error),
+        }
+
+        let path = self.sharded_path(id)?;
+        let parent = path.parent().unwrap();
+        tokio::fs::create_dir_all(parent).await?;
+
+        let tmp_path = parent.join(format!(".{}.{:016x}.tmp", id.0, rand::random::<u64>()));
+
+        let res = async {
+            let mut file = tokio::fs::File::create(&tmp_path).await?;
+            file.write_all(&data).await?;
+            file.sync_all().await?;
+            tokio::fs::rename(&tmp_path, &path).await
+        }
+        .await;
+
+        if res.is_err() {
+            let _ = tokio::fs::remove_file(&tmp_path).await;
+        }
+
+        Ok(res?)
+    }
+
+    async fn get(&self, id: &ImageId) -> Result<Vec<u8>, StorageError> {
+        self.with_path(id, async |path| {
+            tokio::fs::read(path).await.map_err(not_found)
+        })
+        .await
+    }
+
+    async fn read(
+        &self,
+        id: &ImageId,
+        range: Option<Range<u64>>,
+    ) -> Result<ObjectStream, StorageError> {
+        let mut file = self
+            .with_path(id, async |path| {
+                tokio::fs::File::open(path).await.map_err(not_found)
+            })
+            .await?;
+
+        let Some(range) = range else {
+            return Ok(ReaderStream::new(file).boxed());
+        };
+
+        file.seek(SeekFrom::Start(range.start)).await?;
+        let len = range.end.saturating_sub(range.start);
+
+        Ok(ReaderStream::new(file.take(len)).boxed())
+    }
+
+    async fn exists(&self, id: &ImageId) -> Result<bool, StorageError> {
+        Ok(self.find(id).await?.is_some())
+    }
+
+    async fn stat(&self, id: &ImageId) -> Result<ObjectMeta, StorageError> {
+        self.with_path(id, async |path| Self::meta(id.clone(), &path).await)
+            .await
+    }
+
+    async fn delete(
+        &self,
+        id: &ImageId,
+        unmodified_since: Option<DateTime<Utc>>,
+    ) -> Result<(), StorageError> {
+        let _lock = self.lock(id).await;
+
+        self.with_path(id, async |path| {
+            if let Some(since) = unmodified_since {
+                let meta = Self::meta(id.clone(), &path).await?;
+
+                if meta.modified_at > since {
+                    return Err(StorageError::Modified);
+                }
+            }
+
+            tokio::fs::remove_file(path).await.map_err(not_found)
+        })
+        .await
+    }
+
+    async fn list(&self) -> Result<Vec<ObjectMeta>, StorageError> {
+        let mut objects = Vec::new();
+
+        Self::list_dir(&self.path, &mut objects).await?;
+
+        for outer in Self::shard_dirs(&self.path).await? {
+            for inner in Self::shard_dirs(&outer).await? {
+                Self::list_dir(&inner, &mut objects).await?;
+            }
+        }
+
+        // an image being migrated may show up in both places
+        objects.sort_by(|a, b| a.id.0.cmp(&b.id.0));
+        objects.dedup_by(|a, b| a.id == b.id);
+
+        Ok(objects)
+    }
+
+    /// Walks the shards from the one `after` is stored in, stopping once a
+    /// shard completes the page. Flat files left over from the old layout are
+    /// listed on every page, they are moved into their shards on startup.
+    async fn list_page(
+        &self,
+        after: Option<&ImageId>,
+        limit: usize,
+    ) -> Result<Vec<ObjectMeta>, StorageError> {
+        let after_key = after.map(Self::order_key);
+        let is_next = |id: &ImageId| after_key.is_none_or(|key| Self::order_key(id) > key);
+
+        // shard of `after`, the shards before it hold nothing to list
+        let start = after_key.map_or("", |(hash, _)| hash.get(..4).unwrap_or(hash));
+
+        let mut ids = Self::list_ids(&self.path).await?;
+        ids.retain(|(id, _)| is_next(id));
+
+        'shards: for outer in Self::shard_dirs(&self.path).await? {
+            let outer_name = outer.file_name().unwrap().to_string_lossy().into_owned();
+
+            if outer_name.as_str() < &start[..start.len().min(2)] {
+                continue;
+            }
+
+            for inner in Self::shard_dirs(&outer).await? {
+                let name = format!(
+                    "{outer_name}{}",
+                    inner.file_name().unwrap().to_string_lossy()
+                );
+
+                if name.as_str() < start {
+                    continue;
+                }
+
+                let mut shard = Self::list_ids(&inner).await?;
+                shard.retain(|(id, _)| is_next(id));
+                ids.extend(shard);
+
+                // every id listed so far comes before those of later shards,
+                // apart from flat files, which already are in `ids`
+                let listed = ids
+                    .iter()
+                    .filter(|(id, _)| {
+                        Self::order_key(id).0.get(..4).unwrap_or_default() <= name.as_str()
+                    })
+                    .count();
+
+                if listed >= limit {
+                    break 'shards;
+                }
+            }
+        }
+
+        ids.sort_by(|a, b| Self::order_key(&a.0).cmp(&Self::order_key(&b.0)));
+        // an image being migrated may show up in both places
+        ids.dedup_by(|a, b| a.0 == b.0);
+
+        let mut objects = Vec::with_capacity(limit);
+
+        for (id, _) in ids {
+            if objects.len() == limit {
+                break;
+            }
+
+            // looked up again as the migration may have moved it
+            match self.stat(&id).await {
+                Ok(meta) => objects.push(meta),
+                // moved or deleted concurrently
+                Err(StorageError::NotFound) => continue,
+                Err(error) => return Err(error),
+            }
+        }
+
+        Ok(objects)
+    }
+
+    async fn quarantine(&self, id: &ImageId) -> Result<(), StorageError> {
+        let _lock = self.lock(id).await;
+
+        let dir = self.path.join("quarantine");
+        tokio::fs::create_dir_all(&dir).await?;
+
+        let target = dir.join(format!("{}.png", validate_id(id)?));
+
+        self.with_path(id, async |path| {
+            tokio::fs::rename(path, &target).await.map_err(not_found)
+        })
+        .await
+    }
+}
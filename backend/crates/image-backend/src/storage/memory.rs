@@ -0,0 +1,119 @@
+use std::collections::BTreeMap;
+use std::ops::Bound;
+
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use tokio::sync::RwLock;
+
+use super::{ObjectMeta, Storage, StorageError, validate_id};
+use crate::model::ImageId;
+
+struct Object {
+    data: Vec<u8>,
+    modified_at: DateTime<Utc>,
+}
+
+impl Object {
+    fn meta(&self, id: &str) -> ObjectMeta {
+        ObjectMeta {
+            id: ImageId(id.to_string()),
+            size: self.data.len() as u64,
+            modified_at: self.modified_at,
+        }
+    }
+}
+
+/// Keeps images in memory. Nothing survives a restart, so this is only
+/// meant for tests.
+#[derive(Default)]
+pub struct MemoryStorage {
+    objects: RwLock<BTreeMap<String, Object>>,
+    quarantined: RwLock<BTreeMap<String, Object>>,
+}
+
+#[async_trait]
+impl Storage for MemoryStorage {
+    async fn put(&self, id: &ImageId, data: Vec<u8>) -> Result<(), StorageError> {
+        validate_id(id)?;
+        let mut objects = self.objects.write().await;
+        let object = objects.entry(id.0.clone()).or_insert(Object {
+            data,
+            modified_at: Utc::now(),
+        });
+        object.modified_at = Utc::now();
+        Ok(())
+    }
+
+    async fn get(&self, id: &ImageId) -> Result<Vec<u8>, StorageError> {
+        validate_id(id)?;
+        let objects = self.objects.read().await;
+        let object = objects.get(&id.0).ok_or(StorageError::NotFound)?;
+        Ok(object.data.clone())
+    }
+
+    async fn exists(&self, id: &ImageId) -> Result<bool, StorageError> {
+        validate_id(id)?;
+        Ok(self.objects.read().await.contains_key(&id.0))
+    }
+
+    async fn stat(&self, id: &ImageId) -> Result<ObjectMeta, StorageError> {
+        validate_id(id)?;
+        let objects = self.objects.read().await;
+        let object = objects.get(&id.0).ok_or(StorageError::NotFound)?;
+        Ok(object.meta(&id.0))
+    }
+
+    async fn delete(
+        &self,
+        id: &ImageId,
+        unmodified_since: Option<DateTime<Utc>>,
+    ) -> Result<(), StorageError> {
+        validate_id(id)?;
+        // checked and removed under the lock writes take as well
+        let mut objects = self.objects.write().await;
+        let object = objects.get(&id.0).ok_or(StorageError::NotFound)?;
+
+        if unmodified_since.is_some_and(|v| object.modified_at > v) {
+            return Err(StorageError::Modified);
+        }
+
+        objects.remove(&id.0);
+        Ok(())
+    }
+
+    async fn list(&self) -> Result<Vec<ObjectMeta>, StorageError> {
+        let objects = self.objects.read().await;
+        Ok(objects.iter().map(|(id, object)| object.meta(id)).collect())
+    }
+
+    async fn list_page(
+        &self,
+        after: Option<&ImageId>,
+        limit: usize,
+    ) -> Result<Vec<ObjectMeta>, StorageError> {
+        let start = match after {
+            Some(after) => Bound::Excluded(after.0.as_str()),
+            None => Bound::Unbounded,
+        };
+
+        let objects = self.objects.read().await;
+
+        Ok(objects
+            .range::<str, _>((start, Bound::Unbounded))
+            .take(limit)
+            .map(|(id, object)| object.meta(id))
+            .collect())
+    }
+
+    async fn quarantine(&self, id: &ImageId) -> Result<(), StorageError> {
+        validate_id(id)?;
+        let object = self
+            .objects
+            .write()
+            .await
+            .remove(&id.0)
+            .ok_or(StorageError::NotFound)?;
+        self.quarantined.write().await.insert(id.0.clone(), object);
+        Ok(())
+    }
+}
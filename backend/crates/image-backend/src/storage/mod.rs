@@ -0,0 +1,157 @@
+mod local;
+mod memory;
+mod s3;
+
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use async_trait::async_trait;
+use axum::body::Bytes;
+use chrono::{DateTime, Utc};
+use futures_util::StreamExt as _;
+use futures_util::stream::BoxStream;
+
+pub use self::local::LocalStorage;
+pub use self::memory::MemoryStorage;
+pub use self::s3::{S3Config, S3Storage};
+use crate::model::ImageId;
+
+#[derive(Debug, thiserror::Error)]
+pub enum StorageError {
+    #[error("object not found")]
+    NotFound,
+    #[error("object modified")]
+    Modified,
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+    #[error("storage backend error: {0}")]
+    Backend(String),
+}
+
+/// Checks an id before it is used to address an object. Ids end up in file
+/// paths and object keys, so anything other than the alphanumeric ids
+/// produced by this service is rejected outright, and every backend reports
+/// such ids as missing.
+pub(crate) fn validate_id(id: &ImageId) -> Result<&str, StorageError> {
+    let valid = id.0.len() > 5 && id.0.bytes().all(|b| b.is_ascii_alphanumeric());
+
+    if valid {
+        Ok(&id.0)
+    } else {
+        Err(StorageError::NotFound)
+    }
+}
+
+/// Contents of an object, read in chunks.
+pub type ObjectStream = BoxStream<'static, std::io::Result<Bytes>>;
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct ObjectMeta {
+    pub id: ImageId,
+    pub size: u64,
+    pub modified_at: DateTime<Utc>,
+}
+
+/// Storage for encoded images, addressed by their id.
+#[async_trait]
+pub trait Storage: Send + Sync {
+    /// Stores `data` under `id`. Images are content-addressed, so if the id
+    /// is already present the stored data is kept, but its modification time
+    /// is refreshed: the core service's garbage collector treats recently
+    /// touched images as live.
+    async fn put(&self, id: &ImageId, data: Vec<u8>) -> Result<(), StorageError>;
+
+    async fn get(&self, id: &ImageId) -> Result<Vec<u8>, StorageError>;
+
+    /// Reads an object, or only the bytes in `range`, without holding all of
+    /// it in memory, for sending it on as is. The range is clamped to the
+    /// size of the object. The default implementation reads it whole.
+    async fn read(
+        &self,
+        id: &ImageId,
+        range: Option<Range<u64>>,
+    ) -> Result<ObjectStream, StorageError> {
+        let mut data = Bytes::from(self.get(id).await?);
+
+        if let Some(range) = range {
+            let end = data.len().min(range.end as usize);
+            data = data.slice(end.min(range.start as usize)..end);
+        }
+
+        Ok(futures_util::stream::once(async move { Ok(data) }).boxed())
+    }
+
+    async fn exists(&self, id: &ImageId) -> Result<bool, StorageError>;
+
+    async fn stat(&self, id: &ImageId) -> Result<ObjectMeta, StorageError>;
+
+    /// Deletes an object. With `unmodified_since`, an object modified after
+    /// that time is kept and [`StorageError::Modified`] returned instead, the
+    /// time being checked right before the object is removed.
+    async fn delete(
+        &self,
+        id: &ImageId,
+        unmodified_since: Option<DateTime<Utc>>,
+    ) -> Result<(), StorageError>;
+
+    /// Lists every stored object, ordered by id.
+    async fn list(&self) -> Result<Vec<ObjectMeta>, StorageError>;
+
+    /// Lists up to `limit` objects following `after`, without going through
+    /// the whole store. Pages are ordered by id unless the backend documents
+    /// its own order, which stays the same from one page to the next.
+    async fn list_page(
+        &self,
+        after: Option<&ImageId>,
+        limit: usize,
+    ) -> Result<Vec<ObjectMeta>, StorageError>;
+
+    /// Moves an object out of the way so it is no longer served or listed,
+    /// while keeping its data around for inspection.
+    async fn quarantine(&self, id: &ImageId) -> Result<(), StorageError>;
+}
+
+#[derive(Debug, Clone)]
+pub enum StorageConfig {
+    Local(PathBuf),
+    Memory,
+    S3(S3Config),
+}
+
+impl StorageConfig {
+    pub fn local(path: &Path) -> Self {
+        Self::Local(path.to_path_buf())
+    }
+
+    /// Reads the configuration from `STORAGE_BACKEND` (`local`, `memory` or
+    /// `s3`, defaults to `local`) and the backend-specific variables.
+    pub fn from_env() -> Self {
+        let backend = std::env::var("STORAGE_BACKEND").unwrap_or_else(|_| "local".to_string());
+
+        match backend.as_str() {
+            "local" => Self::Local(PathBuf::from(std::env::var("DATA_PATH").unwrap())),
+            "memory" => Self::Memory,
+            "s3" => Self::S3(S3Config::from_env()),
+            _ => panic!("unknown storage backend: {backend:?}"),
+        }
+    }
+
+    /// Directory for derived files such as renditions. Local storage keeps
+    /// them next to the images, other backends use the `CACHE_PATH` variable
+    /// if set. `None` disables the disk cache.
+    pub fn cache_path(&self) -> Option<PathBuf> {
+        match self {
+            Self::Local(path) => Some(path.join("cache")),
+            Self::Memory | Self::S3(_) => std::env::var_os("CACHE_PATH").map(PathBuf::from),
+        }
+    }
+
+    pub fn build(&self) -> Arc<dyn Storage> {
+        match self {
+            Self::Local(path) => Arc::new(LocalStorage::new(path)),
+            Self::Memory => Arc::new(MemoryStorage::default()),
+            Self::S3(config) => Arc::new(S3Storage::new(config.clone())),
+        }
+    }
+}
@@ -0,0 +1,409 @@
+use std::ops::Range;
+
+use async_trait::async_trait;
+use chrono::{DateTime, SubsecRound as _, Utc};
+use futures_util::{StreamExt as _, TryStreamExt as _};
+use hmac::{Hmac, Mac};
+use percent_encoding::{AsciiSet, NON_ALPHANUMERIC, utf8_percent_encode};
+use reqwest::header::{CONTENT_LENGTH, ETAG, HeaderName, IF_MATCH, LAST_MODIFIED, RANGE};
+use reqwest::{Client, Method, RequestBuilder, Response, StatusCode, Url};
+use serde::Deserialize;
+use sha2::{Digest as _, Sha256};
+
+use super::{ObjectMeta, ObjectStream, Storage, StorageError, validate_id};
+use crate::model::ImageId;
+
+/// Characters left as is by SigV4 URI encoding.
+const UNRESERVED: &AsciiSet = &NON_ALPHANUMERIC
+    .remove(b'-')
+    .remove(b'_')
+    .remove(b'.')
+    .remove(b'~');
+
+#[derive(Debug, Clone)]
+pub struct S3Config {
+    /// Base URL of the service, e.g. `http://127.0.0.1:9000` for MinIO.
+    pub endpoint: String,
+    pub bucket: String,
+    pub region: String,
+    pub access_key: String,
+    pub secret_key: String,
+}
+
+impl S3Config {
+    pub fn from_env() -> Self {
+        Self {
+            endpoint: std::env::var("S3_ENDPOINT").unwrap(),
+            bucket: std::env::var("S3_BUCKET").unwrap(),
+            region: std::env::var("S3_REGION").unwrap_or_else(|_| "us-east-1".to_string()),
+            access_key: std::env::var("S3_ACCESS_KEY").unwrap(),
+            secret_key: std::env::var("S3_SECRET_KEY").unwrap(),
+        }
+    }
+}
+
+/// Stores images as `<id>.png` objects in an S3-compatible bucket, with
+/// quarantined images under the `quarantine/` prefix. Requests use
+/// path-style addressing and are signed with AWS Signature Version 4.
+pub struct S3Storage {
+    client: Client,
+    config: S3Config,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct ListBucketResult {
+    #[serde(default)]
+    contents: Vec<ListContents>,
+    #[serde(default)]
+    is_truncated: bool,
+    next_continuation_token: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct ListContents {
+    key: String,
+    last_modified: DateTime<Utc>,
+    size: u64,
+}
+
+impl S3Storage {
+    pub fn new(config: S3Config) -> Self {
+        Self {
+            client: Client::new(),
+            config,
+        }
+    }
+
+    fn object_key(id: &ImageId) -> Result<String, StorageError> {
+        Ok(format!("{}.png", validate_id(id)?))
+    }
+
+    /// Metadata of an object along with its ETag, if the service sent one.
+    async fn head(&self, id: &ImageId) -> Result<(ObjectMeta, Option<String>), StorageError> {
+        let key = Self::object_key(id)?;
+        let res = self.send(Method::HEAD, Some(&key), &[], Vec::new()).await?;
+
+        let header = |name: HeaderName| {
+            res.headers()
+                .get(&name)
+                .and_then(|v| v.to_str().ok())
+                .ok_or_else(|| StorageError::Backend(format!("missing {name} header")))
+        };
+
+        let size = header(CONTENT_LENGTH)?
+            .parse()
+            .map_err(|_| StorageError::Backend("invalid content-length".to_string()))?;
+
+        let modified_at = DateTime::parse_from_rfc2822(header(LAST_MODIFIED)?)
+            .map_err(|_| StorageError::Backend("invalid last-modified".to_string()))?
+            .to_utc();
+
+        let etag = header(ETAG).ok().map(str::to_string);
+
+        let meta = ObjectMeta {
+            id: id.clone(),
+            size,
+            modified_at,
+        };
+
+        Ok((meta, etag))
+    }
+
+    /// Requests one page of the bucket listing.
+    async fn list_objects(&self, query: &[(&str, &str)]) -> Result<ListBucketResult, StorageError> {
+        let res = self.send(Method::GET, None, query, Vec::new()).await?;
+        let text = res
+            .text()
+            .await
+            .map_err(|e| StorageError::Backend(e.to_string()))?;
+
+        quick_xml::de::from_str(&text).map_err(|e| StorageError::Backend(e.to_string()))
+    }
+
+    /// Metadata of a listed object, `None` for keys that aren't images, such
+    /// as quarantined ones.
+    fn object_meta(object: ListContents) -> Option<ObjectMeta> {
+        if object.key.contains('/') {
+            return None;
+        }
+
+        let id = object.key.strip_suffix(".png")?;
+
+        Some(ObjectMeta {
+            id: ImageId(id.to_string()),
+            size: object.size,
+            modified_at: object.last_modified,
+        })
+    }
+
+    async fn send(
+        &self,
+        method: Method,
+        key: Option<&str>,
+        query: &[(&str, &str)],
+        body: Vec<u8>,
+    ) -> Result<Response, StorageError> {
+        let request = self.request(method, key, query, body)?;
+        Self::execute(request).await
+    }
+
+    /// Builds a signed request. Headers added to it afterwards are sent
+    /// unsigned, which S3 accepts for anything other than `x-amz-*`.
+    fn request(
+        &self,
+        method: Method,
+        key: Option<&str>,
+        query: &[(&str, &str)],
+        body: Vec<u8>,
+    ) -> Result<RequestBuilder, StorageError> {
+        let mut path = format!("/{}", utf8_percent_encode(&self.config.bucket, UNRESERVED));
+        if let Some(key) = key {
+            for segment in key.split('/') {
+                path.push('/');
+                path.push_str(&utf8_percent_encode(segment, UNRESERVED).to_string());
+            }
+        }
+
+        let mut query: Vec<(String, String)> = query
+            .iter()
+            .map(|(k, v)| {
+                (
+                    utf8_percent_encode(k, UNRESERVED).to_string(),
+                    utf8_percent_encode(v, UNRESERVED).to_string(),
+                )
+            })
+            .collect();
+        query.sort();
+        let query = query
+            .iter()
+            .map(|(k, v)| format!("{k}={v}"))
+            .collect::<Vec<_>>()
+            .join("&");
+
+        let mut url = format!("{}{}", self.config.endpoint.trim_end_matches('/'), path);
+        if !query.is_empty() {
+            url.push('?');
+            url.push_str(&query);
+        }
+
+        let url = Url::parse(&url).map_err(|e| StorageError::Backend(e.to_string()))?;
+        let host = match (url.host_str(), url.port()) {
+            (Some(host), Some(port)) => format!("{host}:{port}"),
+            (Some(host), None) => host.to_string(),
+            (None, _) => return Err(StorageError::Backend("invalid endpoint".to_string())),
+        };
+
+        let now = Utc::now();
+        let amz_date = now.format("%Y%m%dT%H%M%SZ").to_string();
+        let date = now.format("%Y%m%d").to_string();
+        let payload_hash = hex::encode(Sha256::digest(&body));
+
+        let signed_headers = "host;x-amz-content-sha256;x-amz-date";
+        let canonical_request = format!(
+            "{method}\n{path}\n{query}\nhost:{host}\nx-amz-content-sha256:{payload_hash}\nx-amz-date:{amz_date}\n\n{signed_headers}\n{payload_hash}"
+        );
+
+        let scope = format!("{date}/{}/s3/aws4_request", self.config.region);
+        let string_to_sign = format!(
+            "AWS4-HMAC-SHA256\n{amz_date}\n{scope}\n{}",
+            hex::encode(Sha256::digest(canonical_request.as_bytes()))
+        );
+
+        let key = hmac_sha256(
+            format!("AWS4{}", self.config.secret_key).as_bytes(),
+            date.as_bytes(),
+        );
+        let key = hmac_sha256(&key, self.config.region.as_bytes());
+        let key = hmac_sha256(&key, b"s3");
+        let key = hmac_sha256(&key, b"aws4_request");
+        let signature = hex::encode(hmac_sha256(&key, string_to_sign.as_bytes()));
+
+        let authorization = format!(
+            "AWS4-HMAC-SHA256 Credential={}/{scope}, SignedHeaders={signed_headers}, Signature={signature}",
+            self.config.access_key
+        );
+
+        Ok(self
+            .client
+            .request(method, url)
+            .header("x-amz-content-sha256", payload_hash)
+            .header("x-amz-date", amz_date)
+            .header("authorization", authorization)
+            .body(body))
+    }
+
+    async fn execute(request: RequestBuilder) -> Result<Response, StorageError> {
+        let res = request
+            .send()
+            .await
+            .map_err(|e| StorageError::Backend(e.to_string()))?;
+
+        match res.status() {
+            StatusCode::NOT_FOUND => Err(StorageError::NotFound),
+            // only conditional requests are sent with preconditions
+            StatusCode::PRECONDITION_FAILED => Err(StorageError::Modified),
+            status if status.is_success() => Ok(res),
+            status => Err(StorageError::Backend(format!("unexpected status {status}"))),
+        }
+    }
+}
+
+fn hmac_sha256(key: &[u8], data: &[u8]) -> Vec<u8> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(key).unwrap();
+    mac.update(data);
+    mac.finalize().into_bytes().to_vec()
+}
+
+#[async_trait]
+impl Storage for S3Storage {
+    async fn put(&self, id: &ImageId, data: Vec<u8>) -> Result<(), StorageError> {
+        // Overwriting an object with the same content is how its
+        // modification time gets refreshed.
+        let key = Self::object_key(id)?;
+        self.send(Method::PUT, Some(&key), &[], data).await?;
+        Ok(())
+    }
+
+    async fn get(&self, id: &ImageId) -> Result<Vec<u8>, StorageError> {
+        let key = Self::object_key(id)?;
+        let res = self.send(Method::GET, Some(&key), &[], Vec::new()).await?;
+        let bytes = res
+            .bytes()
+            .await
+            .map_err(|e| StorageError::Backend(e.to_string()))?;
+        Ok(bytes.to_vec())
+    }
+
+    async fn read(
+        &self,
+        id: &ImageId,
+        range: Option<Range<u64>>,
+    ) -> Result<ObjectStream, StorageError> {
+        let key = Self::object_key(id)?;
+        let mut request = self.request(Method::GET, Some(&key), &[], Vec::new())?;
+
+        if let Some(range) = range {
+            if range.is_empty() {
+                return Ok(futures_util::stream::empty().boxed());
+            }
+
+            request = request.header(RANGE, format!("bytes={}-{}", range.start, range.end - 1));
+        }
+
+        let res = Self::execute(request).await?;
+        Ok(res.bytes_stream().map_err(std::io::Error::other).boxed())
+    }
+
+    async fn exists(&self, id: &ImageId) -> Result<bool, StorageError> {
+        match self.stat(id).await {
+            Ok(_) => Ok(true),
+            Err(StorageError::NotFound) => Ok(false),
+            Err(error) => Err(error),
+        }
+    }
+
+    async fn stat(&self, id: &ImageId) -> Result<ObjectMeta, StorageError> {
+        let (meta, _) = self.head(id).await?;
+        Ok(meta)
+    }
+
+    async fn delete(
+        &self,
+        id: &ImageId,
+        unmodified_since: Option<DateTime<Utc>>,
+    ) -> Result<(), StorageError> {
+        // S3 reports success for missing keys, so check first to keep the
+        // same semantics as the other backends.
+        let (meta, etag) = self.head(id).await?;
+
+        // `Last-Modified` only has second precision, so anything modified
+        // within the same second counts as modified
+        if unmodified_since.is_some_and(|v| meta.modified_at >= v.trunc_subsecs(0)) {
+            return Err(StorageError::Modified);
+        }
+
+        let key = Self::object_key(id)?;
+        let mut request = self.request(Method::DELETE, Some(&key), &[], Vec::new())?;
+
+        // the object may be replaced between the check and the delete, which
+        // then fails with 412 instead of removing the new object
+        if let (Some(_), Some(etag)) = (unmodified_since, etag) {
+            request = request.header(IF_MATCH, etag);
+        }
+
+        Self::execute(request).await?;
+        Ok(())
+    }
+
+    async fn list(&self) -> Result<Vec<ObjectMeta>, StorageError> {
+        let mut objects = Vec::new();
+        let mut continuation_token: Option<String> = None;
+
+        loop {
+            let mut query = vec![("list-type", "2")];
+            if let Some(token) = &continuation_token {
+                query.push(("continuation-token", token));
+            }
+
+            let result = self.list_objects(&query).await?;
+            objects.extend(result.contents.into_iter().filter_map(Self::object_meta));
+
+            match result.next_continuation_token {
+                Some(token) if result.is_truncated => continuation_token = Some(token),
+                _ => break,
+            }
+        }
+
+        objects.sort_by(|a, b| a.id.0.cmp(&b.id.0));
+
+        Ok(objects)
+    }
+
+    /// Keys sort like the ids they start with, so the listing starts right
+    /// after the key of `after`.
+    async fn list_page(
+        &self,
+        after: Option<&ImageId>,
+        limit: usize,
+    ) -> Result<Vec<ObjectMeta>, StorageError> {
+        // any id can be a starting point, it is never used as a key itself
+        let start_after = after.map(|id| format!("{}.png", id.0));
+        let max_keys = limit.to_string();
+
+        let mut objects = Vec::new();
+        let mut continuation_token: Option<String> = None;
+
+        while objects.len() < limit {
+            let mut query = vec![("list-type", "2"), ("max-keys", max_keys.as_str())];
+
+            match (&continuation_token, &start_after) {
+                (Some(token), _) => query.push(("continuation-token", token)),
+                (None, Some(key)) => query.push(("start-after", key)),
+                (None, None) => {}
+            }
+
+            let result = self.list_objects(&query).await?;
+            objects.extend(result.contents.into_iter().filter_map(Self::object_meta));
+
+            match result.next_continuation_token {
+                Some(token) if result.is_truncated => continuation_token = Some(token),
+                _ => break,
+            }
+        }
+
+        objects.truncate(limit);
+
+        Ok(objects)
+    }
+
+    async fn quarantine(&self, id: &ImageId) -> Result<(), StorageError> {
+        let data = self.get(id).await?;
+
+        let key = format!("quarantine/{}", Self::object_key(id)?);
+        self.send(Method::PUT, Some(&key), &[], data).await?;
+
+        self.delete(id, None).await
+    }
+}
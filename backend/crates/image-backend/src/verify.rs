@@ -0,0 +1,74 @@
+use tokio::task::spawn_blocking;
+
+use crate::codec;
+use crate::globals::Globals;
+use crate::model::{ImageId, VerifyReport};
+use crate::storage::StorageError;
+
+/// Re-decodes stored images and recomputes their ids, starting after
+/// `after` and checking at most `limit` images, so a run reads one page of
+/// the store at a time. With `quarantine` set, corrupt images are moved out
+/// of the way and dropped from the caches so they stop being served, and the
+/// next upload of the same content writes a fresh copy.
+pub async fn verify_images(
+    globals: &Globals,
+    after: Option<&ImageId>,
+    limit: usize,
+    quarantine: bool,
+) -> Result<VerifyReport, StorageError> {
+    let storage = &*globals.storage;
+
+    // one more than asked for tells whether there is a next page
+    let mut objects = storage.list_page(after, limit + 1).await?;
+
+    let next_after = if objects.len() > limit {
+        objects.truncate(limit);
+        objects.last().map(|object| object.id.clone())
+    } else {
+        None
+    };
+
+    let mut report = VerifyReport {
+        checked: 0,
+        corrupt: Vec::new(),
+        quarantined: quarantine,
+        next_after,
+    };
+
+    for object in &objects {
+        let data = match storage.get(&object.id).await {
+            Ok(data) => data,
+            // deleted concurrently
+            Err(StorageError::NotFound) => continue,
+            Err(error) => return Err(error),
+        };
+
+        report.checked += 1;
+
+        let id = object.id.clone();
+        let valid = spawn_blocking(move || {
+            codec::decode(&data).is_ok_and(|image| codec::matches(&id, &image))
+        })
+        .await
+        .unwrap();
+
+        if valid {
+            continue;
+        }
+
+        tracing::warn!(id = object.id.0, "stored image is corrupt");
+
+        if quarantine {
+            storage.quarantine(&object.id).await?;
+            globals.conversions.remove_image(&object.id);
+
+            if let Some(renditions) = &globals.renditions {
+                renditions.remove_image(&object.id).await;
+            }
+        }
+
+        report.corrupt.push(object.id.clone());
+    }
+
+    Ok(report)
+}
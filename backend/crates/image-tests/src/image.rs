@@ -1,11 +1,22 @@
+use std::io::Cursor;
+
 use axum::http::StatusCode;
-use axum::http::header::CONTENT_TYPE;
-use axum_test::TestServer;
+use axum::http::header::{
+    ACCEPT, ACCEPT_RANGES, CACHE_CONTROL, CONTENT_RANGE, CONTENT_TYPE, ETAG, IF_NONE_MATCH,
+    IF_RANGE, RANGE, VARY,
+};
 use axum_test::multipart::{MultipartForm, Part};
-use image_backend::model::UploadResult;
+use axum_test::{TestResponse, TestServer};
+use image::codecs::gif::GifEncoder;
+use image::{DynamicImage, Frame, ImageFormat, Rgb, RgbImage, Rgba, RgbaImage};
+use image_backend::model::{
+    DiffResult, Fit, FlipDirection, ImageId, ImagePage, Pipeline, Region, StorageStats,
+    UploadResult,
+};
+use image_backend::storage::StorageConfig;
 use tempfile::tempdir;
 
-async fn create_test_image(server: &TestServer) -> UploadResult {
+pub async fn create_test_image(server: &TestServer) -> UploadResult {
     let image = std::fs::read(concat!(
         env!("CARGO_MANIFEST_DIR"),
         "/../../test-data/kitten.png"
@@ -26,7 +37,7 @@ async fn create_test_image(server: &TestServer) -> UploadResult {
 #[tokio::test]
 async fn create_image() {
     let data_path = tempdir().unwrap();
-    let app = image_backend::build_app(data_path.path());
+    let app = image_backend::build_app(StorageConfig::local(data_path.path()));
     let server = TestServer::new(app).unwrap();
     create_test_image(&server).await;
 }
@@ -34,7 +45,7 @@ async fn create_image() {
 #[tokio::test]
 async fn get_image() {
     let data_path = tempdir().unwrap();
-    let app = image_backend::build_app(data_path.path());
+    let app = image_backend::build_app(StorageConfig::local(data_path.path()));
     let server = TestServer::new(app).unwrap();
 
     let upload = create_test_image(&server).await;
@@ -47,7 +58,7 @@ async fn get_image() {
 #[tokio::test]
 async fn delete_image() {
     let data_path = tempdir().unwrap();
-    let app = image_backend::build_app(data_path.path());
+    let app = image_backend::build_app(StorageConfig::local(data_path.path()));
     let server = TestServer::new(app).unwrap();
 
     let upload = create_test_image(&server).await;
@@ -60,3 +71,952 @@ async fn delete_image() {
     let res = server.get(&format!("/api/v1/image/{}", upload.id.0)).await;
     res.assert_status_not_found();
 }
+
+#[tokio::test]
+async fn list_images() {
+    let data_path = tempdir().unwrap();
+    let app = image_backend::build_app(StorageConfig::local(data_path.path()));
+    let server = TestServer::new(app).unwrap();
+
+    let kitten = create_test_image(&server).await;
+
+    let res = server
+        .post("/api/v1/image")
+        .add_query_param("width", 64)
+        .add_query_param("height", 32)
+        .await;
+    res.assert_status_ok();
+    let blank: UploadResult = res.json();
+
+    let mut expected = [(kitten.id, 512, 512), (blank.id, 64, 32)];
+    expected.sort_by(|a, b| a.0.0.cmp(&b.0.0));
+
+    let res = server
+        .get("/api/v1/image")
+        .add_query_param("limit", 1)
+        .await;
+    res.assert_status_ok();
+
+    let first: ImagePage = res.json();
+    assert_eq!(first.items.len(), 1);
+    assert_eq!(first.items[0].id, expected[0].0);
+    assert_eq!(first.items[0].width, Some(expected[0].1));
+    assert_eq!(first.items[0].height, Some(expected[0].2));
+    assert_eq!(first.next_after.as_ref(), Some(&expected[0].0));
+
+    let res = server
+        .get("/api/v1/image")
+        .add_query_param("limit", 1)
+        .add_query_param("after", &first.next_after.unwrap().0)
+        .await;
+    res.assert_status_ok();
+
+    let second: ImagePage = res.json();
+    assert_eq!(second.items.len(), 1);
+    assert_eq!(second.items[0].id, expected[1].0);
+    assert_eq!(second.items[0].width, Some(expected[1].1));
+    assert_eq!(second.items[0].height, Some(expected[1].2));
+    assert_eq!(second.next_after, None);
+}
+
+#[tokio::test]
+async fn list_images_with_invalid_file() {
+    let data_path = tempdir().unwrap();
+    let app = image_backend::build_app(StorageConfig::local(data_path.path()));
+    let server = TestServer::new(app).unwrap();
+
+    let upload = create_test_image(&server).await;
+    let id = &upload.id.0;
+
+    let path = data_path
+        .path()
+        .join(&id[1..3])
+        .join(&id[3..5])
+        .join(format!("{id}.png"));
+    std::fs::write(&path, b"not a png").unwrap();
+
+    let res = server.get("/api/v1/image").await;
+    res.assert_status_ok();
+
+    let page: ImagePage = res.json();
+    assert_eq!(page.items.len(), 1);
+    assert_eq!(page.items[0].id, upload.id);
+    assert_eq!(page.items[0].width, None);
+    assert_eq!(page.items[0].height, None);
+}
+
+#[tokio::test]
+async fn get_stats() {
+    let data_path = tempdir().unwrap();
+    let app = image_backend::build_app(StorageConfig::local(data_path.path()));
+    let server = TestServer::new(app).unwrap();
+
+    let res = server.get("/api/v1/image/stats").await;
+    res.assert_status_ok();
+    let stats: StorageStats = res.json();
+    assert_eq!(stats.count, 0);
+    assert_eq!(stats.oldest_modified_at, None);
+
+    create_test_image(&server).await;
+
+    let res = server.get("/api/v1/image/stats").await;
+    res.assert_status_ok();
+    let stats: StorageStats = res.json();
+    assert_eq!(stats.count, 1);
+    assert!(stats.total_size > 0);
+    assert!(stats.oldest_modified_at.is_some());
+    assert_eq!(stats.oldest_modified_at, stats.newest_modified_at);
+}
+
+#[tokio::test]
+async fn apply_pipeline() {
+    let data_path = tempdir().unwrap();
+    let app = image_backend::build_app(StorageConfig::local(data_path.path()));
+    let server = TestServer::new(app).unwrap();
+
+    let upload = create_test_image(&server).await;
+
+    let pipeline = Pipeline::new()
+        .crop(0, 0, 256, 128)
+        .rotate(90)
+        .flip(FlipDirection::Horizontal)
+        .blur(2.0);
+
+    let res = server
+        .post(&format!("/api/v1/image/{}/pipeline", upload.id.0))
+        .json(&pipeline)
+        .await;
+    res.assert_status_ok();
+    let result: UploadResult = res.json();
+
+    assert_eq!((result.width, result.height), (128, 256));
+
+    let res = server.get(&format!("/api/v1/image/{}", result.id.0)).await;
+    res.assert_status_ok();
+
+    let res = server
+        .post(&format!("/api/v1/image/{}/pipeline", upload.id.0))
+        .json(&Pipeline::new().rotate(45))
+        .await;
+    res.assert_status_bad_request();
+
+    let res = server
+        .post(&format!("/api/v1/image/{}/pipeline", upload.id.0))
+        .json(&Pipeline::new().crop(500, 0, 100, 100))
+        .await;
+    res.assert_status_bad_request();
+
+    // the limit applies to every image along the way, not just the result
+    let pipeline = Pipeline::new().resize_canvas(4096, 128).crop(0, 0, 64, 64);
+
+    let res = server
+        .post(&format!("/api/v1/image/{}/pipeline", upload.id.0))
+        .json(&pipeline)
+        .await;
+    res.assert_status_ok();
+
+    let res = server
+        .post(&format!("/api/v1/image/{}/pipeline", upload.id.0))
+        .add_query_param("max_dimension", 2048)
+        .json(&pipeline)
+        .await;
+    res.assert_status_bad_request();
+}
+
+#[tokio::test]
+async fn blur_and_sharpen() {
+    let data_path = tempdir().unwrap();
+    let app = image_backend::build_app(StorageConfig::local(data_path.path()));
+    let server = TestServer::new(app).unwrap();
+
+    let upload = create_test_image(&server).await;
+    let blur_path = format!("/api/v1/image/{}/blur", upload.id.0);
+    let sharpen_path = format!("/api/v1/image/{}/sharpen", upload.id.0);
+
+    let res = server.post(&blur_path).await;
+    res.assert_status_ok();
+    let default_blur: UploadResult = res.json();
+
+    let res = server
+        .post(&blur_path)
+        .add_query_param("sigma", 10)
+        .add_query_param("kind", "fast")
+        .await;
+    res.assert_status_ok();
+    let fast_blur: UploadResult = res.json();
+
+    assert_eq!(default_blur.id, fast_blur.id);
+
+    let res = server
+        .post(&blur_path)
+        .add_query_param("sigma", 2.5)
+        .add_query_param("kind", "gaussian")
+        .await;
+    res.assert_status_ok();
+    let gaussian_blur: UploadResult = res.json();
+
+    assert_ne!(gaussian_blur.id, fast_blur.id);
+
+    let res = server
+        .post(&sharpen_path)
+        .add_query_param("sigma", 1.5)
+        .add_query_param("threshold", 4)
+        .await;
+    res.assert_status_ok();
+    let sharpened: UploadResult = res.json();
+
+    assert_ne!(sharpened.id, upload.id);
+
+    let res = server.post(&blur_path).add_query_param("sigma", 0).await;
+    res.assert_status_bad_request();
+
+    let res = server.post(&blur_path).add_query_param("kind", "box").await;
+    res.assert_status_bad_request();
+
+    let res = server
+        .post(&sharpen_path)
+        .add_query_param("threshold", 300)
+        .await;
+    res.assert_status_bad_request();
+}
+
+async fn get_dynamic_image(server: &TestServer, id: &ImageId) -> DynamicImage {
+    let res = server.get(&format!("/api/v1/image/{}", id.0)).await;
+    res.assert_status_ok();
+    image::load_from_memory(res.as_bytes()).unwrap()
+}
+
+async fn get_rgb_image(server: &TestServer, id: &ImageId) -> RgbImage {
+    get_dynamic_image(server, id).await.to_rgb8()
+}
+
+#[tokio::test]
+async fn crop_and_regions() {
+    let data_path = tempdir().unwrap();
+    let app = image_backend::build_app(StorageConfig::local(data_path.path()));
+    let server = TestServer::new(app).unwrap();
+
+    const WHITE: Rgb<u8> = Rgb([255, 255, 255]);
+    const BLACK: Rgb<u8> = Rgb([0, 0, 0]);
+
+    let res = server
+        .post("/api/v1/image")
+        .add_query_param("width", 64)
+        .add_query_param("height", 32)
+        .await;
+    res.assert_status_ok();
+    let blank: UploadResult = res.json();
+
+    // black square in the top left corner
+    let res = server
+        .post(&format!("/api/v1/image/{}/invert", blank.id.0))
+        .add_query_param("region", "0,0,16,16")
+        .await;
+    res.assert_status_ok();
+    let inverted: UploadResult = res.json();
+
+    let image = get_rgb_image(&server, &inverted.id).await;
+    assert_eq!(*image.get_pixel(15, 15), BLACK);
+    assert_eq!(*image.get_pixel(16, 16), WHITE);
+
+    let res = server
+        .post(&format!("/api/v1/image/{}/crop", inverted.id.0))
+        .add_query_param("x", 8)
+        .add_query_param("y", 8)
+        .add_query_param("width", 16)
+        .add_query_param("height", 16)
+        .await;
+    res.assert_status_ok();
+    let cropped: UploadResult = res.json();
+
+    assert_eq!((cropped.width, cropped.height), (16, 16));
+    let image = get_rgb_image(&server, &cropped.id).await;
+    assert_eq!(*image.get_pixel(7, 7), BLACK);
+    assert_eq!(*image.get_pixel(8, 8), WHITE);
+
+    let resize_path = format!("/api/v1/image/{}/resize", inverted.id.0);
+
+    let res = server
+        .post(&resize_path)
+        .add_query_param("width", 80)
+        .add_query_param("height", 40)
+        .add_query_param("fill", true)
+        .add_query_param("anchor", "bottom_right")
+        .await;
+    res.assert_status_ok();
+    let anchored: UploadResult = res.json();
+
+    let image = get_rgb_image(&server, &anchored.id).await;
+    assert_eq!(*image.get_pixel(15, 7), WHITE);
+    assert_eq!(*image.get_pixel(16, 8), BLACK);
+
+    let res = server
+        .post(&resize_path)
+        .add_query_param("width", 64)
+        .add_query_param("height", 32)
+        .add_query_param("fill", true)
+        .add_query_param("anchor", "center")
+        .add_query_param("x", -8)
+        .await;
+    res.assert_status_ok();
+    let shifted: UploadResult = res.json();
+
+    let image = get_rgb_image(&server, &shifted.id).await;
+    assert_eq!(*image.get_pixel(7, 0), BLACK);
+    assert_eq!(*image.get_pixel(8, 0), WHITE);
+
+    let res = server
+        .post(&format!("/api/v1/image/{}/blur", inverted.id.0))
+        .add_query_param("region", "32,0,32,32")
+        .await;
+    res.assert_status_ok();
+    let blurred: UploadResult = res.json();
+
+    // the black square is outside of the blurred region
+    let image = get_rgb_image(&server, &blurred.id).await;
+    assert_eq!(*image.get_pixel(0, 0), BLACK);
+
+    let res = server
+        .post(&format!("/api/v1/image/{}/blur", inverted.id.0))
+        .add_query_param("region", "60,0,8,8")
+        .await;
+    res.assert_status_bad_request();
+
+    let res = server
+        .post(&format!("/api/v1/image/{}/crop", inverted.id.0))
+        .add_query_param("x", 0)
+        .add_query_param("y", 0)
+        .add_query_param("width", 0)
+        .add_query_param("height", 16)
+        .await;
+    res.assert_status_bad_request();
+}
+
+#[tokio::test]
+async fn transparent_background() {
+    let data_path = tempdir().unwrap();
+    let app = image_backend::build_app(StorageConfig::local(data_path.path()));
+    let server = TestServer::new(app).unwrap();
+
+    let res = server
+        .post("/api/v1/image")
+        .add_query_param("width", 32)
+        .add_query_param("height", 32)
+        .add_query_param("background", "transparent")
+        .await;
+    res.assert_status_ok();
+    let blank: UploadResult = res.json();
+
+    let image = get_dynamic_image(&server, &blank.id).await;
+    assert!(image.color().has_alpha());
+    assert_eq!(image.to_rgba8().get_pixel(0, 0).0[3], 0);
+
+    // transforms keep the alpha channel
+    let res = server
+        .post(&format!("/api/v1/image/{}/invert", blank.id.0))
+        .add_query_param("region", "0,0,8,8")
+        .await;
+    res.assert_status_ok();
+    let inverted: UploadResult = res.json();
+
+    let image = get_dynamic_image(&server, &inverted.id).await.to_rgba8();
+    assert_eq!(image.get_pixel(0, 0).0, [255, 255, 255, 0]);
+    assert_eq!(image.get_pixel(8, 8).0, [0, 0, 0, 0]);
+
+    let res = server
+        .post(&format!("/api/v1/image/{}/resize", inverted.id.0))
+        .add_query_param("width", 48)
+        .add_query_param("height", 32)
+        .add_query_param("fill", true)
+        .add_query_param("background", "#ff000080")
+        .await;
+    res.assert_status_ok();
+    let resized: UploadResult = res.json();
+
+    let image = get_dynamic_image(&server, &resized.id).await.to_rgba8();
+    assert_eq!(image.get_pixel(0, 0).0, [255, 255, 255, 0]);
+    assert_eq!(image.get_pixel(40, 0).0, [255, 0, 0, 128]);
+
+    // opaque and transparent images with the same colours differ
+    let res = server
+        .post("/api/v1/image")
+        .add_query_param("width", 32)
+        .add_query_param("height", 32)
+        .add_query_param("background", "#000000")
+        .await;
+    res.assert_status_ok();
+    let black: UploadResult = res.json();
+    assert_ne!(black.id, blank.id);
+
+    let res = server
+        .post("/api/v1/image")
+        .add_query_param("width", 32)
+        .add_query_param("height", 32)
+        .add_query_param("background", "#00000")
+        .await;
+    res.assert_status_bad_request();
+}
+
+async fn upload_bytes(server: &TestServer, data: Vec<u8>) -> TestResponse {
+    // the declared type is ignored, the format is detected from the content
+    server
+        .post("/api/v1/image")
+        .multipart(MultipartForm::new().add_part(
+            "image",
+            Part::bytes(data).mime_type("application/octet-stream"),
+        ))
+        .await
+}
+
+#[tokio::test]
+async fn upload_other_formats() {
+    let data_path = tempdir().unwrap();
+    let app = image_backend::build_app(StorageConfig::local(data_path.path()));
+    let server = TestServer::new(app).unwrap();
+
+    let image = DynamicImage::ImageRgb8(RgbImage::from_pixel(24, 16, Rgb([200, 100, 50])));
+
+    for format in [
+        ImageFormat::Jpeg,
+        ImageFormat::WebP,
+        ImageFormat::Gif,
+        ImageFormat::Bmp,
+        ImageFormat::Tiff,
+    ] {
+        let mut data = Vec::new();
+        image.write_to(&mut Cursor::new(&mut data), format).unwrap();
+
+        let res = upload_bytes(&server, data).await;
+        res.assert_status_ok();
+        let upload: UploadResult = res.json();
+        assert_eq!((upload.width, upload.height), (24, 16), "{format:?}");
+
+        // stored in the canonical format
+        let res = server.get(&format!("/api/v1/image/{}", upload.id.0)).await;
+        res.assert_status_ok();
+        res.assert_header(CONTENT_TYPE, "image/png");
+        assert_eq!(
+            image::guess_format(res.as_bytes()).unwrap(),
+            ImageFormat::Png
+        );
+    }
+
+    let res = upload_bytes(&server, b"<svg></svg>".to_vec()).await;
+    res.assert_status_bad_request();
+}
+
+#[tokio::test]
+async fn upload_animated_gif() {
+    let data_path = tempdir().unwrap();
+    let app = image_backend::build_app(StorageConfig::local(data_path.path()));
+    let server = TestServer::new(app).unwrap();
+
+    let mut data = Vec::new();
+    GifEncoder::new(&mut data)
+        .encode_frames([
+            Frame::new(RgbaImage::from_pixel(8, 8, Rgba([255, 0, 0, 255]))),
+            Frame::new(RgbaImage::from_pixel(8, 8, Rgba([0, 0, 255, 255]))),
+        ])
+        .unwrap();
+
+    let res = upload_bytes(&server, data).await;
+    res.assert_status_ok();
+    let upload: UploadResult = res.json();
+
+    let image = get_rgb_image(&server, &upload.id).await;
+    assert_eq!(*image.get_pixel(0, 0), Rgb([255, 0, 0]));
+}
+
+#[tokio::test]
+async fn export_formats() {
+    let data_path = tempdir().unwrap();
+    let app = image_backend::build_app(StorageConfig::local(data_path.path()));
+    let server = TestServer::new(app).unwrap();
+
+    let upload = create_test_image(&server).await;
+    let path = format!("/api/v1/image/{}", upload.id.0);
+
+    for (format, mime_type, image_format) in [
+        ("jpeg", "image/jpeg", ImageFormat::Jpeg),
+        ("webp", "image/webp", ImageFormat::WebP),
+        ("bmp", "image/bmp", ImageFormat::Bmp),
+        ("tiff", "image/tiff", ImageFormat::Tiff),
+    ] {
+        let res = server.get(&path).add_query_param("format", format).await;
+        res.assert_status_ok();
+        res.assert_header(CONTENT_TYPE, mime_type);
+        assert_eq!(image::guess_format(res.as_bytes()).unwrap(), image_format);
+    }
+
+    let res = server
+        .get(&path)
+        .add_header(ACCEPT, "image/png;q=0.5, image/webp")
+        .await;
+    res.assert_status_ok();
+    res.assert_header(CONTENT_TYPE, "image/webp");
+    res.assert_header(VARY, "accept");
+
+    let res = server
+        .get(&path)
+        .add_header(ACCEPT, "text/html, */*;q=0.1")
+        .await;
+    res.assert_status_ok();
+    res.assert_header(CONTENT_TYPE, "image/png");
+
+    let res = server.get(&path).add_header(ACCEPT, "text/html").await;
+    res.assert_status(StatusCode::NOT_ACCEPTABLE);
+
+    let low = server
+        .get(&path)
+        .add_query_param("format", "jpeg")
+        .add_query_param("quality", 10)
+        .await;
+    low.assert_status_ok();
+
+    let high = server
+        .get(&path)
+        .add_query_param("format", "jpeg")
+        .add_query_param("quality", 95)
+        .await;
+    high.assert_status_ok();
+
+    assert!(low.as_bytes().len() < high.as_bytes().len());
+
+    let res = server
+        .get(&path)
+        .add_query_param("format", "jpeg")
+        .add_query_param("quality", 0)
+        .await;
+    res.assert_status_bad_request();
+
+    // conversions are served from the cache without touching storage
+    let id = &upload.id.0;
+    std::fs::remove_file(
+        data_path
+            .path()
+            .join(&id[1..3])
+            .join(&id[3..5])
+            .join(format!("{id}.png")),
+    )
+    .unwrap();
+
+    let res = server.get(&path).add_query_param("format", "webp").await;
+    res.assert_status_ok();
+
+    let res = server.get(&path).await;
+    res.assert_status_not_found();
+}
+
+#[tokio::test]
+async fn renditions() {
+    let data_path = tempdir().unwrap();
+    let app = image_backend::build_app(StorageConfig::local(data_path.path()));
+    let server = TestServer::new(app).unwrap();
+
+    let res = server
+        .post("/api/v1/image")
+        .add_query_param("width", 400)
+        .add_query_param("height", 200)
+        .await;
+    res.assert_status_ok();
+    let upload: UploadResult = res.json();
+    let path = format!("/api/v1/image/{}", upload.id.0);
+
+    for (w, h, fit, expected) in [
+        (Some(100), None, None, (100, 50)),
+        (None, Some(100), None, (200, 100)),
+        (Some(100), Some(100), Some("contain"), (100, 50)),
+        (Some(100), Some(100), Some("cover"), (100, 100)),
+        (Some(800), None, None, (800, 400)),
+    ] {
+        let mut req = server.get(&path);
+        if let Some(w) = w {
+            req = req.add_query_param("w", w);
+        }
+        if let Some(h) = h {
+            req = req.add_query_param("h", h);
+        }
+        if let Some(fit) = fit {
+            req = req.add_query_param("fit", fit);
+        }
+
+        let res = req.await;
+        res.assert_status_ok();
+        let image = image::load_from_memory(res.as_bytes()).unwrap();
+        assert_eq!((image.width(), image.height()), expected);
+    }
+
+    let res = server
+        .get(&path)
+        .add_query_param("w", 64)
+        .add_query_param("format", "webp")
+        .await;
+    res.assert_status_ok();
+    res.assert_header(CONTENT_TYPE, "image/webp");
+    assert_eq!(
+        image::guess_format(res.as_bytes()).unwrap(),
+        ImageFormat::WebP
+    );
+
+    for (w, h) in [(0, 100), (100, 4096)] {
+        let res = server
+            .get(&path)
+            .add_query_param("w", w)
+            .add_query_param("h", h)
+            .await;
+        res.assert_status_bad_request();
+    }
+
+    let res = server.get(&path).add_query_param("fit", "stretch").await;
+    res.assert_status_bad_request();
+
+    // renditions are kept on disk and dropped along with the image
+    let cache_dir = data_path.path().join("cache").join(&upload.id.0);
+    assert_eq!(std::fs::read_dir(&cache_dir).unwrap().count(), 6);
+
+    let res = server.delete(&path).await;
+    res.assert_status(StatusCode::NO_CONTENT);
+    assert!(!cache_dir.exists());
+}
+
+#[tokio::test]
+async fn renditions_are_cached() {
+    let data_path = tempdir().unwrap();
+    let app = image_backend::build_app(StorageConfig::local(data_path.path()));
+    let server = TestServer::new(app).unwrap();
+
+    let upload = create_test_image(&server).await;
+    let path = format!("/api/v1/image/{}", upload.id.0);
+
+    let res = server.get(&path).add_query_param("w", 128).await;
+    res.assert_status_ok();
+    let rendition = res.as_bytes().clone();
+
+    let id = &upload.id.0;
+    std::fs::remove_file(
+        data_path
+            .path()
+            .join(&id[1..3])
+            .join(&id[3..5])
+            .join(format!("{id}.png")),
+    )
+    .unwrap();
+
+    // a restarted service picks up the renditions already on disk
+    let app = image_backend::build_app(StorageConfig::local(data_path.path()));
+    let server = TestServer::new(app).unwrap();
+
+    let res = server.get(&path).add_query_param("w", 128).await;
+    res.assert_status_ok();
+    assert_eq!(res.as_bytes(), &rendition);
+
+    let res = server.get(&path).add_query_param("w", 64).await;
+    res.assert_status_not_found();
+}
+
+#[tokio::test]
+async fn thumbnails() {
+    let data_path = tempdir().unwrap();
+    let app = image_backend::build_app(StorageConfig::local(data_path.path()));
+    let server = TestServer::new(app).unwrap();
+
+    let res = server
+        .post("/api/v1/image")
+        .add_query_param("width", 400)
+        .add_query_param("height", 200)
+        .add_query_param("background", "#000000")
+        .await;
+    res.assert_status_ok();
+    let upload: UploadResult = res.json();
+
+    let thumbnail = |width: u32, height: u32, fit: &'static str| {
+        server
+            .post(&format!("/api/v1/image/{}/thumbnail", upload.id.0))
+            .add_query_param("width", width)
+            .add_query_param("height", height)
+            .add_query_param("fit", fit)
+            .add_query_param("background", "#ff0000")
+    };
+
+    // letterboxed in the background colour
+    let res = thumbnail(100, 100, "contain").await;
+    res.assert_status_ok();
+    let result: UploadResult = res.json();
+    assert_eq!((result.width, result.height), (100, 100));
+
+    let image = get_rgb_image(&server, &result.id).await;
+    assert_eq!(*image.get_pixel(50, 10), Rgb([255, 0, 0]));
+    assert_eq!(*image.get_pixel(50, 50), Rgb([0, 0, 0]));
+    assert_eq!(*image.get_pixel(50, 90), Rgb([255, 0, 0]));
+
+    let res = thumbnail(100, 100, "cover").await;
+    res.assert_status_ok();
+    let result: UploadResult = res.json();
+    assert_eq!((result.width, result.height), (100, 100));
+
+    let image = get_rgb_image(&server, &result.id).await;
+    assert!(image.pixels().all(|pixel| *pixel == Rgb([0, 0, 0])));
+
+    let res = thumbnail(100, 100, "within").await;
+    res.assert_status_ok();
+    let result: UploadResult = res.json();
+    assert_eq!((result.width, result.height), (100, 50));
+
+    // never enlarged
+    let res = thumbnail(1000, 1000, "within").await;
+    res.assert_status_ok();
+    let result: UploadResult = res.json();
+    assert_eq!(result.id, upload.id);
+
+    let res = thumbnail(0, 100, "contain").await;
+    res.assert_status_bad_request();
+
+    let res = server
+        .post(&format!("/api/v1/image/{}/pipeline", upload.id.0))
+        .json(
+            &Pipeline::new()
+                .crop(0, 0, 200, 200)
+                .thumbnail(64, 32, Fit::Cover),
+        )
+        .await;
+    res.assert_status_ok();
+    let result: UploadResult = res.json();
+    assert_eq!((result.width, result.height), (64, 32));
+}
+
+#[tokio::test]
+async fn conditional_requests() {
+    let data_path = tempdir().unwrap();
+    let app = image_backend::build_app(StorageConfig::local(data_path.path()));
+    let server = TestServer::new(app).unwrap();
+
+    let upload = create_test_image(&server).await;
+    let path = format!("/api/v1/image/{}", upload.id.0);
+    let etag = format!("\"{}\"", upload.id.0);
+
+    let res = server.get(&path).await;
+    res.assert_status_ok();
+    res.assert_header(ETAG, &etag);
+    res.assert_header(CACHE_CONTROL, "public, max-age=31536000, immutable");
+
+    for if_none_match in [
+        etag.clone(),
+        format!("W/{etag}"),
+        format!("\"other\", {etag}"),
+        "*".to_string(),
+    ] {
+        let res = server
+            .get(&path)
+            .add_header(IF_NONE_MATCH, &if_none_match)
+            .await;
+        res.assert_status(StatusCode::NOT_MODIFIED);
+        res.assert_header(ETAG, &etag);
+        assert!(res.as_bytes().is_empty());
+    }
+
+    let res = server
+        .get(&path)
+        .add_header(IF_NONE_MATCH, "\"other\"")
+        .await;
+    res.assert_status_ok();
+
+    // conversions and renditions have their own tags
+    let res = server
+        .get(&path)
+        .add_query_param("format", "jpeg")
+        .add_header(IF_NONE_MATCH, &etag)
+        .await;
+    res.assert_status_ok();
+    let jpeg_etag = format!("\"{}.jpeg-q90\"", upload.id.0);
+    res.assert_header(ETAG, &jpeg_etag);
+
+    let res = server
+        .get(&path)
+        .add_query_param("format", "jpeg")
+        .add_header(IF_NONE_MATCH, &jpeg_etag)
+        .await;
+    res.assert_status(StatusCode::NOT_MODIFIED);
+
+    let res = server.get(&path).add_query_param("w", 64).await;
+    res.assert_status_ok();
+    let rendition_etag = res.header(ETAG);
+    assert_ne!(rendition_etag, etag);
+
+    let res = server
+        .get(&path)
+        .add_query_param("w", 64)
+        .add_header(IF_NONE_MATCH, rendition_etag)
+        .await;
+    res.assert_status(StatusCode::NOT_MODIFIED);
+
+    let res = server.delete(&path).await;
+    res.assert_status(StatusCode::NO_CONTENT);
+
+    let res = server.get(&path).add_header(IF_NONE_MATCH, &etag).await;
+    res.assert_status_not_found();
+}
+
+#[tokio::test]
+async fn range_requests() {
+    let data_path = tempdir().unwrap();
+    let app = image_backend::build_app(StorageConfig::local(data_path.path()));
+    let server = TestServer::new(app).unwrap();
+
+    let upload = create_test_image(&server).await;
+    let path = format!("/api/v1/image/{}", upload.id.0);
+    let etag = format!("\"{}\"", upload.id.0);
+
+    let res = server.get(&path).await;
+    res.assert_status_ok();
+    res.assert_header(ACCEPT_RANGES, "bytes");
+    let full = res.as_bytes().clone();
+    let len = full.len();
+
+    for (range, expected) in [
+        ("bytes=0-99", 0..100),
+        ("bytes=100-", 100..len),
+        ("bytes=-50", len - 50..len),
+        ("bytes=10-99999999", 10..len),
+    ] {
+        let res = server.get(&path).add_header(RANGE, range).await;
+        res.assert_status(StatusCode::PARTIAL_CONTENT);
+        res.assert_header(
+            CONTENT_RANGE,
+            format!("bytes {}-{}/{len}", expected.start, expected.end - 1),
+        );
+        assert_eq!(res.as_bytes(), &full[expected]);
+    }
+
+    // resuming a download
+    let res = server
+        .get(&path)
+        .add_header(RANGE, "bytes=1000-")
+        .add_header(IF_RANGE, &etag)
+        .await;
+    res.assert_status(StatusCode::PARTIAL_CONTENT);
+    assert_eq!(res.as_bytes(), &full[1000..]);
+
+    // the copy being resumed is outdated
+    let res = server
+        .get(&path)
+        .add_header(RANGE, "bytes=1000-")
+        .add_header(IF_RANGE, "\"other\"")
+        .await;
+    res.assert_status_ok();
+    assert_eq!(res.as_bytes(), &full);
+
+    // ignored
+    for range in ["bytes=0-1,5-6", "lines=1-2", "bytes=5-1", "bytes=x-"] {
+        let res = server.get(&path).add_header(RANGE, range).await;
+        res.assert_status_ok();
+        assert_eq!(res.as_bytes(), &full);
+    }
+
+    for range in [format!("bytes={len}-"), "bytes=-0".to_string()] {
+        let res = server.get(&path).add_header(RANGE, &range).await;
+        res.assert_status(StatusCode::RANGE_NOT_SATISFIABLE);
+        res.assert_header(CONTENT_RANGE, format!("bytes */{len}"));
+    }
+
+    // conversions can be resumed too
+    let res = server
+        .get(&path)
+        .add_query_param("format", "webp")
+        .add_header(RANGE, "bytes=0-9")
+        .await;
+    res.assert_status(StatusCode::PARTIAL_CONTENT);
+    assert_eq!(res.as_bytes().len(), 10);
+}
+
+#[tokio::test]
+async fn diff_images() {
+    let data_path = tempdir().unwrap();
+    let app = image_backend::build_app(StorageConfig::local(data_path.path()));
+    let server = TestServer::new(app).unwrap();
+
+    let res = server
+        .post("/api/v1/image")
+        .add_query_param("width", 100)
+        .add_query_param("height", 100)
+        .add_query_param("background", "#000000")
+        .await;
+    res.assert_status_ok();
+    let upload: UploadResult = res.json();
+
+    let res = server
+        .post(&format!("/api/v1/image/{}/invert", upload.id.0))
+        .add_query_param("region", "10,20,30,40")
+        .await;
+    res.assert_status_ok();
+    let inverted: UploadResult = res.json();
+
+    let diff =
+        |a: &ImageId, b: &ImageId| server.post(&format!("/api/v1/image/{}/diff/{}", a.0, b.0));
+
+    let res = diff(&upload.id, &inverted.id).await;
+    res.assert_status_ok();
+    let result: DiffResult = res.json();
+
+    assert_eq!((result.width, result.height), (100, 100));
+    assert_eq!(result.changed_pixels, 1200);
+    assert_eq!(result.percent_changed, 12.0);
+    assert_eq!(
+        result.bounding_box,
+        Some(Region {
+            x: 10,
+            y: 20,
+            width: 30,
+            height: 40,
+        })
+    );
+
+    // changes in red on a faded copy of the second image
+    let image = get_rgb_image(&server, result.id.as_ref().unwrap()).await;
+    assert_eq!(*image.get_pixel(20, 30), Rgb([255, 0, 0]));
+    assert_eq!(*image.get_pixel(80, 80), Rgb([192, 192, 192]));
+
+    let res = diff(&upload.id, &inverted.id)
+        .add_query_param("stats_only", true)
+        .await;
+    res.assert_status_ok();
+    let stats: DiffResult = res.json();
+    assert_eq!(stats.id, None);
+    assert_eq!(stats.changed_pixels, 1200);
+
+    let res = diff(&upload.id, &upload.id).await;
+    res.assert_status_ok();
+    let result: DiffResult = res.json();
+    assert_eq!(result.changed_pixels, 0);
+    assert_eq!(result.bounding_box, None);
+
+    // pixels of only one of the images count as changed
+    let res = server
+        .post(&format!("/api/v1/image/{}/resize", upload.id.0))
+        .add_query_param("width", 150)
+        .add_query_param("height", 100)
+        .add_query_param("fill", true)
+        .add_query_param("background", "#000000")
+        .add_query_param("anchor", "top_left")
+        .await;
+    res.assert_status_ok();
+    let wider: UploadResult = res.json();
+
+    let res = diff(&wider.id, &upload.id).await;
+    res.assert_status_ok();
+    let result: DiffResult = res.json();
+
+    assert_eq!((result.width, result.height), (150, 100));
+    assert_eq!(result.changed_pixels, 5000);
+    assert_eq!(
+        result.bounding_box,
+        Some(Region {
+            x: 100,
+            y: 0,
+            width: 50,
+            height: 100,
+        })
+    );
+
+    let res = diff(&upload.id, &ImageId("missing".to_string())).await;
+    res.assert_status_not_found();
+}
@@ -1,2 +1,4 @@
 #[cfg(test)]
 mod image;
+#[cfg(test)]
+mod storage;
@@ -0,0 +1,534 @@
+use std::collections::BTreeMap;
+use std::io::Cursor;
+use std::sync::{Arc, Mutex};
+
+use axum::Router;
+use axum::body::Bytes;
+use axum::extract::{Path, Query, State};
+use axum::http::header::{
+    AUTHORIZATION, CONTENT_LENGTH, CONTENT_RANGE, ETAG, IF_MATCH, LAST_MODIFIED, RANGE,
+};
+use axum::http::{HeaderMap, StatusCode};
+use axum::response::IntoResponse;
+use axum::routing::get;
+use axum_test::TestServer;
+use chrono::{DateTime, TimeDelta, Utc};
+use image::{ImageFormat, Rgb, RgbImage};
+use image_backend::model::{ImageId, ImagePage, UploadResult, VerifyReport};
+use image_backend::storage::{LocalStorage, S3Config, S3Storage, Storage, StorageConfig};
+use image_backend::{Globals, verify};
+use tempfile::tempdir;
+
+use crate::image::create_test_image;
+
+type Objects = Arc<Mutex<BTreeMap<String, (Vec<u8>, DateTime<Utc>)>>>;
+
+/// Page size of the fake list endpoint, small enough to exercise
+/// continuation tokens.
+const LIST_PAGE_SIZE: usize = 2;
+
+/// Starts a minimal S3-compatible server standing in for MinIO. It keeps
+/// objects of a single bucket in memory and only checks that requests
+/// carry a SigV4 authorization header. Returns the configuration to reach it
+/// and its objects by key.
+async fn start_fake_s3() -> (S3Config, Objects) {
+    let objects = Objects::default();
+
+    let app = Router::new()
+        .route("/{bucket}", get(list_objects))
+        .route(
+            "/{bucket}/{*key}",
+            get(get_object).put(put_object).delete(delete_object),
+        )
+        .with_state(objects.clone());
+
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+
+    let config = S3Config {
+        endpoint: format!("http://{addr}"),
+        bucket: "images".to_string(),
+        region: "us-east-1".to_string(),
+        access_key: "minio".to_string(),
+        secret_key: "minio123".to_string(),
+    };
+
+    (config, objects)
+}
+
+fn is_signed(headers: &HeaderMap) -> bool {
+    headers
+        .get(AUTHORIZATION)
+        .and_then(|v| v.to_str().ok())
+        .is_some_and(|v| v.starts_with("AWS4-HMAC-SHA256 Credential=minio/"))
+        && headers.contains_key("x-amz-date")
+        && headers.contains_key("x-amz-content-sha256")
+}
+
+async fn put_object(
+    State(objects): State<Objects>,
+    Path((_, key)): Path<(String, String)>,
+    headers: HeaderMap,
+    body: Bytes,
+) -> StatusCode {
+    if !is_signed(&headers) {
+        return StatusCode::FORBIDDEN;
+    }
+
+    let mut objects = objects.lock().unwrap();
+    objects.insert(key, (body.to_vec(), Utc::now()));
+    StatusCode::OK
+}
+
+async fn get_object(
+    State(objects): State<Objects>,
+    Path((_, key)): Path<(String, String)>,
+    headers: HeaderMap,
+) -> impl IntoResponse {
+    if !is_signed(&headers) {
+        return Err(StatusCode::FORBIDDEN);
+    }
+
+    let objects = objects.lock().unwrap();
+    let (data, modified_at) = objects.get(&key).ok_or(StatusCode::NOT_FOUND)?;
+
+    // only the `bytes=<start>-<end>` form sent by the storage backend
+    let range = headers
+        .get(RANGE)
+        .and_then(|v| v.to_str().ok()?.strip_prefix("bytes=")?.split_once('-'))
+        .map(|(start, end)| start.parse::<usize>().unwrap()..end.parse::<usize>().unwrap() + 1);
+
+    let mut headers = HeaderMap::new();
+    headers.insert(ETAG, etag(data).parse().unwrap());
+    headers.insert(
+        LAST_MODIFIED,
+        modified_at
+            .format("%a, %d %b %Y %H:%M:%S GMT")
+            .to_string()
+            .parse()
+            .unwrap(),
+    );
+
+    let Some(range) = range else {
+        headers.insert(CONTENT_LENGTH, data.len().into());
+        return Ok((StatusCode::OK, headers, data.clone()));
+    };
+
+    let content_range = format!("bytes {}-{}/{}", range.start, range.end - 1, data.len());
+    headers.insert(CONTENT_RANGE, content_range.parse().unwrap());
+    headers.insert(CONTENT_LENGTH, range.len().into());
+
+    Ok((StatusCode::PARTIAL_CONTENT, headers, data[range].to_vec()))
+}
+
+async fn delete_object(
+    State(objects): State<Objects>,
+    Path((_, key)): Path<(String, String)>,
+    headers: HeaderMap,
+) -> StatusCode {
+    if !is_signed(&headers) {
+        return StatusCode::FORBIDDEN;
+    }
+
+    let mut objects = objects.lock().unwrap();
+
+    let if_match = headers.get(IF_MATCH).and_then(|v| v.to_str().ok());
+    let current = objects.get(&key).map(|(data, _)| etag(data));
+
+    if if_match.is_some_and(|v| Some(v) != current.as_deref()) {
+        return StatusCode::PRECONDITION_FAILED;
+    }
+
+    objects.remove(&key);
+    StatusCode::NO_CONTENT
+}
+
+/// ETag of an object, derived from its content like the ones S3 sends.
+fn etag(data: &[u8]) -> String {
+    format!("\"{}\"", blake3::hash(data).to_hex())
+}
+
+async fn list_objects(
+    State(objects): State<Objects>,
+    Query(query): Query<BTreeMap<String, String>>,
+    headers: HeaderMap,
+) -> Result<String, StatusCode> {
+    if !is_signed(&headers) || query.get("list-type").map(String::as_str) != Some("2") {
+        return Err(StatusCode::FORBIDDEN);
+    }
+
+    let objects = objects.lock().unwrap();
+    let start = match (query.get("continuation-token"), query.get("start-after")) {
+        (Some(token), _) => token.parse().unwrap(),
+        (None, Some(key)) => objects.keys().take_while(|v| *v <= key).count(),
+        (None, None) => 0,
+    };
+
+    let mut xml = String::from("<ListBucketResult>");
+    for (key, (data, modified_at)) in objects.iter().skip(start).take(LIST_PAGE_SIZE) {
+        xml.push_str(&format!(
+            "<Contents><Key>{key}</Key><LastModified>{}</LastModified><Size>{}</Size></Contents>",
+            modified_at.to_rfc3339(),
+            data.len()
+        ));
+    }
+
+    if start + LIST_PAGE_SIZE < objects.len() {
+        xml.push_str(&format!(
+            "<IsTruncated>true</IsTruncated><NextContinuationToken>{}</NextContinuationToken>",
+            start + LIST_PAGE_SIZE
+        ));
+    } else {
+        xml.push_str("<IsTruncated>false</IsTruncated>");
+    }
+
+    xml.push_str("</ListBucketResult>");
+
+    Ok(xml)
+}
+
+async fn exercise_storage(server: &TestServer) {
+    let kitten = create_test_image(server).await;
+
+    for (width, height) in [(16, 16), (32, 16)] {
+        server
+            .post("/api/v1/image")
+            .add_query_param("width", width)
+            .add_query_param("height", height)
+            .await
+            .assert_status_ok();
+    }
+
+    let res = server.get("/api/v1/image").await;
+    res.assert_status_ok();
+    let page: ImagePage = res.json();
+    assert_eq!(page.items.len(), 3);
+    assert!(page.items.iter().any(|image| image.id == kitten.id));
+
+    // walking the pages one image at a time gives the same listing
+    let mut walked = Vec::new();
+    let mut after = None;
+
+    loop {
+        let mut req = server.get("/api/v1/image").add_query_param("limit", 1);
+        if let Some(after) = &after {
+            req = req.add_query_param("after", after);
+        }
+
+        let res = req.await;
+        res.assert_status_ok();
+        let page: ImagePage = res.json();
+        walked.extend(page.items);
+
+        match page.next_after {
+            Some(next_after) => after = Some(next_after.0),
+            None => break,
+        }
+    }
+
+    assert_eq!(walked, page.items);
+
+    // verifying a page at a time checks every image once
+    let mut checked = 0;
+    let mut after = None;
+
+    loop {
+        let mut req = server
+            .post("/api/v1/admin/verify")
+            .add_query_param("limit", 2);
+        if let Some(after) = &after {
+            req = req.add_query_param("after", after);
+        }
+
+        let res = req.await;
+        res.assert_status_ok();
+        let report: VerifyReport = res.json();
+        assert!(report.corrupt.is_empty());
+        checked += report.checked;
+
+        match report.next_after {
+            Some(next_after) => after = Some(next_after.0),
+            None => break,
+        }
+    }
+
+    assert_eq!(checked, 3);
+
+    let res = server.get(&format!("/api/v1/image/{}", kitten.id.0)).await;
+    res.assert_status_ok();
+    let full = res.as_bytes().clone();
+
+    let res = server
+        .get(&format!("/api/v1/image/{}", kitten.id.0))
+        .add_header(RANGE, "bytes=10-109")
+        .await;
+    res.assert_status(StatusCode::PARTIAL_CONTENT);
+    assert_eq!(res.as_bytes(), &full[10..110]);
+
+    // an image uploaded again after being listed survives a delete that is
+    // conditional on the listed time, as done by the garbage collector
+    let listed = page
+        .items
+        .iter()
+        .find(|image| image.id == kitten.id)
+        .unwrap()
+        .modified_at;
+
+    create_test_image(server).await;
+
+    let res = server
+        .delete(&format!("/api/v1/image/{}", kitten.id.0))
+        .add_query_param("unmodified_since", listed)
+        .await;
+    res.assert_status(StatusCode::PRECONDITION_FAILED);
+
+    let res = server.get(&format!("/api/v1/image/{}", kitten.id.0)).await;
+    res.assert_status_ok();
+
+    let orphan = page
+        .items
+        .iter()
+        .find(|image| image.id != kitten.id)
+        .unwrap();
+
+    let res = server
+        .delete(&format!("/api/v1/image/{}", orphan.id.0))
+        .add_query_param("unmodified_since", Utc::now() + TimeDelta::seconds(1))
+        .await;
+    res.assert_status(StatusCode::NO_CONTENT);
+
+    let res = server
+        .delete(&format!("/api/v1/image/{}", kitten.id.0))
+        .await;
+    res.assert_status(StatusCode::NO_CONTENT);
+
+    let res = server.get(&format!("/api/v1/image/{}", kitten.id.0)).await;
+    res.assert_status_not_found();
+
+    let res = server
+        .delete(&format!("/api/v1/image/{}", kitten.id.0))
+        .await;
+    res.assert_status_not_found();
+}
+
+#[tokio::test]
+async fn memory_storage() {
+    let app = image_backend::build_app(StorageConfig::Memory);
+    let server = TestServer::new(app).unwrap();
+    exercise_storage(&server).await;
+}
+
+#[tokio::test]
+async fn s3_storage() {
+    let (config, _) = start_fake_s3().await;
+
+    let app = image_backend::build_app(StorageConfig::S3(config));
+    let server = TestServer::new(app).unwrap();
+    exercise_storage(&server).await;
+}
+
+#[tokio::test]
+async fn s3_rejects_keys_outside_images() {
+    let (config, objects) = start_fake_s3().await;
+
+    let app = image_backend::build_app(StorageConfig::S3(config.clone()));
+    let server = TestServer::new(app).unwrap();
+
+    let upload = create_test_image(&server).await;
+    S3Storage::new(config).quarantine(&upload.id).await.unwrap();
+
+    // decoded into an id containing '/', which must not reach other keys
+    let path = format!("/api/v1/image/quarantine%2F{}", upload.id.0);
+
+    let res = server.get(&path).await;
+    res.assert_status_not_found();
+
+    let res = server.delete(&path).await;
+    res.assert_status_not_found();
+
+    let key = format!("quarantine/{}.png", upload.id.0);
+    assert!(objects.lock().unwrap().contains_key(&key));
+}
+
+#[tokio::test]
+async fn local_storage_is_sharded() {
+    let data_path = tempdir().unwrap();
+    let app = image_backend::build_app(StorageConfig::local(data_path.path()));
+    let server = TestServer::new(app).unwrap();
+
+    let upload = create_test_image(&server).await;
+    let id = &upload.id.0;
+
+    let sharded = data_path
+        .path()
+        .join(&id[1..3])
+        .join(&id[3..5])
+        .join(format!("{id}.png"));
+    assert!(sharded.exists());
+
+    exercise_storage(&server).await;
+}
+
+#[tokio::test]
+async fn migrate_flat_layout() {
+    let data_path = tempdir().unwrap();
+    let app = image_backend::build_app(StorageConfig::local(data_path.path()));
+    let server = TestServer::new(app).unwrap();
+
+    let upload = create_test_image(&server).await;
+    let id = &upload.id.0;
+
+    let sharded = data_path
+        .path()
+        .join(&id[1..3])
+        .join(&id[3..5])
+        .join(format!("{id}.png"));
+    let flat = data_path.path().join(format!("{id}.png"));
+    std::fs::rename(&sharded, &flat).unwrap();
+
+    // still served from the old location before the migration
+    let res = server.get(&format!("/api/v1/image/{id}")).await;
+    res.assert_status_ok();
+
+    let res = server.get("/api/v1/image").await;
+    let page: ImagePage = res.json();
+    assert_eq!(page.items.len(), 1);
+
+    let moved = LocalStorage::new(data_path.path())
+        .migrate_flat_layout()
+        .await
+        .unwrap();
+    assert_eq!(moved, 1);
+    assert!(sharded.exists());
+    assert!(!flat.exists());
+
+    let res = server.get(&format!("/api/v1/image/{id}")).await;
+    res.assert_status_ok();
+}
+
+#[tokio::test]
+async fn reject_path_traversal() {
+    let data_path = tempdir().unwrap();
+    let app = image_backend::build_app(StorageConfig::local(data_path.path()));
+    let server = TestServer::new(app).unwrap();
+
+    let res = server.get("/api/v1/image/..%2F..%2Fetc%2Fpasswd").await;
+    res.assert_status_not_found();
+}
+
+#[tokio::test]
+async fn verify_and_quarantine_corrupt_image() {
+    let data_path = tempdir().unwrap();
+    let app = image_backend::build_app(StorageConfig::local(data_path.path()));
+    let server = TestServer::new(app).unwrap();
+
+    let upload = create_test_image(&server).await;
+    let id = &upload.id.0;
+
+    let res = server.post("/api/v1/admin/verify").await;
+    res.assert_status_ok();
+    let report: VerifyReport = res.json();
+    assert_eq!(report.checked, 1);
+    assert!(report.corrupt.is_empty());
+
+    // fill the caches of converted and scaled images
+    let variants = [("format", "jpeg"), ("w", "64")];
+
+    for (name, value) in variants {
+        let res = server
+            .get(&format!("/api/v1/image/{id}"))
+            .add_query_param(name, value)
+            .await;
+        res.assert_status_ok();
+    }
+
+    // simulate a crash in the middle of a write
+    let path = data_path
+        .path()
+        .join(&id[1..3])
+        .join(&id[3..5])
+        .join(format!("{id}.png"));
+    let data = std::fs::read(&path).unwrap();
+    std::fs::write(&path, &data[..data.len() / 2]).unwrap();
+
+    let res = server.post("/api/v1/admin/verify").await;
+    res.assert_status_ok();
+    let report: VerifyReport = res.json();
+    assert_eq!(report.corrupt, vec![upload.id.clone()]);
+    assert!(path.exists());
+
+    let res = server
+        .post("/api/v1/admin/verify")
+        .add_query_param("quarantine", true)
+        .await;
+    res.assert_status_ok();
+    let report: VerifyReport = res.json();
+    assert_eq!(report.corrupt, vec![upload.id.clone()]);
+    assert!(!path.exists());
+    assert!(
+        data_path
+            .path()
+            .join("quarantine")
+            .join(format!("{id}.png"))
+            .exists()
+    );
+
+    let res = server.get(&format!("/api/v1/image/{id}")).await;
+    res.assert_status_not_found();
+
+    for (name, value) in variants {
+        let res = server
+            .get(&format!("/api/v1/image/{id}"))
+            .add_query_param(name, value)
+            .await;
+        res.assert_status_not_found();
+    }
+
+    // uploading the same content again repairs the image
+    create_test_image(&server).await;
+    assert_eq!(std::fs::read(&path).unwrap(), data);
+}
+
+#[tokio::test]
+async fn verify_legacy_rgb_image() {
+    let data_path = tempdir().unwrap();
+    let globals = Globals::new(&StorageConfig::local(data_path.path()));
+    let storage = LocalStorage::new(data_path.path());
+
+    // images stored before alpha support are hashed as rgb under "v0"
+    let image = RgbImage::from_pixel(4, 2, Rgb([10, 20, 30]));
+    let mut hasher = blake3::Hasher::new();
+    hasher.update(b"v0");
+    hasher.update(&4u32.to_le_bytes());
+    hasher.update(&2u32.to_le_bytes());
+    hasher.update(image.as_raw());
+    let id = ImageId(format!("0{}", hasher.finalize().to_hex()));
+
+    let mut data = Vec::new();
+    image
+        .write_to(&mut Cursor::new(&mut data), ImageFormat::Png)
+        .unwrap();
+    storage.put(&id, data).await.unwrap();
+
+    let report = verify::verify_images(&globals, None, 100, false)
+        .await
+        .unwrap();
+    assert_eq!(report.checked, 1);
+    assert!(report.corrupt.is_empty());
+
+    // transforms store the result under a current id
+    let app = image_backend::build_app(StorageConfig::local(data_path.path()));
+    let server = TestServer::new(app).unwrap();
+
+    let res = server.post(&format!("/api/v1/image/{}/invert", id.0)).await;
+    res.assert_status_ok();
+    let upload: UploadResult = res.json();
+    assert!(upload.id.0.starts_with('1'));
+
+    let report = verify::verify_images(&globals, None, 100, false)
+        .await
+        .unwrap();
+    assert_eq!(report.checked, 2);
+    assert!(report.corrupt.is_empty());
+}
@@ -0,0 +1 @@
+alter table drawings drop column background;
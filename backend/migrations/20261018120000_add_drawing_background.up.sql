@@ -0,0 +1 @@
+alter table drawings add column background text not null default 'white';
@@ -0,0 +1,5 @@
+alter table drawing_versions
+    drop column operation,
+    drop column parameters,
+    drop column author,
+    drop column parent_version_id;
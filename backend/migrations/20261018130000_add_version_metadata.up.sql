@@ -0,0 +1,17 @@
+alter table drawing_versions
+    add column operation text not null default 'unknown',
+    add column parameters jsonb,
+    add column author text references users (username),
+    add column parent_version_id integer;
+
+-- versions used to be appended one after another by the owner
+update drawing_versions set parent_version_id = nullif(version_id - 1, 0);
+
+-- versions of deleted drawings are kept, but there is no owner left to take
+-- their author from, so the column stays nullable for them
+update drawing_versions v
+set author = d.owner
+from drawings d
+where d.id = v.drawing_id;
+
+alter table drawing_versions alter column operation drop default;
@@ -0,0 +1 @@
+alter table drawings drop column current_version_id;
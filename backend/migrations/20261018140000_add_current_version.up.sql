@@ -0,0 +1,19 @@
+alter table drawings add column current_version_id integer;
+
+-- the image a drawing was created with becomes its first version, so edits
+-- can be undone all the way back to it. Drawings edited before this only
+-- kept the image of each edit, the one they were created with is gone, so
+-- their history keeps starting at the first edit and that can't be undone.
+insert into drawing_versions (
+    drawing_id, version_id, width, height, image_id, thumbnail_image_id, created_at,
+    operation, author)
+select id, 1, width, height, image_id, thumbnail_image_id, created_at, 'create', owner
+from drawings d
+where not exists (select 1 from drawing_versions v where v.drawing_id = d.id);
+
+update drawings d
+set current_version_id = (
+    select max(version_id) from drawing_versions v where v.drawing_id = d.id
+);
+
+alter table drawings alter column current_version_id set not null;
@@ -0,0 +1 @@
+drop table drawing_permissions;
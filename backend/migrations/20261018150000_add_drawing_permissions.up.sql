@@ -0,0 +1,9 @@
+create table drawing_permissions (
+    drawing_id integer not null references drawings (id) on delete cascade,
+    username text not null references users (username),
+    role text not null,
+    created_at timestamp not null,
+    primary key (drawing_id, username)
+);
+
+create index drawing_permissions_username_idx on drawing_permissions (username);
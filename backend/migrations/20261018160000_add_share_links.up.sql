@@ -0,0 +1,18 @@
+create table share_links (
+    id serial primary key,
+    token bytea not null unique,
+    drawing_id integer not null references drawings (id) on delete cascade,
+    -- shares the drawing's current version when null
+    version_id integer,
+    password_hash text,
+    created_by text not null references users (username),
+    created_at timestamp not null,
+    expires_at timestamp,
+    last_used_at timestamp,
+    last_ip_address text,
+    use_count integer not null default 0,
+    foreign key (drawing_id, version_id)
+        references drawing_versions (drawing_id, version_id) on delete cascade
+);
+
+create index share_links_drawing_id_idx on share_links (drawing_id);
@@ -0,0 +1,5 @@
+alter table drawings drop column team_id;
+
+drop table team_invites;
+drop table team_members;
+drop table teams;
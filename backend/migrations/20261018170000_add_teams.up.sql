@@ -0,0 +1,30 @@
+create table teams (
+    id serial primary key,
+    name text not null,
+    created_at timestamp not null
+);
+
+create table team_members (
+    team_id integer not null references teams (id) on delete cascade,
+    username text not null references users (username),
+    role text not null,
+    joined_at timestamp not null,
+    primary key (team_id, username)
+);
+
+create index team_members_username_idx on team_members (username);
+
+create table team_invites (
+    team_id integer not null references teams (id) on delete cascade,
+    username text not null references users (username),
+    role text not null,
+    invited_by text not null references users (username),
+    created_at timestamp not null,
+    primary key (team_id, username)
+);
+
+-- team drawings are still owned by one of the members, who is replaced when
+-- they leave the team
+alter table drawings add column team_id integer references teams (id);
+
+create index drawings_team_id_idx on drawings (team_id);
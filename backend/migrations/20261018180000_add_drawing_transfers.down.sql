@@ -0,0 +1,2 @@
+drop table drawing_owner_history;
+drop table drawing_transfers;
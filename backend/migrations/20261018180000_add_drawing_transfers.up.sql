@@ -0,0 +1,27 @@
+create table drawing_transfers (
+    id serial primary key,
+    drawing_id integer not null references drawings (id) on delete cascade,
+    from_user text not null references users (username),
+    to_user text not null references users (username),
+    status text not null,
+    created_at timestamp not null,
+    resolved_at timestamp
+);
+
+-- a drawing can only be offered to one user at a time
+create unique index drawing_transfers_pending_idx on drawing_transfers (drawing_id)
+where status = 'pending';
+
+create index drawing_transfers_to_user_idx on drawing_transfers (to_user);
+
+create table drawing_owner_history (
+    id serial primary key,
+    drawing_id integer not null references drawings (id) on delete cascade,
+    previous_owner text not null references users (username),
+    new_owner text not null references users (username),
+    changed_at timestamp not null,
+    -- null for changes not requested by the owner, e.g. when leaving a team
+    transfer_id integer references drawing_transfers (id)
+);
+
+create index drawing_owner_history_drawing_id_idx on drawing_owner_history (drawing_id);
@@ -0,0 +1,4 @@
+drop table password_resets;
+drop table email_verifications;
+
+alter table users drop column email_verified_at;
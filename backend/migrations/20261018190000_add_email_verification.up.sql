@@ -0,0 +1,24 @@
+alter table users add column email_verified_at timestamp;
+
+-- accounts created before verification existed keep working
+update users set email_verified_at = now() at time zone 'utc';
+
+create table email_verifications (
+    token_hash bytea primary key,
+    username text not null references users (username) on delete cascade,
+    -- address the token was sent to, the user's email may change meanwhile
+    email text not null,
+    created_at timestamp not null,
+    expires_at timestamp not null
+);
+
+create index email_verifications_username_idx on email_verifications (username);
+
+create table password_resets (
+    token_hash bytea primary key,
+    username text not null references users (username) on delete cascade,
+    created_at timestamp not null,
+    expires_at timestamp not null
+);
+
+create index password_resets_username_idx on password_resets (username);
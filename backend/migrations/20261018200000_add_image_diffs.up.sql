@@ -0,0 +1,14 @@
+-- results of comparing two images, computed once by the image service.
+-- Images never change, so neither do their diffs. The highlight image is
+-- only stored once it is first requested.
+create table image_diffs (
+    image_id text not null,
+    other_image_id text not null,
+    width integer not null,
+    height integer not null,
+    changed_pixels bigint not null,
+    percent_changed double precision not null,
+    bounding_box jsonb,
+    diff_image_id text,
+    primary key (image_id, other_image_id)
+);
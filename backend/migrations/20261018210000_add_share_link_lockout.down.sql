@@ -0,0 +1,3 @@
+alter table share_links
+    drop column failed_attempts,
+    drop column locked_until;
@@ -0,0 +1,4 @@
+-- wrong passwords in a row, the link is locked for a while after too many
+alter table share_links
+    add column failed_attempts integer not null default 0,
+    add column locked_until timestamp;
@@ -0,0 +1 @@
+alter table password_resets drop column email;
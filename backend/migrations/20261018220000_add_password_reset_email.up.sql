@@ -0,0 +1,6 @@
+-- pending resets don't know which address they were sent to, they expire
+-- within the hour anyway
+delete from password_resets;
+
+-- address the token was sent to, the user's email may change meanwhile
+alter table password_resets add column email text not null;